@@ -0,0 +1,28 @@
+-- The schema before migrations were bundled. Deployments set up from it back then already
+-- have these, hence IF NOT EXISTS.
+CREATE EXTENSION IF NOT EXISTS citext;
+
+CREATE TABLE IF NOT EXISTS users (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username CITEXT UNIQUE NOT NULL,
+    password_hash TEXT NOT NULL,
+    meta JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id),
+    data JSONB NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS appauth (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT UNIQUE NOT NULL,
+    description TEXT,
+    token TEXT UNIQUE NOT NULL,
+    meta JSONB NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS idx_appauth__token ON appauth (token);
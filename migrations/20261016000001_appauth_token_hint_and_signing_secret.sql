@@ -0,0 +1,20 @@
+ALTER TABLE appauth
+    ADD COLUMN IF NOT EXISTS token_hint TEXT NOT NULL DEFAULT '',
+    ADD COLUMN IF NOT EXISTS signing_secret TEXT;
+
+-- Existing tokens get the hint `appauth::token_hint` gives new ones: the prefix up to the first
+-- `_`, and the last 4 characters unless that would reveal most of a short token.
+UPDATE appauth
+SET token_hint = hinted.prefix || '...' || CASE
+        WHEN char_length(appauth.token) < char_length(hinted.prefix) + 12 THEN ''
+        ELSE right(appauth.token, 4)
+    END
+FROM (
+    SELECT id, CASE
+            WHEN strpos(token, '_') BETWEEN 1 AND char_length(token) - 1
+                THEN left(token, strpos(token, '_'))
+            ELSE ''
+        END AS prefix
+    FROM appauth
+) hinted
+WHERE hinted.id = appauth.id AND appauth.token_hint = '';
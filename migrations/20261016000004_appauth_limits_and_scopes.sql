@@ -0,0 +1,5 @@
+ALTER TABLE appauth
+    ADD COLUMN IF NOT EXISTS rate_limit INTEGER,
+    ADD COLUMN IF NOT EXISTS remaining_quota BIGINT,
+    ADD COLUMN IF NOT EXISTS last_used_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,4 @@
+-- Session ids are whatever the id generator makes them, UUIDs only by default.
+ALTER TABLE sessions
+    ALTER COLUMN id DROP DEFAULT,
+    ALTER COLUMN id TYPE TEXT;
@@ -1,16 +1,28 @@
-CREATE EXTENSION citext;
+CREATE EXTENSION IF NOT EXISTS citext;
 
 CREATE TABLE users (
     id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
     username CITEXT UNIQUE NOT NULL,
     password_hash TEXT NOT NULL,
-    meta JSONB NOT NULL DEFAULT '{}'
+    meta JSONB NOT NULL DEFAULT '{}',
+    version INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deleted_at TIMESTAMPTZ
 );
 
 CREATE TABLE sessions (
-    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
-    user_id UUID NOT NULL REFERENCES users(id),
+    id TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
     data JSONB NOT NULL DEFAULT '{}',
+    expires_at TIMESTAMPTZ NOT NULL,
+    absolute_expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE password_resets (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
     expires_at TIMESTAMPTZ NOT NULL
 );
 
@@ -19,8 +31,15 @@ CREATE TABLE appauth (
     name TEXT UNIQUE NOT NULL,
     description TEXT,
     token TEXT UNIQUE NOT NULL,
+    token_hint TEXT NOT NULL DEFAULT '',
+    signing_secret TEXT,
     meta JSONB NOT NULL DEFAULT '{}',
-    expires_at TIMESTAMPTZ
+    expires_at TIMESTAMPTZ,
+    rate_limit INTEGER,
+    remaining_quota BIGINT,
+    last_used_at TIMESTAMPTZ,
+    scopes TEXT[] NOT NULL DEFAULT '{}',
+    version INTEGER NOT NULL DEFAULT 0
 );
 
-CREATE INDEX idx_appauth__token ON appauth (token);
\ No newline at end of file
+CREATE INDEX idx_appauth__token ON appauth (token);
@@ -2,35 +2,648 @@ pub mod postgres_redis;
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use secrecy::Secret;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 #[nova::newtype(serde, sqlx, copy, new)]
 pub type AppAuthId = uuid::Uuid;
 
-#[derive(Debug)]
+/// Prefix of generated tokens, which look like `tca_{base62 id}_{secret}`.
+pub const TOKEN_PREFIX: &str = "tca_";
+
+const BASE62: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Length of a base62 encoded id, enough to hold any `u128`.
+const ENCODED_ID_LEN: usize = 22;
+
+/// Length of the secret part of a token. Its characters are drawn from `rand::thread_rng`, a
+/// CSPRNG, so 32 alphanumerics make for about 190 bits.
+const SECRET_LEN: usize = 32;
+
+impl AppAuthId {
+    /// Extracts the id embedded in a generated token, without checking the secret part.
+    pub fn from_token(token: &str) -> Option<Self> {
+        let rest = token.strip_prefix(TOKEN_PREFIX)?;
+        let (encoded_id, secret) = rest.split_once('_')?;
+
+        if encoded_id.len() != ENCODED_ID_LEN || secret.is_empty() {
+            return None;
+        }
+
+        let mut n: u128 = 0;
+        for c in encoded_id.bytes() {
+            let digit = BASE62.iter().position(|&b| b == c)? as u128;
+            n = n.checked_mul(62)?.checked_add(digit)?;
+        }
+
+        Some(AppAuthId(uuid::Uuid::from_u128(n)))
+    }
+
+    /// A random token embedding this id, like those of [`NewAppAuth::generate`].
+    pub fn generate_token(self) -> Secret<String> {
+        let secret = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(SECRET_LEN)
+            .map(char::from)
+            .collect::<String>();
+        Secret::new(format!("{}{}_{}", TOKEN_PREFIX, self.to_base62(), secret))
+    }
+
+    fn to_base62(self) -> String {
+        let mut n = self.as_u128();
+        let mut out = [b'0'; ENCODED_ID_LEN];
+        for c in out.iter_mut().rev() {
+            *c = BASE62[(n % 62) as usize];
+            n /= 62;
+        }
+        out.iter().map(|&c| c as char).collect()
+    }
+}
+
 pub struct NewAppAuth {
     pub name: String,
     pub description: Option<String>,
     pub token: Secret<String>,
     pub meta: serde_json::Value,
     pub expires_at: Option<DateTime<Utc>>,
+    pub id: Option<AppAuthId>,
+    /// Shared secret for verifying request signatures, see
+    /// [`AppAuthBackend::verify_signature`].
+    pub signing_secret: Option<Secret<String>>,
+    /// Requests allowed per rate window, see [`AppAuthBackend::check_rate`]. `None` is
+    /// unlimited.
+    pub rate_limit: Option<u32>,
+    /// Requests allowed in total, see [`AppAuthBackend::consume_quota`]. `None` is unmetered.
+    pub remaining_quota: Option<i64>,
+    /// What the app auth may do, see [`AppAuthBackend::verify_token_with_scope`].
+    pub scopes: Vec<String>,
+}
+
+// Meta may hold anything, so it's kept out of logs along with the secrets.
+impl std::fmt::Debug for NewAppAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewAppAuth")
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("token", &self.token)
+            .field("meta", &"[REDACTED]")
+            .field("expires_at", &self.expires_at)
+            .field("id", &self.id)
+            .field("signing_secret", &self.signing_secret)
+            .field("rate_limit", &self.rate_limit)
+            .field("remaining_quota", &self.remaining_quota)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
+
+impl NewAppAuth {
+    /// Creates an app auth with a fresh id and a random token embedding that id, so that the
+    /// token alone is enough to authenticate. The token is returned so it can be handed out once.
+    pub fn generate(
+        name: String,
+        description: Option<String>,
+        meta: serde_json::Value,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> (Self, Secret<String>) {
+        let id = AppAuthId(uuid::Uuid::new_v4());
+        let token = id.generate_token();
+
+        let app_auth = Self {
+            name,
+            description,
+            token: token.clone(),
+            meta,
+            expires_at,
+            id: Some(id),
+            signing_secret: None,
+            rate_limit: None,
+            remaining_quota: None,
+            scopes: Vec::new(),
+        };
+
+        (app_auth, token)
+    }
+
+    pub fn with_signing_secret(mut self, signing_secret: Secret<String>) -> Self {
+        self.signing_secret = Some(signing_secret);
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: u32) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn with_quota(mut self, quota: i64) -> Self {
+        self.remaining_quota = Some(quota);
+        self
+    }
+
+    pub fn with_scopes(mut self, scopes: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.scopes = scopes.into_iter().map(Into::into).collect();
+        self
+    }
+}
+
+/// Checks a hex encoded HMAC-SHA256 of `payload`, optionally prefixed with `sha256=` as sent by
+/// GitHub, in constant time.
+pub fn signature_matches(signing_secret: &str, payload: &[u8], signature: &str) -> bool {
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = match hex::decode(signature) {
+        Ok(v) => v,
+        Err(_) => return false,
+    };
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(signing_secret.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(payload);
+    mac.verify_slice(&signature).is_ok()
+}
+
+/// Definition of an app auth without its token, for moving credentials between environments.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AppAuthExport {
+    pub name: String,
+    pub description: Option<String>,
+    pub meta: serde_json::Value,
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub rate_limit: Option<u32>,
+    #[serde(default)]
+    pub remaining_quota: Option<i64>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+}
+
+impl AppAuthExport {
+    /// Turns the definition into an app auth with a fresh id and token, see
+    /// [`NewAppAuth::generate`].
+    pub fn into_new_appauth(self) -> (NewAppAuth, Secret<String>) {
+        let (mut app_auth, token) =
+            NewAppAuth::generate(self.name, self.description, self.meta, self.expires_at);
+        app_auth.rate_limit = self.rate_limit;
+        app_auth.remaining_quota = self.remaining_quota;
+        app_auth.scopes = self.scopes;
+        (app_auth, token)
+    }
 }
 
+impl From<&AppAuth> for AppAuthExport {
+    fn from(app_auth: &AppAuth) -> Self {
+        Self {
+            name: app_auth.name.clone(),
+            description: app_auth.description.clone(),
+            meta: app_auth.meta.clone(),
+            expires_at: app_auth.expires_at,
+            rate_limit: app_auth.rate_limit,
+            remaining_quota: app_auth.remaining_quota,
+            scopes: app_auth.scopes.clone(),
+        }
+    }
+}
+
+/// The fields of an app auth that can be changed after creation, see
+/// [`AppAuthBackend::update_appauth`].
 #[derive(Debug, Clone)]
+pub struct AppAuthUpdate {
+    pub description: Option<String>,
+    pub meta: serde_json::Value,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub rate_limit: Option<u32>,
+    /// Replaces the balance left, e.g. to top it up. Quota consumed since the app auth was read
+    /// is forgotten, as [`AppAuthBackend::consume_quota`] doesn't bump the version.
+    pub remaining_quota: Option<i64>,
+    /// Replaces the scopes, see [`AppAuthBackend::verify_token_with_scope`].
+    pub scopes: Vec<String>,
+}
+
+impl From<&AppAuth> for AppAuthUpdate {
+    fn from(app_auth: &AppAuth) -> Self {
+        Self {
+            description: app_auth.description.clone(),
+            meta: app_auth.meta.clone(),
+            expires_at: app_auth.expires_at,
+            rate_limit: app_auth.rate_limit,
+            remaining_quota: app_auth.remaining_quota,
+            scopes: app_auth.scopes.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct AppAuth {
     pub id: AppAuthId,
     pub name: String,
     pub description: Option<String>,
     pub token: Secret<String>,
+    /// Masked form of the token (e.g. `tca_...a1b2`), safe to display.
+    pub token_hint: String,
+    pub signing_secret: Option<Secret<String>>,
     pub meta: serde_json::Value,
     pub expires_at: Option<DateTime<Utc>>,
+    /// Requests allowed per rate window, `None` being unlimited.
+    pub rate_limit: Option<u32>,
+    /// Requests left in total, `None` being unmetered.
+    pub remaining_quota: Option<i64>,
+    pub scopes: Vec<String>,
+    /// When the token last verified, to the minute. `None` if it never did.
+    pub last_used_at: Option<DateTime<Utc>>,
+    /// Bumped on every update, for optimistic concurrency control (see
+    /// [`AppAuthBackend::update_appauth`]).
+    pub version: i32,
+}
+
+impl std::fmt::Debug for AppAuth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppAuth")
+            .field("id", &self.id)
+            .field("name", &self.name)
+            .field("description", &self.description)
+            .field("token", &self.token)
+            .field("token_hint", &self.token_hint)
+            .field("signing_secret", &self.signing_secret)
+            .field("meta", &"[REDACTED]")
+            .field("expires_at", &self.expires_at)
+            .field("rate_limit", &self.rate_limit)
+            .field("remaining_quota", &self.remaining_quota)
+            .field("scopes", &self.scopes)
+            .field("last_used_at", &self.last_used_at)
+            .field("version", &self.version)
+            .finish()
+    }
+}
+
+/// Outcome of taking a request from an app auth's rate budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateStatus {
+    /// Requests allowed per rate window.
+    pub limit: u32,
+    /// Requests left right now.
+    pub remaining: u32,
+    /// When the full budget will be available again, if no more requests are made.
+    pub reset_at: DateTime<Utc>,
+}
+
+/// Builds the masked hint stored alongside a token so that UIs can tell credentials apart
+/// without revealing them. Keeps the prefix up to the first `_` and the last 4 characters.
+pub fn token_hint(token: &str) -> String {
+    let chars = token.chars().collect::<Vec<_>>();
+
+    let prefix = match token.find('_') {
+        Some(i) if i + 1 < token.len() => &token[..=i],
+        _ => "",
+    };
+
+    // Short tokens would be mostly revealed by their last 4 characters.
+    if chars.len() < prefix.chars().count() + 12 {
+        return format!("{}...", prefix);
+    }
+
+    let suffix = chars[chars.len() - 4..].iter().collect::<String>();
+    format!("{}...{}", prefix, suffix)
+}
+
+/// A page of app auths in id order, see [`AppAuthBackend::list_appauths`].
+#[derive(Debug)]
+pub struct AppAuthPage {
+    pub app_auths: Vec<AppAuth>,
+    /// Cursor for the next page, or `None` if this was the last one.
+    pub next: Option<AppAuthId>,
 }
 
+/// Returned by [`AppAuthBackend`] methods that a backend doesn't provide. Only creating app auths
+/// and verifying their tokens is required of every backend.
+#[derive(Debug, thiserror::Error)]
+#[error("Not supported by this app auth backend")]
+pub struct Unsupported;
+
 #[async_trait]
 pub trait AppAuthBackend {
-    type Error: std::error::Error;
+    type Error: std::error::Error + From<Unsupported>;
 
     async fn create_appauth(&self, app_auth: NewAppAuth) -> Result<AppAuth, Self::Error>;
-    // async fn find_appauth_by_id(&self, id: AppAuthId) -> Result<AppAuth, Self::Error>;
+
+    async fn find_appauth_by_id(&self, _id: AppAuthId) -> Result<AppAuth, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Lists up to `limit` app auths with ids after `after`, in id order. Pass the returned
+    /// [`AppAuthPage::next`] as `after` to get the next page. `limit` must be positive, and
+    /// backends may cap it.
+    async fn list_appauths(
+        &self,
+        _after: Option<AppAuthId>,
+        _limit: i64,
+    ) -> Result<AppAuthPage, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Applies `update`. With `expected_version`, the update only goes through if the app auth
+    /// is still at that version, i.e. nobody else updated it since it was read.
+    async fn update_appauth(
+        &self,
+        _id: AppAuthId,
+        _update: AppAuthUpdate,
+        _expected_version: Option<i32>,
+    ) -> Result<AppAuth, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Checks `token` against the app auth's, recording the use in
+    /// [`AppAuth::last_used_at`] if it matches.
     async fn verify_token(&self, id: AppAuthId, token: &str) -> Result<(), Self::Error>;
+
+    /// Replaces the app auth's token with a fresh one from [`AppAuthId::generate_token`], which
+    /// is returned so it can be handed out once. The old token no longer verifies afterwards.
+    async fn rotate_appauth_token(&self, _id: AppAuthId) -> Result<Secret<String>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Deletes the app auth, after which its token no longer verifies, cached or not.
+    async fn revoke_appauth(&self, _id: AppAuthId) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Like [`AppAuthBackend::verify_token`], but also checks that the app auth was given the
+    /// `required` scope.
+    async fn verify_token_with_scope(
+        &self,
+        _id: AppAuthId,
+        _token: &str,
+        _required: &str,
+    ) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Like [`AppAuthBackend::verify_token`], but keeps the token wrapped until it is compared.
+    async fn verify_token_secret(
+        &self,
+        id: AppAuthId,
+        token: &Secret<String>,
+    ) -> Result<(), Self::Error> {
+        self.verify_token(id, token.expose_secret()).await
+    }
+
+    /// Authenticates a token made by [`NewAppAuth::generate`], using the id embedded in it.
+    async fn authenticate_bearer(&self, _bearer: &str) -> Result<AppAuth, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Checks that `signature` is the HMAC-SHA256 of `payload` under the app auth's signing
+    /// secret (see [`signature_matches`]), as used for signing webhooks.
+    async fn verify_signature(
+        &self,
+        _id: AppAuthId,
+        _payload: &[u8],
+        _signature: &str,
+    ) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Takes one request from the app auth's rate budget, which refills continuously (a token
+    /// bucket). Returns `None` if the app auth has no rate limit. Meant to be called after
+    /// authenticating a request.
+    async fn check_rate(&self, _id: AppAuthId) -> Result<Option<RateStatus>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Atomically takes `n` requests from the app auth's total quota and returns what is left,
+    /// failing without taking any if fewer than `n` are left. Returns `None` if the app auth is
+    /// unmetered.
+    async fn consume_quota(&self, _id: AppAuthId, _n: u32) -> Result<Option<i64>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Definitions of all app auths, without their tokens.
+    async fn export_appauths(&self) -> Result<Vec<AppAuthExport>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Creates all of `app_auths`, or none of them. Pair with
+    /// [`AppAuthExport::into_new_appauth`] to provision exported definitions with fresh tokens.
+    async fn import_appauths(
+        &self,
+        _app_auths: Vec<NewAppAuth>,
+    ) -> Result<Vec<AppAuth>, Self::Error> {
+        Err(Unsupported.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use secrecy::{ExposeSecret, Secret};
+
+    use super::{
+        signature_matches, token_hint, AppAuth, AppAuthBackend, AppAuthExport, AppAuthId,
+        NewAppAuth, Unsupported, ENCODED_ID_LEN, SECRET_LEN, TOKEN_PREFIX,
+    };
+
+    #[test]
+    fn debug_hides_secrets() {
+        let (app_auth, token) = NewAppAuth::generate(
+            "app".into(),
+            None,
+            serde_json::json!({ "api_key": "sk_live_abcdef" }),
+            None,
+        );
+        let app_auth = app_auth.with_signing_secret(Secret::new("whsec_abcdef".into()));
+
+        let debug = format!("{:?}", app_auth);
+        assert!(debug.contains("app"));
+        assert!(!debug.contains(token.expose_secret()));
+        assert!(!debug.contains("whsec_abcdef"));
+        assert!(!debug.contains("sk_live_abcdef"));
+    }
+
+    #[test]
+    fn generated_tokens_are_unique() {
+        let tokens = (0..1000)
+            .map(|_| {
+                let (_, token) = NewAppAuth::generate("app".into(), None, Default::default(), None);
+                token.expose_secret().clone()
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(tokens.len(), 1000);
+
+        for token in &tokens {
+            assert_eq!(
+                token.len(),
+                TOKEN_PREFIX.len() + ENCODED_ID_LEN + 1 + SECRET_LEN
+            );
+            let secret = token.rsplit('_').next().unwrap();
+            assert_eq!(secret.len(), SECRET_LEN);
+            assert!(secret.bytes().all(|b| b.is_ascii_alphanumeric()));
+        }
+    }
+
+    #[test]
+    fn generated_token_embeds_id() {
+        let (app_auth, token) = NewAppAuth::generate("app".into(), None, Default::default(), None);
+
+        assert!(token.expose_secret().starts_with("tca_"));
+        assert_eq!(token.expose_secret(), app_auth.token.expose_secret());
+        assert_eq!(AppAuthId::from_token(token.expose_secret()), app_auth.id);
+
+        // Rotated tokens embed the same id, but differ.
+        let id = app_auth.id.unwrap();
+        let rotated = id.generate_token();
+        assert_eq!(AppAuthId::from_token(rotated.expose_secret()), Some(id));
+        assert_ne!(rotated.expose_secret(), token.expose_secret());
+    }
+
+    #[test]
+    fn malformed_bearer_has_no_id() {
+        assert_eq!(AppAuthId::from_token(""), None);
+        assert_eq!(AppAuthId::from_token("some-opaque-token"), None);
+        assert_eq!(AppAuthId::from_token("tca_abc_secret"), None);
+        assert_eq!(AppAuthId::from_token("tca_0000000000000000000001_"), None);
+        assert_eq!(
+            AppAuthId::from_token("tca_00000000000000000000-1_secret"),
+            None
+        );
+        // Larger than any u128.
+        assert_eq!(
+            AppAuthId::from_token("tca_zzzzzzzzzzzzzzzzzzzzzz_secret"),
+            None
+        );
+    }
+
+    #[test]
+    fn token_hint_masks_token() {
+        let token = "tca_5Hq2xW9vLmN3pR7tK1a1b2";
+        let hint = token_hint(token);
+
+        assert_eq!(hint, "tca_...a1b2");
+        assert!(!hint.contains(&token[4..token.len() - 4]));
+    }
+
+    #[test]
+    fn token_hint_short_token() {
+        assert_eq!(token_hint("abc123"), "...");
+        assert_eq!(token_hint("tca_abc123"), "tca_...");
+    }
+
+    #[test]
+    fn export_round_trips_without_token() {
+        let (new, token) = NewAppAuth::generate(
+            "app".into(),
+            Some("description".into()),
+            serde_json::json!({ "team": "integrations" }),
+            None,
+        );
+        let new = new
+            .with_rate_limit(60)
+            .with_quota(1000)
+            .with_scopes(["read"]);
+        let app_auth = AppAuth {
+            id: new.id.unwrap(),
+            name: new.name,
+            description: new.description,
+            token: new.token,
+            token_hint: token_hint(token.expose_secret()),
+            signing_secret: None,
+            meta: new.meta,
+            expires_at: new.expires_at,
+            rate_limit: new.rate_limit,
+            remaining_quota: new.remaining_quota,
+            scopes: new.scopes,
+            last_used_at: None,
+            version: 0,
+        };
+
+        let exported = serde_json::to_string(&AppAuthExport::from(&app_auth)).unwrap();
+        assert!(!exported.contains(token.expose_secret().as_str()));
+
+        let imported = serde_json::from_str::<AppAuthExport>(&exported).unwrap();
+        let (reprovisioned, new_token) = imported.into_new_appauth();
+        assert_eq!(reprovisioned.name, app_auth.name);
+        assert_eq!(reprovisioned.description, app_auth.description);
+        assert_eq!(reprovisioned.meta, app_auth.meta);
+        assert_eq!(reprovisioned.expires_at, app_auth.expires_at);
+        assert_eq!(reprovisioned.rate_limit, Some(60));
+        assert_eq!(reprovisioned.remaining_quota, Some(1000));
+        assert_eq!(reprovisioned.scopes, vec!["read".to_string()]);
+        assert_ne!(reprovisioned.id, Some(app_auth.id));
+        assert_ne!(new_token.expose_secret(), token.expose_secret());
+    }
+
+    #[derive(Debug, thiserror::Error)]
+    enum SingleTokenError {
+        #[error("invalid token")]
+        InvalidToken,
+        #[error(transparent)]
+        Unsupported(#[from] Unsupported),
+    }
+
+    /// Knows a single token, only `verify_token` is usable.
+    struct SingleToken {
+        id: AppAuthId,
+        token: Secret<String>,
+    }
+
+    #[async_trait]
+    impl AppAuthBackend for SingleToken {
+        type Error = SingleTokenError;
+
+        async fn create_appauth(&self, _app_auth: NewAppAuth) -> Result<AppAuth, Self::Error> {
+            Err(Unsupported.into())
+        }
+
+        async fn verify_token(&self, id: AppAuthId, token: &str) -> Result<(), Self::Error> {
+            match id == self.id && token == self.token.expose_secret() {
+                true => Ok(()),
+                false => Err(SingleTokenError::InvalidToken),
+            }
+        }
+    }
+
+    #[test]
+    fn verify_token_secret() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let backend = SingleToken {
+                id: app_auth.id.unwrap(),
+                token: token.clone(),
+            };
+
+            assert!(backend
+                .verify_token_secret(backend.id, &token)
+                .await
+                .is_ok());
+            assert!(backend
+                .verify_token_secret(backend.id, &Secret::new("tca_wrong".into()))
+                .await
+                .is_err());
+
+            // Left out methods fall back to failing.
+            assert!(matches!(
+                backend.find_appauth_by_id(backend.id).await,
+                Err(SingleTokenError::Unsupported(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn signature_vector() {
+        // RFC 4231, test case 2.
+        let signature = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        let payload = b"what do ya want for nothing?";
+
+        assert!(signature_matches("Jefe", payload, signature));
+        assert!(signature_matches(
+            "Jefe",
+            payload,
+            &format!("sha256={}", signature)
+        ));
+        assert!(!signature_matches("Jeff", payload, signature));
+        assert!(!signature_matches("Jefe", b"what do ya want?", signature));
+        assert!(!signature_matches("Jefe", payload, "not hex"));
+    }
 }
@@ -1,75 +1,234 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
+use chrono::{Duration, Utc};
 use deadpool_redis::PoolError;
 use redis::RedisError;
-use secrecy::ExposeSecret;
-use sqlx::PgPool;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{Connection, PgConnection, PgPool};
+use subtle::ConstantTimeEq;
 
 #[cfg(feature = "deadpool")]
 use crate::util;
+use crate::{
+    meta_cipher::{self, MetaCipher},
+    util::ConnSource,
+};
 
-use super::{AppAuth, AppAuthId, NewAppAuth};
+use super::{
+    signature_matches, AppAuth, AppAuthExport, AppAuthId, AppAuthPage, AppAuthUpdate, NewAppAuth,
+    RateStatus,
+};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[cfg(feature = "deadpool")]
-    #[error("sqlx error")]
+    #[error("connection pool error: {0}")]
     SqlxPool(#[from] deadpool::managed::PoolError<sqlx::Error>),
 
-    #[error("sqlx error")]
+    #[error("sqlx error: {0}")]
     Sqlx(#[from] sqlx::Error),
 
-    #[error("redis error")]
+    #[error("redis error: {0}")]
     RedisPool(#[from] deadpool_redis::PoolError),
 
-    #[error("redis error")]
+    #[error("redis error: {0}")]
     Redis(#[from] RedisError),
 
     // #[error("invalid username")]
     // Username(#[source] Box<dyn std::error::Error + Sync + Send>),
     #[error("The provided token was invalid.")]
     InvalidToken,
+
+    #[error("The provided bearer token is malformed.")]
+    MalformedBearer,
+
+    #[error("The provided signature was invalid.")]
+    InvalidSignature,
+
+    #[error("meta encryption error: {0}")]
+    MetaCipher(#[from] meta_cipher::Error),
+
+    #[error("The app auth was updated by someone else since it was read.")]
+    Conflict,
+
+    #[error("Rate limit of {} requests exceeded, fully replenished at {}.", .0.limit, .0.reset_at)]
+    RateLimited(RateStatus),
+
+    #[error("Quota exhausted, only {remaining} requests are left.")]
+    QuotaExhausted { remaining: i64 },
+
+    #[error("The app auth does not exist.")]
+    NotFound,
+
+    #[error("The app auth lacks the {0} scope.")]
+    InsufficientScope(String),
+
+    #[error("The page limit must be positive.")]
+    InvalidLimit,
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
 }
 
-pub struct Backend {
-    pg_pool: PgPool,
+/// Turns a missing row into [`Error::NotFound`], for queries looking up a single app auth.
+fn not_found(e: sqlx::Error) -> Error {
+    match e {
+        sqlx::Error::RowNotFound => Error::NotFound,
+        e => Error::Sqlx(e),
+    }
+}
+
+/// App auth backend storing credentials in Postgres and caching tokens in Redis, generic over
+/// where Postgres connections come from (see [`ConnSource`]).
+pub struct Backend<C = PgPool> {
+    pg_pool: Arc<C>,
     redis_pool: deadpool_redis::Pool,
     table_name: &'static str,
+    meta_cipher: Option<MetaCipher>,
+    rate_window: Duration,
 }
 
-impl Backend {
-    pub fn new(
-        pg_pool: PgPool,
-        redis_pool: deadpool_redis::Pool,
-        table_name: &'static str,
-    ) -> Self {
+impl<C> Backend<C> {
+    pub fn new(pg_pool: C, redis_pool: deadpool_redis::Pool, table_name: &'static str) -> Self {
         Self {
-            pg_pool,
+            pg_pool: Arc::new(pg_pool),
             redis_pool,
             table_name,
+            meta_cipher: None,
+            rate_window: Duration::minutes(1),
         }
     }
+
+    /// How long an app auth takes to earn back its full rate limit. Defaults to a minute, making
+    /// [`AppAuth::rate_limit`] a number of requests per minute.
+    pub fn with_rate_window(mut self, rate_window: Duration) -> Self {
+        self.rate_window = rate_window;
+        self
+    }
+
+    /// Stores `meta` encrypted, see [`MetaCipher`].
+    pub fn with_meta_cipher(mut self, meta_cipher: MetaCipher) -> Self {
+        self.meta_cipher = Some(meta_cipher);
+        self
+    }
+
+    fn seal(&self, mut app_auth: NewAppAuth) -> Result<NewAppAuth, Error> {
+        if let Some(cipher) = self.meta_cipher.as_ref() {
+            // Encrypted meta is bound to the id, so that has to be known before inserting.
+            let id = *app_auth
+                .id
+                .get_or_insert_with(|| AppAuthId(uuid::Uuid::new_v4()));
+            app_auth.meta = cipher.encrypt(*id, &app_auth.meta)?;
+        }
+        Ok(app_auth)
+    }
+
+    fn open(&self, mut app_auth: AppAuth) -> Result<AppAuth, Error> {
+        if let Some(cipher) = self.meta_cipher.as_ref() {
+            app_auth.meta = cipher.decrypt(*app_auth.id, app_auth.meta)?;
+        }
+        Ok(app_auth)
+    }
 }
 
-#[cfg(feature = "deadpool")]
-pub struct DeadpoolBackend {
-    pg_pool: util::deadpool::PgPool,
-    redis_pool: deadpool_redis::Pool,
-    table_name: &'static str,
+/// Cached rate limit of app auths without one.
+const UNLIMITED: &str = "none";
+
+/// Takes a request from the token bucket (KEYS[2]) of the app auth whose rate limit is cached
+/// at KEYS[1], refilling it by the limit every ARGV[1] milliseconds. Returns `false` if the
+/// rate limit isn't cached, an empty array if there is none, and otherwise
+/// `{allowed, remaining, limit, milliseconds until full}`.
+const CHECK_RATE: &str = r#"
+    local limit = redis.call("GET", KEYS[1])
+    if not limit then
+        return false
+    end
+    limit = tonumber(limit)
+    if not limit then
+        return {}
+    end
+
+    -- TIME is non-deterministic, so writes must be replicated as effects on Redis < 5.
+    if redis.replicate_commands then
+        redis.replicate_commands()
+    end
+    local window = tonumber(ARGV[1])
+    local time = redis.call("TIME")
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+
+    local bucket = redis.call("HMGET", KEYS[2], "tokens", "ts")
+    local tokens = tonumber(bucket[1]) or limit
+    local ts = tonumber(bucket[2]) or now
+    tokens = math.min(limit, tokens + (now - ts) * limit / window)
+
+    local allowed = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+        allowed = 1
+    end
+    redis.call("HSET", KEYS[2], "tokens", tostring(tokens), "ts", tostring(now))
+    redis.call("PEXPIRE", KEYS[2], window)
+
+    return {allowed, math.floor(tokens), limit, math.ceil((limit - tokens) * window / limit)}
+"#;
+
+fn rate_limit_key(id: AppAuthId) -> String {
+    format!("appauth/{}/rate-limit", *id)
+}
+
+fn rate_bucket_key(id: AppAuthId) -> String {
+    format!("appauth/{}/rate-bucket", *id)
+}
+
+/// How often, at most, `last_used_at` is written for an app auth in constant use.
+const RECORD_USE_INTERVAL_SECONDS: usize = 60;
+
+/// Set while `last_used_at` of the app auth is fresh enough, see [`Backend::record_use`].
+fn used_key(id: AppAuthId) -> String {
+    format!("appauth/{}/used", *id)
 }
 
 #[cfg(feature = "deadpool")]
-impl DeadpoolBackend {
-    pub fn new(
-        pg_pool: util::deadpool::PgPool,
-        redis_pool: deadpool_redis::Pool,
-        table_name: &'static str,
-    ) -> Self {
-        Self {
-            pg_pool,
-            redis_pool,
-            table_name,
-        }
-    }
+pub type DeadpoolBackend = Backend<util::deadpool::PgPool>;
+
+/// Marks the cached version of a revoked app auth, see [`CACHE_APPAUTH`].
+const REVOKED: &str = "revoked";
+
+/// How long a revoked app auth stays marked. Only verifications that read it from Postgres
+/// before it was deleted could put it back in the cache, so this needs to outlast those.
+const REVOKED_TTL_SECONDS: usize = 3600;
+
+/// Caches the token (KEYS[1]) and rate limit (KEYS[2]) of an app auth at version ARGV[4],
+/// expiring at the timestamp ARGV[3] if not empty. Does nothing if a newer version is cached
+/// (KEYS[3]), or the app auth was revoked, so that a verification which read the app auth
+/// before it was rotated or revoked can't put the old token back.
+const CACHE_APPAUTH: &str = r#"
+    local cached = redis.call("GET", KEYS[3])
+    if cached == ARGV[5] then
+        return 0
+    end
+    if cached and tonumber(cached) and tonumber(cached) > tonumber(ARGV[4]) then
+        return 0
+    end
+
+    local values = {ARGV[1], ARGV[2], ARGV[4]}
+    for i = 1, 3 do
+        if ARGV[3] == "" then
+            redis.call("SET", KEYS[i], values[i])
+        else
+            redis.call("SET", KEYS[i], values[i], "EXAT", ARGV[3])
+        end
+    end
+    return 1
+"#;
+
+fn token_key(id: AppAuthId) -> String {
+    format!("appauth/{}", *id)
+}
+
+fn version_key(id: AppAuthId) -> String {
+    format!("appauth/{}/version", *id)
 }
 
 async fn set_redis_token(
@@ -77,94 +236,424 @@ async fn set_redis_token(
     appauth: &AppAuth,
 ) -> Result<(), PoolError> {
     let mut conn = redis_pool.get().await?;
-    let mut q = redis::cmd("SET");
-    let mut q = q
-        .arg(format!("appauth/{}", *appauth.id))
-        .arg(appauth.token.expose_secret());
+    let rate_limit = match appauth.rate_limit {
+        Some(rate_limit) => rate_limit.to_string(),
+        None => UNLIMITED.to_string(),
+    };
+    let expires_at = match appauth.expires_at {
+        Some(expires_at) => expires_at.timestamp().to_string(),
+        None => String::new(),
+    };
 
-    if let Some(expiry) = appauth.expires_at.as_ref() {
-        q = q.arg("EXAT").arg(expiry.timestamp());
-    }
+    redis::Script::new(CACHE_APPAUTH)
+        .key(token_key(appauth.id))
+        .key(rate_limit_key(appauth.id))
+        .key(version_key(appauth.id))
+        .arg(appauth.token.expose_secret())
+        .arg(rate_limit)
+        .arg(expires_at)
+        .arg(appauth.version)
+        .arg(REVOKED)
+        .invoke_async::<_, ()>(&mut conn)
+        .await?;
+
+    Ok(())
+}
 
-    q.query_async(&mut conn).await?;
+/// Forgets the cached token and rate limit of a deleted app auth, marking it revoked so that it
+/// isn't cached again.
+async fn clear_redis_token(redis_pool: &deadpool_redis::Pool, id: AppAuthId) -> Result<(), Error> {
+    let mut conn = redis_pool.get().await?;
+    redis::pipe()
+        .atomic()
+        .cmd("DEL")
+        .arg(token_key(id))
+        .arg(rate_limit_key(id))
+        .arg(rate_bucket_key(id))
+        .arg(used_key(id))
+        .ignore()
+        .cmd("SET")
+        .arg(version_key(id))
+        .arg(REVOKED)
+        .arg("EX")
+        .arg(REVOKED_TTL_SECONDS)
+        .ignore()
+        .query_async::<_, ()>(&mut conn)
+        .await?;
 
     Ok(())
 }
 
+fn tokens_match(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+async fn insert_app_auth(
+    conn: &mut PgConnection,
+    app_auth: NewAppAuth,
+    table_name: &'static str,
+) -> Result<AppAuthId, sqlx::Error> {
+    match app_auth.id {
+        Some(id) => database::insert_app_auth_with_id(conn, id, app_auth, table_name).await,
+        None => database::insert_app_auth(conn, app_auth, table_name).await,
+    }
+}
+
+async fn authenticate_bearer(
+    conn: &mut PgConnection,
+    bearer: &str,
+    table_name: &'static str,
+) -> Result<AppAuth, Error> {
+    let id = AppAuthId::from_token(bearer).ok_or(Error::MalformedBearer)?;
+    // An id that doesn't exist, or no longer does, makes for just another invalid token.
+    let record = database::find_appauth_by_id(conn, id, table_name)
+        .await
+        .map_err(|e| match not_found(e) {
+            Error::NotFound => Error::InvalidToken,
+            e => e,
+        })?;
+
+    if !tokens_match(bearer, record.token.expose_secret()) {
+        return Err(Error::InvalidToken);
+    }
+
+    if matches!(record.expires_at, Some(expires_at) if expires_at <= Utc::now()) {
+        return Err(Error::InvalidToken);
+    }
+
+    Ok(record)
+}
+
+impl<C> Backend<C>
+where
+    C: ConnSource + 'static,
+{
+    /// Updates `last_used_at` after a successful verification, in the background so that the
+    /// caller doesn't wait on Postgres. Redis lets through one update a minute per app auth, so
+    /// busy tokens don't cost a write each. A failure to record the use is no reason to reject
+    /// the caller, so it's ignored.
+    fn record_use(&self, id: AppAuthId) {
+        let pg_pool = self.pg_pool.clone();
+        let redis_pool = self.redis_pool.clone();
+        let table_name = self.table_name;
+
+        tokio::spawn(async move {
+            let mut conn = match redis_pool.get().await {
+                Ok(conn) => conn,
+                Err(_) => return,
+            };
+            let first_in_interval: Result<Option<String>, _> = redis::cmd("SET")
+                .arg(used_key(id))
+                .arg(1)
+                .arg("NX")
+                .arg("EX")
+                .arg(RECORD_USE_INTERVAL_SECONDS)
+                .query_async(&mut conn)
+                .await;
+            if !matches!(first_in_interval, Ok(Some(_))) {
+                return;
+            }
+
+            if let Ok(mut conn) = pg_pool.acquire().await {
+                let _ = database::touch_appauth(&mut conn, id, table_name).await;
+            }
+        });
+    }
+}
+
 #[async_trait]
-impl super::AppAuthBackend for Backend {
+impl<C> super::AppAuthBackend for Backend<C>
+where
+    C: ConnSource + 'static,
+    Error: From<C::Error>,
+{
     type Error = Error;
 
     async fn create_appauth(&self, app_auth: NewAppAuth) -> Result<AppAuth, Self::Error> {
+        let app_auth = self.seal(app_auth)?;
         let mut conn = self.pg_pool.acquire().await?;
-        let id = database::insert_app_auth(&mut conn, app_auth, self.table_name).await?;
+        let id = insert_app_auth(&mut conn, app_auth, self.table_name).await?;
         let appauth = database::find_appauth_by_id(&mut conn, id, self.table_name).await?;
         set_redis_token(&self.redis_pool, &appauth).await?;
 
-        Ok(appauth)
+        self.open(appauth)
+    }
+
+    async fn find_appauth_by_id(&self, id: AppAuthId) -> Result<AppAuth, Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let appauth = database::find_appauth_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(not_found)?;
+        self.open(appauth)
+    }
+
+    async fn list_appauths(
+        &self,
+        after: Option<AppAuthId>,
+        limit: i64,
+    ) -> Result<AppAuthPage, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pg_pool.acquire().await?;
+        let limit = limit.min(database::MAX_PAGE_LIMIT);
+        let app_auths = database::list_appauths_after(&mut conn, after, limit, self.table_name)
+            .await?
+            .into_iter()
+            .map(|a| self.open(a))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // A short page is the last one. A full one may be too, which the next call finds out.
+        let next = match app_auths.len() as i64 == limit {
+            true => app_auths.last().map(|a| a.id),
+            false => None,
+        };
+        Ok(AppAuthPage { app_auths, next })
+    }
+
+    async fn update_appauth(
+        &self,
+        id: AppAuthId,
+        mut update: AppAuthUpdate,
+        expected_version: Option<i32>,
+    ) -> Result<AppAuth, Self::Error> {
+        if let Some(cipher) = self.meta_cipher.as_ref() {
+            update.meta = cipher.encrypt(*id, &update.meta)?;
+        }
+
+        let mut conn = self.pg_pool.acquire().await?;
+        let appauth = match database::update_appauth(
+            &mut conn,
+            id,
+            update,
+            expected_version,
+            self.table_name,
+        )
+        .await?
+        {
+            Some(appauth) => appauth,
+            // Either the app auth is gone or it moved on.
+            None => {
+                database::find_appauth_by_id(&mut conn, id, self.table_name)
+                    .await
+                    .map_err(not_found)?;
+                return Err(Error::Conflict);
+            }
+        };
+
+        // The expiry may have changed.
+        set_redis_token(&self.redis_pool, &appauth).await?;
+        self.open(appauth)
     }
 
     async fn verify_token(&self, id: AppAuthId, token: &str) -> Result<(), Self::Error> {
         let mut conn = self.redis_pool.get().await?;
 
-        let redis_token: Option<String> = redis::cmd("GET")
-            .arg(format!("appauth/{}", *id))
+        let (redis_token, version): (Option<String>, Option<String>) = redis::cmd("MGET")
+            .arg(token_key(id))
+            .arg(version_key(id))
             .query_async(&mut conn)
             .await?;
 
-        if let Some(redis_token) = redis_token {
-            if redis_token == token {
+        match (redis_token, version) {
+            (_, Some(version)) if version == REVOKED => return Err(Error::NotFound),
+            // The cache is authoritative, so guessing tokens never reaches Postgres.
+            (Some(redis_token), Some(_)) => {
+                if !tokens_match(&redis_token, token) {
+                    return Err(Error::InvalidToken);
+                }
+                self.record_use(id);
                 return Ok(());
             }
+            _ => {}
         }
 
+        // Not cached, e.g. after Redis was flushed, or only partly so. Either way the cache is
+        // refreshed, so the next verification is served by it.
         let mut conn = self.pg_pool.acquire().await?;
-        let record = database::find_appauth_by_id(&mut conn, id, self.table_name).await?;
-        let real_token = record.token.expose_secret();
-        if token != real_token {
-            set_redis_token(&self.redis_pool, &record).await?;
+        let record = database::find_appauth_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(not_found)?;
+        if matches!(record.expires_at, Some(expires_at) if expires_at <= Utc::now()) {
             return Err(Error::InvalidToken);
         }
+        set_redis_token(&self.redis_pool, &record).await?;
+
+        if !tokens_match(token, record.token.expose_secret()) {
+            return Err(Error::InvalidToken);
+        }
+        self.record_use(id);
         Ok(())
     }
-}
 
-#[cfg(feature = "deadpool")]
-#[async_trait]
-impl super::AppAuthBackend for DeadpoolBackend {
-    type Error = Error;
+    async fn verify_token_with_scope(
+        &self,
+        id: AppAuthId,
+        token: &str,
+        required: &str,
+    ) -> Result<(), Self::Error> {
+        self.verify_token(id, token).await?;
 
-    async fn create_appauth(&self, app_auth: NewAppAuth) -> Result<AppAuth, Self::Error> {
+        // Scopes aren't cached, so this reads them from Postgres.
         let mut conn = self.pg_pool.acquire().await?;
-        let id = database::insert_app_auth(&mut conn, app_auth, self.table_name).await?;
-        let appauth = database::find_appauth_by_id(&mut conn, id, self.table_name).await?;
+        let record = database::find_appauth_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(not_found)?;
+        match record.scopes.iter().any(|scope| scope == required) {
+            true => Ok(()),
+            false => Err(Error::InsufficientScope(required.to_string())),
+        }
+    }
+
+    /// The cached token is overwritten, so the old one stops verifying right away. Verifications
+    /// that read the old token before the rotation can't cache it again, as its version is
+    /// older.
+    async fn rotate_appauth_token(&self, id: AppAuthId) -> Result<Secret<String>, Self::Error> {
+        let new_token = id.generate_token();
+        let mut conn = self.pg_pool.acquire().await?;
+        let appauth = database::set_token(&mut conn, id, new_token.clone(), self.table_name)
+            .await?
+            .ok_or(Error::NotFound)?;
         set_redis_token(&self.redis_pool, &appauth).await?;
 
-        Ok(appauth)
+        Ok(new_token)
     }
 
-    async fn verify_token(&self, id: AppAuthId, token: &str) -> Result<(), Self::Error> {
-        let mut conn = self.redis_pool.get().await?;
+    /// The row goes first, so that verifications missing the cache from then on can't put the
+    /// token back. Those that read the row before it went can't either, as the cache remembers
+    /// the revocation for a while.
+    async fn revoke_appauth(&self, id: AppAuthId) -> Result<(), Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let deleted = database::delete_appauth(&mut conn, id, self.table_name).await?;
+        clear_redis_token(&self.redis_pool, id).await?;
 
-        let redis_token: Option<String> = redis::cmd("GET")
-            .arg(format!("appauth/{}", *id))
-            .query_async(&mut conn)
-            .await?;
+        match deleted {
+            true => Ok(()),
+            false => Err(Error::NotFound),
+        }
+    }
 
-        if let Some(redis_token) = redis_token {
-            if redis_token == token {
-                return Ok(());
+    async fn authenticate_bearer(&self, bearer: &str) -> Result<AppAuth, Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let appauth = authenticate_bearer(&mut conn, bearer, self.table_name).await?;
+        self.record_use(appauth.id);
+        self.open(appauth)
+    }
+
+    async fn verify_signature(
+        &self,
+        id: AppAuthId,
+        payload: &[u8],
+        signature: &str,
+    ) -> Result<(), Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let record = database::find_appauth_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(not_found)?;
+
+        if matches!(record.expires_at, Some(expires_at) if expires_at <= Utc::now()) {
+            return Err(Error::InvalidSignature);
+        }
+
+        match record.signing_secret {
+            Some(secret) if signature_matches(secret.expose_secret(), payload, signature) => Ok(()),
+            _ => Err(Error::InvalidSignature),
+        }
+    }
+
+    async fn check_rate(&self, id: AppAuthId) -> Result<Option<RateStatus>, Self::Error> {
+        let script = redis::Script::new(CHECK_RATE);
+        let mut invocation = script.prepare_invoke();
+        invocation
+            .key(rate_limit_key(id))
+            .key(rate_bucket_key(id))
+            .arg(self.rate_window.num_milliseconds().max(1));
+
+        let mut conn = self.redis_pool.get().await?;
+        let mut result: Option<Vec<i64>> = invocation.invoke_async(&mut conn).await?;
+        if result.is_none() {
+            // Not cached, e.g. evicted or created by another deployment.
+            let mut pg_conn = self.pg_pool.acquire().await?;
+            let record = database::find_appauth_by_id(&mut pg_conn, id, self.table_name)
+                .await
+                .map_err(not_found)?;
+            set_redis_token(&self.redis_pool, &record).await?;
+            result = invocation.invoke_async(&mut conn).await?;
+        }
+
+        let (allowed, status) = match result.as_deref() {
+            Some(&[allowed, remaining, limit, reset_ms]) => {
+                let status = RateStatus {
+                    limit: limit as u32,
+                    remaining: remaining as u32,
+                    reset_at: Utc::now() + Duration::milliseconds(reset_ms),
+                };
+                (allowed == 1, status)
             }
+            _ => return Ok(None),
+        };
+
+        match allowed {
+            true => Ok(Some(status)),
+            false => Err(Error::RateLimited(status)),
         }
+    }
 
+    /// The quota lives in Postgres alone, where the decrement is a single conditional `UPDATE`
+    /// so that concurrent requests can't overspend it.
+    async fn consume_quota(&self, id: AppAuthId, n: u32) -> Result<Option<i64>, Self::Error> {
         let mut conn = self.pg_pool.acquire().await?;
-        let record = database::find_appauth_by_id(&mut conn, id, self.table_name).await?;
-        let real_token = record.token.expose_secret();
-        if token != real_token {
-            set_redis_token(&self.redis_pool, &record).await?;
-            return Err(Error::InvalidToken);
+        if let Some(remaining) =
+            database::consume_quota(&mut conn, id, n.into(), self.table_name).await?
+        {
+            return Ok(Some(remaining));
+        }
+
+        // Unmetered, short of quota, or gone.
+        let record = database::find_appauth_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(not_found)?;
+        match record.remaining_quota {
+            Some(remaining) => Err(Error::QuotaExhausted { remaining }),
+            None => Ok(None),
         }
-        Ok(())
+    }
+
+    async fn export_appauths(&self) -> Result<Vec<AppAuthExport>, Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let exports = database::export_appauths(&mut conn, self.table_name).await?;
+        exports
+            .into_iter()
+            .map(|(id, mut export)| {
+                if let Some(cipher) = self.meta_cipher.as_ref() {
+                    export.meta = cipher.decrypt(*id, export.meta)?;
+                }
+                Ok(export)
+            })
+            .collect()
+    }
+
+    async fn import_appauths(
+        &self,
+        app_auths: Vec<NewAppAuth>,
+    ) -> Result<Vec<AppAuth>, Self::Error> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut imported = Vec::with_capacity(app_auths.len());
+        for app_auth in app_auths {
+            let app_auth = self.seal(app_auth)?;
+            let id = insert_app_auth(&mut tx, app_auth, self.table_name).await?;
+            imported.push(database::find_appauth_by_id(&mut tx, id, self.table_name).await?);
+        }
+        tx.commit().await?;
+
+        // Only cached once committed, so a failed import leaves no tokens behind.
+        for appauth in &imported {
+            set_redis_token(&self.redis_pool, appauth).await?;
+        }
+
+        imported.into_iter().map(|a| self.open(a)).collect()
     }
 }
 
@@ -172,7 +661,35 @@ mod database {
     use secrecy::{ExposeSecret, Secret};
     use sqlx::{PgConnection, Row};
 
-    use crate::appauth::{AppAuth, AppAuthId, NewAppAuth};
+    use sqlx::postgres::PgRow;
+
+    use crate::appauth::{
+        token_hint, AppAuth, AppAuthExport, AppAuthId, AppAuthUpdate, NewAppAuth,
+    };
+
+    const COLUMNS: &str = "id, name, description, token, token_hint, signing_secret, meta, \
+        expires_at, rate_limit, version, remaining_quota, scopes, last_used_at";
+
+    /// Upper bound on the number of rows in a page.
+    pub const MAX_PAGE_LIMIT: i64 = 1000;
+
+    fn appauth_from_row(r: &PgRow) -> AppAuth {
+        AppAuth {
+            id: r.get(0),
+            name: r.get(1),
+            description: r.get(2),
+            token: Secret::new(r.get(3)),
+            token_hint: r.get(4),
+            signing_secret: r.get::<Option<String>, _>(5).map(Secret::new),
+            meta: r.get(6),
+            expires_at: r.get(7),
+            rate_limit: r.get::<Option<i32>, _>(8).map(|v| v as u32),
+            version: r.get(9),
+            remaining_quota: r.get(10),
+            scopes: r.get(11),
+            last_used_at: r.get(12),
+        }
+    }
 
     pub async fn find_appauth_by_id(
         conn: &mut PgConnection,
@@ -181,22 +698,194 @@ mod database {
     ) -> Result<AppAuth, sqlx::Error> {
         let r = sqlx::query(&format!(
             r#"
-                SELECT * FROM {} WHERE id = $1
+                SELECT {}
+                FROM {}
+                WHERE id = $1
             "#,
-            table_name
+            COLUMNS, table_name
         ))
         .bind(*id)
         .fetch_one(conn)
         .await?;
 
-        Ok(AppAuth {
-            id: r.get(0),
-            name: r.get(1),
-            description: r.get(2),
-            token: Secret::new(r.get(3)),
-            meta: r.get(4),
-            expires_at: r.get(5),
-        })
+        Ok(appauth_from_row(&r))
+    }
+
+    pub async fn list_appauths_after(
+        conn: &mut PgConnection,
+        after: Option<AppAuthId>,
+        limit: i64,
+        table_name: &'static str,
+    ) -> Result<Vec<AppAuth>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT {}
+                FROM {}
+                WHERE $1::UUID IS NULL OR id > $1
+                ORDER BY id
+                LIMIT $2
+            "#,
+            COLUMNS, table_name
+        ))
+        .bind(after)
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows.iter().map(appauth_from_row).collect())
+    }
+
+    /// Applies `update` if the app auth is at `expected_version` when given. Returns `None` if
+    /// no row matched.
+    pub async fn update_appauth(
+        conn: &mut PgConnection,
+        id: AppAuthId,
+        update: AppAuthUpdate,
+        expected_version: Option<i32>,
+        table_name: &'static str,
+    ) -> Result<Option<AppAuth>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {}
+                SET description = $1, meta = $2, expires_at = $3, rate_limit = $4,
+                    remaining_quota = $5, scopes = $6, version = version + 1
+                WHERE id = $7 AND ($8::INTEGER IS NULL OR version = $8)
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(update.description)
+        .bind(update.meta)
+        .bind(update.expires_at)
+        .bind(update.rate_limit.map(|v| v as i32))
+        .bind(update.remaining_quota)
+        .bind(update.scopes)
+        .bind(*id)
+        .bind(expected_version)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.as_ref().map(appauth_from_row))
+    }
+
+    /// Replaces the token and its hint, returning `None` if there is no such app auth.
+    pub async fn set_token(
+        conn: &mut PgConnection,
+        id: AppAuthId,
+        token: Secret<String>,
+        table_name: &'static str,
+    ) -> Result<Option<AppAuth>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {}
+                SET token = $1, token_hint = $2, version = version + 1
+                WHERE id = $3
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(token.expose_secret())
+        .bind(token_hint(token.expose_secret()))
+        .bind(*id)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.as_ref().map(appauth_from_row))
+    }
+
+    /// Sets `last_used_at` to now, unless it already is within the last minute, which spares
+    /// a write on most verifications of a busy token.
+    pub async fn touch_appauth(
+        conn: &mut PgConnection,
+        id: AppAuthId,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            r#"
+                UPDATE {}
+                SET last_used_at = now()
+                WHERE id = $1
+                    AND (last_used_at IS NULL OR last_used_at < now() - interval '1 minute')
+            "#,
+            table_name
+        ))
+        .bind(*id)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns whether there was an app auth to delete.
+    pub async fn delete_appauth(
+        conn: &mut PgConnection,
+        id: AppAuthId,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE id = $1", table_name))
+            .bind(*id)
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Takes `n` from the quota if at least that much is left, returning the new balance.
+    pub async fn consume_quota(
+        conn: &mut PgConnection,
+        id: AppAuthId,
+        n: i64,
+        table_name: &'static str,
+    ) -> Result<Option<i64>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {}
+                SET remaining_quota = remaining_quota - $1
+                WHERE id = $2 AND remaining_quota >= $1
+                RETURNING remaining_quota
+            "#,
+            table_name
+        ))
+        .bind(n)
+        .bind(*id)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.map(|r| r.get(0)))
+    }
+
+    /// Exports every app auth, along with its id.
+    pub async fn export_appauths(
+        conn: &mut PgConnection,
+        table_name: &'static str,
+    ) -> Result<Vec<(AppAuthId, AppAuthExport)>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT name, description, meta, expires_at, rate_limit, remaining_quota, scopes,
+                    id
+                FROM {}
+                ORDER BY name
+            "#,
+            table_name
+        ))
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|r| {
+                let export = AppAuthExport {
+                    name: r.get(0),
+                    description: r.get(1),
+                    meta: r.get(2),
+                    expires_at: r.get(3),
+                    rate_limit: r.get::<Option<i32>, _>(4).map(|v| v as u32),
+                    remaining_quota: r.get(5),
+                    scopes: r.get(6),
+                };
+                (AppAuthId(r.get(7)), export)
+            })
+            .collect())
     }
 
     pub async fn insert_app_auth(
@@ -206,7 +895,7 @@ mod database {
     ) -> Result<AppAuthId, sqlx::Error> {
         let rec = sqlx::query(&format!(
             r#"
-                INSERT INTO {}(name, description, token, meta, expires_at) VALUES ($1, $2, $3, $4, $5)
+                INSERT INTO {}(name, description, token, token_hint, signing_secret, meta, expires_at, rate_limit, remaining_quota, scopes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
                 RETURNING id;
             "#,
             table_name
@@ -214,8 +903,13 @@ mod database {
         .bind(appauth.name)
         .bind(appauth.description)
         .bind(appauth.token.expose_secret())
+        .bind(token_hint(appauth.token.expose_secret()))
+        .bind(appauth.signing_secret.as_ref().map(|s| s.expose_secret()))
         .bind(appauth.meta)
         .bind(appauth.expires_at)
+        .bind(appauth.rate_limit.map(|v| v as i32))
+        .bind(appauth.remaining_quota)
+        .bind(appauth.scopes)
         .fetch_one(conn)
         .await?;
 
@@ -230,7 +924,7 @@ mod database {
     ) -> Result<AppAuthId, sqlx::Error> {
         let rec = sqlx::query(&format!(
             r#"
-                INSERT INTO {}(id, name, description, token, meta, expires_at) VALUES ($1, $2, $3, $4, $5, $6)
+                INSERT INTO {}(id, name, description, token, token_hint, signing_secret, meta, expires_at, rate_limit, remaining_quota, scopes) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
                 RETURNING id;
             "#,
             table_name
@@ -239,11 +933,641 @@ mod database {
         .bind(appauth.name)
         .bind(appauth.description)
         .bind(appauth.token.expose_secret())
+        .bind(token_hint(appauth.token.expose_secret()))
+        .bind(appauth.signing_secret.as_ref().map(|s| s.expose_secret()))
         .bind(appauth.meta)
         .bind(appauth.expires_at)
+        .bind(appauth.rate_limit.map(|v| v as i32))
+        .bind(appauth.remaining_quota)
+        .bind(appauth.scopes)
         .fetch_one(conn)
         .await?;
 
         Ok(AppAuthId(rec.get(0)))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Duration;
+    use secrecy::ExposeSecret;
+
+    use crate::{
+        appauth::{AppAuthBackend, AppAuthId, AppAuthUpdate, NewAppAuth},
+        util::test_db,
+    };
+
+    use super::{
+        authenticate_bearer, database, insert_app_auth, set_redis_token, tokens_match, Backend,
+        Error,
+    };
+
+    /// `last_used_at` of the app auth, once recorded in the background, or `None` if that
+    /// doesn't happen within a second.
+    async fn recorded_use(
+        backend: &Backend,
+        id: AppAuthId,
+    ) -> Option<chrono::DateTime<chrono::Utc>> {
+        for _ in 0..20 {
+            let last_used_at = backend.find_appauth_by_id(id).await.unwrap().last_used_at;
+            if last_used_at.is_some() {
+                return last_used_at;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        }
+        None
+    }
+
+    #[test]
+    fn tokens_match_whole_tokens() {
+        let (_, token) = NewAppAuth::generate("app".into(), None, Default::default(), None);
+        let token = token.expose_secret();
+
+        assert!(tokens_match(token, token));
+        assert!(!tokens_match(token, &token[..token.len() - 1]));
+        assert!(!tokens_match(token, &format!("{}x", token)));
+        assert!(!tokens_match(token, &token.to_uppercase()));
+        assert!(!tokens_match(token, ""));
+    }
+
+    #[test]
+    fn rate_limit_refills() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend =
+                Backend::new(pg_pool, redis_pool, "appauth").with_rate_window(Duration::seconds(1));
+            let (app_auth, _) = NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend
+                .create_appauth(app_auth.with_rate_limit(2))
+                .await
+                .unwrap();
+
+            let first = backend.check_rate(app_auth.id).await.unwrap().unwrap();
+            assert_eq!((first.limit, first.remaining), (2, 1));
+            backend.check_rate(app_auth.id).await.unwrap().unwrap();
+            assert!(matches!(
+                backend.check_rate(app_auth.id).await,
+                Err(Error::RateLimited(status)) if status.remaining == 0
+            ));
+
+            tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+            let refilled = backend.check_rate(app_auth.id).await.unwrap().unwrap();
+            assert_eq!(refilled.remaining, 1);
+        });
+    }
+
+    #[test]
+    fn revoke_appauth() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+
+            // Served by the cache, which creating the app auth filled.
+            backend
+                .verify_token(app_auth.id, token.expose_secret())
+                .await
+                .unwrap();
+
+            backend.revoke_appauth(app_auth.id).await.unwrap();
+            assert!(matches!(
+                backend
+                    .verify_token(app_auth.id, token.expose_secret())
+                    .await,
+                Err(Error::NotFound)
+            ));
+            assert!(matches!(
+                backend.authenticate_bearer(token.expose_secret()).await,
+                Err(Error::InvalidToken)
+            ));
+            assert!(matches!(
+                backend
+                    .verify_signature(app_auth.id, b"payload", "signature")
+                    .await,
+                Err(Error::NotFound)
+            ));
+            assert!(matches!(
+                backend.check_rate(app_auth.id).await,
+                Err(Error::NotFound)
+            ));
+            assert!(matches!(
+                backend.revoke_appauth(app_auth.id).await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn revoke_appauth_while_verifying() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Arc::new(Backend::new(pg_pool.clone(), redis_pool.clone(), "appauth"));
+
+            // A verification that missed the cache and read the row just before it was deleted,
+            // but only gets to cache it afterwards.
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+            let mut conn = pg_pool.acquire().await.unwrap();
+            let stale = database::find_appauth_by_id(&mut conn, app_auth.id, "appauth")
+                .await
+                .unwrap();
+            backend.revoke_appauth(app_auth.id).await.unwrap();
+            set_redis_token(&redis_pool, &stale).await.unwrap();
+            assert!(matches!(
+                backend
+                    .verify_token(app_auth.id, token.expose_secret())
+                    .await,
+                Err(Error::NotFound)
+            ));
+
+            // And racing for real, with the cache cleared so that verifications go to Postgres.
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let id = backend.create_appauth(app_auth).await.unwrap().id;
+            let mut redis_conn = redis_pool.get().await.unwrap();
+            redis::cmd("DEL")
+                .arg(format!("appauth/{}", *id))
+                .query_async::<_, ()>(&mut redis_conn)
+                .await
+                .unwrap();
+            let verifications = (0..20)
+                .map(|_| {
+                    let backend = backend.clone();
+                    let token = token.clone();
+                    tokio::spawn(
+                        async move { backend.verify_token(id, token.expose_secret()).await },
+                    )
+                })
+                .collect::<Vec<_>>();
+            backend.revoke_appauth(id).await.unwrap();
+            for verification in verifications {
+                let _ = verification.await.unwrap();
+            }
+            assert!(matches!(
+                backend.verify_token(id, token.expose_secret()).await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn rotate_appauth_token() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool.clone(), redis_pool.clone(), "appauth");
+            let (app_auth, old_token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+            backend
+                .verify_token(app_auth.id, old_token.expose_secret())
+                .await
+                .unwrap();
+
+            // As if a verification read the app auth before the rotation, and cached it after.
+            let mut conn = pg_pool.acquire().await.unwrap();
+            let stale = database::find_appauth_by_id(&mut conn, app_auth.id, "appauth")
+                .await
+                .unwrap();
+            let new_token = backend.rotate_appauth_token(app_auth.id).await.unwrap();
+            assert_eq!(
+                AppAuthId::from_token(new_token.expose_secret()),
+                Some(app_auth.id)
+            );
+            set_redis_token(&redis_pool, &stale).await.unwrap();
+
+            assert!(matches!(
+                backend
+                    .verify_token(app_auth.id, old_token.expose_secret())
+                    .await,
+                Err(Error::InvalidToken)
+            ));
+            backend
+                .verify_token(app_auth.id, new_token.expose_secret())
+                .await
+                .unwrap();
+            let authenticated = backend
+                .authenticate_bearer(new_token.expose_secret())
+                .await
+                .unwrap();
+            assert_eq!(authenticated.id, app_auth.id);
+            assert!(matches!(
+                backend.authenticate_bearer(old_token.expose_secret()).await,
+                Err(Error::InvalidToken)
+            ));
+        });
+    }
+
+    #[test]
+    fn bearer_of_unknown_app_auth_is_invalid() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pg_pool = test_db::pool().await;
+            let mut conn = pg_pool.acquire().await.unwrap();
+            let token = AppAuthId(uuid::Uuid::new_v4()).generate_token();
+
+            assert!(matches!(
+                authenticate_bearer(&mut conn, token.expose_secret(), "appauth").await,
+                Err(Error::InvalidToken)
+            ));
+            assert!(matches!(
+                authenticate_bearer(&mut conn, "not a bearer token", "appauth").await,
+                Err(Error::MalformedBearer)
+            ));
+        });
+    }
+
+    #[test]
+    fn expired_token_fails_without_cache() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool.clone(), redis_pool, "appauth");
+            let (app_auth, token) = NewAppAuth::generate(
+                "app".into(),
+                None,
+                Default::default(),
+                Some(chrono::Utc::now() - Duration::minutes(1)),
+            );
+
+            // Inserted directly, so nothing is cached and verification goes to Postgres.
+            let mut conn = pg_pool.acquire().await.unwrap();
+            let id = insert_app_auth(&mut conn, app_auth, "appauth")
+                .await
+                .unwrap();
+            assert!(matches!(
+                backend.verify_token(id, token.expose_secret()).await,
+                Err(Error::InvalidToken)
+            ));
+        });
+    }
+
+    #[test]
+    fn verify_token_with_scope() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend
+                .create_appauth(app_auth.with_scopes(["read"]))
+                .await
+                .unwrap();
+            assert_eq!(app_auth.scopes, vec!["read".to_string()]);
+
+            backend
+                .verify_token_with_scope(app_auth.id, token.expose_secret(), "read")
+                .await
+                .unwrap();
+            assert!(matches!(
+                backend
+                    .verify_token_with_scope(app_auth.id, token.expose_secret(), "write")
+                    .await,
+                Err(Error::InsufficientScope(scope)) if scope == "write"
+            ));
+            assert!(matches!(
+                backend
+                    .verify_token_with_scope(app_auth.id, "not the token", "read")
+                    .await,
+                Err(Error::InvalidToken)
+            ));
+
+            // Granting a scope takes effect right away.
+            let update = AppAuthUpdate {
+                scopes: vec!["read".into(), "write".into()],
+                ..(&app_auth).into()
+            };
+            let updated = backend
+                .update_appauth(app_auth.id, update, Some(app_auth.version))
+                .await
+                .unwrap();
+            assert_eq!(
+                updated.scopes,
+                vec!["read".to_string(), "write".to_string()]
+            );
+            backend
+                .verify_token_with_scope(app_auth.id, token.expose_secret(), "write")
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn verify_token_records_use() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+            assert_eq!(app_auth.last_used_at, None);
+
+            backend
+                .verify_token(app_auth.id, "not the token")
+                .await
+                .unwrap_err();
+            let found = backend.find_appauth_by_id(app_auth.id).await.unwrap();
+            assert_eq!(found.last_used_at, None);
+
+            backend
+                .verify_token(app_auth.id, token.expose_secret())
+                .await
+                .unwrap();
+            assert!(recorded_use(&backend, app_auth.id).await.is_some());
+        });
+    }
+
+    #[test]
+    fn authenticate_bearer_records_use() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool.clone(), redis_pool, "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let mut conn = pg_pool.acquire().await.unwrap();
+            let id = insert_app_auth(&mut conn, app_auth, "appauth")
+                .await
+                .unwrap();
+            assert_eq!(
+                backend.find_appauth_by_id(id).await.unwrap().last_used_at,
+                None
+            );
+
+            let before = chrono::Utc::now() - Duration::seconds(1);
+            backend
+                .authenticate_bearer(token.expose_secret())
+                .await
+                .unwrap();
+            let last_used_at = recorded_use(&backend, id).await.unwrap();
+            assert!(last_used_at > before);
+
+            // Within the minute, further uses aren't written.
+            sqlx::query("UPDATE appauth SET last_used_at = NULL WHERE id = $1")
+                .bind(id)
+                .execute(&pg_pool)
+                .await
+                .unwrap();
+            backend
+                .authenticate_bearer(token.expose_secret())
+                .await
+                .unwrap();
+            assert_eq!(recorded_use(&backend, id).await, None);
+        });
+    }
+
+    #[test]
+    fn find_appauth_by_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+            let (app_auth, _) = NewAppAuth::generate(
+                "app".into(),
+                Some("Fetched back".into()),
+                serde_json::json!({ "team": "blue" }),
+                None,
+            );
+            let created = backend.create_appauth(app_auth).await.unwrap();
+
+            let found = backend.find_appauth_by_id(created.id).await.unwrap();
+            assert_eq!(found.id, created.id);
+            assert_eq!(found.name, "app");
+            assert_eq!(found.description.as_deref(), Some("Fetched back"));
+            assert_eq!(found.meta, serde_json::json!({ "team": "blue" }));
+
+            assert!(matches!(
+                backend
+                    .find_appauth_by_id(AppAuthId(uuid::Uuid::new_v4()))
+                    .await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn stale_appauth_update_conflicts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+            let (app_auth, _) = NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let created = backend.create_appauth(app_auth).await.unwrap();
+
+            let update = |description: &str| AppAuthUpdate {
+                description: Some(description.into()),
+                ..(&created).into()
+            };
+            let (a, b) = tokio::join!(
+                backend.update_appauth(created.id, update("a"), Some(created.version)),
+                backend.update_appauth(created.id, update("b"), Some(created.version)),
+            );
+            let (updated, stale) = match (a, b) {
+                (Ok(updated), stale) | (stale, Ok(updated)) => (updated, stale),
+                _ => panic!("neither update went through"),
+            };
+            assert!(matches!(stale, Err(Error::Conflict)));
+            assert_eq!(updated.version, created.version + 1);
+
+            let found = backend.find_appauth_by_id(created.id).await.unwrap();
+            assert_eq!(found.description, updated.description);
+
+            // Unconditional updates always go through.
+            let forced = backend
+                .update_appauth(created.id, update("c"), None)
+                .await
+                .unwrap();
+            assert_eq!(forced.version, created.version + 2);
+
+            assert!(matches!(
+                backend
+                    .update_appauth(AppAuthId(uuid::Uuid::new_v4()), update("d"), None)
+                    .await,
+                Err(Error::NotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn list_appauths() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool, redis_pool, "appauth");
+
+            let mut ids = Vec::new();
+            let mut tokens = Vec::new();
+            for name in ["a", "b", "c"] {
+                let (app_auth, token) =
+                    NewAppAuth::generate(name.into(), None, Default::default(), None);
+                let app_auth = app_auth.with_scopes([name]);
+                ids.push(backend.create_appauth(app_auth).await.unwrap().id);
+                tokens.push(token);
+            }
+            ids.sort_by_key(|id| id.0);
+
+            let mut listed = Vec::new();
+            let mut after = None;
+            loop {
+                let page = backend.list_appauths(after, 2).await.unwrap();
+                assert!(page.app_auths.len() <= 2);
+                listed.extend(page.app_auths);
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+
+            assert_eq!(listed.iter().map(|a| a.id).collect::<Vec<_>>(), ids);
+            for token in &tokens {
+                assert!(listed
+                    .iter()
+                    .any(|a| a.token.expose_secret() == token.expose_secret()));
+            }
+            assert!(listed.iter().all(|a| a.scopes == vec![a.name.clone()]));
+            assert!(matches!(
+                backend.list_appauths(None, 0).await,
+                Err(Error::InvalidLimit)
+            ));
+        });
+    }
+
+    #[test]
+    fn quota_never_goes_negative() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Arc::new(Backend::new(pg_pool, redis_pool, "appauth"));
+
+            let (metered, _) =
+                NewAppAuth::generate("metered".into(), None, Default::default(), None);
+            let (unmetered, _) =
+                NewAppAuth::generate("unmetered".into(), None, Default::default(), None);
+            let metered = backend
+                .create_appauth(metered.with_quota(100))
+                .await
+                .unwrap()
+                .id;
+            let unmetered = backend.create_appauth(unmetered).await.unwrap().id;
+
+            let tasks = (0..40)
+                .map(|_| {
+                    let backend = backend.clone();
+                    tokio::spawn(async move { backend.consume_quota(metered, 3).await })
+                })
+                .collect::<Vec<_>>();
+            let mut granted = 0;
+            for task in tasks {
+                match task.await.unwrap() {
+                    Ok(Some(remaining)) => {
+                        assert!(remaining >= 0);
+                        granted += 1;
+                    }
+                    Err(Error::QuotaExhausted { remaining }) => assert!(remaining < 3),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(granted, 33);
+
+            assert_eq!(backend.consume_quota(metered, 1).await.unwrap(), Some(0));
+            assert!(matches!(
+                backend.consume_quota(metered, 1).await,
+                Err(Error::QuotaExhausted { remaining: 0 })
+            ));
+            assert_eq!(backend.consume_quota(unmetered, 1).await.unwrap(), None);
+
+            // Topping the quota up makes it usable again.
+            let record = backend.find_appauth_by_id(metered).await.unwrap();
+            let update = AppAuthUpdate {
+                remaining_quota: Some(5),
+                ..(&record).into()
+            };
+            backend.update_appauth(metered, update, None).await.unwrap();
+            assert_eq!(backend.consume_quota(metered, 5).await.unwrap(), Some(0));
+        });
+    }
+
+    #[test]
+    fn verify_token_warms_cache() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool.clone(), redis_pool.clone(), "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+
+            // As if Redis had been flushed.
+            let key = format!("appauth/{}", *app_auth.id);
+            let mut conn = redis_pool.get().await.unwrap();
+            redis::cmd("DEL")
+                .arg(&key)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .unwrap();
+
+            backend
+                .verify_token(app_auth.id, token.expose_secret())
+                .await
+                .unwrap();
+            let cached: Option<String> = redis::cmd("GET")
+                .arg(&key)
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(cached.as_deref(), Some(token.expose_secret().as_str()));
+
+            // With the row gone, only the cache can still vouch for the token.
+            sqlx::query("DELETE FROM appauth WHERE id = $1")
+                .bind(app_auth.id)
+                .execute(&pg_pool)
+                .await
+                .unwrap();
+            backend
+                .verify_token(app_auth.id, token.expose_secret())
+                .await
+                .unwrap();
+        });
+    }
+
+    #[test]
+    fn verify_token_mismatch_stays_in_cache() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let (pg_pool, redis_pool) = (test_db::pool().await, test_db::redis_pool());
+            let backend = Backend::new(pg_pool.clone(), redis_pool.clone(), "appauth");
+            let (app_auth, token) =
+                NewAppAuth::generate("app".into(), None, Default::default(), None);
+            let app_auth = backend.create_appauth(app_auth).await.unwrap();
+
+            // With the row gone, a guess that went to Postgres would find nothing.
+            sqlx::query("DELETE FROM appauth WHERE id = $1")
+                .bind(app_auth.id)
+                .execute(&pg_pool)
+                .await
+                .unwrap();
+            assert!(matches!(
+                backend.verify_token(app_auth.id, "not the token").await,
+                Err(Error::InvalidToken)
+            ));
+
+            let mut conn = redis_pool.get().await.unwrap();
+            let cached: Option<String> = redis::cmd("GET")
+                .arg(format!("appauth/{}", *app_auth.id))
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            assert_eq!(cached.as_deref(), Some(token.expose_secret().as_str()));
+        });
+    }
+}
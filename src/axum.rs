@@ -0,0 +1,424 @@
+//! Axum extractors for sessions and app auth bearer tokens.
+//!
+//! Add [`SessionAuth`] and/or [`AppAuthState`] to the router state (directly, or through
+//! [`FromRef`]), then take [`AuthenticatedUser`] or [`AppAuthPrincipal`] as handler arguments.
+//! Both reject with `401 Unauthorized` when the request isn't authenticated, which for sessions
+//! includes a missing or malformed cookie and an unknown or expired session. Failures of the
+//! backend itself are answered with `500 Internal Server Error` instead, see [`AuthError`].
+//!
+//! To load the session once for a whole router instead, add a [`SessionLayer`]. It puts the
+//! [`AuthenticatedUser`] into the request's extensions, where the extractor finds it.
+//!
+//! ```
+//! use std::sync::Arc;
+//!
+//! use axum::{routing::get, Router};
+//! use chrono::Duration;
+//! use thetc_auth::{
+//!     axum::{AuthenticatedUser, SessionAuth, SessionIdSource},
+//!     session::memory,
+//! };
+//!
+//! async fn me(user: AuthenticatedUser<uuid::Uuid>) -> String {
+//!     format!("Hello, {}", user.user_id)
+//! }
+//!
+//! let sessions = Arc::new(memory::SessionManager::new(
+//!     true,
+//!     Duration::hours(1),
+//!     memory::Backend::default(),
+//! ));
+//! let app: Router = Router::new()
+//!     .route("/me", get(me))
+//!     .with_state(SessionAuth::new(
+//!         sessions,
+//!         SessionIdSource::Cookie("sid".into()),
+//!     ));
+//! ```
+
+use std::{
+    convert::TryFrom,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use ::axum::{
+    extract::{FromRef, FromRequestParts},
+    http::{header, request::Parts, HeaderMap, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use async_trait::async_trait;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::{
+    appauth::{self, AppAuth, AppAuthBackend},
+    session::{self, SessionBackend, SessionId, SessionManager, SessionUser},
+};
+
+/// Tells errors meaning that the request isn't authenticated, answered with
+/// `401 Unauthorized`, from failures of the backend, answered with `500 Internal Server Error`
+/// so that an outage doesn't pass for bad credentials.
+pub trait AuthError {
+    fn is_unauthorized(&self) -> bool;
+}
+
+impl AuthError for session::memory::Error {
+    fn is_unauthorized(&self) -> bool {
+        use session::memory::Error::*;
+        matches!(self, NotFound(_) | Expired(_))
+    }
+}
+
+impl<U> AuthError for session::postgres::Error<U> {
+    fn is_unauthorized(&self) -> bool {
+        use session::postgres::Error::*;
+        matches!(self, NotFound(_) | Expired(_) | UserNotFound(_))
+    }
+}
+
+impl AuthError for session::redis::Error {
+    fn is_unauthorized(&self) -> bool {
+        matches!(self, session::redis::Error::NotFound(_))
+    }
+}
+
+#[cfg(feature = "jwt")]
+impl AuthError for session::jwt::Error {
+    fn is_unauthorized(&self) -> bool {
+        use session::jwt::Error::*;
+        matches!(self, Jwt(_) | Expired | Revoked(_) | TooLong)
+    }
+}
+
+impl AuthError for appauth::postgres_redis::Error {
+    fn is_unauthorized(&self) -> bool {
+        use appauth::postgres_redis::Error::*;
+        matches!(self, InvalidToken | MalformedBearer | NotFound)
+    }
+}
+
+/// The status to reject a request with after `e`.
+fn rejection(e: &impl AuthError) -> StatusCode {
+    match e.is_unauthorized() {
+        true => StatusCode::UNAUTHORIZED,
+        false => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+/// Where requests carry their session id.
+#[derive(Debug, Clone)]
+pub enum SessionIdSource {
+    Cookie(String),
+    Header(String),
+}
+
+impl SessionIdSource {
+    fn session_id(&self, headers: &HeaderMap) -> Option<SessionId> {
+        let raw = match self {
+            SessionIdSource::Cookie(name) => headers
+                .get_all(header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v)?,
+            SessionIdSource::Header(name) => headers.get(name.as_str())?.to_str().ok()?,
+        };
+
+        SessionId::try_from(raw.trim()).ok()
+    }
+}
+
+/// Object-safe view of a [`SessionManager`], so the state doesn't carry its type parameters.
+#[async_trait]
+trait ResolveSession<U>: Send + Sync {
+    async fn resolve(&self, id: SessionId) -> Result<U, StatusCode>;
+}
+
+#[async_trait]
+impl<T, S, U, E> ResolveSession<U> for SessionManager<T, S, U, E>
+where
+    T: SessionBackend<Error = E, Session = S, UserId = U>,
+    S: SessionUser<UserId = U> + Send,
+    U: Clone + Send + Sync,
+    E: std::error::Error + AuthError + Send,
+{
+    async fn resolve(&self, id: SessionId) -> Result<U, StatusCode> {
+        let session = self.session(id).await.map_err(|e| rejection(&e))?;
+        Ok(session.user_id().clone())
+    }
+}
+
+/// State needed by [`AuthenticatedUser`].
+pub struct SessionAuth<U> {
+    sessions: Arc<dyn ResolveSession<U>>,
+    source: SessionIdSource,
+}
+
+impl<U> Clone for SessionAuth<U> {
+    fn clone(&self) -> Self {
+        Self {
+            sessions: self.sessions.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<U> SessionAuth<U> {
+    /// The user of the session the headers carry, if it exists and hasn't expired.
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<AuthenticatedUser<U>, StatusCode> {
+        let session_id = self
+            .source
+            .session_id(headers)
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        let user_id = self.sessions.resolve(session_id.clone()).await?;
+
+        Ok(AuthenticatedUser {
+            session_id,
+            user_id,
+        })
+    }
+}
+
+impl<U: Clone + Send + Sync + 'static> SessionAuth<U> {
+    pub fn new<T, S, E>(
+        session_manager: Arc<SessionManager<T, S, U, E>>,
+        source: SessionIdSource,
+    ) -> Self
+    where
+        T: SessionBackend<Error = E, Session = S, UserId = U> + 'static,
+        S: SessionUser<UserId = U> + Send + 'static,
+        E: std::error::Error + AuthError + Send + 'static,
+    {
+        Self {
+            sessions: session_manager,
+            source,
+        }
+    }
+}
+
+/// The user of the request's session. Resolving it refreshes the session if the manager is set
+/// to `auto_refresh`. Taken from the request's extensions if a [`SessionLayer`] already did.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedUser<U> {
+    pub session_id: SessionId,
+    pub user_id: U,
+}
+
+#[async_trait]
+impl<U, St> FromRequestParts<St> for AuthenticatedUser<U>
+where
+    SessionAuth<U>: FromRef<St>,
+    St: Send + Sync,
+    U: Clone + Send + Sync + 'static,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &St) -> Result<Self, Self::Rejection> {
+        if let Some(user) = parts.extensions.get::<AuthenticatedUser<U>>() {
+            return Ok(user.clone());
+        }
+
+        SessionAuth::<U>::from_ref(state)
+            .authenticate(&parts.headers)
+            .await
+    }
+}
+
+/// Loads the session of every request once, and puts its [`AuthenticatedUser`] into the
+/// request's extensions, for handlers to take with the extractor or `Extension`. Requests
+/// without a valid session get `401 Unauthorized`, unless made optional with
+/// [`SessionLayer::with_anonymous_requests`]. Failures of the backend get
+/// `500 Internal Server Error` either way.
+///
+/// ```
+/// use std::sync::Arc;
+///
+/// use axum::{routing::get, Extension, Router};
+/// use chrono::Duration;
+/// use thetc_auth::{
+///     axum::{AuthenticatedUser, SessionIdSource, SessionLayer},
+///     session::memory,
+/// };
+///
+/// async fn me(Extension(user): Extension<AuthenticatedUser<uuid::Uuid>>) -> String {
+///     format!("Hello, {}", user.user_id)
+/// }
+///
+/// let sessions = Arc::new(memory::SessionManager::new(
+///     true,
+///     Duration::hours(1),
+///     memory::Backend::default(),
+/// ));
+/// let app: Router = Router::new()
+///     .route("/me", get(me))
+///     .layer(SessionLayer::<uuid::Uuid>::new(
+///         sessions,
+///         SessionIdSource::Cookie("sid".into()),
+///     ));
+/// ```
+pub struct SessionLayer<U> {
+    auth: SessionAuth<U>,
+    reject_anonymous: bool,
+}
+
+impl<U> Clone for SessionLayer<U> {
+    fn clone(&self) -> Self {
+        Self {
+            auth: self.auth.clone(),
+            reject_anonymous: self.reject_anonymous,
+        }
+    }
+}
+
+impl<U: Clone + Send + Sync + 'static> SessionLayer<U> {
+    pub fn new<T, S, E>(
+        session_manager: Arc<SessionManager<T, S, U, E>>,
+        source: SessionIdSource,
+    ) -> Self
+    where
+        T: SessionBackend<Error = E, Session = S, UserId = U> + 'static,
+        S: SessionUser<UserId = U> + Send + 'static,
+        E: std::error::Error + AuthError + Send + 'static,
+    {
+        Self {
+            auth: SessionAuth::new(session_manager, source),
+            reject_anonymous: true,
+        }
+    }
+
+    /// Passes requests without a valid session on, with no [`AuthenticatedUser`] in their
+    /// extensions, for routes that work either way.
+    pub fn with_anonymous_requests(mut self) -> Self {
+        self.reject_anonymous = false;
+        self
+    }
+}
+
+impl<Svc, U> Layer<Svc> for SessionLayer<U> {
+    type Service = SessionService<Svc, U>;
+
+    fn layer(&self, inner: Svc) -> Self::Service {
+        SessionService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service [`SessionLayer`] wraps others in.
+pub struct SessionService<Svc, U> {
+    inner: Svc,
+    layer: SessionLayer<U>,
+}
+
+impl<Svc: Clone, U> Clone for SessionService<Svc, U> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            layer: self.layer.clone(),
+        }
+    }
+}
+
+impl<Svc, U, B> Service<Request<B>> for SessionService<Svc, U>
+where
+    Svc: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    Svc::Future: Send,
+    U: Clone + Send + Sync + 'static,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = Svc::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, Svc::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        // The clone may not be ready, so call the service that was polled and keep the clone.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let layer = self.layer.clone();
+
+        Box::pin(async move {
+            match layer.auth.authenticate(request.headers()).await {
+                Ok(user) => {
+                    request.extensions_mut().insert(user);
+                }
+                Err(StatusCode::UNAUTHORIZED) if !layer.reject_anonymous => {}
+                Err(status) => return Ok(status.into_response()),
+            }
+            inner.call(request).await
+        })
+    }
+}
+
+/// Object-safe view of an [`AppAuthBackend`], so the state doesn't carry its type.
+#[async_trait]
+trait AuthenticateBearer: Send + Sync {
+    async fn authenticate(&self, bearer: &str) -> Result<AppAuth, StatusCode>;
+}
+
+#[async_trait]
+impl<B> AuthenticateBearer for B
+where
+    B: AppAuthBackend + Send + Sync,
+    B::Error: AuthError,
+{
+    async fn authenticate(&self, bearer: &str) -> Result<AppAuth, StatusCode> {
+        self.authenticate_bearer(bearer)
+            .await
+            .map_err(|e| rejection(&e))
+    }
+}
+
+/// State needed by [`AppAuthPrincipal`].
+#[derive(Clone)]
+pub struct AppAuthState {
+    backend: Arc<dyn AuthenticateBearer>,
+}
+
+impl AppAuthState {
+    pub fn new<B>(backend: Arc<B>) -> Self
+    where
+        B: AppAuthBackend + Send + Sync + 'static,
+        B::Error: AuthError,
+    {
+        Self { backend }
+    }
+}
+
+/// The app auth authenticated by the request's `Authorization: Bearer` token.
+#[derive(Debug)]
+pub struct AppAuthPrincipal(pub AppAuth);
+
+#[async_trait]
+impl<St> FromRequestParts<St> for AppAuthPrincipal
+where
+    AppAuthState: FromRef<St>,
+    St: Send + Sync,
+{
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, state: &St) -> Result<Self, Self::Rejection> {
+        let bearer = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let app_auth = AppAuthState::from_ref(state)
+            .backend
+            .authenticate(bearer.trim())
+            .await?;
+
+        Ok(AppAuthPrincipal(app_auth))
+    }
+}
@@ -1,13 +1,42 @@
+//! Authentication and authorization tools: users with hashed passwords, sessions and app auth
+//! tokens, backed by Postgres, Redis and optionally SQLite.
+//!
+//! # Features
+//!
+//! None are enabled by default.
+//!
+//! - `axum`: extractors and a session layer for axum, in `thetc_auth::axum`.
+//! - `deadpool`: backends over deadpool's Postgres pool, `PgPool`, as an alternative to sqlx's.
+//! - `jwt`: stateless sessions in signed tokens, `session::jwt`.
+//! - `bcrypt`: bcrypt hashing, `password_strategy::BcryptStrategy`, mostly for hashes from
+//!   other systems.
+//! - `scrypt`: scrypt hashing, `password_strategy::ScryptStrategy`.
+//! - `ldap3`: passwords checked against an LDAP directory, `password_strategy::LdapStrategy`.
+//! - `zxcvbn`: a minimum strength for new passwords, `Argon2idStrategy::with_min_strength`.
+//! - `unicode`: usernames of printable Unicode, `username::unicode::UnicodeUsername`.
+//! - `sqlite`: the SQLite user backend, `user::SqliteUsers`. There is no SQLite session
+//!   backend.
+//! - `test-containers`: only for running this crate's backend tests against throwaway
+//!   Postgres and Redis containers. Needs Docker.
+
 pub mod appauth;
+#[cfg(feature = "axum")]
+pub mod axum;
+pub mod meta_cipher;
 pub mod password_strategy;
+pub mod schema;
 pub mod session;
 pub mod user;
 pub mod username;
 
+pub use meta_cipher::MetaCipher;
+pub use schema::migrate;
 pub use user::postgres::PgPasswordResetBackend;
 
 mod util;
 
+pub use util::{warmup_pg_pool, ConnSource};
+
 #[cfg(feature = "deadpool")]
 pub use util::deadpool::{PgHandle, PgPool};
 
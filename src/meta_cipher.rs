@@ -0,0 +1,206 @@
+//! Optional encryption at rest for `meta` columns.
+//!
+//! Encrypted meta is stored as `{"$enc": {"kid": ..., "nonce": ..., "ct": ...}}`, so the column
+//! stays valid JSON. Values without the `$enc` envelope are passed through on read, which lets
+//! existing plaintext rows be read (and re-encrypted on their next write) after enabling it.
+//!
+//! The id of the row the meta belongs to is authenticated along with it, so that ciphertext
+//! copied into another row fails to decrypt instead of handing that row someone else's meta.
+
+use std::collections::HashMap;
+
+use chacha20poly1305::{
+    aead::{Aead, Payload},
+    KeyInit, XChaCha20Poly1305, XNonce,
+};
+use rand::RngCore;
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const ENVELOPE_KEY: &str = "$enc";
+
+const NONCE_LEN: usize = 24;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Meta was encrypted with unknown key {0:?}.")]
+    UnknownKey(String),
+
+    #[error("Encrypted meta is malformed.")]
+    Malformed,
+
+    #[error("Meta could not be decrypted.")]
+    Decrypt,
+
+    #[error("Meta could not be encrypted.")]
+    Encrypt,
+
+    #[error("json error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    kid: String,
+    nonce: String,
+    ct: String,
+}
+
+/// Encrypts meta with XChaCha20-Poly1305 under the current key, and decrypts meta written under
+/// the current or any retired key.
+pub struct MetaCipher {
+    current_key_id: String,
+    keys: HashMap<String, XChaCha20Poly1305>,
+}
+
+impl MetaCipher {
+    pub fn new(key_id: impl Into<String>, key: Secret<[u8; 32]>) -> Self {
+        let current_key_id = key_id.into();
+        let mut keys = HashMap::new();
+        keys.insert(
+            current_key_id.clone(),
+            XChaCha20Poly1305::new(key.expose_secret().into()),
+        );
+
+        Self {
+            current_key_id,
+            keys,
+        }
+    }
+
+    /// Keeps decrypting meta written under a key that has been rotated out.
+    pub fn with_retired_key(mut self, key_id: impl Into<String>, key: Secret<[u8; 32]>) -> Self {
+        self.keys
+            .entry(key_id.into())
+            .or_insert_with(|| XChaCha20Poly1305::new(key.expose_secret().into()));
+        self
+    }
+
+    /// Encrypts the meta of the row `id`, which it will only decrypt for.
+    pub fn encrypt(&self, id: Uuid, meta: &serde_json::Value) -> Result<serde_json::Value, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let plaintext = serde_json::to_vec(meta)?;
+        let ciphertext = self.keys[&self.current_key_id]
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: id.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Encrypt)?;
+
+        let envelope = Envelope {
+            kid: self.current_key_id.clone(),
+            nonce: hex::encode(nonce),
+            ct: hex::encode(ciphertext),
+        };
+        Ok(serde_json::json!({ ENVELOPE_KEY: envelope }))
+    }
+
+    /// Decrypts meta produced by [`MetaCipher::encrypt`] for the same `id`. Anything else is
+    /// returned as is.
+    pub fn decrypt(&self, id: Uuid, stored: serde_json::Value) -> Result<serde_json::Value, Error> {
+        let envelope = match stored {
+            serde_json::Value::Object(mut map) if map.len() == 1 => {
+                match map.remove(ENVELOPE_KEY) {
+                    Some(envelope) => envelope,
+                    None => return Ok(serde_json::Value::Object(map)),
+                }
+            }
+            stored => return Ok(stored),
+        };
+
+        let envelope: Envelope = serde_json::from_value(envelope).map_err(|_| Error::Malformed)?;
+        let cipher = self
+            .keys
+            .get(&envelope.kid)
+            .ok_or_else(|| Error::UnknownKey(envelope.kid.clone()))?;
+        let nonce = hex::decode(&envelope.nonce).map_err(|_| Error::Malformed)?;
+        if nonce.len() != NONCE_LEN {
+            return Err(Error::Malformed);
+        }
+        let ciphertext = hex::decode(&envelope.ct).map_err(|_| Error::Malformed)?;
+
+        let plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: id.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Decrypt)?;
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use uuid::Uuid;
+
+    use super::{Error, MetaCipher};
+
+    const ID: Uuid = Uuid::from_u128(1);
+
+    #[test]
+    fn round_trip() {
+        let cipher = MetaCipher::new("k1", Secret::new([7; 32]));
+        let meta = serde_json::json!({ "email": "alice@example.com", "age": 42 });
+
+        let stored = cipher.encrypt(ID, &meta).unwrap();
+        assert_eq!(cipher.decrypt(ID, stored).unwrap(), meta);
+    }
+
+    #[test]
+    fn bound_to_row() {
+        let cipher = MetaCipher::new("k1", Secret::new([7; 32]));
+        let meta = serde_json::json!({ "email": "alice@example.com" });
+
+        let stored = cipher.encrypt(ID, &meta).unwrap();
+        assert!(matches!(
+            cipher.decrypt(Uuid::from_u128(2), stored),
+            Err(Error::Decrypt)
+        ));
+    }
+
+    #[test]
+    fn stored_value_is_not_plaintext() {
+        let cipher = MetaCipher::new("k1", Secret::new([7; 32]));
+        let meta = serde_json::json!({ "email": "alice@example.com" });
+
+        let stored = cipher.encrypt(ID, &meta).unwrap().to_string();
+        assert!(!stored.contains("alice"));
+        assert!(!stored.contains("email"));
+    }
+
+    #[test]
+    fn plaintext_passes_through() {
+        let cipher = MetaCipher::new("k1", Secret::new([7; 32]));
+        let meta = serde_json::json!({ "email": "alice@example.com" });
+
+        assert_eq!(cipher.decrypt(ID, meta.clone()).unwrap(), meta);
+    }
+
+    #[test]
+    fn rotation() {
+        let old = MetaCipher::new("k1", Secret::new([7; 32]));
+        let meta = serde_json::json!({ "email": "alice@example.com" });
+        let stored = old.encrypt(ID, &meta).unwrap();
+
+        let rotated = MetaCipher::new("k2", Secret::new([8; 32]))
+            .with_retired_key("k1", Secret::new([7; 32]));
+        assert_eq!(rotated.decrypt(ID, stored.clone()).unwrap(), meta);
+
+        let forgetful = MetaCipher::new("k2", Secret::new([8; 32]));
+        assert!(matches!(
+            forgetful.decrypt(ID, stored),
+            Err(Error::UnknownKey(kid)) if kid == "k1"
+        ));
+    }
+}
@@ -1,24 +1,68 @@
+pub mod actor;
+#[cfg(feature = "ldap3")]
+pub mod ldap;
+
 use std::convert::TryFrom;
 
 use argon2::{
     password_hash::{Salt, SaltString},
-    Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier,
+    Argon2, Params, ParamsBuilder, PasswordHash, PasswordHasher, PasswordVerifier,
 };
-use secrecy::Secret;
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+
+pub use actor::ActorStrategy;
+pub use argon2::Algorithm;
+#[cfg(feature = "ldap3")]
+pub use ldap::LdapStrategy;
 
 pub trait Strategy: Send + Sync {
     fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error>;
     fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error>;
+
+    /// Whether `hash` was generated with settings other than the configured ones, and should be
+    /// replaced by a fresh hash the next time the password is known.
+    fn needs_rehash(&self, _hash: &str) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Verifies `input`, returning `None` if it is wrong and otherwise whether the hash should
+    /// be replaced. Knowing the password can tell more than [`Strategy::needs_rehash`] alone,
+    /// e.g. that the hash was made with a retired pepper.
+    fn verify_and_check_rehash(&self, hash: &str, input: &str) -> Result<Option<bool>, Error> {
+        match self.verify_password(hash, input)? {
+            true => self.needs_rehash(hash).map(Some),
+            false => Ok(None),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+/// Argon2id hashing with a pepper. Argon2i or Argon2d can be picked instead with
+/// [`Argon2idStrategy::with_algorithm`].
+///
+/// New hashes are always generated with the configured params. Verification instead uses the
+/// variant and params embedded in the stored hash, and accepts any Argon2 hash whose params lie
+/// between
+/// the construction minimums and the `MAX_VERIFY_*` bounds. Hashes made under an earlier
+/// configuration therefore keep verifying while params are migrated, and
+/// [`Strategy::needs_rehash`] tells which of them are due for an upgrade. Anything
+/// weaker than the minimums (a downgrade) or costlier than the bounds (a DoS) is rejected.
 pub struct Argon2idStrategy {
     /// Goes with a salt. A shared salt that is mixed into all password hashing to ensure that if
     /// the database is leaked, without this extra piece, brute forcing is going to be
     /// effectively impossible.
-    ///
-    /// TODO: fix this with Secret.
-    pepper: Vec<u8>,
+    pepper: Secret<Vec<u8>>,
+
+    /// Peppers rotated out. Verification still accepts hashes made with them, but those need a
+    /// rehash.
+    retired_peppers: Vec<Secret<Vec<u8>>>,
+
+    /// Names the current pepper in new hashes, so that hashes made with another one can be
+    /// told apart without knowing the password.
+    pepper_id: Option<Vec<u8>>,
+
+    /// Argon2 variant new hashes are generated with.
+    algorithm: Algorithm,
 
     /// Memory to use in megabytes. Minimum is 15MB.
     memory_mib: u32,
@@ -28,8 +72,36 @@ pub struct Argon2idStrategy {
 
     /// Parallelism level. Minimum is 1.
     parallelism_degree: u32,
+
+    /// Shortest password, in bytes, new hashes are generated for. Minimum is 8.
+    min_password_length: usize,
+
+    /// Longest password, in bytes, that is hashed or verified at all.
+    max_password_length: usize,
+
+    /// Lowest zxcvbn score (0 to 4) a new password must reach.
+    #[cfg(feature = "zxcvbn")]
+    min_strength: Option<u8>,
 }
 
+/// Cost params of an [`Argon2idStrategy`], for loading from configuration. The pepper is
+/// deliberately not part of them, see [`Argon2idStrategy::from_params`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Argon2idParams {
+    pub memory_mib: u32,
+    pub iteration_count: u32,
+    pub parallelism_degree: u32,
+}
+
+/// Largest memory cost, in megabytes, of a stored hash that will be verified.
+const MAX_VERIFY_MEMORY_MIB: u32 = 4096;
+
+/// Largest iteration count of a stored hash that will be verified.
+const MAX_VERIFY_ITERATIONS: u32 = 64;
+
+/// Largest parallelism degree of a stored hash that will be verified.
+const MAX_VERIFY_PARALLELISM: u32 = 64;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Provided pepper is too weak. Minimum size: 8")]
@@ -44,21 +116,70 @@ pub enum Error {
     #[error("Parallelism must be at least 1.")]
     ParallelismTooWeak,
 
-    #[error("Password must be at least 8 characters.")]
+    #[error(
+        "Hashing params exceed what is verified. Maximum: 4096 MiB, 64 iterations, parallelism 64"
+    )]
+    ParamsTooCostly,
+
+    #[error("Pepper id must be 1 to 8 bytes long.")]
+    InvalidPepperId,
+
+    #[error("Minimum password length is too weak. Minimum: 8")]
+    MinPasswordLengthTooWeak,
+
+    #[error("Maximum password length is below the minimum password length.")]
+    MaxPasswordLengthBelowMin,
+
+    #[cfg(feature = "bcrypt")]
+    #[error("bcrypt cost is too weak. Minimum: 10")]
+    CostTooWeak,
+
+    #[cfg(feature = "scrypt")]
+    #[error("scrypt params are too weak. Minimum: log_n 15, r 8, p 1")]
+    ScryptParamsTooWeak,
+
+    #[cfg(feature = "scrypt")]
+    #[error("scrypt params exceed what is verified. Maximum: log_n 20, r 32, p 16")]
+    ScryptParamsTooCostly,
+
+    #[error("Password is shorter than the minimum length.")]
     PasswordTooShort,
 
+    #[error("Password is longer than the maximum length.")]
+    PasswordTooLong,
+
+    #[cfg(feature = "zxcvbn")]
+    #[error("Password is too weak, its strength score is {score} of 4.")]
+    PasswordTooWeak { score: u8 },
+
+    #[error("Too many password hashing requests are waiting.")]
+    Overloaded,
+
+    #[cfg(feature = "ldap3")]
+    #[error("Passwords are verified by the directory and can't be hashed locally.")]
+    HashingUnsupported,
+
+    #[cfg(feature = "ldap3")]
+    #[error("Stored hash doesn't name an LDAP bind DN.")]
+    NotLdapHash,
+
+    #[error(
+        "Stored hash is of another algorithm or its params are outside of the accepted bounds."
+    )]
+    HashParamsOutOfBounds,
+
     #[error("A strategy function has been misused")]
     Strategy(#[from] Box<dyn std::error::Error + Send + Sync>),
 }
 
 impl Argon2idStrategy {
     pub fn new(
-        pepper: Vec<u8>,
+        pepper: Secret<Vec<u8>>,
         memory_mib: u32,
         iteration_count: u32,
         parallelism_degree: u32,
     ) -> Result<Self, Error> {
-        if pepper.len() < 8 {
+        if pepper.expose_secret().len() < 8 {
             return Err(Error::PepperTooWeak);
         }
 
@@ -74,31 +195,289 @@ impl Argon2idStrategy {
             return Err(Error::ParallelismTooWeak);
         }
 
+        // Hashes beyond these would be rejected by the strategy's own verification.
+        if memory_mib > MAX_VERIFY_MEMORY_MIB
+            || iteration_count > MAX_VERIFY_ITERATIONS
+            || parallelism_degree > MAX_VERIFY_PARALLELISM
+        {
+            return Err(Error::ParamsTooCostly);
+        }
+
         Ok(Self {
             pepper,
+            retired_peppers: Vec::new(),
+            pepper_id: None,
+            algorithm: Algorithm::Argon2id,
             memory_mib,
             iteration_count,
             parallelism_degree,
+            min_password_length: 8,
+            max_password_length: 1024,
+            #[cfg(feature = "zxcvbn")]
+            min_strength: None,
         })
     }
+
+    /// Same as [`Argon2idStrategy::new`], with the cost params taken from `params`.
+    pub fn from_params(params: Argon2idParams, pepper: Secret<Vec<u8>>) -> Result<Self, Error> {
+        Self::new(
+            pepper,
+            params.memory_mib,
+            params.iteration_count,
+            params.parallelism_degree,
+        )
+    }
+
+    /// The OWASP Password Storage Cheat Sheet's recommended minimum, a good default for logins.
+    pub fn owasp_default(pepper: Secret<Vec<u8>>) -> Result<Self, Error> {
+        // m=19 MiB, t=2, p=1. OWASP lists trade-offs with less memory and more iterations, but
+        // this is the only one clearing the 15 MiB floor enforced by `new`.
+        Self::new(pepper, 19, 2, 1)
+    }
+
+    /// Costlier than [`Argon2idStrategy::owasp_default`], for servers that can spare the memory.
+    pub fn moderate(pepper: Secret<Vec<u8>>) -> Result<Self, Error> {
+        // m=256 MiB, t=3, p=1, libsodium's "moderate" limits.
+        Self::new(pepper, 256, 3, 1)
+    }
+
+    /// For rarely used, high value credentials, such as those guarding encryption keys.
+    pub fn sensitive(pepper: Secret<Vec<u8>>) -> Result<Self, Error> {
+        // m=1 GiB, t=4, p=1, libsodium's "sensitive" limits. Each hash holds a gigabyte for its
+        // duration, so this is unsuited to logins under load.
+        Self::new(pepper, 1024, 4, 1)
+    }
+
+    /// Picks params whose hashes take about `target` on this host, by timing trial hashes.
+    ///
+    /// Memory is doubled from the 15 MiB minimum while a hash takes at most half of `target`,
+    /// up to [`MAX_CALIBRATE_MEMORY_MIB`], then iterations are added to fill the remaining time.
+    /// Parallelism is 1. The params never go below the minimums, so a hash may take longer than
+    /// `target` on a slow host. This takes a few times `target`, so do it once at startup and
+    /// keep the strategy, or better, log the params and configure them.
+    pub fn calibrate(target: std::time::Duration, pepper: Secret<Vec<u8>>) -> Result<Self, Error> {
+        let mut memory_mib = 15;
+        let mut elapsed = time_hash(memory_mib, 2)?;
+        while elapsed * 2 <= target && memory_mib * 2 <= MAX_CALIBRATE_MEMORY_MIB {
+            memory_mib *= 2;
+            elapsed = time_hash(memory_mib, 2)?;
+        }
+
+        // Hashing time grows linearly with the iteration count.
+        let per_iteration = (elapsed / 2).max(std::time::Duration::from_micros(1));
+        let iteration_count = (target.as_micros() / per_iteration.as_micros())
+            .clamp(2, MAX_VERIFY_ITERATIONS as u128) as u32;
+
+        Self::new(pepper, memory_mib, iteration_count, 1)
+    }
+}
+
+/// Largest memory cost, in megabytes, [`Argon2idStrategy::calibrate`] picks.
+pub const MAX_CALIBRATE_MEMORY_MIB: u32 = 1024;
+
+/// How long an Argon2id hash with the given costs and parallelism 1 takes.
+fn time_hash(memory_mib: u32, iteration_count: u32) -> Result<std::time::Duration, Error> {
+    let params = Params::new(memory_mib * 1024, iteration_count, 1, None)
+        .map_err(|e| Error::Strategy(Box::new(e)))?;
+    let argon2 = Argon2::new(Algorithm::Argon2id, Default::default(), params);
+    let mut out = [0u8; 32];
+
+    let started = std::time::Instant::now();
+    argon2
+        .hash_password_into(b"calibration password", b"calibration salt", &mut out)
+        .map_err(|e| Error::Strategy(Box::new(e)))?;
+    Ok(started.elapsed())
 }
 
 impl Argon2idStrategy {
-    fn argon2_instance(&self) -> Argon2<'_> {
+    /// Keeps verifying hashes made with a pepper that has been rotated out. Retired peppers are
+    /// tried in the order they were added, after the current one.
+    pub fn with_retired_pepper(mut self, pepper: Secret<Vec<u8>>) -> Self {
+        self.retired_peppers.push(pepper);
+        self
+    }
+
+    /// Stores `pepper_id` (1 to 8 bytes) in new hashes as the Argon2 `keyid`. Every pepper
+    /// needs an id of its own, e.g. a counter bumped on each rotation, for
+    /// [`Strategy::needs_rehash`] to report hashes made with a retired pepper, and with it
+    /// [`crate::user::UserBackend::users_needing_rehash`]. Without an id that takes knowing the
+    /// password. Once an id is set, hashes without one are reported as well.
+    pub fn with_pepper_id(mut self, pepper_id: &[u8]) -> Result<Self, Error> {
+        if pepper_id.is_empty() || pepper_id.len() > Params::MAX_KEYID_LEN {
+            return Err(Error::InvalidPepperId);
+        }
+
+        self.pepper_id = Some(pepper_id.to_vec());
+        Ok(self)
+    }
+
+    /// Rejects new passwords shorter than `min_password_length` bytes. Defaults to 8, which is
+    /// also the lowest accepted. It can't exceed the maximum password length.
+    pub fn with_min_password_length(mut self, min_password_length: usize) -> Result<Self, Error> {
+        if min_password_length < 8 {
+            return Err(Error::MinPasswordLengthTooWeak);
+        }
+        if min_password_length > self.max_password_length {
+            return Err(Error::MaxPasswordLengthBelowMin);
+        }
+
+        self.min_password_length = min_password_length;
+        Ok(self)
+    }
+
+    /// Generates hashes with another Argon2 variant. Defaults to Argon2id, which is what should
+    /// be used unless something mandates otherwise.
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Rejects passwords longer than `max_password_length` bytes before hashing them, as
+    /// hashing time grows with the length. Defaults to 1024, and can't be below the minimum
+    /// password length, as no new password could be set then.
+    pub fn with_max_password_length(mut self, max_password_length: usize) -> Result<Self, Error> {
+        if max_password_length < self.min_password_length {
+            return Err(Error::MaxPasswordLengthBelowMin);
+        }
+
+        self.max_password_length = max_password_length;
+        Ok(self)
+    }
+
+    /// Rejects new passwords whose zxcvbn score (0 to 4) is below `min_strength`.
+    #[cfg(feature = "zxcvbn")]
+    pub fn with_min_strength(mut self, min_strength: u8) -> Self {
+        self.min_strength = Some(min_strength);
+        self
+    }
+
+    #[cfg(feature = "zxcvbn")]
+    fn check_strength(&self, input: &str) -> Result<(), Error> {
+        let min_strength = match self.min_strength {
+            Some(min_strength) => min_strength,
+            None => return Ok(()),
+        };
+
+        let score = zxcvbn::zxcvbn(input, &[])
+            .map_err(|e| Error::Strategy(Box::new(e)))?
+            .score();
+        match score >= min_strength {
+            true => Ok(()),
+            false => Err(Error::PasswordTooWeak { score }),
+        }
+    }
+}
+
+// `Secret<Vec<u8>>` is neither `Clone` nor `Debug`, so these are spelled out.
+impl Clone for Argon2idStrategy {
+    fn clone(&self) -> Self {
+        Self {
+            pepper: Secret::new(self.pepper.expose_secret().clone()),
+            retired_peppers: self
+                .retired_peppers
+                .iter()
+                .map(|p| Secret::new(p.expose_secret().clone()))
+                .collect(),
+            pepper_id: self.pepper_id.clone(),
+            algorithm: self.algorithm,
+            memory_mib: self.memory_mib,
+            iteration_count: self.iteration_count,
+            parallelism_degree: self.parallelism_degree,
+            min_password_length: self.min_password_length,
+            max_password_length: self.max_password_length,
+            #[cfg(feature = "zxcvbn")]
+            min_strength: self.min_strength,
+        }
+    }
+}
+
+impl std::fmt::Debug for Argon2idStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut debug = f.debug_struct("Argon2idStrategy");
+        debug
+            .field("pepper", &"[REDACTED]")
+            .field("retired_peppers", &self.retired_peppers.len())
+            .field("pepper_id", &self.pepper_id)
+            .field("algorithm", &self.algorithm)
+            .field("memory_mib", &self.memory_mib)
+            .field("iteration_count", &self.iteration_count)
+            .field("parallelism_degree", &self.parallelism_degree)
+            .field("min_password_length", &self.min_password_length)
+            .field("max_password_length", &self.max_password_length);
+        #[cfg(feature = "zxcvbn")]
+        debug.field("min_strength", &self.min_strength);
+        debug.finish()
+    }
+}
+
+impl Argon2idStrategy {
+    /// Params new hashes are generated with.
+    fn generation_params(&self) -> Params {
+        let mut params = ParamsBuilder::new();
+        params
+            .m_cost(self.memory_mib * 1024)
+            .unwrap()
+            .t_cost(self.iteration_count)
+            .unwrap()
+            .p_cost(self.parallelism_degree)
+            .unwrap();
+        if let Some(pepper_id) = &self.pepper_id {
+            params.keyid(pepper_id).unwrap();
+        }
+        params.params().unwrap()
+    }
+
+    fn argon2_instance<'a>(&self, pepper: &'a Secret<Vec<u8>>) -> Argon2<'a> {
         Argon2::new_with_secret(
-            &self.pepper,
-            Default::default(),
+            pepper.expose_secret(),
+            self.algorithm,
             Default::default(),
-            Params::new(
-                self.memory_mib * 1024,
-                self.iteration_count,
-                self.parallelism_degree,
-                None,
-            )
-            .unwrap(),
+            self.generation_params(),
         )
         .unwrap()
     }
+
+    /// Index of the pepper `hash` was made with, 0 being the current one, if `input` matches.
+    fn matching_pepper(&self, hash: &str, input: &str) -> Result<Option<usize>, Error> {
+        if input.len() > self.max_password_length {
+            return Err(Error::PasswordTooLong);
+        }
+
+        let hash = PasswordHash::new(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
+        accepted_params(&hash)?;
+
+        let peppers = std::iter::once(&self.pepper).chain(&self.retired_peppers);
+        for (i, pepper) in peppers.enumerate() {
+            // Verifies against the params embedded in `hash`, not the configured ones.
+            match self
+                .argon2_instance(pepper)
+                .verify_password(input.as_bytes(), &hash)
+            {
+                Ok(_) => return Ok(Some(i)),
+                Err(argon2::password_hash::Error::Password) => continue,
+                Err(e) => return Err(Error::Strategy(Box::new(e))),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Params of a stored hash, if they are within what verification accepts.
+fn accepted_params(hash: &PasswordHash<'_>) -> Result<Params, Error> {
+    if Algorithm::try_from(hash.algorithm).is_err() {
+        return Err(Error::HashParamsOutOfBounds);
+    }
+
+    let params = Params::try_from(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
+    let memory_ok = (15 * 1024..=MAX_VERIFY_MEMORY_MIB * 1024).contains(&params.m_cost());
+    let iterations_ok = (2..=MAX_VERIFY_ITERATIONS).contains(&params.t_cost());
+    let parallelism_ok = (1..=MAX_VERIFY_PARALLELISM).contains(&params.p_cost());
+
+    match memory_ok && iterations_ok && parallelism_ok {
+        true => Ok(params),
+        false => Err(Error::HashParamsOutOfBounds),
+    }
 }
 
 pub mod argon2id {
@@ -111,11 +490,18 @@ pub mod argon2id {
 
 impl Strategy for Argon2idStrategy {
     fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error> {
-        if input.len() < 8 {
+        if input.len() < self.min_password_length {
             return Err(Error::PasswordTooShort);
         }
 
-        let argon2 = self.argon2_instance();
+        if input.len() > self.max_password_length {
+            return Err(Error::PasswordTooLong);
+        }
+
+        #[cfg(feature = "zxcvbn")]
+        self.check_strength(input)?;
+
+        let argon2 = self.argon2_instance(&self.pepper);
         let salt = SaltString::generate(&mut rand::thread_rng());
 
         let result = argon2
@@ -127,28 +513,188 @@ impl Strategy for Argon2idStrategy {
     }
 
     fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
-        let argon2 = self.argon2_instance();
+        Ok(self.matching_pepper(hash, input)?.is_some())
+    }
 
+    fn needs_rehash(&self, hash: &str) -> Result<bool, Error> {
         let hash = PasswordHash::new(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
-        match argon2.verify_password(input.as_bytes(), &hash) {
+        let params = accepted_params(&hash)?;
+        let current = self.generation_params();
+
+        Ok(hash.algorithm != self.algorithm.ident()
+            || params.m_cost() != current.m_cost()
+            || params.t_cost() != current.t_cost()
+            || params.p_cost() != current.p_cost()
+            || (self.pepper_id.is_some() && params.keyid() != current.keyid()))
+    }
+
+    fn verify_and_check_rehash(&self, hash: &str, input: &str) -> Result<Option<bool>, Error> {
+        match self.matching_pepper(hash, input)? {
+            Some(0) => self.needs_rehash(hash).map(Some),
+            Some(_) => Ok(Some(true)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// bcrypt hashing, mostly for verifying hashes carried over from other systems. Produces `$2b$`
+/// hashes and verifies any `$2a$`/`$2b$`/`$2y$` hash. Only the first 72 bytes of a password
+/// are significant.
+#[cfg(feature = "bcrypt")]
+#[derive(Debug, Clone)]
+pub struct BcryptStrategy {
+    /// Log2 of the number of rounds. Minimum is 10, maximum 31.
+    cost: u32,
+}
+
+#[cfg(feature = "bcrypt")]
+impl BcryptStrategy {
+    pub fn new(cost: u32) -> Result<Self, Error> {
+        if cost < 10 {
+            return Err(Error::CostTooWeak);
+        }
+
+        // The largest cost bcrypt accepts.
+        if cost > 31 {
+            return Err(Error::Strategy(Box::new(
+                bcrypt::BcryptError::CostNotAllowed(cost),
+            )));
+        }
+
+        Ok(Self { cost })
+    }
+}
+
+#[cfg(feature = "bcrypt")]
+impl Strategy for BcryptStrategy {
+    fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error> {
+        if input.len() < 8 {
+            return Err(Error::PasswordTooShort);
+        }
+
+        let result = bcrypt::hash(input, self.cost).map_err(|e| Error::Strategy(Box::new(e)))?;
+        Ok(Secret::new(result))
+    }
+
+    fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
+        bcrypt::verify(input, hash).map_err(|e| Error::Strategy(Box::new(e)))
+    }
+
+    fn needs_rehash(&self, hash: &str) -> Result<bool, Error> {
+        let parts: bcrypt::HashParts = hash.parse().map_err(|e| Error::Strategy(Box::new(e)))?;
+        Ok(parts.get_cost() != self.cost)
+    }
+}
+
+/// scrypt hashing. Produces PHC strings (`$scrypt$ln=..,r=..,p=..$...`) and verifies against
+/// the params embedded in the stored hash, as long as they lie between the construction
+/// minimums and the `MAX_VERIFY_SCRYPT_*` bounds.
+#[cfg(feature = "scrypt")]
+#[derive(Debug, Clone)]
+pub struct ScryptStrategy {
+    params: scrypt::Params,
+}
+
+#[cfg(feature = "scrypt")]
+impl ScryptStrategy {
+    /// `log_n` is the log2 of the CPU/memory cost. Minimums are 15, 8 and 1 respectively, and
+    /// maximums the `MAX_VERIFY_SCRYPT_*` bounds, beyond which hashes wouldn't verify.
+    pub fn new(log_n: u8, r: u32, p: u32) -> Result<Self, Error> {
+        if log_n < 15 || r < 8 || p < 1 {
+            return Err(Error::ScryptParamsTooWeak);
+        }
+
+        if log_n > MAX_VERIFY_SCRYPT_LOG_N || r > MAX_VERIFY_SCRYPT_R || p > MAX_VERIFY_SCRYPT_P {
+            return Err(Error::ScryptParamsTooCostly);
+        }
+
+        let params = scrypt::Params::new(log_n, r, p).map_err(|e| Error::Strategy(Box::new(e)))?;
+        Ok(Self { params })
+    }
+}
+
+/// Largest log2 of the cost of a stored scrypt hash that will be verified. With `r` at 8 that is
+/// 1 GiB of memory.
+#[cfg(feature = "scrypt")]
+const MAX_VERIFY_SCRYPT_LOG_N: u8 = 20;
+
+/// Largest block size of a stored scrypt hash that will be verified.
+#[cfg(feature = "scrypt")]
+const MAX_VERIFY_SCRYPT_R: u32 = 32;
+
+/// Largest parallelism of a stored scrypt hash that will be verified.
+#[cfg(feature = "scrypt")]
+const MAX_VERIFY_SCRYPT_P: u32 = 16;
+
+/// Params of a stored scrypt hash, if they are within what verification accepts.
+#[cfg(feature = "scrypt")]
+fn accepted_scrypt_params(hash: &PasswordHash<'_>) -> Result<scrypt::Params, Error> {
+    if hash.algorithm != scrypt::ALG_ID {
+        return Err(Error::HashParamsOutOfBounds);
+    }
+
+    let params = scrypt::Params::try_from(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
+    let log_n_ok = (15..=MAX_VERIFY_SCRYPT_LOG_N).contains(&params.log_n());
+    let r_ok = (8..=MAX_VERIFY_SCRYPT_R).contains(&params.r());
+    let p_ok = (1..=MAX_VERIFY_SCRYPT_P).contains(&params.p());
+
+    match log_n_ok && r_ok && p_ok {
+        true => Ok(params),
+        false => Err(Error::HashParamsOutOfBounds),
+    }
+}
+
+#[cfg(feature = "scrypt")]
+impl Strategy for ScryptStrategy {
+    fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error> {
+        if input.len() < 8 {
+            return Err(Error::PasswordTooShort);
+        }
+
+        let salt = SaltString::generate(&mut rand::thread_rng());
+        let result = scrypt::Scrypt
+            .hash_password_customized(input.as_bytes(), None, None, self.params, &salt)
+            .map_err(|e| Error::Strategy(Box::new(e)))?
+            .to_string();
+
+        Ok(Secret::new(result))
+    }
+
+    fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
+        let hash = PasswordHash::new(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
+        accepted_scrypt_params(&hash)?;
+
+        match scrypt::Scrypt.verify_password(input.as_bytes(), &hash) {
             Ok(_) => Ok(true),
-            Err(e) => match e {
-                argon2::password_hash::Error::Password => Ok(false),
-                _ => Err(Error::Strategy(Box::new(e))),
-            },
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(e) => Err(Error::Strategy(Box::new(e))),
         }
     }
+
+    fn needs_rehash(&self, hash: &str) -> Result<bool, Error> {
+        let hash = PasswordHash::new(hash).map_err(|e| Error::Strategy(Box::new(e)))?;
+        let params = accepted_scrypt_params(&hash)?;
+
+        Ok(params.log_n() != self.params.log_n()
+            || params.r() != self.params.r()
+            || params.p() != self.params.p())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use secrecy::ExposeSecret;
+    use secrecy::{ExposeSecret, Secret};
 
-    use super::{Argon2idStrategy, Strategy};
+    use super::{
+        Algorithm, Argon2idParams, Argon2idStrategy, Error, Strategy, MAX_VERIFY_ITERATIONS,
+        MAX_VERIFY_MEMORY_MIB, MAX_VERIFY_PARALLELISM,
+    };
 
     #[test]
     fn generate_password() {
-        let strat = Argon2idStrategy::new("hello pepper is my friend".into(), 15, 4, 1).unwrap();
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 4, 1)
+                .unwrap();
         let result = strat.generate_password_hash("this is my password").unwrap();
         println!("{}", result.expose_secret());
 
@@ -159,4 +705,433 @@ mod tests {
             .verify_password(result.expose_secret(), "this is not my password")
             .unwrap());
     }
+
+    #[test]
+    fn debug_hides_pepper() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap();
+        let debug = format!("{:?}", strat);
+
+        assert!(!debug.contains("pepper is my friend"));
+        assert!(!debug.contains(&format!("{:?}", b"hello pepper is my friend")));
+        assert!(debug.contains("memory_mib: 15"));
+    }
+
+    #[test]
+    fn hash_from_prior_params_verifies_and_needs_rehash() {
+        let old = Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+            .unwrap();
+        let new = Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 4, 1)
+            .unwrap();
+
+        let old_hash = old.generate_password_hash("this is my password").unwrap();
+        assert!(new
+            .verify_password(old_hash.expose_secret(), "this is my password")
+            .unwrap());
+        assert!(new.needs_rehash(old_hash.expose_secret()).unwrap());
+
+        let new_hash = new.generate_password_hash("this is my password").unwrap();
+        assert!(!new.needs_rehash(new_hash.expose_secret()).unwrap());
+    }
+
+    #[test]
+    fn hash_with_retired_pepper_verifies_and_needs_rehash() {
+        let old = Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+            .unwrap();
+        let old_hash = old.generate_password_hash("this is my password").unwrap();
+
+        let rotated = Argon2idStrategy::new(Secret::new("a brand new pepper".into()), 15, 2, 1)
+            .unwrap()
+            .with_retired_pepper(Secret::new("hello pepper is my friend".into()));
+        assert!(rotated
+            .verify_password(old_hash.expose_secret(), "this is my password")
+            .unwrap());
+        assert!(!rotated
+            .verify_password(old_hash.expose_secret(), "this is not my password")
+            .unwrap());
+        assert_eq!(
+            rotated
+                .verify_and_check_rehash(old_hash.expose_secret(), "this is my password")
+                .unwrap(),
+            Some(true)
+        );
+
+        // New hashes only verify with the current pepper.
+        let new_hash = rotated
+            .generate_password_hash("this is my password")
+            .unwrap();
+        assert_eq!(
+            rotated
+                .verify_and_check_rehash(new_hash.expose_secret(), "this is my password")
+                .unwrap(),
+            Some(false)
+        );
+        assert!(!old
+            .verify_password(new_hash.expose_secret(), "this is my password")
+            .unwrap());
+
+        let forgetful =
+            Argon2idStrategy::new(Secret::new("a brand new pepper".into()), 15, 2, 1).unwrap();
+        assert!(!forgetful
+            .verify_password(old_hash.expose_secret(), "this is my password")
+            .unwrap());
+    }
+
+    #[test]
+    fn pepper_id_tells_retired_pepper_hashes_apart() {
+        let pepper = || Secret::new("hello pepper is my friend".into());
+        let untagged = Argon2idStrategy::new(pepper(), 15, 2, 1).unwrap();
+        let old = untagged.clone().with_pepper_id(b"1").unwrap();
+        let rotated = Argon2idStrategy::new(Secret::new("a brand new pepper".into()), 15, 2, 1)
+            .unwrap()
+            .with_pepper_id(b"2")
+            .unwrap()
+            .with_retired_pepper(pepper());
+
+        let untagged_hash = untagged
+            .generate_password_hash("this is my password")
+            .unwrap();
+        let old_hash = old.generate_password_hash("this is my password").unwrap();
+        let new_hash = rotated
+            .generate_password_hash("this is my password")
+            .unwrap();
+        assert!(!old.needs_rehash(old_hash.expose_secret()).unwrap());
+        assert!(old.needs_rehash(untagged_hash.expose_secret()).unwrap());
+        assert!(rotated.needs_rehash(old_hash.expose_secret()).unwrap());
+        assert!(!rotated.needs_rehash(new_hash.expose_secret()).unwrap());
+
+        // The id doesn't go into the hash itself.
+        assert!(rotated
+            .verify_password(old_hash.expose_secret(), "this is my password")
+            .unwrap());
+        assert!(old
+            .verify_password(untagged_hash.expose_secret(), "this is my password")
+            .unwrap());
+
+        assert!(matches!(
+            untagged.clone().with_pepper_id(b""),
+            Err(Error::InvalidPepperId)
+        ));
+        assert!(matches!(
+            untagged.with_pepper_id(b"123456789"),
+            Err(Error::InvalidPepperId)
+        ));
+    }
+
+    #[test]
+    fn hash_outside_bounds_is_rejected() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap();
+        let hash = strat.generate_password_hash("this is my password").unwrap();
+
+        let downgraded = hash.expose_secret().replace("t=2", "t=1");
+        assert!(matches!(
+            strat.verify_password(&downgraded, "this is my password"),
+            Err(Error::HashParamsOutOfBounds)
+        ));
+
+        let expensive = hash.expose_secret().replace("t=2", "t=1000");
+        assert!(matches!(
+            strat.needs_rehash(&expensive),
+            Err(Error::HashParamsOutOfBounds)
+        ));
+    }
+
+    #[test]
+    fn params_beyond_verify_bounds_are_rejected() {
+        let pepper = || Secret::new("hello pepper is my friend".into());
+        for (memory_mib, iteration_count, parallelism_degree) in
+            [(4097, 2, 1), (15, 65, 1), (15, 2, 65)]
+        {
+            assert!(matches!(
+                Argon2idStrategy::new(pepper(), memory_mib, iteration_count, parallelism_degree),
+                Err(Error::ParamsTooCostly)
+            ));
+        }
+
+        // The bounds themselves are fine, and hashes made with them verify.
+        assert!(
+            Argon2idStrategy::new(pepper(), MAX_VERIFY_MEMORY_MIB, MAX_VERIFY_ITERATIONS, 1)
+                .is_ok()
+        );
+        let strat = Argon2idStrategy::new(pepper(), 15, 2, MAX_VERIFY_PARALLELISM).unwrap();
+        let hash = strat.generate_password_hash("this is my password").unwrap();
+        assert!(strat
+            .verify_password(hash.expose_secret(), "this is my password")
+            .unwrap());
+    }
+
+    #[test]
+    fn argon2_variants() {
+        let pepper = || Secret::new("hello pepper is my friend".into());
+        let strats =
+            [Algorithm::Argon2i, Algorithm::Argon2d, Algorithm::Argon2id].map(|algorithm| {
+                Argon2idStrategy::new(pepper(), 15, 2, 1)
+                    .unwrap()
+                    .with_algorithm(algorithm)
+            });
+
+        for strat in &strats {
+            let hash = strat.generate_password_hash("this is my password").unwrap();
+            assert!(hash
+                .expose_secret()
+                .starts_with(&format!("${}$", strat.algorithm.ident())));
+            assert!(!strat.needs_rehash(hash.expose_secret()).unwrap());
+
+            // Every strategy verifies every variant, but wants to replace the others.
+            for other in &strats {
+                assert!(other
+                    .verify_password(hash.expose_secret(), "this is my password")
+                    .unwrap());
+                assert!(!other
+                    .verify_password(hash.expose_secret(), "this is not my password")
+                    .unwrap());
+                assert_eq!(
+                    other.needs_rehash(hash.expose_secret()).unwrap(),
+                    other.algorithm != strat.algorithm
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn params_round_trip() {
+        let params = Argon2idParams {
+            memory_mib: 64,
+            iteration_count: 3,
+            parallelism_degree: 2,
+        };
+
+        let json = serde_json::to_string(&params).unwrap();
+        assert_eq!(
+            json,
+            r#"{"memory_mib":64,"iteration_count":3,"parallelism_degree":2}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Argon2idParams>(&json).unwrap(),
+            params
+        );
+
+        let strat =
+            Argon2idStrategy::from_params(params, Secret::new("hello pepper is my friend".into()))
+                .unwrap();
+        let hash = strat.generate_password_hash("this is my password").unwrap();
+        assert!(hash.expose_secret().contains("m=65536,t=3,p=2"));
+
+        let weak = Argon2idParams {
+            memory_mib: 8,
+            ..params
+        };
+        assert!(matches!(
+            Argon2idStrategy::from_params(weak, Secret::new("hello pepper is my friend".into())),
+            Err(Error::MemoryUseTooWeak)
+        ));
+    }
+
+    // Slow, and depends on the host's speed. Run with `cargo test --release -- --ignored`.
+    #[test]
+    #[ignore]
+    fn calibrate() {
+        let strat = Argon2idStrategy::calibrate(
+            std::time::Duration::from_millis(250),
+            Secret::new("hello pepper is my friend".into()),
+        )
+        .unwrap();
+        assert!(strat.memory_mib >= 15);
+        assert!(strat.iteration_count >= 2);
+        assert!(strat.memory_mib > 15 || strat.iteration_count > 2);
+
+        let started = std::time::Instant::now();
+        strat.generate_password_hash("this is my password").unwrap();
+        let elapsed = started.elapsed();
+        assert!(elapsed > std::time::Duration::from_millis(100));
+        assert!(elapsed < std::time::Duration::from_millis(750));
+    }
+
+    #[test]
+    fn presets() {
+        let pepper = || Secret::new("hello pepper is my friend".into());
+
+        let strat = Argon2idStrategy::owasp_default(pepper()).unwrap();
+        let hash = strat.generate_password_hash("this is my password").unwrap();
+        assert!(hash.expose_secret().contains("m=19456,t=2,p=1"));
+        assert!(strat
+            .verify_password(hash.expose_secret(), "this is my password")
+            .unwrap());
+
+        assert!(Argon2idStrategy::moderate(pepper()).is_ok());
+        assert!(Argon2idStrategy::sensitive(pepper()).is_ok());
+        assert!(matches!(
+            Argon2idStrategy::owasp_default(Secret::new("short".into())),
+            Err(Error::PepperTooWeak)
+        ));
+    }
+
+    #[test]
+    fn min_password_length() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap();
+        assert!(matches!(
+            strat.clone().with_min_password_length(7),
+            Err(Error::MinPasswordLengthTooWeak)
+        ));
+
+        let strat = strat.with_min_password_length(12).unwrap();
+        assert!(matches!(
+            strat.generate_password_hash("0123456789"),
+            Err(Error::PasswordTooShort)
+        ));
+        assert!(strat.generate_password_hash("0123456789ab").is_ok());
+    }
+
+    #[test]
+    fn long_password_is_rejected_before_hashing() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap();
+        let hash = strat.generate_password_hash("this is my password").unwrap();
+        let long = "a".repeat(100 * 1024);
+
+        assert!(matches!(
+            strat.generate_password_hash(&long),
+            Err(Error::PasswordTooLong)
+        ));
+        assert!(matches!(
+            strat.verify_password(hash.expose_secret(), &long),
+            Err(Error::PasswordTooLong)
+        ));
+
+        let strat = strat.with_max_password_length(16).unwrap();
+        assert!(matches!(
+            strat.generate_password_hash("this is my password"),
+            Err(Error::PasswordTooLong)
+        ));
+    }
+
+    #[test]
+    fn max_password_length_not_below_min() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap();
+        for max in [0, 7] {
+            assert!(matches!(
+                strat.clone().with_max_password_length(max),
+                Err(Error::MaxPasswordLengthBelowMin)
+            ));
+        }
+
+        let strat = strat.with_min_password_length(12).unwrap();
+        assert!(matches!(
+            strat.clone().with_max_password_length(11),
+            Err(Error::MaxPasswordLengthBelowMin)
+        ));
+        let strat = strat.with_max_password_length(12).unwrap();
+        assert!(matches!(
+            strat.with_min_password_length(13),
+            Err(Error::MaxPasswordLengthBelowMin)
+        ));
+    }
+
+    #[cfg(feature = "zxcvbn")]
+    #[test]
+    fn weak_password_is_rejected() {
+        let strat =
+            Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1)
+                .unwrap()
+                .with_min_strength(3);
+
+        assert!(strat
+            .generate_password_hash("correcthorsebatterystaple")
+            .is_ok());
+        assert!(matches!(
+            strat.generate_password_hash("password1"),
+            Err(Error::PasswordTooWeak { score }) if score < 3
+        ));
+    }
+
+    #[cfg(feature = "bcrypt")]
+    #[test]
+    fn bcrypt_password() {
+        use super::BcryptStrategy;
+
+        assert!(matches!(BcryptStrategy::new(9), Err(Error::CostTooWeak)));
+
+        let strat = BcryptStrategy::new(10).unwrap();
+        assert!(matches!(
+            strat.generate_password_hash("short"),
+            Err(Error::PasswordTooShort)
+        ));
+
+        let result = strat.generate_password_hash("this is my password").unwrap();
+        assert!(result.expose_secret().starts_with("$2b$10$"));
+        assert!(strat
+            .verify_password(result.expose_secret(), "this is my password")
+            .unwrap());
+        assert!(!strat
+            .verify_password(result.expose_secret(), "this is not my password")
+            .unwrap());
+    }
+
+    #[cfg(feature = "bcrypt")]
+    #[test]
+    fn bcrypt_verifies_legacy_hash() {
+        use super::BcryptStrategy;
+
+        // PHP's `password_hash` writes `$2y$` hashes.
+        let legacy = bcrypt::hash_with_result("password", 10)
+            .unwrap()
+            .format_for_version(bcrypt::Version::TwoY);
+        assert!(legacy.starts_with("$2y$10$"));
+
+        let strat = BcryptStrategy::new(12).unwrap();
+        assert!(strat.verify_password(&legacy, "password").unwrap());
+        assert!(!strat.verify_password(&legacy, "Password").unwrap());
+        assert!(strat.needs_rehash(&legacy).unwrap());
+    }
+
+    #[cfg(feature = "scrypt")]
+    #[test]
+    fn scrypt_password() {
+        use super::ScryptStrategy;
+
+        assert!(matches!(
+            ScryptStrategy::new(14, 8, 1),
+            Err(Error::ScryptParamsTooWeak)
+        ));
+        for (log_n, r, p) in [(21, 8, 1), (15, 33, 1), (15, 8, 17)] {
+            assert!(matches!(
+                ScryptStrategy::new(log_n, r, p),
+                Err(Error::ScryptParamsTooCostly)
+            ));
+        }
+        assert!(ScryptStrategy::new(20, 32, 16).is_ok());
+
+        let strat = ScryptStrategy::new(15, 8, 1).unwrap();
+        let result = strat.generate_password_hash("this is my password").unwrap();
+        assert!(result.expose_secret().starts_with("$scrypt$"));
+
+        assert!(strat
+            .verify_password(result.expose_secret(), "this is my password")
+            .unwrap());
+        assert!(!strat
+            .verify_password(result.expose_secret(), "this is not my password")
+            .unwrap());
+
+        // Costlier params than verification accepts are rejected before doing the work.
+        for (from, to) in [("ln=15", "ln=30"), ("r=8", "r=4096"), ("p=1", "p=1024")] {
+            let costly = result.expose_secret().replace(from, to);
+            assert!(matches!(
+                strat.verify_password(&costly, "this is my password"),
+                Err(Error::HashParamsOutOfBounds)
+            ));
+        }
+        let downgraded = result.expose_secret().replace("ln=15", "ln=10");
+        assert!(matches!(
+            strat.verify_password(&downgraded, "this is my password"),
+            Err(Error::HashParamsOutOfBounds)
+        ));
+    }
 }
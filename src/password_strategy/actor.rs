@@ -0,0 +1,217 @@
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+
+use secrecy::{ExposeSecret, Secret};
+use tokio::sync::{mpsc, oneshot};
+
+use super::{Error, Strategy};
+
+/// Work for a worker, handed the wrapped strategy. Replying is up to the job, so that callers
+/// can wait for it asynchronously or by blocking.
+type Job<S> = Box<dyn FnOnce(&S) + Send>;
+
+/// Runs a [`Strategy`] on a fixed number of worker threads fed by a bounded queue.
+///
+/// At most `workers` hashes are computed at once, and at most `queue_size` more are waiting.
+/// Anything beyond that is rejected with [`Error::Overloaded`] instead of piling up in memory.
+///
+/// The async methods wait for their job without blocking the runtime. As a [`Strategy`], e.g.
+/// when handed to a user backend, the calling thread blocks until a worker is done, as it
+/// would while hashing itself, but the queue bounds apply all the same.
+pub struct ActorStrategy<S> {
+    jobs: mpsc::Sender<Job<S>>,
+    strategy: Arc<S>,
+}
+
+impl<S> Clone for ActorStrategy<S> {
+    fn clone(&self) -> Self {
+        Self {
+            jobs: self.jobs.clone(),
+            strategy: self.strategy.clone(),
+        }
+    }
+}
+
+impl<S: Strategy + 'static> ActorStrategy<S> {
+    /// Spawns the workers, which exit once every clone of the returned strategy is dropped.
+    pub fn new(strategy: S, workers: usize, queue_size: usize) -> Self {
+        let (jobs, rx) = mpsc::channel::<Job<S>>(queue_size.max(1));
+        let rx = Arc::new(Mutex::new(rx));
+        let strategy = Arc::new(strategy);
+
+        // Plain threads rather than blocking tasks, so that waiting on them from a runtime
+        // thread can't starve them, and they don't hold up the runtime's shutdown.
+        for _ in 0..workers.max(1) {
+            let rx = rx.clone();
+            let strategy = strategy.clone();
+
+            std::thread::spawn(move || loop {
+                let job = match rx.lock().unwrap().blocking_recv() {
+                    Some(job) => job,
+                    None => break,
+                };
+                job(&*strategy);
+            });
+        }
+
+        Self { jobs, strategy }
+    }
+
+    /// The wrapped strategy, for calls that don't need to go through the queue.
+    pub fn strategy(&self) -> &S {
+        &self.strategy
+    }
+
+    pub async fn generate_password_hash(
+        &self,
+        input: Secret<String>,
+    ) -> Result<Secret<String>, Error> {
+        let (reply, rx) = oneshot::channel();
+        // The caller may have given up waiting, in which case the result is simply dropped.
+        self.submit(Box::new(move |strategy| {
+            let _ = reply.send(strategy.generate_password_hash(input.expose_secret()));
+        }))?;
+        rx.await.map_err(|e| Error::Strategy(Box::new(e)))?
+    }
+
+    pub async fn verify_password(&self, hash: &str, input: Secret<String>) -> Result<bool, Error> {
+        let (reply, rx) = oneshot::channel();
+        let hash = hash.to_string();
+        self.submit(Box::new(move |strategy| {
+            let _ = reply.send(strategy.verify_password(&hash, input.expose_secret()));
+        }))?;
+        rx.await.map_err(|e| Error::Strategy(Box::new(e)))?
+    }
+
+    /// Cheap enough to not go through the queue.
+    pub fn needs_rehash(&self, hash: &str) -> Result<bool, Error> {
+        self.strategy.needs_rehash(hash)
+    }
+
+    /// Runs `f` on a worker, blocking until it is done.
+    fn run_blocking<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S) -> Result<T, Error> + Send + 'static,
+    {
+        let (reply, rx) = std_mpsc::sync_channel(1);
+        self.submit(Box::new(move |strategy| {
+            let _ = reply.send(f(strategy));
+        }))?;
+        rx.recv().map_err(|e| Error::Strategy(Box::new(e)))?
+    }
+
+    fn submit(&self, job: Job<S>) -> Result<(), Error> {
+        match self.jobs.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(mpsc::error::TrySendError::Full(_)) => Err(Error::Overloaded),
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                Err(Error::Strategy("hashing workers have shut down".into()))
+            }
+        }
+    }
+}
+
+impl<S: Strategy + 'static> Strategy for ActorStrategy<S> {
+    fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error> {
+        let input = Secret::new(input.to_string());
+        self.run_blocking(move |strategy| strategy.generate_password_hash(input.expose_secret()))
+    }
+
+    fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
+        let (hash, input) = (hash.to_string(), Secret::new(input.to_string()));
+        self.run_blocking(move |strategy| strategy.verify_password(&hash, input.expose_secret()))
+    }
+
+    fn needs_rehash(&self, hash: &str) -> Result<bool, Error> {
+        self.strategy.needs_rehash(hash)
+    }
+
+    fn verify_and_check_rehash(&self, hash: &str, input: &str) -> Result<Option<bool>, Error> {
+        let (hash, input) = (hash.to_string(), Secret::new(input.to_string()));
+        self.run_blocking(move |strategy| {
+            strategy.verify_and_check_rehash(&hash, input.expose_secret())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Condvar;
+
+    use super::*;
+
+    /// Blocks hashing until the gate is opened, counting the hashes started.
+    struct GatedStrategy(Arc<(Mutex<(bool, usize)>, Condvar)>);
+
+    impl Strategy for GatedStrategy {
+        fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, Error> {
+            let (state, cvar) = &*self.0;
+            let mut state = state.lock().unwrap();
+            state.1 += 1;
+            cvar.notify_all();
+            let _state = cvar.wait_while(state, |(open, _)| !*open).unwrap();
+            Ok(Secret::new(input.to_string()))
+        }
+
+        fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
+            Ok(hash == input)
+        }
+    }
+
+    #[test]
+    fn rejects_when_queue_is_full() {
+        let gate = Arc::new((Mutex::new((false, 0)), Condvar::new()));
+        let strategy = ActorStrategy::new(GatedStrategy(gate.clone()), 1, 1);
+        let submit = || {
+            let (reply, rx) = std_mpsc::sync_channel(1);
+            strategy
+                .submit(Box::new(move |strategy: &GatedStrategy| {
+                    let _ = reply.send(strategy.generate_password_hash("password"));
+                }))
+                .map(|()| rx)
+        };
+
+        // Once the only worker is busy, one job fits in the queue.
+        let hashing = submit().unwrap();
+        let (state, cvar) = &*gate;
+        drop(cvar.wait_while(state.lock().unwrap(), |(_, started)| *started == 0));
+        let queued = submit().unwrap();
+        for _ in 0..8 {
+            assert!(matches!(submit(), Err(Error::Overloaded)));
+        }
+
+        state.lock().unwrap().0 = true;
+        cvar.notify_all();
+        for rx in [hashing, queued] {
+            assert_eq!(rx.recv().unwrap().unwrap().expose_secret(), "password");
+        }
+    }
+
+    #[test]
+    fn usable_as_strategy() {
+        fn hash_with(strategy: &impl Strategy) -> Secret<String> {
+            strategy.generate_password_hash("password").unwrap()
+        }
+
+        let gate = Arc::new((Mutex::new((true, 0)), Condvar::new()));
+        let strategy = ActorStrategy::new(GatedStrategy(gate), 2, 4);
+        let hash = hash_with(&strategy);
+        assert!(Strategy::verify_password(&strategy, hash.expose_secret(), "password").unwrap());
+        assert_eq!(
+            strategy
+                .verify_and_check_rehash(hash.expose_secret(), "password")
+                .unwrap(),
+            Some(false)
+        );
+
+        // Also from within a runtime, alongside the async methods.
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let hash = hash_with(&strategy);
+            assert!(strategy
+                .verify_password(hash.expose_secret(), Secret::new("password".into()))
+                .await
+                .unwrap());
+        });
+    }
+}
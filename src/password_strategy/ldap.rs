@@ -0,0 +1,209 @@
+use std::time::Duration;
+
+use ldap3::{LdapConnAsync, LdapConnSettings};
+use secrecy::Secret;
+
+use super::{Error, Strategy};
+
+/// Prefix of the stored "hash" of a directory user. The rest of it is the user's bind DN.
+pub const HASH_PREFIX: &str = "ldap:";
+
+/// `invalidCredentials` in RFC 4511.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Verifies passwords with an LDAP simple bind, for users whose passwords live in a directory.
+///
+/// No hash is kept locally. Instead the stored hash is a sentinel naming the user's bind DN, see
+/// [`LdapStrategy::password_hash_for`], and a password is correct if the directory accepts a
+/// bind as that DN. Hashes can't be generated, so users must be created with the sentinel.
+///
+/// Only `ldap://` is supported out of the box. Enable `ldap3`'s `tls` or `tls-rustls` feature
+/// for `ldaps://`.
+#[derive(Debug, Clone)]
+pub struct LdapStrategy {
+    url: String,
+    timeout: Duration,
+}
+
+impl LdapStrategy {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// How long connecting and binding may take. Defaults to 5 seconds.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// The sentinel to store as the password hash of the user bound as `dn`.
+    pub fn password_hash_for(dn: &str) -> Secret<String> {
+        Secret::new(format!("{}{}", HASH_PREFIX, dn))
+    }
+
+    /// Binds as the DN in `hash` with `input`. Prefer this over [`Strategy::verify_password`],
+    /// which blocks the calling thread for the round trips.
+    pub async fn verify_password_async(&self, hash: &str, input: &str) -> Result<bool, Error> {
+        let dn = hash.strip_prefix(HASH_PREFIX).ok_or(Error::NotLdapHash)?;
+
+        // An empty password is an unauthenticated bind, which directories accept for any DN.
+        if dn.is_empty() || input.is_empty() {
+            return Ok(false);
+        }
+
+        let settings = LdapConnSettings::new().set_conn_timeout(self.timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url)
+            .await
+            .map_err(|e| Error::Strategy(Box::new(e)))?;
+        ldap3::drive!(conn);
+
+        let result = ldap
+            .with_timeout(self.timeout)
+            .simple_bind(dn, input)
+            .await
+            .map_err(|e| Error::Strategy(Box::new(e)))?;
+        let _ = ldap.unbind().await;
+
+        match result.rc {
+            INVALID_CREDENTIALS => Ok(false),
+            _ => result
+                .success()
+                .map(|_| true)
+                .map_err(|e| Error::Strategy(Box::new(e))),
+        }
+    }
+}
+
+impl Strategy for LdapStrategy {
+    fn generate_password_hash(&self, _input: &str) -> Result<Secret<String>, Error> {
+        Err(Error::HashingUnsupported)
+    }
+
+    /// Runs the bind on a thread of its own, so this may be called from within a Tokio runtime.
+    fn verify_password(&self, hash: &str, input: &str) -> Result<bool, Error> {
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    tokio::runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()
+                        .map_err(|e| Error::Strategy(Box::new(e)))?
+                        .block_on(self.verify_password_async(hash, input))
+                })
+                .join()
+                .map_err(|_| Error::Strategy("LDAP verification thread panicked".into()))?
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::ExposeSecret;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+
+    const DN: &str = "uid=alice,ou=people,dc=example,dc=com";
+    const PASSWORD: &str = "this is my password";
+
+    /// Reads one BER element, returning its tag and contents.
+    async fn read_element(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+        let tag = stream.read_u8().await.ok()?;
+        let mut len = stream.read_u8().await.ok()? as usize;
+        if len & 0x80 != 0 {
+            let mut bytes = vec![0; len & 0x7f];
+            stream.read_exact(&mut bytes).await.ok()?;
+            len = bytes.iter().fold(0, |acc, b| acc << 8 | *b as usize);
+        }
+        let mut contents = vec![0; len];
+        stream.read_exact(&mut contents).await.ok()?;
+        Some((tag, contents))
+    }
+
+    /// Splits the first BER element (with a short length) off `bytes`.
+    fn split_element(bytes: &[u8]) -> (u8, &[u8], &[u8]) {
+        let len = bytes[1] as usize;
+        (bytes[0], &bytes[2..2 + len], &bytes[2 + len..])
+    }
+
+    /// Accepts binds as [`DN`] with [`PASSWORD`] and rejects all others.
+    async fn mock_directory() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    while let Some((_, message)) = read_element(&mut stream).await {
+                        let (_, message_id, op) = split_element(&message);
+                        let (op_tag, bind, _) = split_element(op);
+                        if op_tag != 0x60 {
+                            // Anything but a bind request, i.e. the unbind.
+                            break;
+                        }
+
+                        let (_, _version, rest) = split_element(bind);
+                        let (_, name, rest) = split_element(rest);
+                        let (_, password, _) = split_element(rest);
+                        let rc = if name == DN.as_bytes() && password == PASSWORD.as_bytes() {
+                            0
+                        } else {
+                            INVALID_CREDENTIALS as u8
+                        };
+
+                        let mut response = vec![0x30, 0, 0x02, message_id.len() as u8];
+                        response.extend_from_slice(message_id);
+                        response.extend_from_slice(&[0x61, 0x07, 0x0a, 0x01, rc, 0x04, 0x00]);
+                        response.extend_from_slice(&[0x04, 0x00]);
+                        response[1] = (response.len() - 2) as u8;
+                        stream.write_all(&response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn bind_against_directory() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let strat = LdapStrategy::new(mock_directory().await);
+            let hash = LdapStrategy::password_hash_for(DN);
+
+            assert!(strat
+                .verify_password_async(hash.expose_secret(), PASSWORD)
+                .await
+                .unwrap());
+            assert!(!strat
+                .verify_password_async(hash.expose_secret(), "this is not my password")
+                .await
+                .unwrap());
+            assert!(!strat
+                .verify_password_async(hash.expose_secret(), "")
+                .await
+                .unwrap());
+            assert!(matches!(
+                strat.verify_password_async("$argon2id$...", PASSWORD).await,
+                Err(Error::NotLdapHash)
+            ));
+
+            // The sync path works from within the runtime too.
+            assert!(strat
+                .verify_password(hash.expose_secret(), PASSWORD)
+                .unwrap());
+            assert!(matches!(
+                strat.generate_password_hash(PASSWORD),
+                Err(Error::HashingUnsupported)
+            ));
+        });
+    }
+}
@@ -0,0 +1,373 @@
+//! The Postgres schema the backends' queries expect.
+//!
+//! Each function returns the statements creating one table under the given name, matching what
+//! is passed as `table_name` to the backend's constructor. Run them once, in order, e.g. with
+//! `sqlx::Executor::execute` or from a migration. `resources/postgres_setup.sql` holds the same
+//! schema with the default table names, which [`migrate`] sets up step by step.
+//!
+//! [`sqlite_users`] is the odd one out, for the SQLite backend.
+
+use std::collections::HashMap;
+
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    Executor,
+};
+
+/// Table in which [`migrate`] records the migrations it applied. It's ours alone, so that
+/// applications with sqlx migrations of their own keep using `_sqlx_migrations` as usual.
+pub const MIGRATIONS_TABLE: &str = "thetc_auth_migrations";
+
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Key of the advisory lock held while migrating, so that processes starting at the same time
+/// don't both apply a migration.
+const MIGRATE_LOCK: i64 = 0x7468_6574_635f_6d67;
+
+/// Creates the users, sessions, password reset and app auth tables with their default names,
+/// from the migrations in `migrations/`, recording them in [`MIGRATIONS_TABLE`]. Migrations
+/// already applied are skipped, so this can run on every start. The first one leaves tables
+/// set up from an older `resources/postgres_setup.sql` alone, and the rest bring them up to
+/// date.
+///
+/// All of the migrations are applied in a single transaction.
+pub async fn migrate(pool: &sqlx::PgPool) -> Result<(), MigrateError> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock($1)")
+        .bind(MIGRATE_LOCK)
+        .execute(&mut tx)
+        .await?;
+    tx.execute(&*format!(
+        r#"CREATE TABLE IF NOT EXISTS {} (
+    version BIGINT PRIMARY KEY,
+    description TEXT NOT NULL,
+    checksum BYTEA NOT NULL,
+    applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+)"#,
+        MIGRATIONS_TABLE
+    ))
+    .await?;
+
+    let mut applied: HashMap<i64, Vec<u8>> = sqlx::query_as(&format!(
+        "SELECT version, checksum FROM {}",
+        MIGRATIONS_TABLE
+    ))
+    .fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .collect();
+
+    for migration in MIGRATOR.iter() {
+        match applied.remove(&migration.version) {
+            Some(checksum) if checksum != *migration.checksum => {
+                return Err(MigrateError::VersionMismatch(migration.version));
+            }
+            Some(_) => {}
+            None => {
+                tx.execute(&*migration.sql).await?;
+                sqlx::query(&format!(
+                    "INSERT INTO {} (version, description, checksum) VALUES ($1, $2, $3)",
+                    MIGRATIONS_TABLE
+                ))
+                .bind(migration.version)
+                .bind(&*migration.description)
+                .bind(&*migration.checksum)
+                .execute(&mut tx)
+                .await?;
+            }
+        }
+    }
+
+    // Applied by a newer version of this crate.
+    if let Some(version) = applied.keys().min() {
+        return Err(MigrateError::VersionMissing(*version));
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// `CREATE` statements for the users table of [`crate::user::PgUsers`]. Also enables the
+/// `citext` extension, which needs sufficient privileges the first time.
+pub fn users(table_name: &str) -> String {
+    format!(
+        r#"CREATE EXTENSION IF NOT EXISTS citext;
+
+CREATE TABLE {} (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    username CITEXT UNIQUE NOT NULL,
+    password_hash TEXT NOT NULL,
+    meta JSONB NOT NULL DEFAULT '{{}}',
+    version INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    deleted_at TIMESTAMPTZ
+);
+"#,
+        table_name
+    )
+}
+
+/// `CREATE` statements for the sessions table of [`crate::session::postgres::Backend`], whose
+/// sessions are deleted along with their user in `users_table_name`.
+pub fn sessions(table_name: &str, users_table_name: &str) -> String {
+    format!(
+        r#"CREATE TABLE {} (
+    id TEXT PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES {}(id) ON DELETE CASCADE,
+    data JSONB NOT NULL DEFAULT '{{}}',
+    expires_at TIMESTAMPTZ NOT NULL,
+    absolute_expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+"#,
+        table_name, users_table_name
+    )
+}
+
+/// `CREATE` statements for the password reset table of [`crate::session::postgres::Backend`],
+/// whose ids are deleted along with their user in `users_table_name`.
+pub fn password_resets(table_name: &str, users_table_name: &str) -> String {
+    format!(
+        r#"CREATE TABLE {} (
+    id UUID PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES {}(id) ON DELETE CASCADE,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+"#,
+        table_name, users_table_name
+    )
+}
+
+/// `CREATE` statements for the app auth table of [`crate::appauth::postgres_redis::Backend`].
+pub fn appauth(table_name: &str) -> String {
+    format!(
+        r#"CREATE TABLE {0} (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    name TEXT UNIQUE NOT NULL,
+    description TEXT,
+    token TEXT UNIQUE NOT NULL,
+    token_hint TEXT NOT NULL DEFAULT '',
+    signing_secret TEXT,
+    meta JSONB NOT NULL DEFAULT '{{}}',
+    expires_at TIMESTAMPTZ,
+    rate_limit INTEGER,
+    remaining_quota BIGINT,
+    last_used_at TIMESTAMPTZ,
+    scopes TEXT[] NOT NULL DEFAULT '{{}}',
+    version INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX idx_{0}__token ON {0} (token);
+"#,
+        table_name
+    )
+}
+
+/// `CREATE` statements for the users table of [`crate::user::SqliteUsers`]. Usernames are
+/// unique ignoring ASCII case, which is all SQLite's `NOCASE` folds.
+#[cfg(feature = "sqlite")]
+pub fn sqlite_users(table_name: &str) -> String {
+    format!(
+        r#"CREATE TABLE {} (
+    id BLOB PRIMARY KEY NOT NULL,
+    username TEXT UNIQUE NOT NULL COLLATE NOCASE,
+    password_hash TEXT NOT NULL,
+    meta TEXT NOT NULL DEFAULT '{{}}',
+    version INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+"#,
+        table_name
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Executor, PgPool};
+
+    use crate::{
+        user::{NewUser, PgUsers, UserBackend},
+        username::ascii::AsciiUsername,
+    };
+
+    #[test]
+    fn setup_sql_is_default_schema() {
+        let schema = [
+            super::users("users"),
+            super::sessions("sessions", "users"),
+            super::password_resets("password_resets", "users"),
+            super::appauth("appauth"),
+        ]
+        .join("\n");
+
+        assert_eq!(
+            schema,
+            include_str!("../resources/postgres_setup.sql"),
+            "resources/postgres_setup.sql is out of date"
+        );
+    }
+
+    /// Columns, indexes and foreign keys of the tables in the pool's schema, apart from
+    /// [`super::MIGRATIONS_TABLE`].
+    async fn describe(pool: &PgPool) -> Vec<String> {
+        let mut described: Vec<String> = sqlx::query_scalar(
+            r#"SELECT concat_ws(' ', table_name, column_name, udt_name, is_nullable, column_default)
+FROM information_schema.columns
+WHERE table_schema = current_schema() AND table_name <> $1
+UNION ALL
+SELECT replace(indexdef, schemaname || '.', '')
+FROM pg_indexes
+WHERE schemaname = current_schema() AND tablename <> $1
+UNION ALL
+SELECT concat_ws(' ', constraint_name, delete_rule)
+FROM information_schema.referential_constraints
+WHERE constraint_schema = current_schema()"#,
+        )
+        .bind(super::MIGRATIONS_TABLE)
+        .fetch_all(pool)
+        .await
+        .unwrap();
+        described.sort();
+        described
+    }
+
+    /// A schema set up from `resources/postgres_setup.sql`, which the migrations must match.
+    async fn set_up_from_sql() -> PgPool {
+        let pool = crate::util::test_db::empty_pool().await;
+        pool.execute(include_str!("../resources/postgres_setup.sql"))
+            .await
+            .unwrap();
+        pool
+    }
+
+    #[test]
+    fn migrations_add_up_to_schema() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let migrated = crate::util::test_db::empty_pool().await;
+            let fresh = set_up_from_sql().await;
+            super::migrate(&migrated).await.unwrap();
+
+            let expected = describe(&fresh).await;
+            assert!(!expected.is_empty());
+            assert_eq!(describe(&migrated).await, expected);
+        });
+    }
+
+    #[test]
+    fn migrate_upgrades_old_setup() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::empty_pool().await;
+            // Set up without migrate, from the first migration, which is what
+            // `resources/postgres_setup.sql` used to be.
+            pool.execute(include_str!("../migrations/20261016000000_setup.sql"))
+                .await
+                .unwrap();
+            pool.execute("INSERT INTO users (username, password_hash) VALUES ('alice', 'x')")
+                .await
+                .unwrap();
+            let tokens = [
+                "tca_0123456789abcdefghij",
+                "tca_short",
+                "0123456789abcdefghij",
+                "trailing_",
+            ];
+            for (i, token) in tokens.iter().enumerate() {
+                sqlx::query("INSERT INTO appauth (name, token) VALUES ($1, $2)")
+                    .bind(format!("app{}", i))
+                    .bind(token)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+            }
+            super::migrate(&pool).await.unwrap();
+
+            let fresh = set_up_from_sql().await;
+            assert_eq!(describe(&pool).await, describe(&fresh).await);
+
+            // Tokens from before hints were stored get theirs filled in.
+            for token in tokens {
+                let hint: String =
+                    sqlx::query_scalar("SELECT token_hint FROM appauth WHERE token = $1")
+                        .bind(token)
+                        .fetch_one(&pool)
+                        .await
+                        .unwrap();
+                assert_eq!(hint, crate::appauth::token_hint(token));
+            }
+        });
+    }
+
+    #[test]
+    fn migrate_keeps_out_of_sqlx_migrations() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::empty_pool().await;
+            super::migrate(&pool).await.unwrap();
+
+            let tables: Vec<String> = sqlx::query_scalar(
+                "SELECT tablename::TEXT FROM pg_tables WHERE schemaname = current_schema()",
+            )
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+            assert!(tables.iter().any(|t| t == super::MIGRATIONS_TABLE));
+            assert!(!tables.iter().any(|t| t == "_sqlx_migrations"));
+        });
+    }
+
+    #[test]
+    fn create_user_after_migrate() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::empty_pool().await;
+            super::migrate(&pool).await.unwrap();
+            // Nothing left to do the second time.
+            super::migrate(&pool).await.unwrap();
+
+            let strategy = crate::util::test_db::strategy();
+            let users = PgUsers::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                users.find_user_by_username("alice").await.unwrap().id,
+                user.id
+            );
+        });
+    }
+
+    #[test]
+    fn create_user_in_generated_schema() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::empty_pool().await;
+            pool.execute(&*super::users("accounts")).await.unwrap();
+            pool.execute(&*super::sessions("account_sessions", "accounts"))
+                .await
+                .unwrap();
+
+            let strategy = crate::util::test_db::strategy();
+            let users = PgUsers::<_, AsciiUsername>::new(pool, "accounts", strategy);
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                users.find_user_by_username("alice").await.unwrap().id,
+                user.id
+            );
+        });
+    }
+}
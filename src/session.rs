@@ -2,9 +2,12 @@ use std::{convert::TryFrom, fmt::Display};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use rand::RngCore;
 
 use crate::user::{User, UserId};
 
+#[cfg(feature = "jwt")]
+pub mod jwt;
 pub mod memory;
 pub mod postgres;
 pub mod redis;
@@ -24,24 +27,100 @@ impl Default for PasswordResetId {
     }
 }
 
+impl Display for PasswordResetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&**self, f)
+    }
+}
+
+/// Returned by [`SessionBackend`] methods that a backend can't provide, e.g. listing sessions
+/// when they aren't stored.
+#[derive(Debug, thiserror::Error)]
+#[error("Not supported by this session backend")]
+pub struct Unsupported;
+
+/// Storage for sessions and password reset ids.
+///
+/// This (like the other backend traits) is declared through `async_trait` rather than native
+/// `async fn` in traits on purpose: the boxed futures are guaranteed to be `Send`, which generic
+/// callers spawning work on a multi-threaded runtime rely on. The allocation per call is
+/// negligible next to the I/O every backend method does.
 #[async_trait]
 pub trait SessionBackend: Send + Sync {
-    type Error: std::error::Error;
+    type Error: std::error::Error + From<Unsupported>;
     type Session;
-    type UserId;
+    type UserId: Send;
 
+    /// Creates a session with id `id` carrying `data` that idles out at `expires_at`. Extending
+    /// it never pushes its expiry past `absolute_expires_at`, no matter how actively it is used.
     async fn new_session(
         &self,
-        id: Self::UserId,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
         expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error>;
+    /// Creates a new session and expires every other session of the user as one atomic step,
+    /// so that concurrent logins never leave more than one session alive.
+    async fn new_exclusive_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error>;
+    /// Creates a new session, then expires the user's oldest sessions beyond `max`, as one
+    /// atomic step, so that concurrent logins never leave more than `max` sessions alive. The
+    /// new session itself is never expired. Backends that can't count sessions fail with
+    /// [`Unsupported`].
+    async fn new_limited_session(
+        &self,
+        _id: SessionId,
+        _user_id: Self::UserId,
+        _data: serde_json::Value,
+        _expires_at: DateTime<Utc>,
+        _absolute_expires_at: Option<DateTime<Utc>>,
+        _max: usize,
+    ) -> Result<Self::Session, Self::Error> {
+        Err(Unsupported.into())
+    }
     async fn session(
         &self,
         id: SessionId,
         extend_expiry: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error>;
+    /// Remaining lifetime of a session. Unlike [`SessionBackend::session`], this never extends
+    /// the expiry date.
+    async fn session_ttl(&self, id: SessionId) -> Result<chrono::Duration, Self::Error>;
     async fn clear_stale_sessions(&self) -> Result<(), Self::Error>;
     async fn expire(&self, session: Self::Session) -> Result<(), Self::Error>;
+    /// Moves the session to the fresh id `new_id`, keeping its data and expiry, so that an id
+    /// learned before a privilege change (e.g. logging in) is of no use afterwards.
+    async fn regenerate_id(
+        &self,
+        session: Self::Session,
+        new_id: SessionId,
+    ) -> Result<Self::Session, Self::Error>;
+    /// Expires every session of the user, except `keep` if given, e.g. to log them out
+    /// everywhere after a password change. Returns how many were expired.
+    async fn expire_user_sessions(
+        &self,
+        user_id: Self::UserId,
+        keep: Option<SessionId>,
+    ) -> Result<u64, Self::Error>;
+    /// Expires every session created before `cutoff`, whoever it belongs to, returning how many
+    /// were expired. Meant for incident response, e.g. after credentials have leaked.
+    async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, Self::Error>;
+    /// Sessions of the user that haven't expired, oldest first, e.g. for listing signed-in
+    /// devices. Backends that can't enumerate sessions fail with [`Unsupported`].
+    async fn sessions_for_user(
+        &self,
+        _user_id: Self::UserId,
+    ) -> Result<Vec<Self::Session>, Self::Error> {
+        Err(Unsupported.into())
+    }
     async fn extend_expiry_date(
         &self,
         session: Self::Session,
@@ -72,12 +151,23 @@ pub trait SessionBackend: Send + Sync {
     // ) -> Result<(), Self::Error>;
 }
 
-#[nova::newtype(sqlx, serde, copy)]
-pub type SessionId = uuid::Uuid;
+/// What every backend's session knows, for code that is generic over the backend.
+pub trait SessionUser {
+    type UserId;
+
+    fn session_id(&self) -> &SessionId;
+    fn user_id(&self) -> &Self::UserId;
+}
+
+/// Id of a session, which the client holds on to, e.g. in a cookie. Backends treat it as opaque,
+/// so its form is up to the [`SessionIdGenerator`]: random v4 UUIDs by default.
+#[nova::newtype(sqlx, serde)]
+pub type SessionId = String;
 
 impl SessionId {
+    /// A random v4 UUID, as [`UuidV4Generator`] generates.
     pub fn new() -> Self {
-        SessionId(uuid::Uuid::new_v4())
+        SessionId(uuid::Uuid::new_v4().to_string())
     }
 }
 
@@ -93,23 +183,93 @@ impl Display for SessionId {
     }
 }
 
+/// Longest [`SessionId`] accepted from a client.
+pub const MAX_SESSION_ID_LEN: usize = 1024;
+
+/// The string can't be a [`SessionId`]: it is empty, longer than [`MAX_SESSION_ID_LEN`], or
+/// holds characters a cookie value can't carry unquoted, i.e. anything but printable ASCII
+/// without spaces, `"`, `,`, `;` and `\`.
+#[derive(Debug, thiserror::Error)]
+#[error("invalid session id")]
+pub struct InvalidSessionId;
+
 impl TryFrom<&str> for SessionId {
-    type Error = uuid::Error;
+    type Error = InvalidSessionId;
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
-        let uuid = uuid::Uuid::parse_str(value)?;
-        Ok(Self(uuid))
+        let valid = !value.is_empty()
+            && value.len() <= MAX_SESSION_ID_LEN
+            && value
+                .bytes()
+                .all(|b| b.is_ascii_graphic() && !matches!(b, b'"' | b',' | b';' | b'\\'));
+        match valid {
+            true => Ok(Self(value.to_string())),
+            false => Err(InvalidSessionId),
+        }
     }
 }
 
 impl TryFrom<String> for SessionId {
-    type Error = uuid::Error;
+    type Error = InvalidSessionId;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         <SessionId as TryFrom<&str>>::try_from(&value)
     }
 }
 
+/// Source of the ids of new sessions. The ids must be unique and unguessable, and be valid
+/// [`SessionId`]s (see [`InvalidSessionId`]). Backends refuse to create a session under an id
+/// that is taken already.
+pub trait SessionIdGenerator: Send + Sync {
+    fn generate(&self) -> SessionId;
+}
+
+/// Generates random (v4) UUIDs, the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UuidV4Generator;
+
+impl SessionIdGenerator for UuidV4Generator {
+    fn generate(&self) -> SessionId {
+        SessionId::new()
+    }
+}
+
+/// Generates ids of `len` random bytes, hex encoded, for more entropy than the 122 bits of a v4
+/// UUID. The bytes come from `rand::thread_rng`, a CSPRNG.
+#[derive(Debug, Clone, Copy)]
+pub struct RandomBytesGenerator {
+    len: usize,
+}
+
+impl RandomBytesGenerator {
+    /// # Panics
+    ///
+    /// Panics if `len` is zero or the ids would be longer than [`MAX_SESSION_ID_LEN`].
+    pub fn new(len: usize) -> Self {
+        assert!(
+            len > 0 && len * 2 <= MAX_SESSION_ID_LEN,
+            "session id length out of range, got {} bytes",
+            len
+        );
+        Self { len }
+    }
+}
+
+/// 32 bytes, i.e. 256 bits.
+impl Default for RandomBytesGenerator {
+    fn default() -> Self {
+        Self::new(32)
+    }
+}
+
+impl SessionIdGenerator for RandomBytesGenerator {
+    fn generate(&self) -> SessionId {
+        let mut bytes = vec![0u8; self.len];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        SessionId(hex::encode(bytes))
+    }
+}
+
 pub struct SessionManager<T, S, U, E>
 where
     T: SessionBackend<Error = E, Session = S, UserId = U>,
@@ -117,9 +277,21 @@ where
     /// Session automatically refreshes expires_at date upon access.
     auto_refresh: bool,
 
-    /// Duration before session expires.
+    /// Duration before session expires (when idle, if `auto_refresh` is set).
     alive_duration: chrono::Duration,
 
+    /// Duration after creation at which the session expires, regardless of activity.
+    absolute_lifetime: Option<chrono::Duration>,
+
+    /// Creating a session expires all other sessions of the same user.
+    single_session: bool,
+
+    /// Creating a session past this many sessions of the same user expires the oldest.
+    max_sessions_per_user: Option<usize>,
+
+    /// Picks the ids of new sessions.
+    id_generator: Box<dyn SessionIdGenerator>,
+
     /// Session backend abstraction.
     backend: T,
 }
@@ -129,14 +301,80 @@ where
     E: std::error::Error,
     T: SessionBackend<Error = E, Session = S, UserId = U>,
 {
+    /// # Panics
+    ///
+    /// Panics if `alive_duration` isn't positive, as every session would be born expired.
     pub fn new(auto_refresh: bool, alive_duration: chrono::Duration, backend: T) -> Self {
+        assert!(
+            alive_duration > chrono::Duration::zero(),
+            "session alive_duration must be positive, got {}",
+            alive_duration
+        );
+
         Self {
             auto_refresh,
             alive_duration,
+            absolute_lifetime: None,
+            single_session: false,
+            max_sessions_per_user: None,
+            id_generator: Box::new(UuidV4Generator),
             backend,
         }
     }
 
+    /// Only allow one active session per user: logging in expires the user's other sessions.
+    pub fn with_single_session(mut self, single_session: bool) -> Self {
+        self.single_session = single_session;
+        self
+    }
+
+    /// Caps how many sessions a user may hold at once: logging in beyond that expires the
+    /// user's oldest sessions. Creating sessions fails with [`Unsupported`] on backends that
+    /// can't count them (see [`SessionBackend::new_limited_session`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_sessions_per_user` is zero.
+    pub fn with_max_sessions_per_user(mut self, max_sessions_per_user: usize) -> Self {
+        assert!(
+            max_sessions_per_user > 0,
+            "session max_sessions_per_user must be positive"
+        );
+        self.max_sessions_per_user = Some(max_sessions_per_user);
+        self
+    }
+
+    /// Generates session ids with `id_generator` instead of [`UuidV4Generator`].
+    pub fn with_id_generator(mut self, id_generator: impl SessionIdGenerator + 'static) -> Self {
+        self.id_generator = Box::new(id_generator);
+        self
+    }
+
+    /// Caps how long a session lives, however actively it is used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `absolute_lifetime` isn't positive.
+    pub fn with_absolute_lifetime(mut self, absolute_lifetime: chrono::Duration) -> Self {
+        assert!(
+            absolute_lifetime > chrono::Duration::zero(),
+            "session absolute_lifetime must be positive, got {}",
+            absolute_lifetime
+        );
+        self.absolute_lifetime = Some(absolute_lifetime);
+        self
+    }
+
+    /// How long a session lives without being accessed.
+    pub fn idle_timeout(&self) -> chrono::Duration {
+        self.alive_duration
+    }
+
+    /// How long a session lives at most, if capped.
+    pub fn absolute_lifetime(&self) -> Option<chrono::Duration> {
+        self.absolute_lifetime
+    }
+
     #[inline]
     pub async fn extend_expiry_date(&self, session: S) -> Result<S, E> {
         let expires_at = Utc::now() + self.alive_duration;
@@ -145,8 +383,38 @@ where
 
     #[inline]
     pub async fn new_session(&self, user_id: U) -> Result<S, E> {
-        let expires_at = Utc::now() + self.alive_duration;
-        self.backend.new_session(user_id, expires_at).await
+        self.new_session_with_data(user_id, serde_json::Value::Object(Default::default()))
+            .await
+    }
+
+    /// Like [`SessionManager::new_session`], with `data` (e.g. a CSRF token) stored alongside
+    /// the session.
+    pub async fn new_session_with_data(&self, user_id: U, data: serde_json::Value) -> Result<S, E> {
+        let now = Utc::now();
+        let absolute_expires_at = self.absolute_lifetime.map(|lifetime| now + lifetime);
+        let expires_at = match absolute_expires_at {
+            Some(absolute) => std::cmp::min(now + self.alive_duration, absolute),
+            None => now + self.alive_duration,
+        };
+        let id = self.id_generator.generate();
+
+        match (self.single_session, self.max_sessions_per_user) {
+            (true, _) => {
+                self.backend
+                    .new_exclusive_session(id, user_id, data, expires_at, absolute_expires_at)
+                    .await
+            }
+            (false, Some(max)) => {
+                self.backend
+                    .new_limited_session(id, user_id, data, expires_at, absolute_expires_at, max)
+                    .await
+            }
+            (false, None) => {
+                self.backend
+                    .new_session(id, user_id, data, expires_at, absolute_expires_at)
+                    .await
+            }
+        }
     }
 
     #[inline]
@@ -159,6 +427,11 @@ where
         self.backend.session(session_id, extend_expiry).await
     }
 
+    #[inline]
+    pub async fn session_ttl(&self, session_id: SessionId) -> Result<chrono::Duration, E> {
+        self.backend.session_ttl(session_id).await
+    }
+
     #[inline]
     pub async fn clear_stale_sessions(&self) -> Result<(), E> {
         self.backend.clear_stale_sessions().await
@@ -169,6 +442,31 @@ where
         self.backend.expire(session).await
     }
 
+    #[inline]
+    pub async fn regenerate_id(&self, session: S) -> Result<S, E> {
+        let new_id = self.id_generator.generate();
+        self.backend.regenerate_id(session, new_id).await
+    }
+
+    #[inline]
+    pub async fn expire_user_sessions(
+        &self,
+        user_id: U,
+        keep: Option<SessionId>,
+    ) -> Result<u64, E> {
+        self.backend.expire_user_sessions(user_id, keep).await
+    }
+
+    #[inline]
+    pub async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, E> {
+        self.backend.expire_created_before(cutoff).await
+    }
+
+    #[inline]
+    pub async fn sessions_for_user(&self, user_id: U) -> Result<Vec<S>, E> {
+        self.backend.sessions_for_user(user_id).await
+    }
+
     pub async fn generate_password_reset_id(
         &self,
         user_id: U,
@@ -222,22 +520,553 @@ mod tests {
                 memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
             let user_id = UserId::random();
             let session = handler.new_session(user_id).await.unwrap();
-            let _mm = handler.session(session.id).await.unwrap();
+            let _mm = handler.session(session.id.clone()).await.unwrap();
         })
     }
 
     #[test]
-    fn memory_expired_session() {
+    fn memory_session_data() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
+            let data = serde_json::json!({ "csrf": "abc123", "roles": ["admin"] });
+            let session = handler
+                .new_session_with_data(UserId::random(), data.clone())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                handler.session(session.id.clone()).await.unwrap().data,
+                data
+            );
+            let plain = handler.new_session(UserId::random()).await.unwrap();
+            assert_eq!(plain.data, serde_json::json!({}));
+        })
+    }
+
+    /// Hands out ids counting up from 1.
+    #[derive(Default)]
+    struct SequentialIds(std::sync::atomic::AtomicU64);
+
+    impl SessionIdGenerator for SequentialIds {
+        fn generate(&self) -> SessionId {
+            let n = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
+            SessionId(format!("session-{}", n))
+        }
+    }
+
+    #[test]
+    fn memory_custom_id_generator() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default())
+                    .with_id_generator(SequentialIds::default());
+            let user_id = UserId::random();
+
+            let first = handler.new_session(user_id).await.unwrap();
+            let second = handler.new_session(user_id).await.unwrap();
+            assert_eq!(first.id.as_str(), "session-1");
+            assert_eq!(second.id.as_str(), "session-2");
+            assert_eq!(
+                handler.session(first.id.clone()).await.unwrap().user_id,
+                user_id
+            );
+
+            let regenerated = handler.regenerate_id(first).await.unwrap();
+            assert_eq!(regenerated.id.as_str(), "session-3");
+        })
+    }
+
+    /// Hands out the same id every time.
+    struct ConstantId;
+
+    impl SessionIdGenerator for ConstantId {
+        fn generate(&self) -> SessionId {
+            SessionId("the-one".into())
+        }
+    }
+
+    #[test]
+    fn memory_rejects_taken_ids() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default())
+                    .with_id_generator(ConstantId);
+            let user_id = UserId::random();
+
+            let first = handler.new_session(user_id).await.unwrap();
+            assert!(matches!(
+                handler.new_session(UserId::random()).await,
+                Err(memory::Error::IdTaken(id)) if id == first.id
+            ));
+            assert!(matches!(
+                handler.regenerate_id(first.clone()).await,
+                Err(memory::Error::IdTaken(_))
+            ));
+            // The first session is left alone.
+            assert_eq!(
+                handler.session(first.id.clone()).await.unwrap().user_id,
+                user_id
+            );
+        })
+    }
+
+    #[test]
+    fn random_bytes_ids() {
+        let generator = RandomBytesGenerator::default();
+        let (a, b) = (generator.generate(), generator.generate());
+        assert_eq!(a.len(), 64);
+        assert_ne!(a, b);
+        assert!(SessionId::try_from(a.as_str()).is_ok());
+    }
+
+    #[test]
+    fn parse_session_ids() {
+        assert!(SessionId::try_from("0123456789abcdef").is_ok());
+        assert!(SessionId::try_from(SessionId::new().as_str()).is_ok());
+        assert!(SessionId::try_from("").is_err());
+        assert!(SessionId::try_from("two words").is_err());
+        assert!(SessionId::try_from("eyJh.eyJz-_x=").is_ok());
+        assert!(SessionId::try_from("a;b=c").is_err());
+        assert!(SessionId::try_from("x".repeat(MAX_SESSION_ID_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn memory_last_accessed_at_advances() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
+            let session = handler.new_session(UserId::random()).await.unwrap();
+            assert_eq!(session.last_accessed_at, session.created_at);
+
+            let first = handler.session(session.id.clone()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let second = handler.session(session.id.clone()).await.unwrap();
+            assert!(second.last_accessed_at >= first.last_accessed_at + Duration::seconds(1));
+        })
+    }
+
+    #[test]
+    fn memory_capacity_evicts_least_recently_accessed() {
         let rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async move {
             let handler = memory::SessionManager::new(
                 true,
-                Duration::seconds(-1),
+                Duration::minutes(5),
+                memory::Backend::with_capacity(3),
+            );
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(UserId::random()).await.unwrap());
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+            // The first session is now the most recently accessed, the second the least.
+            handler.session(sessions[0].id.clone()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+            let newest = handler.new_session(UserId::random()).await.unwrap();
+            assert!(matches!(
+                handler.session(sessions[1].id.clone()).await,
+                Err(memory::Error::NotFound(_))
+            ));
+            for id in [sessions[0].id.clone(), sessions[2].id.clone(), newest.id] {
+                assert!(handler.session(id).await.is_ok());
+            }
+        })
+    }
+
+    #[test]
+    fn memory_expired_session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = memory::SessionManager::new(
+                false,
+                Duration::milliseconds(1),
                 memory::Backend::default(),
             );
             let user_id = UserId::random();
             let session = handler.new_session(user_id).await.unwrap();
-            assert!(handler.session(session.id).await.is_err())
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(memory::Error::Expired(id)) if id == session.id
+            ));
+
+            // Once reported, the session is gone for good.
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(memory::Error::NotFound(_))
+            ));
+            assert!(matches!(
+                handler.session(SessionId::new()).await,
+                Err(memory::Error::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "alive_duration must be positive")]
+    fn non_positive_alive_duration() {
+        memory::SessionManager::<UserId>::new(
+            true,
+            Duration::seconds(-1),
+            memory::Backend::default(),
+        );
+    }
+
+    #[test]
+    fn memory_session_ttl() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::minutes(5), memory::Backend::default());
+            let session = handler.new_session(UserId::random()).await.unwrap();
+
+            let ttl = handler.session_ttl(session.id.clone()).await.unwrap();
+            assert!(ttl <= Duration::minutes(5));
+            assert!(ttl > Duration::minutes(5) - Duration::seconds(5));
+
+            assert!(matches!(
+                handler.session_ttl(SessionId::new()).await,
+                Err(memory::Error::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn memory_single_session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = std::sync::Arc::new(
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default())
+                    .with_single_session(true),
+            );
+            let user_id = UserId::random();
+
+            let first = tokio::spawn({
+                let handler = handler.clone();
+                async move { handler.new_session(user_id).await.unwrap() }
+            });
+            let second = tokio::spawn({
+                let handler = handler.clone();
+                async move { handler.new_session(user_id).await.unwrap() }
+            });
+            let (first, second) = (first.await.unwrap(), second.await.unwrap());
+
+            let first_alive = handler.session(first.id.clone()).await.is_ok();
+            let second_alive = handler.session(second.id.clone()).await.is_ok();
+            assert!(first_alive ^ second_alive);
+        });
+    }
+
+    #[test]
+    fn memory_max_sessions_per_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::minutes(5), memory::Backend::default())
+                    .with_max_sessions_per_user(3);
+            let user_id = UserId::random();
+            let someone_else = handler.new_session(UserId::random()).await.unwrap();
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+                tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+            }
+
+            let fourth = handler.new_session(user_id).await.unwrap();
+            assert!(matches!(
+                handler.session(sessions[0].id.clone()).await,
+                Err(memory::Error::NotFound(_))
+            ));
+            for id in [
+                sessions[1].id.clone(),
+                sessions[2].id.clone(),
+                fourth.id.clone(),
+                someone_else.id,
+            ] {
+                assert!(handler.session(id).await.is_ok());
+            }
+            assert_eq!(handler.sessions_for_user(user_id).await.unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn memory_expire_user_sessions_keeps_current() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
+            let user_id = UserId::random();
+            let current = handler.new_session(user_id).await.unwrap();
+            let other = handler.new_session(user_id).await.unwrap();
+            let someone_else = handler.new_session(UserId::random()).await.unwrap();
+
+            handler
+                .expire_user_sessions(user_id, Some(current.id.clone()))
+                .await
+                .unwrap();
+
+            assert!(handler.session(current.id.clone()).await.is_ok());
+            assert!(handler.session(other.id.clone()).await.is_err());
+            assert!(handler.session(someone_else.id.clone()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn memory_sessions_for_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = memory::SessionManager::new(
+                false,
+                Duration::milliseconds(200),
+                memory::Backend::default(),
+            );
+            let user_id = UserId::random();
+            let expired = handler.new_session(user_id).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+            let first = handler.new_session(user_id).await.unwrap();
+            let second = handler.new_session(user_id).await.unwrap();
+            handler.new_session(UserId::random()).await.unwrap();
+
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|s| s.id.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![first.id, second.id]);
+            assert!(!ids.contains(&expired.id));
+        });
+    }
+
+    #[test]
+    fn memory_regenerate_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
+            let user_id = UserId::random();
+            let data = serde_json::json!({ "csrf": "abc123" });
+            let old = handler
+                .new_session_with_data(user_id, data.clone())
+                .await
+                .unwrap();
+            let old_id = old.id.clone();
+
+            let new = handler.regenerate_id(old).await.unwrap();
+            assert_ne!(new.id, old_id);
+            assert!(matches!(
+                handler.session(old_id).await,
+                Err(memory::Error::NotFound(_))
+            ));
+            let found = handler.session(new.id.clone()).await.unwrap();
+            assert_eq!(found.user_id, user_id);
+            assert_eq!(found.data, data);
+
+            handler.expire(found.clone()).await.unwrap();
+            assert!(matches!(
+                handler.regenerate_id(found).await,
+                Err(memory::Error::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn memory_expire_all_user_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::seconds(5), memory::Backend::default());
+            let user_id = UserId::random();
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+            }
+            let someone_else = handler.new_session(UserId::random()).await.unwrap();
+
+            assert_eq!(
+                handler.expire_user_sessions(user_id, None).await.unwrap(),
+                3
+            );
+            for session in sessions {
+                assert!(matches!(
+                    handler.session(session.id.clone()).await,
+                    Err(memory::Error::NotFound(_))
+                ));
+            }
+            assert!(handler.session(someone_else.id.clone()).await.is_ok());
+            assert_eq!(
+                handler.expire_user_sessions(user_id, None).await.unwrap(),
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn memory_expire_created_before() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::minutes(5), memory::Backend::default());
+            let user_id = UserId::random();
+            let old = handler.new_session(user_id).await.unwrap();
+            let old_other_user = handler.new_session(UserId::random()).await.unwrap();
+
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let cutoff = Utc::now();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let new = handler.new_session(user_id).await.unwrap();
+
+            assert_eq!(handler.expire_created_before(cutoff).await.unwrap(), 2);
+            assert!(handler.session(old.id.clone()).await.is_err());
+            assert!(handler.session(old_other_user.id.clone()).await.is_err());
+            assert!(handler.session(new.id.clone()).await.is_ok());
+            assert_eq!(handler.expire_created_before(cutoff).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn memory_password_reset() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::minutes(5), memory::Backend::default());
+            let user_id = UserId::random();
+
+            let id = handler
+                .generate_password_reset_id(user_id, Utc::now() + Duration::minutes(5))
+                .await
+                .unwrap();
+            assert_eq!(handler.verify_password_reset_id(id).await.unwrap(), user_id);
+            assert_eq!(handler.verify_password_reset_id(id).await.unwrap(), user_id);
+            assert_eq!(
+                handler.consume_password_reset_id(id).await.unwrap(),
+                user_id
+            );
+
+            // Single use.
+            assert!(matches!(
+                handler.consume_password_reset_id(id).await,
+                Err(memory::Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler.verify_password_reset_id(id).await,
+                Err(memory::Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler
+                    .verify_password_reset_id(PasswordResetId::new())
+                    .await,
+                Err(memory::Error::PasswordResetNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn memory_password_reset_consumed_once_under_contention() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = std::sync::Arc::new(memory::SessionManager::new(
+                true,
+                Duration::minutes(5),
+                memory::Backend::default(),
+            ));
+            let id = handler
+                .generate_password_reset_id(UserId::random(), Utc::now() + Duration::minutes(5))
+                .await
+                .unwrap();
+
+            let tasks = (0..16)
+                .map(|_| {
+                    let handler = handler.clone();
+                    tokio::spawn(async move { handler.consume_password_reset_id(id).await })
+                })
+                .collect::<Vec<_>>();
+            let mut consumed = 0;
+            for task in tasks {
+                match task.await.unwrap() {
+                    Ok(_) => consumed += 1,
+                    Err(memory::Error::PasswordResetNotFound(_)) => {}
+                    Err(e) => panic!("unexpected {:?}", e),
+                }
+            }
+            assert_eq!(consumed, 1);
+        });
+    }
+
+    #[test]
+    fn memory_expired_password_reset() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler =
+                memory::SessionManager::new(true, Duration::minutes(5), memory::Backend::default());
+            let id = handler
+                .generate_password_reset_id(
+                    UserId::random(),
+                    Utc::now() + Duration::milliseconds(1),
+                )
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+            assert!(matches!(
+                handler.verify_password_reset_id(id).await,
+                Err(memory::Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler.consume_password_reset_id(id).await,
+                Err(memory::Error::PasswordResetNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn memory_session_dies_at_absolute_lifetime() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = memory::SessionManager::new(
+                true,
+                Duration::milliseconds(300),
+                memory::Backend::default(),
+            )
+            .with_absolute_lifetime(Duration::milliseconds(600));
+            let session = handler.new_session(UserId::random()).await.unwrap();
+
+            // Kept alive by activity past the idle timeout...
+            for _ in 0..3 {
+                tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+                assert!(handler.session(session.id.clone()).await.is_ok());
+            }
+
+            // ...but not past the absolute lifetime.
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(memory::Error::Expired(id)) if id == session.id
+            ));
+        });
+    }
+
+    #[test]
+    fn memory_session_dies_when_idle() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let handler = memory::SessionManager::new(
+                true,
+                Duration::milliseconds(100),
+                memory::Backend::default(),
+            )
+            .with_absolute_lifetime(Duration::minutes(5));
+            let session = handler.new_session(UserId::random()).await.unwrap();
+
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            assert!(handler.session(session.id.clone()).await.is_err());
         });
     }
 }
@@ -0,0 +1,1136 @@
+//! Stateless sessions, kept in a signed token the client holds rather than in storage.
+//!
+//! The token is the [`SessionId`]: [`Backend`] signs the user id, data and expiry into it when
+//! creating a session, and checks the signature and expiry to get them back, without a round
+//! trip to a database. The id the [`SessionIdGenerator`](super::SessionIdGenerator) comes up
+//! with becomes the token's `jti`, by which it is revoked. Tokens longer than
+//! [`MAX_SESSION_ID_LEN`](super::MAX_SESSION_ID_LEN) aren't accepted back from clients, so keep
+//! session data small.
+//!
+//! A token stays valid until it expires unless it is revoked, which is what [`Revocations`]
+//! keep track of. [`MemoryRevocations`] only know about revocations in this process, so services
+//! sharing tokens should share [`RedisRevocations`] instead. Revoking all of a user's sessions,
+//! or all sessions created before some time, records a cutoff rather than every token, which is
+//! remembered for as long as tokens live at most (see [`Backend::with_max_lifetime`]).
+//!
+//! What doesn't fit a stateless backend:
+//!
+//! - The expiry is signed into the token, so extending it means handing the client a new
+//!   token. [`SessionBackend::session`](super::SessionBackend::session) can't do that and
+//!   ignores `extend_expiry`, while
+//!   [`SessionBackend::extend_expiry_date`](super::SessionBackend::extend_expiry_date) returns
+//!   the session under its new token.
+//! - Tokens aren't stored, so they can't be listed or counted, and there are no password reset
+//!   ids. Those methods fail with [`Error::Unsupported`], apart from the revoking ones, which
+//!   revoke but report 0 sessions.
+//!
+//! ```
+//! use thetc_auth::session::jwt;
+//!
+//! let sessions = jwt::SessionManager::<uuid::Uuid>::new(
+//!     false,
+//!     chrono::Duration::minutes(15),
+//!     jwt::Backend::with_hmac_secret(b"a long random secret"),
+//! );
+//! # tokio::runtime::Runtime::new().unwrap().block_on(async {
+//! let user_id = uuid::Uuid::new_v4();
+//! let session = sessions.new_session(user_id).await.unwrap();
+//!
+//! // `session.id` is the token to hand to the client.
+//! assert_eq!(sessions.session(session.id).await.unwrap().user_id, user_id);
+//! # });
+//! ```
+
+use std::{collections::HashMap, convert::TryFrom, marker::PhantomData, sync::RwLock};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, SubsecRound, Utc};
+use deadpool_redis::{Config, Runtime};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{PasswordResetId, SessionId, SessionUser};
+
+pub type SessionManager<U, R = MemoryRevocations> =
+    super::SessionManager<Backend<U, R>, Session<U>, U, Error>;
+
+#[derive(Debug, Clone)]
+pub struct Session<U> {
+    /// The signed token.
+    pub id: SessionId,
+    /// Id of the token, by which it is revoked. Shared with the tokens
+    /// [`SessionBackend::extend_expiry_date`](super::SessionBackend::extend_expiry_date) hands
+    /// out in its place.
+    pub jti: String,
+    pub user_id: U,
+    pub data: serde_json::Value,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+}
+
+impl<U> SessionUser for Session<U> {
+    type UserId = U;
+
+    fn session_id(&self) -> &SessionId {
+        &self.id
+    }
+
+    fn user_id(&self) -> &U {
+        &self.user_id
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Claims<U> {
+    sub: U,
+    jti: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    iat: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    exp: DateTime<Utc>,
+    /// `iat` to the millisecond, to compare against revocation cutoffs.
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    created_at: DateTime<Utc>,
+    #[serde(with = "chrono::serde::ts_seconds_option")]
+    absolute_expires_at: Option<DateTime<Utc>>,
+    data: serde_json::Value,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The token is malformed, its signature doesn't match, or a key couldn't be read.
+    #[error("Invalid token: {0}")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+
+    #[error("Session has expired")]
+    Expired,
+
+    #[error("Session {0} has been revoked")]
+    Revoked(String),
+
+    /// The backend was built from a public key only, so can verify tokens but not sign them.
+    #[error("No key to sign tokens with")]
+    CannotIssue,
+
+    /// The token would be longer than [`MAX_SESSION_ID_LEN`](super::MAX_SESSION_ID_LEN),
+    /// because of the session data.
+    #[error("Token too long")]
+    TooLong,
+
+    /// Tokens aren't stored, so can't be listed, and there are no password reset ids.
+    #[error("Not supported by stateless sessions")]
+    Unsupported,
+
+    #[error("Json parsing error")]
+    Json(#[from] serde_json::Error),
+
+    #[error("Error establishing connection to Redis pool")]
+    Pool(#[from] deadpool_redis::PoolError),
+
+    #[error("Redis error")]
+    Redis(#[from] redis::RedisError),
+}
+
+impl From<super::Unsupported> for Error {
+    fn from(_: super::Unsupported) -> Self {
+        Error::Unsupported
+    }
+}
+
+/// Where [`Backend`] keeps track of revoked tokens. Every token checked is looked up with
+/// [`Revocations::is_revoked`].
+///
+/// Users are identified by their id serialized as JSON.
+#[async_trait]
+pub trait Revocations: Send + Sync {
+    /// Revokes the token `jti` until it would have expired anyway, at `expires_at`.
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), Error>;
+
+    /// Revokes the user's tokens created up to `cutoff`, other than `keep`, for `ttl`.
+    async fn revoke_user(
+        &self,
+        user: &str,
+        cutoff: DateTime<Utc>,
+        keep: Option<&str>,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    /// Revokes every token created before `cutoff`, for `ttl`.
+    async fn revoke_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        ttl: Duration,
+    ) -> Result<(), Error>;
+
+    async fn is_revoked(
+        &self,
+        jti: &str,
+        user: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<bool, Error>;
+
+    /// Forgets revocations that no longer matter.
+    async fn clear_stale(&self) -> Result<(), Error>;
+}
+
+#[derive(Debug)]
+struct UserCutoff {
+    cutoff: DateTime<Utc>,
+    keep: Option<String>,
+    /// When the user's tokens created up to the cutoff have all expired anyway.
+    until: DateTime<Utc>,
+}
+
+/// Revocations known to this process only.
+#[derive(Debug, Default)]
+pub struct MemoryRevocations {
+    /// Revoked token ids, with when they would have expired anyway.
+    tokens: RwLock<HashMap<String, DateTime<Utc>>>,
+    users: RwLock<HashMap<String, UserCutoff>>,
+    created_before: RwLock<Option<(DateTime<Utc>, DateTime<Utc>)>>,
+}
+
+#[async_trait]
+impl Revocations for MemoryRevocations {
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), Error> {
+        self.tokens
+            .write()
+            .unwrap()
+            .insert(jti.to_string(), expires_at);
+        Ok(())
+    }
+
+    async fn revoke_user(
+        &self,
+        user: &str,
+        cutoff: DateTime<Utc>,
+        keep: Option<&str>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        self.users.write().unwrap().insert(
+            user.to_string(),
+            UserCutoff {
+                cutoff,
+                keep: keep.map(str::to_string),
+                until: Utc::now() + ttl,
+            },
+        );
+        Ok(())
+    }
+
+    async fn revoke_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        *self.created_before.write().unwrap() = Some((cutoff, Utc::now() + ttl));
+        Ok(())
+    }
+
+    async fn is_revoked(
+        &self,
+        jti: &str,
+        user: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        if self.tokens.read().unwrap().contains_key(jti) {
+            return Ok(true);
+        }
+        if let Some((cutoff, _)) = *self.created_before.read().unwrap() {
+            if created_at < cutoff {
+                return Ok(true);
+            }
+        }
+        Ok(match self.users.read().unwrap().get(user) {
+            Some(user) => created_at <= user.cutoff && user.keep.as_deref() != Some(jti),
+            None => false,
+        })
+    }
+
+    async fn clear_stale(&self) -> Result<(), Error> {
+        let now = Utc::now();
+        self.tokens
+            .write()
+            .unwrap()
+            .retain(|_, expires_at| now < *expires_at);
+        self.users
+            .write()
+            .unwrap()
+            .retain(|_, user| now < user.until);
+        let mut created_before = self.created_before.write().unwrap();
+        if matches!(*created_before, Some((_, until)) if until <= now) {
+            *created_before = None;
+        }
+        Ok(())
+    }
+}
+
+/// Revocations shared through Redis, which drops them by itself once they stop mattering.
+pub struct RedisRevocations {
+    pool: deadpool_redis::Pool,
+}
+
+impl RedisRevocations {
+    pub fn new(url: &str) -> Result<Self, deadpool_redis::CreatePoolError> {
+        let config = Config::from_url(url);
+        let pool = config.create_pool(Some(Runtime::Tokio1))?;
+        Ok(Self { pool })
+    }
+
+    pub fn with_pool(pool: deadpool_redis::Pool) -> Self {
+        Self { pool }
+    }
+}
+
+const CREATED_BEFORE_KEY: &str = "jwt/created-before";
+
+fn revoked_key(jti: &str) -> String {
+    format!("jwt/revoked/{}", jti)
+}
+
+fn user_cutoff_key(user: &str) -> String {
+    format!("jwt/user/{}/cutoff", user)
+}
+
+#[async_trait]
+impl Revocations for RedisRevocations {
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), Error> {
+        let ttl = expires_at - Utc::now();
+        // Expired already, and Redis refuses a TTL that isn't positive.
+        if ttl <= Duration::zero() {
+            return Ok(());
+        }
+        let mut conn = self.pool.get().await?;
+        redis::cmd("SET")
+            .arg(revoked_key(jti))
+            .arg(1)
+            .arg("PX")
+            .arg(ttl.num_milliseconds().max(1))
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_user(
+        &self,
+        user: &str,
+        cutoff: DateTime<Utc>,
+        keep: Option<&str>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        // The cutoff in milliseconds, then the kept token id, if any.
+        redis::cmd("SET")
+            .arg(user_cutoff_key(user))
+            .arg(format!(
+                "{}:{}",
+                cutoff.timestamp_millis(),
+                keep.unwrap_or("")
+            ))
+            .arg("PX")
+            .arg(ttl.num_milliseconds().max(1))
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn revoke_created_before(
+        &self,
+        cutoff: DateTime<Utc>,
+        ttl: Duration,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        redis::cmd("SET")
+            .arg(CREATED_BEFORE_KEY)
+            .arg(cutoff.timestamp_millis())
+            .arg("PX")
+            .arg(ttl.num_milliseconds().max(1))
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_revoked(
+        &self,
+        jti: &str,
+        user: &str,
+        created_at: DateTime<Utc>,
+    ) -> Result<bool, Error> {
+        let mut conn = self.pool.get().await?;
+        let (revoked, created_before, user_cutoff): (Option<String>, Option<i64>, Option<String>) =
+            redis::cmd("MGET")
+                .arg(revoked_key(jti))
+                .arg(CREATED_BEFORE_KEY)
+                .arg(user_cutoff_key(user))
+                .query_async(&mut conn)
+                .await?;
+
+        let created_at = created_at.timestamp_millis();
+        if revoked.is_some() || matches!(created_before, Some(cutoff) if created_at < cutoff) {
+            return Ok(true);
+        }
+        Ok(
+            match user_cutoff.as_deref().and_then(|v| v.split_once(':')) {
+                // A cutoff that doesn't parse revokes, to be safe.
+                Some((cutoff, keep)) => {
+                    created_at <= cutoff.parse().unwrap_or(i64::MAX) && keep != jti
+                }
+                None => false,
+            },
+        )
+    }
+
+    /// Redis drops revocations by itself once they expire, so this does nothing.
+    async fn clear_stale(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub struct Backend<U, R = MemoryRevocations> {
+    encoding_key: Option<EncodingKey>,
+    decoding_key: DecodingKey,
+    validation: Validation,
+    max_lifetime: Duration,
+    revocations: R,
+    _user_id: PhantomData<fn() -> U>,
+}
+
+impl<U> Backend<U> {
+    fn with_keys(
+        algorithm: Algorithm,
+        encoding_key: Option<EncodingKey>,
+        decoding_key: DecodingKey,
+    ) -> Self {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = 0;
+
+        Self {
+            encoding_key,
+            decoding_key,
+            validation,
+            max_lifetime: Duration::days(1),
+            revocations: MemoryRevocations::default(),
+            _user_id: PhantomData,
+        }
+    }
+
+    /// Signs and verifies tokens with HS256, for when the same service does both.
+    pub fn with_hmac_secret(secret: &[u8]) -> Self {
+        Self::with_keys(
+            Algorithm::HS256,
+            Some(EncodingKey::from_secret(secret)),
+            DecodingKey::from_secret(secret),
+        )
+    }
+
+    /// Signs and verifies tokens with RS256, from PEM encoded keys.
+    pub fn with_rsa_pem(private_key: &[u8], public_key: &[u8]) -> Result<Self, Error> {
+        Ok(Self::with_keys(
+            Algorithm::RS256,
+            Some(EncodingKey::from_rsa_pem(private_key)?),
+            DecodingKey::from_rsa_pem(public_key)?,
+        ))
+    }
+
+    /// Only verifies RS256 tokens, e.g. in a service that trusts the one issuing them. Creating
+    /// sessions fails with [`Error::CannotIssue`].
+    pub fn verifier_with_rsa_pem(public_key: &[u8]) -> Result<Self, Error> {
+        Ok(Self::with_keys(
+            Algorithm::RS256,
+            None,
+            DecodingKey::from_rsa_pem(public_key)?,
+        ))
+    }
+}
+
+impl<U, R> Backend<U, R> {
+    /// Keeps track of revoked tokens in `revocations` rather than in this process.
+    pub fn with_revocations<R2: Revocations>(self, revocations: R2) -> Backend<U, R2> {
+        Backend {
+            encoding_key: self.encoding_key,
+            decoding_key: self.decoding_key,
+            validation: self.validation,
+            max_lifetime: self.max_lifetime,
+            revocations,
+            _user_id: PhantomData,
+        }
+    }
+
+    /// Caps how long after its creation a token expires, one day by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_lifetime` isn't positive.
+    pub fn with_max_lifetime(mut self, max_lifetime: Duration) -> Self {
+        assert!(
+            max_lifetime > Duration::zero(),
+            "token max_lifetime must be positive, got {}",
+            max_lifetime
+        );
+        self.max_lifetime = max_lifetime;
+        self
+    }
+}
+
+impl<U, R> Backend<U, R>
+where
+    U: Serialize + DeserializeOwned,
+    R: Revocations,
+{
+    fn user_key(user_id: &U) -> Result<String, Error> {
+        Ok(serde_json::to_string(user_id)?)
+    }
+
+    /// Signs a token for the session, capping its expiry at `max_lifetime` after
+    /// `created_at`.
+    fn issue(
+        &self,
+        jti: String,
+        user_id: U,
+        data: serde_json::Value,
+        created_at: DateTime<Utc>,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Session<U>, Error> {
+        let key = self.encoding_key.as_ref().ok_or(Error::CannotIssue)?;
+        // As precise as the claims, so that the session matches what the token says.
+        let created_at = created_at.trunc_subsecs(3);
+        let expires_at = expires_at.trunc_subsecs(0);
+        let last = (created_at + self.max_lifetime).trunc_subsecs(0);
+        let claims = Claims {
+            sub: user_id,
+            jti,
+            iat: created_at,
+            exp: std::cmp::min(expires_at, last),
+            created_at,
+            absolute_expires_at: Some(absolute_expires_at.map_or(last, |a| std::cmp::min(a, last))),
+            data,
+        };
+        let token =
+            jsonwebtoken::encode(&Header::new(self.validation.algorithms[0]), &claims, key)?;
+
+        Ok(Session {
+            id: SessionId::try_from(token).map_err(|_| Error::TooLong)?,
+            ..claims.into()
+        })
+    }
+
+    /// The session in `token`, if it's signed by us, hasn't expired and hasn't been revoked.
+    async fn verify(&self, token: SessionId) -> Result<Session<U>, Error> {
+        let claims =
+            jsonwebtoken::decode::<Claims<U>>(&token, &self.decoding_key, &self.validation)
+                .map_err(|e| match e.kind() {
+                    ErrorKind::ExpiredSignature => Error::Expired,
+                    _ => Error::Jwt(e),
+                })?
+                .claims;
+
+        if self
+            .revocations
+            .is_revoked(
+                &claims.jti,
+                &Self::user_key(&claims.sub)?,
+                claims.created_at,
+            )
+            .await?
+        {
+            return Err(Error::Revoked(claims.jti));
+        }
+
+        Ok(Session {
+            id: token,
+            ..claims.into()
+        })
+    }
+}
+
+/// The session in the claims, under an empty id for the token to be filled in.
+impl<U> From<Claims<U>> for Session<U> {
+    fn from(claims: Claims<U>) -> Self {
+        Self {
+            id: SessionId(String::new()),
+            jti: claims.jti,
+            user_id: claims.sub,
+            data: claims.data,
+            created_at: claims.created_at,
+            expires_at: claims.exp,
+            absolute_expires_at: claims.absolute_expires_at,
+        }
+    }
+}
+
+#[async_trait]
+impl<U, R> super::SessionBackend for Backend<U, R>
+where
+    U: Serialize + DeserializeOwned + Send + Sync,
+    R: Revocations,
+{
+    type Error = Error;
+    type Session = Session<U>;
+    type UserId = U;
+
+    async fn new_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        self.issue(
+            id.to_string(),
+            user_id,
+            data,
+            Utc::now(),
+            expires_at,
+            absolute_expires_at,
+        )
+    }
+
+    async fn new_exclusive_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        let now = Utc::now();
+        self.revocations
+            .revoke_user(
+                &Self::user_key(&user_id)?,
+                now,
+                Some(&id),
+                self.max_lifetime,
+            )
+            .await?;
+        self.issue(
+            id.to_string(),
+            user_id,
+            data,
+            now,
+            expires_at,
+            absolute_expires_at,
+        )
+    }
+
+    /// Ignores `extend_expiry`, as the expiry can't change without a new token (see
+    /// [`SessionBackend::extend_expiry_date`](super::SessionBackend::extend_expiry_date)).
+    async fn session(
+        &self,
+        id: SessionId,
+        _extend_expiry: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        self.verify(id).await
+    }
+
+    async fn session_ttl(&self, id: SessionId) -> Result<Duration, Self::Error> {
+        Ok(self.verify(id).await?.expires_at - Utc::now())
+    }
+
+    async fn clear_stale_sessions(&self) -> Result<(), Self::Error> {
+        self.revocations.clear_stale().await
+    }
+
+    async fn expire(&self, session: Self::Session) -> Result<(), Self::Error> {
+        // Tokens with this `jti` expire by `max_lifetime` after its creation, whatever the
+        // expiry of this one.
+        self.revocations
+            .revoke(&session.jti, session.created_at + self.max_lifetime)
+            .await
+    }
+
+    /// Revokes the session's token, and issues one with the same claims but `new_id` as its
+    /// `jti`.
+    async fn regenerate_id(
+        &self,
+        session: Self::Session,
+        new_id: SessionId,
+    ) -> Result<Self::Session, Self::Error> {
+        let session = self.verify(session.id).await?;
+        self.revocations
+            .revoke(&session.jti, session.created_at + self.max_lifetime)
+            .await?;
+        self.issue(
+            new_id.to_string(),
+            session.user_id,
+            session.data,
+            session.created_at,
+            session.expires_at,
+            session.absolute_expires_at,
+        )
+    }
+
+    /// Revokes the user's tokens, but can't tell how many there were, so returns 0.
+    async fn expire_user_sessions(
+        &self,
+        user_id: Self::UserId,
+        keep: Option<SessionId>,
+    ) -> Result<u64, Self::Error> {
+        let keep = match keep {
+            Some(token) => Some(self.verify(token).await?.jti),
+            None => None,
+        };
+        self.revocations
+            .revoke_user(
+                &Self::user_key(&user_id)?,
+                Utc::now(),
+                keep.as_deref(),
+                self.max_lifetime,
+            )
+            .await?;
+        Ok(0)
+    }
+
+    /// Revokes the tokens, but can't tell how many there were, so returns 0.
+    async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, Self::Error> {
+        self.revocations
+            .revoke_created_before(cutoff, self.max_lifetime)
+            .await?;
+        Ok(0)
+    }
+
+    async fn sessions_for_user(
+        &self,
+        _user_id: Self::UserId,
+    ) -> Result<Vec<Self::Session>, Self::Error> {
+        Err(Error::Unsupported)
+    }
+
+    /// Issues a token with the same `jti` expiring at `expires_at`, which the client has to be
+    /// handed in place of the old one. The old one stays valid until it expires, and revoking
+    /// either revokes both.
+    async fn extend_expiry_date(
+        &self,
+        session: Self::Session,
+        expires_at: DateTime<Utc>,
+    ) -> Result<Self::Session, Self::Error> {
+        let session = self.verify(session.id).await?;
+        let expires_at = match session.absolute_expires_at {
+            Some(absolute) => std::cmp::min(expires_at, absolute),
+            None => expires_at,
+        };
+        self.issue(
+            session.jti,
+            session.user_id,
+            session.data,
+            session.created_at,
+            expires_at,
+            session.absolute_expires_at,
+        )
+    }
+
+    async fn generate_password_reset_id(
+        &self,
+        _user_id: Self::UserId,
+        _expires_at: DateTime<Utc>,
+    ) -> Result<PasswordResetId, Self::Error> {
+        Err(Error::Unsupported)
+    }
+
+    async fn consume_password_reset_id(
+        &self,
+        _password_reset_id: PasswordResetId,
+    ) -> Result<Self::UserId, Self::Error> {
+        Err(Error::Unsupported)
+    }
+
+    async fn verify_password_reset_id(
+        &self,
+        _password_reset_id: PasswordResetId,
+    ) -> Result<Self::UserId, Self::Error> {
+        Err(Error::Unsupported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    use super::*;
+    use crate::session::SessionBackend;
+
+    const SECRET: &[u8] = b"not a very secret secret";
+
+    fn manager() -> SessionManager<uuid::Uuid> {
+        SessionManager::new(
+            false,
+            Duration::minutes(5),
+            Backend::with_hmac_secret(SECRET),
+        )
+    }
+
+    /// A fresh RSA key pair in PEM, private key first, or `None` without the `openssl` command.
+    fn rsa_key_pair() -> Option<(Vec<u8>, Vec<u8>)> {
+        let private_key = Command::new("openssl")
+            .args([
+                "genpkey",
+                "-algorithm",
+                "RSA",
+                "-pkeyopt",
+                "rsa_keygen_bits:2048",
+            ])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        assert!(private_key.status.success());
+
+        let mut child = Command::new("openssl")
+            .args(["pkey", "-pubout"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&private_key.stdout)
+            .unwrap();
+        let public_key = child.wait_with_output().unwrap();
+        assert!(public_key.status.success());
+
+        Some((private_key.stdout, public_key.stdout))
+    }
+
+    #[test]
+    fn round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let user_id = uuid::Uuid::new_v4();
+            let data = serde_json::json!({ "csrf": "abc123" });
+            let issued = sessions
+                .new_session_with_data(user_id, data.clone())
+                .await
+                .unwrap();
+
+            let session = sessions.session(issued.id.clone()).await.unwrap();
+            assert_eq!(session.jti, issued.jti);
+            assert_eq!(session.user_id, user_id);
+            assert_eq!(session.data, data);
+            assert_eq!(session.created_at, issued.created_at);
+            assert_eq!(
+                session.expires_at.timestamp(),
+                issued.expires_at.timestamp()
+            );
+            assert!(sessions.session_ttl(issued.id.clone()).await.unwrap() > Duration::minutes(4));
+
+            let other = Backend::<uuid::Uuid>::with_hmac_secret(b"another secret");
+            assert!(matches!(
+                other.session(issued.id, None).await,
+                Err(Error::Jwt(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn expired_token() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let backend = Backend::<uuid::Uuid>::with_hmac_secret(SECRET);
+            let session = backend
+                .new_session(
+                    SessionId::new(),
+                    uuid::Uuid::new_v4(),
+                    Default::default(),
+                    Utc::now() - Duration::seconds(1),
+                    None,
+                )
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                backend.session(session.id, None).await,
+                Err(Error::Expired)
+            ));
+        });
+    }
+
+    #[test]
+    fn lifetime_is_capped() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = SessionManager::<uuid::Uuid>::new(
+                false,
+                Duration::days(30),
+                Backend::with_hmac_secret(SECRET).with_max_lifetime(Duration::hours(1)),
+            );
+            let session = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap();
+            let last = (session.created_at + Duration::hours(1)).timestamp();
+            assert_eq!(session.expires_at.timestamp(), last);
+
+            let extended = sessions.extend_expiry_date(session).await.unwrap();
+            assert_eq!(extended.expires_at.timestamp(), last);
+        });
+    }
+
+    #[test]
+    fn too_much_data() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let data = serde_json::json!({ "blob": "x".repeat(1024) });
+            assert!(matches!(
+                manager()
+                    .new_session_with_data(uuid::Uuid::new_v4(), data)
+                    .await,
+                Err(Error::TooLong)
+            ));
+        });
+    }
+
+    #[test]
+    fn tampered_signature() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let token = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap().id;
+
+            let (unsigned, signature) = token.rsplit_once('.').unwrap();
+            let mut signature = signature.as_bytes().to_vec();
+            signature[0] = if signature[0] == b'A' { b'B' } else { b'A' };
+            let tampered = format!("{}.{}", unsigned, String::from_utf8(signature).unwrap());
+
+            assert!(matches!(
+                sessions
+                    .session(SessionId::try_from(tampered).unwrap())
+                    .await,
+                Err(Error::Jwt(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn tampered_claims() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let token = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap().id;
+            let other = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap().id;
+
+            // Someone else's claims under this token's signature.
+            let parts = token.split('.').collect::<Vec<_>>();
+            let other_claims = other.split('.').nth(1).unwrap();
+            let tampered = format!("{}.{}.{}", parts[0], other_claims, parts[2]);
+
+            assert!(matches!(
+                sessions
+                    .session(SessionId::try_from(tampered).unwrap())
+                    .await,
+                Err(Error::Jwt(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn expire() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let session = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap();
+            let other = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            let (id, jti) = (session.id.clone(), session.jti.clone());
+            sessions.expire(session).await.unwrap();
+            assert!(
+                matches!(sessions.session(id.clone()).await, Err(Error::Revoked(x)) if x == jti)
+            );
+            assert!(sessions.session(other.id).await.is_ok());
+
+            sessions.clear_stale_sessions().await.unwrap();
+            assert!(sessions.session(id).await.is_err());
+        });
+    }
+
+    #[test]
+    fn regenerate_and_extend() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let old = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            let new = sessions.regenerate_id(old.clone()).await.unwrap();
+            assert_ne!(new.jti, old.jti);
+            assert_eq!(new.created_at, old.created_at);
+            assert!(matches!(
+                sessions.session(old.id).await,
+                Err(Error::Revoked(_))
+            ));
+
+            // A second apart, so that the new token differs from the old one.
+            std::thread::sleep(std::time::Duration::from_secs(1));
+            let extended = sessions.extend_expiry_date(new.clone()).await.unwrap();
+            assert_eq!(extended.jti, new.jti);
+            assert_ne!(extended.id, new.id);
+            assert!(extended.expires_at > new.expires_at);
+            assert!(sessions.session(extended.id.clone()).await.is_ok());
+
+            // Revoking the new token revokes the one it replaced.
+            sessions.expire(extended).await.unwrap();
+            assert!(sessions.session(new.id).await.is_err());
+        });
+    }
+
+    #[test]
+    fn expire_user_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            let user_id = uuid::Uuid::new_v4();
+            let current = sessions.new_session(user_id).await.unwrap();
+            let other = sessions.new_session(user_id).await.unwrap();
+            let someone_else = sessions.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            sessions
+                .expire_user_sessions(user_id, Some(current.id.clone()))
+                .await
+                .unwrap();
+            assert!(sessions.session(current.id.clone()).await.is_ok());
+            assert!(sessions.session(other.id).await.is_err());
+            assert!(sessions.session(someone_else.id.clone()).await.is_ok());
+
+            // Logging in again afterwards works, from the next millisecond on.
+            std::thread::sleep(std::time::Duration::from_millis(2));
+            let later = sessions.new_session(user_id).await.unwrap();
+            assert!(sessions.session(later.id.clone()).await.is_ok());
+
+            assert_eq!(
+                sessions.expire_user_sessions(user_id, None).await.unwrap(),
+                0
+            );
+            assert!(sessions.session(current.id).await.is_err());
+            assert!(sessions.session(later.id).await.is_err());
+
+            sessions.expire_created_before(Utc::now()).await.unwrap();
+            assert!(sessions.session(someone_else.id).await.is_err());
+        });
+    }
+
+    #[test]
+    fn single_session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager().with_single_session(true);
+            let user_id = uuid::Uuid::new_v4();
+            let first = sessions.new_session(user_id).await.unwrap();
+            let second = sessions.new_session(user_id).await.unwrap();
+
+            assert!(sessions.session(first.id).await.is_err());
+            assert!(sessions.session(second.id).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn unsupported() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let sessions = manager();
+            assert!(matches!(
+                sessions.sessions_for_user(uuid::Uuid::new_v4()).await,
+                Err(Error::Unsupported)
+            ));
+            assert!(matches!(
+                sessions
+                    .generate_password_reset_id(uuid::Uuid::new_v4(), Utc::now())
+                    .await,
+                Err(Error::Unsupported)
+            ));
+        });
+    }
+
+    #[test]
+    fn rsa() {
+        let (private_key, public_key) = match rsa_key_pair() {
+            Some(keys) => keys,
+            None => return,
+        };
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let expires_at = Utc::now() + Duration::minutes(5);
+            let backend = Backend::with_rsa_pem(&private_key, &public_key).unwrap();
+            let verifier = Backend::<uuid::Uuid>::verifier_with_rsa_pem(&public_key).unwrap();
+            let user_id = uuid::Uuid::new_v4();
+            let session = backend
+                .new_session(
+                    SessionId::new(),
+                    user_id,
+                    Default::default(),
+                    expires_at,
+                    None,
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(
+                verifier.session(session.id, None).await.unwrap().user_id,
+                user_id
+            );
+            assert!(matches!(
+                verifier
+                    .new_session(
+                        SessionId::new(),
+                        user_id,
+                        Default::default(),
+                        expires_at,
+                        None
+                    )
+                    .await,
+                Err(Error::CannotIssue)
+            ));
+
+            // An HMAC token signed with the public key mustn't pass as RS256.
+            let hmac = Backend::with_hmac_secret(&public_key);
+            let forged = hmac
+                .new_session(
+                    SessionId::new(),
+                    user_id,
+                    Default::default(),
+                    expires_at,
+                    None,
+                )
+                .await
+                .unwrap();
+            assert!(verifier.session(forged.id, None).await.is_err());
+        });
+    }
+
+    #[test]
+    fn redis_revocations() {
+        let pool = crate::util::test_db::redis_pool();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            // Two services verifying the same tokens.
+            let issuer = SessionManager::new(
+                false,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_hmac_secret(SECRET)
+                    .with_revocations(RedisRevocations::with_pool(pool.clone())),
+            );
+            let verifier = Backend::<uuid::Uuid>::with_hmac_secret(SECRET)
+                .with_revocations(RedisRevocations::with_pool(pool));
+            let user_id = uuid::Uuid::new_v4();
+
+            let session = issuer.new_session(user_id).await.unwrap();
+            let kept = issuer.new_session(user_id).await.unwrap();
+            let other = issuer.new_session(user_id).await.unwrap();
+            assert!(verifier.session(session.id.clone(), None).await.is_ok());
+
+            issuer.expire(session.clone()).await.unwrap();
+            assert!(matches!(
+                verifier.session(session.id, None).await,
+                Err(Error::Revoked(_))
+            ));
+
+            issuer
+                .expire_user_sessions(user_id, Some(kept.id.clone()))
+                .await
+                .unwrap();
+            assert!(verifier.session(kept.id, None).await.is_ok());
+            assert!(verifier.session(other.id, None).await.is_err());
+        });
+    }
+}
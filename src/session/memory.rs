@@ -3,7 +3,7 @@ use std::{collections::HashMap, sync::RwLock};
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
 
-use super::{PasswordResetId, SessionId};
+use super::{PasswordResetId, SessionId, SessionUser};
 
 pub type SessionManager<U> = super::SessionManager<Backend<U>, Session<U>, U, Error>;
 
@@ -11,47 +11,208 @@ pub type SessionManager<U> = super::SessionManager<Backend<U>, Session<U>, U, Er
 pub struct Session<U: Clone> {
     pub id: SessionId,
     pub user_id: U,
+    pub data: serde_json::Value,
+    /// Idle expiry, moved forward on access but never past `absolute_expires_at`.
     pub expires_at: DateTime<Utc>,
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    /// When the session was last read through
+    /// [`SessionBackend::session`](super::SessionBackend::session).
+    pub last_accessed_at: DateTime<Utc>,
+}
+
+impl<U: Clone> Session<U> {
+    fn capped(&self, expires_at: DateTime<Utc>) -> DateTime<Utc> {
+        match self.absolute_expires_at {
+            Some(absolute) => std::cmp::min(expires_at, absolute),
+            None => expires_at,
+        }
+    }
+}
+
+impl<U: Clone> SessionUser for Session<U> {
+    type UserId = U;
+
+    fn session_id(&self) -> &SessionId {
+        &self.id
+    }
+
+    fn user_id(&self) -> &U {
+        &self.user_id
+    }
 }
 
 #[derive(Debug)]
 pub struct Backend<U: Clone> {
     sessions: RwLock<HashMap<SessionId, Session<U>>>,
+    password_resets: RwLock<HashMap<PasswordResetId, (U, DateTime<Utc>)>>,
+    capacity: Option<usize>,
 }
 
 impl<U: Clone> Default for Backend<U> {
     fn default() -> Self {
         Self {
             sessions: RwLock::new(HashMap::new()),
+            password_resets: RwLock::new(HashMap::new()),
+            capacity: None,
         }
     }
 }
 
+impl<U: Clone> Backend<U> {
+    /// A backend holding at most `max` sessions. Creating one more evicts the session that was
+    /// accessed least recently (see [`Session::last_accessed_at`]), so that a process which
+    /// never clears stale sessions doesn't grow without bound.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    pub fn with_capacity(max: usize) -> Self {
+        assert!(max > 0, "session capacity must be positive");
+        Self {
+            sessions: RwLock::new(HashMap::with_capacity(max)),
+            password_resets: RwLock::new(HashMap::new()),
+            capacity: Some(max),
+        }
+    }
+
+    /// Inserts `session`, first evicting the least recently accessed session if full. Fails if
+    /// its id is taken.
+    fn insert(
+        &self,
+        sessions: &mut HashMap<SessionId, Session<U>>,
+        session: Session<U>,
+    ) -> Result<(), Error> {
+        if sessions.contains_key(&session.id) {
+            return Err(Error::IdTaken(session.id));
+        }
+        if let Some(capacity) = self.capacity {
+            // A linear scan is fine at the sizes an in-memory backend is meant for.
+            while sessions.len() >= capacity {
+                let lru = sessions
+                    .values()
+                    .min_by_key(|v| v.last_accessed_at)
+                    .map(|v| v.id.clone());
+                match lru {
+                    Some(id) => sessions.remove(&id),
+                    None => break,
+                };
+            }
+        }
+        sessions.insert(session.id.clone(), session);
+        Ok(())
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Session not found for given id {0}")]
     NotFound(SessionId),
+
+    /// The session existed but has expired. Expired sessions are forgotten once looking them up
+    /// has reported this, or once stale sessions are cleared, after which they are
+    /// [`Error::NotFound`].
+    #[error("Session {0} has expired")]
+    Expired(SessionId),
+
+    /// The [`SessionIdGenerator`](super::SessionIdGenerator) came up with the id of an existing
+    /// session.
+    #[error("Session id {0} is already taken")]
+    IdTaken(SessionId),
+
+    /// The password reset id is unknown, expired or already consumed.
+    #[error("Password reset not found for given id {0}")]
+    PasswordResetNotFound(PasswordResetId),
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
 }
 
 #[async_trait]
-impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
+impl<U: Clone + PartialEq + Send + Sync> super::SessionBackend for Backend<U> {
     type Error = Error;
     type Session = Session<U>;
     type UserId = U;
 
     async fn new_session(
         &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut guard = self.sessions.write().unwrap();
+        let now = Utc::now();
+        let session = Session {
+            id,
+            user_id,
+            data,
+            expires_at,
+            absolute_expires_at,
+            created_at: now,
+            last_accessed_at: now,
+        };
+        self.insert(&mut guard, session.clone())?;
+        Ok(session)
+    }
+
+    async fn new_exclusive_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut guard = self.sessions.write().unwrap();
+        guard.retain(|_, v| v.user_id != user_id);
+        let now = Utc::now();
+        let session = Session {
+            id,
+            user_id,
+            data,
+            expires_at,
+            absolute_expires_at,
+            created_at: now,
+            last_accessed_at: now,
+        };
+        self.insert(&mut guard, session.clone())?;
+        Ok(session)
+    }
+
+    async fn new_limited_session(
+        &self,
+        id: SessionId,
         user_id: Self::UserId,
+        data: serde_json::Value,
         expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+        max: usize,
     ) -> Result<Self::Session, Self::Error> {
         let mut guard = self.sessions.write().unwrap();
-        let id = SessionId::new();
+        let now = Utc::now();
         let session = Session {
             id,
             user_id,
+            data,
             expires_at,
+            absolute_expires_at,
+            created_at: now,
+            last_accessed_at: now,
         };
-        guard.insert(id, session.clone());
+        self.insert(&mut guard, session.clone())?;
+
+        let mut others = guard
+            .values()
+            .filter(|v| v.user_id == session.user_id && v.id != session.id && now < v.expires_at)
+            .map(|v| (v.created_at, v.id.clone()))
+            .collect::<Vec<_>>();
+        others.sort_by_key(|(created_at, _)| *created_at);
+        let excess = (others.len() + 1).saturating_sub(max);
+        for (_, id) in others.into_iter().take(excess) {
+            guard.remove(&id);
+        }
         Ok(session)
     }
 
@@ -61,28 +222,46 @@ impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
         extend_expiry: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error> {
         let mut guard = self.sessions.write().unwrap();
-        Ok(match guard.get(&id).cloned() {
+        Ok(match guard.get_mut(&id) {
             Some(v) => {
-                if Utc::now() < v.expires_at {
-                    v
+                let now = Utc::now();
+                if now < v.expires_at {
+                    if let Some(expiry) = extend_expiry {
+                        v.expires_at = v.capped(expiry);
+                    }
+                    v.last_accessed_at = now;
+                    v.clone()
                 } else {
                     // Remove because expired.
                     guard.remove(&id);
-                    return Err(Error::NotFound(id));
+                    return Err(Error::Expired(id));
                 }
             }
             None => return Err(Error::NotFound(id)),
         })
     }
 
+    async fn session_ttl(&self, id: SessionId) -> Result<chrono::Duration, Self::Error> {
+        let guard = self.sessions.read().unwrap();
+        let session = match guard.get(&id) {
+            Some(session) => session,
+            None => return Err(Error::NotFound(id)),
+        };
+        let ttl = session.expires_at - Utc::now();
+        if ttl <= chrono::Duration::zero() {
+            return Err(Error::Expired(id));
+        }
+        Ok(ttl)
+    }
+
     async fn clear_stale_sessions(&self) -> Result<(), Self::Error> {
         let keys = {
             let guard = self.sessions.read().unwrap();
             guard
                 .iter()
-                .filter(|(k, v)| Utc::now() >= v.expires_at)
+                .filter(|(_, v)| Utc::now() >= v.expires_at)
                 .map(|(k, _)| k)
-                .copied()
+                .cloned()
                 .collect::<Vec<_>>()
         };
 
@@ -91,6 +270,12 @@ impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
             guard.remove(&key);
         }
 
+        let now = Utc::now();
+        self.password_resets
+            .write()
+            .unwrap()
+            .retain(|_, (_, expires_at)| now < *expires_at);
+
         Ok(())
     }
 
@@ -100,6 +285,56 @@ impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
         Ok(())
     }
 
+    async fn regenerate_id(
+        &self,
+        session: Self::Session,
+        new_id: SessionId,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut guard = self.sessions.write().unwrap();
+        if guard.contains_key(&new_id) {
+            return Err(Error::IdTaken(new_id));
+        }
+        let mut session = guard
+            .remove(&session.id)
+            .ok_or(Error::NotFound(session.id))?;
+        session.id = new_id;
+        guard.insert(session.id.clone(), session.clone());
+        Ok(session)
+    }
+
+    async fn expire_user_sessions(
+        &self,
+        user_id: Self::UserId,
+        keep: Option<SessionId>,
+    ) -> Result<u64, Self::Error> {
+        let mut guard = self.sessions.write().unwrap();
+        let before = guard.len();
+        guard.retain(|id, v| v.user_id != user_id || Some(id) == keep.as_ref());
+        Ok((before - guard.len()) as u64)
+    }
+
+    async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, Self::Error> {
+        let mut guard = self.sessions.write().unwrap();
+        let before = guard.len();
+        guard.retain(|_, v| v.created_at >= cutoff);
+        Ok((before - guard.len()) as u64)
+    }
+
+    async fn sessions_for_user(
+        &self,
+        user_id: Self::UserId,
+    ) -> Result<Vec<Self::Session>, Self::Error> {
+        let guard = self.sessions.read().unwrap();
+        let now = Utc::now();
+        let mut sessions = guard
+            .values()
+            .filter(|v| v.user_id == user_id && now < v.expires_at)
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|v| v.created_at);
+        Ok(sessions)
+    }
+
     async fn extend_expiry_date(
         &self,
         session: Self::Session,
@@ -109,7 +344,7 @@ impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
         let session = guard
             .get_mut(&session.id)
             .ok_or_else(|| Error::NotFound(session.id))?;
-        session.expires_at = expires_at;
+        session.expires_at = session.capped(expires_at);
         Ok(session.clone())
     }
 
@@ -118,20 +353,33 @@ impl<U: Clone + Send + Sync> super::SessionBackend for Backend<U> {
         id: Self::UserId,
         expires_at: DateTime<Utc>,
     ) -> Result<PasswordResetId, Self::Error> {
-        todo!()
+        let password_reset_id = PasswordResetId::new();
+        self.password_resets
+            .write()
+            .unwrap()
+            .insert(password_reset_id, (id, expires_at));
+        Ok(password_reset_id)
     }
 
     async fn verify_password_reset_id(
         &self,
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
-        todo!()
+        let guard = self.password_resets.read().unwrap();
+        match guard.get(&id) {
+            Some((user_id, expires_at)) if Utc::now() < *expires_at => Ok(user_id.clone()),
+            _ => Err(Error::PasswordResetNotFound(id)),
+        }
     }
 
     async fn consume_password_reset_id(
         &self,
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
-        todo!()
+        // Removed even if expired, as it's of no use anymore.
+        match self.password_resets.write().unwrap().remove(&id) {
+            Some((user_id, expires_at)) if Utc::now() < expires_at => Ok(user_id),
+            _ => Err(Error::PasswordResetNotFound(id)),
+        }
     }
 }
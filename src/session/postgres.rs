@@ -1,39 +1,269 @@
-use std::marker::PhantomData;
+use std::{fmt::Debug, marker::PhantomData};
 
 use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use sqlx::{Connection, PgPool, Postgres};
 
-use super::{PasswordResetId, SessionId};
+use crate::util::ConnSource;
 
-pub type SessionManager<U> = super::SessionManager<Backend<U>, Session<U>, U, Error>;
+use super::{PasswordResetId, SessionId, SessionUser};
 
-pub struct Backend<U> {
+pub type SessionManager<U> = super::SessionManager<Backend<U>, Session<U>, U, Error<U>>;
+
+/// Postgres' SQLSTATE for foreign key violations.
+const FOREIGN_KEY_VIOLATION: &str = "23503";
+
+/// Postgres' SQLSTATE for unique violations.
+const UNIQUE_VIOLATION: &str = "23505";
+
+/// Postgres session backend, generic over where connections come from (see [`ConnSource`]).
+///
+/// Sessions and password reset ids reference their user through a foreign key, so none can
+/// outlive it. Declare it `ON DELETE CASCADE` (as [`crate::schema`] does) so that deleting a
+/// user reaps them, instead of failing while any are left.
+pub struct Backend<U, C = PgPool> {
+    pool: C,
+    table_name: &'static str,
+    password_reset_table_name: &'static str,
     _user_ty: PhantomData<U>,
 }
 
+impl<U, C> Backend<U, C> {
+    pub fn new(pool: C, table_name: &'static str) -> Self {
+        Self {
+            pool,
+            table_name,
+            password_reset_table_name: "password_resets",
+            _user_ty: PhantomData,
+        }
+    }
+
+    /// Table password reset ids are kept in. Defaults to `password_resets`.
+    pub fn with_password_reset_table(mut self, table_name: &'static str) -> Self {
+        self.password_reset_table_name = table_name;
+        self
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
-pub enum Error {}
+pub enum Error<U> {
+    #[cfg(feature = "deadpool")]
+    #[error("connection pool error: {0}")]
+    SqlxPool(#[from] deadpool::managed::PoolError<sqlx::Error>),
+
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error("User {0:?} does not exist.")]
+    UserNotFound(U),
+
+    #[error("Session not found for given id {0}")]
+    NotFound(SessionId),
+
+    /// The session existed but has expired. Expired sessions are deleted once looking them up
+    /// has reported this, or once stale sessions are cleared, after which they are
+    /// [`Error::NotFound`].
+    #[error("Session {0} has expired")]
+    Expired(SessionId),
+
+    /// The [`SessionIdGenerator`](super::SessionIdGenerator) came up with the id of an existing
+    /// session.
+    #[error("Session id {0} is already taken")]
+    IdTaken(SessionId),
+
+    #[error("Password reset not found for given id {0}")]
+    PasswordResetNotFound(PasswordResetId),
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
+}
+
+fn error_code(e: &sqlx::Error) -> Option<std::borrow::Cow<'_, str>> {
+    e.as_database_error().and_then(|e| e.code())
+}
+
+/// Tells a row referencing a missing user apart from other insert failures.
+fn insert_error<U>(user_id: U, e: sqlx::Error) -> Error<U> {
+    match error_code(&e).as_deref() {
+        Some(FOREIGN_KEY_VIOLATION) => Error::UserNotFound(user_id),
+        _ => Error::Sqlx(e),
+    }
+}
+
+/// Like [`insert_error`], also telling a session taking an existing session's id apart.
+fn insert_session_error<U>(user_id: U, id: &SessionId, e: sqlx::Error) -> Error<U> {
+    match error_code(&e).as_deref() {
+        Some(UNIQUE_VIOLATION) => Error::IdTaken(id.clone()),
+        _ => insert_error(user_id, e),
+    }
+}
 
 #[async_trait]
-impl<U: sqlx::Type<sqlx::Postgres> + Send + Sync> super::SessionBackend for Backend<U> {
-    type Error = Error;
+impl<U, C> super::SessionBackend for Backend<U, C>
+where
+    U: sqlx::Type<Postgres>
+        + for<'q> sqlx::Encode<'q, Postgres>
+        + for<'r> sqlx::Decode<'r, Postgres>
+        + Clone
+        + Debug
+        + Send
+        + Sync
+        + Unpin,
+    C: ConnSource,
+    Error<U>: From<C::Error>,
+{
+    type Error = Error<U>;
     type UserId = U;
     type Session = Session<Self::UserId>;
 
     async fn new_session(
         &self,
-        id: Self::UserId,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
         expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        let session = Session::new(id, user_id, data, expires_at, absolute_expires_at);
+        database::insert_session(&mut conn, &session, self.table_name)
+            .await
+            .map_err(|e| insert_session_error(session.user_id.clone(), &session.id, e))?;
+        Ok(session)
+    }
+
+    async fn new_exclusive_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let session = Session::new(id, user_id, data, expires_at, absolute_expires_at);
+
+        // Serializes logins of the same user, so that concurrent ones can't both survive.
+        database::lock_user_sessions(&mut tx, &session.user_id, self.table_name).await?;
+        database::delete_user_sessions(&mut tx, &session.user_id, self.table_name).await?;
+        database::insert_session(&mut tx, &session, self.table_name)
+            .await
+            .map_err(|e| insert_session_error(session.user_id.clone(), &session.id, e))?;
+        tx.commit().await?;
+
+        Ok(session)
+    }
+
+    async fn new_limited_session(
+        &self,
+        id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+        max: usize,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let session = Session::new(id, user_id, data, expires_at, absolute_expires_at);
+
+        // Serializes logins of the same user, so that concurrent ones can't overshoot `max`.
+        database::lock_user_sessions(&mut tx, &session.user_id, self.table_name).await?;
+        database::insert_session(&mut tx, &session, self.table_name)
+            .await
+            .map_err(|e| insert_session_error(session.user_id.clone(), &session.id, e))?;
+        database::delete_user_sessions_beyond(
+            &mut tx,
+            &session.user_id,
+            &session.id,
+            max - 1,
+            Utc::now(),
+            self.table_name,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(session)
+    }
+
+    async fn session_ttl(&self, id: SessionId) -> Result<chrono::Duration, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let expires_at = match database::session_expires_at(&mut conn, &id, self.table_name).await?
+        {
+            Some(expires_at) => expires_at,
+            None => return Err(Error::NotFound(id)),
+        };
+
+        let ttl = expires_at - Utc::now();
+        if ttl <= chrono::Duration::zero() {
+            return Err(Error::Expired(id));
+        }
+        Ok(ttl)
     }
 
     async fn clear_stale_sessions(&self) -> Result<(), Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        database::delete_sessions_expired_at(&mut conn, Utc::now(), self.table_name).await?;
+        database::delete_password_resets_expired_at(
+            &mut conn,
+            Utc::now(),
+            self.password_reset_table_name,
+        )
+        .await?;
+        Ok(())
     }
 
     async fn expire(&self, session: Self::Session) -> Result<(), Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        database::delete_session(&mut conn, &session.id, self.table_name).await?;
+        Ok(())
+    }
+
+    async fn regenerate_id(
+        &self,
+        session: Self::Session,
+        new_id: SessionId,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        database::change_session_id(&mut conn, &session.id, &new_id, self.table_name)
+            .await
+            .map_err(|e| match error_code(&e).as_deref() {
+                Some(UNIQUE_VIOLATION) => Error::IdTaken(new_id.clone()),
+                _ => Error::Sqlx(e),
+            })?
+            .ok_or(Error::NotFound(session.id))
+    }
+
+    async fn expire_user_sessions(
+        &self,
+        user_id: Self::UserId,
+        keep: Option<SessionId>,
+    ) -> Result<u64, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::delete_user_sessions_except(
+            &mut conn,
+            &user_id,
+            keep.as_ref(),
+            self.table_name,
+        )
+        .await?)
+    }
+
+    async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::delete_sessions_created_before(&mut conn, cutoff, self.table_name).await?)
+    }
+
+    async fn sessions_for_user(
+        &self,
+        user_id: Self::UserId,
+    ) -> Result<Vec<Self::Session>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(
+            database::user_sessions_alive_at(&mut conn, &user_id, Utc::now(), self.table_name)
+                .await?,
+        )
     }
 
     async fn extend_expiry_date(
@@ -41,7 +271,16 @@ impl<U: sqlx::Type<sqlx::Postgres> + Send + Sync> super::SessionBackend for Back
         session: Self::Session,
         expires_at: DateTime<Utc>,
     ) -> Result<Self::Session, Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        database::touch_session(
+            &mut conn,
+            &session.id,
+            Some(expires_at),
+            None,
+            self.table_name,
+        )
+        .await?
+        .ok_or(Error::NotFound(session.id))
     }
 
     async fn session(
@@ -49,7 +288,19 @@ impl<U: sqlx::Type<sqlx::Postgres> + Send + Sync> super::SessionBackend for Back
         id: SessionId,
         extend_expiry: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        let now = Utc::now();
+        match database::touch_session(&mut conn, &id, extend_expiry, Some(now), self.table_name)
+            .await?
+        {
+            Some(session) => Ok(session),
+            None => match database::delete_session_expired_at(&mut conn, &id, now, self.table_name)
+                .await?
+            {
+                true => Err(Error::Expired(id)),
+                false => Err(Error::NotFound(id)),
+            },
+        }
     }
 
     async fn generate_password_reset_id(
@@ -57,32 +308,985 @@ impl<U: sqlx::Type<sqlx::Postgres> + Send + Sync> super::SessionBackend for Back
         id: Self::UserId,
         expires_at: DateTime<Utc>,
     ) -> Result<PasswordResetId, Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        let password_reset_id = PasswordResetId::new();
+        database::insert_password_reset(
+            &mut conn,
+            password_reset_id,
+            &id,
+            expires_at,
+            self.password_reset_table_name,
+        )
+        .await
+        .map_err(|e| insert_error(id.clone(), e))?;
+        Ok(password_reset_id)
     }
 
     async fn verify_password_reset_id(
         &self,
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
-        todo!();
+        let mut conn = self.pool.acquire().await?;
+        database::password_reset_user(
+            &mut conn,
+            id,
+            Utc::now(),
+            false,
+            self.password_reset_table_name,
+        )
+        .await?
+        .ok_or(Error::PasswordResetNotFound(id))
     }
 
     async fn consume_password_reset_id(
         &self,
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
-        todo!()
+        let mut conn = self.pool.acquire().await?;
+        database::password_reset_user(
+            &mut conn,
+            id,
+            Utc::now(),
+            true,
+            self.password_reset_table_name,
+        )
+        .await?
+        .ok_or(Error::PasswordResetNotFound(id))
     }
 }
 
 pub struct Session<U: sqlx::Type<sqlx::Postgres>> {
-    id: SessionId,
-    user_id: U,
-    data: serde_json::Value,
-    expires_at: chrono::DateTime<chrono::Utc>,
+    pub id: SessionId,
+    pub user_id: U,
+    pub data: serde_json::Value,
+    pub expires_at: DateTime<Utc>,
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl<U: sqlx::Type<sqlx::Postgres>> Session<U> {
+    fn new(
+        id: SessionId,
+        user_id: U,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            id,
+            user_id,
+            data,
+            expires_at,
+            absolute_expires_at,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+impl<U: sqlx::Type<sqlx::Postgres>> SessionUser for Session<U> {
+    type UserId = U;
+
+    fn session_id(&self) -> &SessionId {
+        &self.id
+    }
+
+    fn user_id(&self) -> &U {
+        &self.user_id
+    }
+}
+
+mod database {
+    use std::convert::TryFrom;
+
+    use chrono::{DateTime, Utc};
+    use sqlx::{postgres::PgRow, PgConnection, Postgres, Row};
+
+    use super::{PasswordResetId, Session, SessionId};
+
+    const COLUMNS: &str = "id, user_id, data, expires_at, absolute_expires_at, created_at";
+
+    fn session_from_row<U>(r: &PgRow) -> Session<U>
+    where
+        U: sqlx::Type<Postgres> + for<'r> sqlx::Decode<'r, Postgres>,
+    {
+        Session {
+            id: SessionId(r.get(0)),
+            user_id: r.get(1),
+            data: r.get(2),
+            expires_at: r.get(3),
+            absolute_expires_at: r.get(4),
+            created_at: r.get(5),
+        }
+    }
+
+    pub async fn insert_session<U>(
+        conn: &mut PgConnection,
+        session: &Session<U>,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        sqlx::query(&format!(
+            r#"
+                INSERT INTO {}(id, user_id, data, expires_at, absolute_expires_at, created_at)
+                VALUES ($1, $2, $3, $4, $5, $6)
+            "#,
+            table_name
+        ))
+        .bind(session.id.as_str())
+        .bind(session.user_id.clone())
+        .bind(&session.data)
+        .bind(session.expires_at)
+        .bind(session.absolute_expires_at)
+        .bind(session.created_at)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Takes a transaction-scoped lock on the sessions of a user.
+    pub async fn lock_user_sessions<U>(
+        conn: &mut PgConnection,
+        user_id: &U,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1 || '/' || $2::text))")
+            .bind(table_name)
+            .bind(user_id.clone())
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_user_sessions<U>(
+        conn: &mut PgConnection,
+        user_id: &U,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE user_id = $1", table_name))
+            .bind(user_id.clone())
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Moves the expiry of a session to `expires_at` (capped by its absolute expiry) if given,
+    /// and returns it. With `alive_at`, sessions that have expired by then are left alone.
+    pub async fn touch_session<U>(
+        conn: &mut PgConnection,
+        id: &SessionId,
+        expires_at: Option<DateTime<Utc>>,
+        alive_at: Option<DateTime<Utc>>,
+        table_name: &'static str,
+    ) -> Result<Option<Session<U>>, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'r> sqlx::Decode<'r, Postgres>,
+    {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {0} SET expires_at = CASE
+                    WHEN $2::TIMESTAMPTZ IS NULL THEN expires_at
+                    ELSE LEAST($2, COALESCE(absolute_expires_at, $2))
+                END
+                WHERE id = $1 AND ($3::TIMESTAMPTZ IS NULL OR expires_at > $3)
+                RETURNING {1}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(id.as_str())
+        .bind(expires_at)
+        .bind(alive_at)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.as_ref().map(session_from_row))
+    }
+
+    pub async fn change_session_id<U>(
+        conn: &mut PgConnection,
+        id: &SessionId,
+        new_id: &SessionId,
+        table_name: &'static str,
+    ) -> Result<Option<Session<U>>, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'r> sqlx::Decode<'r, Postgres>,
+    {
+        let r = sqlx::query(&format!(
+            "UPDATE {} SET id = $2 WHERE id = $1 RETURNING {}",
+            table_name, COLUMNS
+        ))
+        .bind(id.as_str())
+        .bind(new_id.as_str())
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.as_ref().map(session_from_row))
+    }
+
+    pub async fn session_expires_at(
+        conn: &mut PgConnection,
+        id: &SessionId,
+        table_name: &'static str,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            "SELECT expires_at FROM {} WHERE id = $1",
+            table_name
+        ))
+        .bind(id.as_str())
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(r.map(|r| r.get(0)))
+    }
+
+    pub async fn delete_session(
+        conn: &mut PgConnection,
+        id: &SessionId,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!("DELETE FROM {} WHERE id = $1", table_name))
+            .bind(id.as_str())
+            .execute(conn)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the session if it has expired by `now`, returning whether it did.
+    pub async fn delete_session_expired_at(
+        conn: &mut PgConnection,
+        id: &SessionId,
+        now: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!(
+            "DELETE FROM {} WHERE id = $1 AND expires_at <= $2",
+            table_name
+        ))
+        .bind(id.as_str())
+        .bind(now)
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn user_sessions_alive_at<U>(
+        conn: &mut PgConnection,
+        user_id: &U,
+        now: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<Vec<Session<U>>, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres>
+            + for<'q> sqlx::Encode<'q, Postgres>
+            + for<'r> sqlx::Decode<'r, Postgres>
+            + Clone
+            + Send,
+    {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT {}
+                FROM {}
+                WHERE user_id = $1 AND expires_at > $2
+                ORDER BY created_at
+            "#,
+            COLUMNS, table_name
+        ))
+        .bind(user_id.clone())
+        .bind(now)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows.iter().map(session_from_row).collect())
+    }
+
+    pub async fn delete_user_sessions_except<U>(
+        conn: &mut PgConnection,
+        user_id: &U,
+        keep: Option<&SessionId>,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        let result = sqlx::query(&format!(
+            "DELETE FROM {} WHERE user_id = $1 AND ($2::TEXT IS NULL OR id <> $2)",
+            table_name
+        ))
+        .bind(user_id.clone())
+        .bind(keep.map(|id| id.as_str()))
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Deletes the live sessions of the user other than `keep`, apart from the `remaining`
+    /// newest.
+    pub async fn delete_user_sessions_beyond<U>(
+        conn: &mut PgConnection,
+        user_id: &U,
+        keep: &SessionId,
+        remaining: usize,
+        now: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        let result = sqlx::query(&format!(
+            "DELETE FROM {table} WHERE id IN (
+                SELECT id FROM {table}
+                WHERE user_id = $1 AND id <> $2 AND expires_at > $4
+                ORDER BY created_at DESC, id DESC
+                OFFSET $3
+            )",
+            table = table_name
+        ))
+        .bind(user_id.clone())
+        .bind(keep.as_str())
+        .bind(i64::try_from(remaining).unwrap_or(i64::MAX))
+        .bind(now)
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_sessions_expired_at(
+        conn: &mut PgConnection,
+        now: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(&format!(
+            "DELETE FROM {} WHERE expires_at <= $1",
+            table_name
+        ))
+        .bind(now)
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn insert_password_reset<U>(
+        conn: &mut PgConnection,
+        id: PasswordResetId,
+        user_id: &U,
+        expires_at: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'q> sqlx::Encode<'q, Postgres> + Clone + Send,
+    {
+        sqlx::query(&format!(
+            "INSERT INTO {}(id, user_id, expires_at) VALUES ($1, $2, $3)",
+            table_name
+        ))
+        .bind(*id)
+        .bind(user_id.clone())
+        .bind(expires_at)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The user of a password reset id that is still valid at `now`, deleting the id if
+    /// `consume` is set.
+    pub async fn password_reset_user<U>(
+        conn: &mut PgConnection,
+        id: PasswordResetId,
+        now: DateTime<Utc>,
+        consume: bool,
+        table_name: &'static str,
+    ) -> Result<Option<U>, sqlx::Error>
+    where
+        U: sqlx::Type<Postgres> + for<'r> sqlx::Decode<'r, Postgres> + Send + Unpin,
+    {
+        let query = match consume {
+            true => format!(
+                "DELETE FROM {} WHERE id = $1 AND expires_at > $2 RETURNING user_id",
+                table_name
+            ),
+            false => format!(
+                "SELECT user_id FROM {} WHERE id = $1 AND expires_at > $2",
+                table_name
+            ),
+        };
+
+        let r = sqlx::query(&query)
+            .bind(*id)
+            .bind(now)
+            .fetch_optional(conn)
+            .await?;
+
+        Ok(r.map(|r| r.get(0)))
+    }
+
+    pub async fn delete_password_resets_expired_at(
+        conn: &mut PgConnection,
+        now: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(&format!(
+            "DELETE FROM {} WHERE expires_at <= $1",
+            table_name
+        ))
+        .bind(now)
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_sessions_created_before(
+        conn: &mut PgConnection,
+        cutoff: DateTime<Utc>,
+        table_name: &'static str,
+    ) -> Result<u64, sqlx::Error> {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE created_at < $1", table_name))
+            .bind(cutoff)
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
 }
 
-struct NewSession<U: sqlx::Type<sqlx::Postgres>> {
-    id: SessionId,
-    user_id: U,
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::{Duration, Utc};
+    use serde_json::json;
+    use sqlx::Row;
+
+    use super::{insert_error, Backend, Error, SessionId, SessionManager};
+    use crate::session::SessionBackend;
+
+    #[derive(Debug)]
+    struct ForeignKeyViolation;
+
+    impl std::fmt::Display for ForeignKeyViolation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(sqlx::error::DatabaseError::message(self))
+        }
+    }
+
+    impl std::error::Error for ForeignKeyViolation {}
+
+    impl sqlx::error::DatabaseError for ForeignKeyViolation {
+        fn message(&self) -> &str {
+            r#"insert or update on table "sessions" violates foreign key constraint "sessions_user_id_fkey""#
+        }
+
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some("23503".into())
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+    }
+
+    #[test]
+    fn session_for_missing_user() {
+        let user_id = uuid::Uuid::new_v4();
+        let err = insert_error(
+            user_id,
+            sqlx::Error::Database(Box::new(ForeignKeyViolation)),
+        );
+
+        assert!(matches!(err, Error::UserNotFound(id) if id == user_id));
+        assert!(matches!(
+            insert_error(user_id, sqlx::Error::RowNotFound),
+            Error::Sqlx(_)
+        ));
+    }
+
+    async fn insert_user(pool: &sqlx::PgPool, username: &str) -> uuid::Uuid {
+        sqlx::query("INSERT INTO users(username, password_hash) VALUES ($1, '') RETURNING id")
+            .bind(username)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .get(0)
+    }
+
+    #[test]
+    fn session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+
+            let session = handler.new_session(user_id).await.unwrap();
+            let found = handler.session(session.id.clone()).await.unwrap();
+            assert_eq!(found.id, session.id);
+            assert_eq!(found.user_id, user_id);
+            assert!(found.expires_at >= session.expires_at);
+
+            handler.expire(found).await.unwrap();
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(Error::NotFound(id)) if id == session.id
+            ));
+            assert!(matches!(
+                handler.new_session(uuid::Uuid::new_v4()).await,
+                Err(Error::UserNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn taken_session_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let backend = Backend::new(pool, "sessions");
+            let expires_at = Utc::now() + Duration::minutes(5);
+            let id = SessionId::try_from("a-much-longer-opaque-token").unwrap();
+
+            let session = backend
+                .new_session(id.clone(), user_id, json!({}), expires_at, None)
+                .await
+                .unwrap();
+            assert!(matches!(
+                backend
+                    .new_session(id.clone(), user_id, json!({}), expires_at, None)
+                    .await,
+                Err(Error::IdTaken(taken)) if taken == id
+            ));
+
+            let other = backend
+                .new_session(SessionId::new(), user_id, json!({}), expires_at, None)
+                .await
+                .unwrap();
+            assert!(matches!(
+                backend.regenerate_id(other, id.clone()).await,
+                Err(Error::IdTaken(_))
+            ));
+            assert_eq!(backend.session(id, None).await.unwrap().id, session.id);
+        });
+    }
+
+    #[test]
+    fn session_data() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+            let data = serde_json::json!({ "csrf": "abc123", "roles": ["admin"] });
+
+            let session = handler
+                .new_session_with_data(user_id, data.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                handler.session(session.id.clone()).await.unwrap().data,
+                data
+            );
+        });
+    }
+
+    #[test]
+    fn expired_session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler = SessionManager::new(
+                false,
+                Duration::milliseconds(1),
+                Backend::new(pool, "sessions"),
+            );
+
+            let session = handler.new_session(user_id).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(Error::Expired(id)) if id == session.id
+            ));
+
+            // Once reported, the session is gone for good.
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(Error::NotFound(_))
+            ));
+            assert!(matches!(
+                handler.session(SessionId::new()).await,
+                Err(Error::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn session_ttl() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+            let session = handler.new_session(user_id).await.unwrap();
+
+            let ttl = handler.session_ttl(session.id.clone()).await.unwrap();
+            assert!(ttl <= Duration::minutes(5));
+            assert!(ttl > Duration::minutes(5) - Duration::seconds(5));
+
+            assert!(matches!(
+                handler.session_ttl(SessionId::new()).await,
+                Err(Error::NotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn session_extends_expiry() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler = SessionManager::new(
+                true,
+                Duration::milliseconds(300),
+                Backend::new(pool, "sessions"),
+            )
+            .with_absolute_lifetime(Duration::milliseconds(600));
+            let session = handler.new_session(user_id).await.unwrap();
+
+            // Kept alive by activity past the idle timeout...
+            for _ in 0..3 {
+                tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+                assert!(handler.session(session.id.clone()).await.is_ok());
+            }
+
+            // ...but not past the absolute lifetime.
+            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
+            assert!(matches!(
+                handler.session(session.id.clone()).await,
+                Err(Error::Expired(id)) if id == session.id
+            ));
+        });
+    }
+
+    #[test]
+    fn single_session() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler = std::sync::Arc::new(
+                SessionManager::new(true, Duration::seconds(5), Backend::new(pool, "sessions"))
+                    .with_single_session(true),
+            );
+
+            let first = tokio::spawn({
+                let handler = handler.clone();
+                async move { handler.new_session(user_id).await.unwrap() }
+            });
+            let second = tokio::spawn({
+                let handler = handler.clone();
+                async move { handler.new_session(user_id).await.unwrap() }
+            });
+            let (first, second) = (first.await.unwrap(), second.await.unwrap());
+
+            let first_alive = handler.session(first.id.clone()).await.is_ok();
+            let second_alive = handler.session(second.id.clone()).await.is_ok();
+            assert!(first_alive ^ second_alive);
+        });
+    }
+
+    #[test]
+    fn max_sessions_per_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler = std::sync::Arc::new(
+                SessionManager::new(true, Duration::seconds(5), Backend::new(pool, "sessions"))
+                    .with_max_sessions_per_user(3),
+            );
+
+            let logins = (0..8)
+                .map(|_| {
+                    let handler = handler.clone();
+                    tokio::spawn(async move { handler.new_session(user_id).await.unwrap() })
+                })
+                .collect::<Vec<_>>();
+            let mut newest = None;
+            for login in logins {
+                newest = Some(login.await.unwrap());
+            }
+            assert_eq!(handler.sessions_for_user(user_id).await.unwrap().len(), 3);
+
+            // Sequential logins keep the newest ones, including the one just created.
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+            }
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, sessions.into_iter().map(|s| s.id).collect::<Vec<_>>());
+            assert!(handler.session(newest.unwrap().id).await.is_err());
+        });
+    }
+
+    #[test]
+    fn expire_user_sessions_keeps_current() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let other_user_id = insert_user(&pool, "bob").await;
+            let handler =
+                SessionManager::new(true, Duration::seconds(5), Backend::new(pool, "sessions"));
+            let current = handler.new_session(user_id).await.unwrap();
+            let other = handler.new_session(user_id).await.unwrap();
+            let someone_else = handler.new_session(other_user_id).await.unwrap();
+
+            handler
+                .expire_user_sessions(user_id, Some(current.id.clone()))
+                .await
+                .unwrap();
+
+            assert!(handler.session(current.id.clone()).await.is_ok());
+            assert!(handler.session(other.id.clone()).await.is_err());
+            assert!(handler.session(someone_else.id.clone()).await.is_ok());
+
+            handler.expire_user_sessions(user_id, None).await.unwrap();
+            assert!(handler.session(current.id.clone()).await.is_err());
+        });
+    }
+
+    #[test]
+    fn sessions_for_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let other_user_id = insert_user(&pool, "bob").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+            let first = handler.new_session(user_id).await.unwrap();
+            let second = handler.new_session(user_id).await.unwrap();
+            handler.new_session(other_user_id).await.unwrap();
+
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|s| s.id.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![first.id, second.id]);
+        });
+    }
+
+    #[test]
+    fn regenerate_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+            let data = serde_json::json!({ "csrf": "abc123" });
+            let old = handler
+                .new_session_with_data(user_id, data.clone())
+                .await
+                .unwrap();
+            let old_id = old.id.clone();
+
+            let new = handler.regenerate_id(old).await.unwrap();
+            assert_ne!(new.id, old_id);
+            assert!(matches!(
+                handler.session(old_id).await,
+                Err(Error::NotFound(_))
+            ));
+            let found = handler.session(new.id.clone()).await.unwrap();
+            assert_eq!(found.user_id, user_id);
+            assert_eq!(found.data, data);
+        });
+    }
+
+    #[test]
+    fn expire_all_user_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let other_user_id = insert_user(&pool, "bob").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+            }
+            let someone_else = handler.new_session(other_user_id).await.unwrap();
+
+            assert_eq!(
+                handler.expire_user_sessions(user_id, None).await.unwrap(),
+                3
+            );
+            for session in sessions {
+                assert!(matches!(
+                    handler.session(session.id.clone()).await,
+                    Err(Error::NotFound(_))
+                ));
+            }
+            assert!(handler.session(someone_else.id.clone()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn clear_stale_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let short = SessionManager::new(
+                false,
+                Duration::milliseconds(1),
+                Backend::new(pool.clone(), "sessions"),
+            );
+            let long = SessionManager::new(
+                false,
+                Duration::minutes(5),
+                Backend::new(pool.clone(), "sessions"),
+            );
+            short.new_session(user_id).await.unwrap();
+            short
+                .generate_password_reset_id(user_id, Utc::now() + Duration::milliseconds(1))
+                .await
+                .unwrap();
+            let alive = long.new_session(user_id).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+
+            long.clear_stale_sessions().await.unwrap();
+            let remaining: Vec<String> = sqlx::query("SELECT id FROM sessions")
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|r| r.get(0))
+                .collect();
+            assert_eq!(remaining, vec![alive.id.to_string()]);
+            let resets: i64 = sqlx::query("SELECT count(*) FROM password_resets")
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get(0);
+            assert_eq!(resets, 0);
+        });
+    }
+
+    #[test]
+    fn password_reset() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler =
+                SessionManager::new(true, Duration::minutes(5), Backend::new(pool, "sessions"));
+
+            let id = handler
+                .generate_password_reset_id(user_id, Utc::now() + Duration::minutes(5))
+                .await
+                .unwrap();
+            assert_eq!(handler.verify_password_reset_id(id).await.unwrap(), user_id);
+            assert_eq!(
+                handler.consume_password_reset_id(id).await.unwrap(),
+                user_id
+            );
+            assert!(matches!(
+                handler.consume_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler.verify_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+
+            let expired = handler
+                .generate_password_reset_id(user_id, Utc::now() + Duration::milliseconds(1))
+                .await
+                .unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            assert!(matches!(
+                handler.verify_password_reset_id(expired).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler
+                    .generate_password_reset_id(
+                        uuid::Uuid::new_v4(),
+                        Utc::now() + Duration::minutes(5)
+                    )
+                    .await,
+                Err(Error::UserNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn expire_created_before() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let user_id = insert_user(&pool, "alice").await;
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::new(pool.clone(), "sessions"),
+            );
+
+            handler.new_session(user_id).await.unwrap();
+            handler.new_session(user_id).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let cutoff = Utc::now();
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            let new = handler.new_session(user_id).await.unwrap();
+
+            assert_eq!(handler.expire_created_before(cutoff).await.unwrap(), 2);
+            let remaining: Vec<String> = sqlx::query("SELECT id FROM sessions")
+                .fetch_all(&pool)
+                .await
+                .unwrap()
+                .iter()
+                .map(|r| r.get(0))
+                .collect();
+            assert_eq!(remaining, vec![new.id.to_string()]);
+        });
+    }
 }
@@ -1,14 +1,177 @@
-use std::marker::PhantomData;
+use std::{convert::TryFrom, marker::PhantomData};
 
 use async_trait::async_trait;
-use chrono::{DateTime, Duration, Utc};
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use deadpool_redis::{Config, Runtime};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
-use super::{PasswordResetId, SessionId};
+use super::{PasswordResetId, SessionId, SessionUser};
 
 pub type SessionManager<U> = super::SessionManager<Backend<U>, Session<U>, U, Error>;
 
+/// Stores a new session (KEYS[1]) unless its id is taken, and adds it to the user's index
+/// (KEYS[2]), dropping the ids of sessions that have expired from it. Returns 0 if the id is
+/// taken.
+const NEW_SESSION: &str = r#"
+    if not redis.call("SET", KEYS[1], ARGV[1], "NX", "EXAT", ARGV[2]) then
+        return 0
+    end
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[2])) do
+        if redis.call("EXISTS", "session/" .. id) == 0 then
+            redis.call("SREM", KEYS[2], id)
+        end
+    end
+    redis.call("SADD", KEYS[2], ARGV[3])
+    return 1
+"#;
+
+/// Like [`NEW_SESSION`], then expires the oldest of the user's other sessions beyond ARGV[4] in
+/// total. Runs as a script so that concurrent logins can't overshoot the limit.
+///
+/// `created_at` is RFC 3339 in UTC with a varying number of fractional digits, so the fraction
+/// is padded to nanoseconds to make it sort as text. Sessions whose data can't be decoded sort
+/// first.
+const NEW_LIMITED_SESSION: &str = r#"
+    if not redis.call("SET", KEYS[1], ARGV[1], "NX", "EXAT", ARGV[2]) then
+        return 0
+    end
+    local others = {}
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[2])) do
+        local data = redis.call("GET", "session/" .. id)
+        if not data then
+            redis.call("SREM", KEYS[2], id)
+        elseif id ~= ARGV[3] then
+            local ok, decoded = pcall(cjson.decode, data)
+            local created = ok and type(decoded) == "table" and decoded["created_at"]
+            if type(created) ~= "string" then
+                created = ""
+            end
+            local base, fraction = string.match(created, "^([^.Z+]*)%.?(%d*)")
+            table.insert(others, {base .. fraction .. string.rep("0", 9 - #fraction), id})
+        end
+    end
+    table.sort(others, function(a, b) return a[1] < b[1] end)
+    for i = 1, #others - (tonumber(ARGV[4]) - 1) do
+        redis.call("DEL", "session/" .. others[i][2])
+        redis.call("SREM", KEYS[2], others[i][2])
+    end
+    redis.call("SADD", KEYS[2], ARGV[3])
+    return 1
+"#;
+
+/// Expires every session in the user's index (KEYS[1]) and stores the new session (KEYS[2]).
+/// Runs as a script so that concurrent logins can't interleave. Returns 0 without expiring
+/// anything if the new session's id is taken.
+const NEW_EXCLUSIVE_SESSION: &str = r#"
+    if redis.call("EXISTS", KEYS[2]) == 1 then
+        return 0
+    end
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[1])) do
+        redis.call("DEL", "session/" .. id)
+    end
+    redis.call("DEL", KEYS[1])
+    redis.call("SET", KEYS[2], ARGV[1], "EXAT", ARGV[2])
+    redis.call("SADD", KEYS[1], ARGV[3])
+    return 1
+"#;
+
+/// Reads a session (KEYS[1]), setting its `last_accessed_at` to ARGV[1], and returns its data
+/// with its TTL in milliseconds. Unless ARGV[2] is empty, first moves its expiry there, capped at the absolute
+/// expiry stored in its data.
+///
+/// The data is patched as text rather than re-encoded, as cjson would mangle e.g. empty arrays
+/// and large numbers. `last_accessed_at` is the last field serde writes, or missing in data
+/// stored before it was tracked.
+const READ_SESSION: &str = r#"
+    local data = redis.call("GET", KEYS[1])
+    if not data then
+        return false
+    end
+    if ARGV[2] ~= "" then
+        local expiry = tonumber(ARGV[2])
+        local absolute = cjson.decode(data)["absolute_expires_at"]
+        if type(absolute) == "number" and absolute < expiry then
+            expiry = absolute
+        end
+        redis.call("EXPIREAT", KEYS[1], expiry)
+    end
+    local ttl = redis.call("PTTL", KEYS[1])
+    if ttl < 0 then
+        return {data, ttl}
+    end
+    local field = '"last_accessed_at":"' .. ARGV[1] .. '"}'
+    local touched, found = string.gsub(data, '"last_accessed_at":"[^"]*"}$', field)
+    if found == 0 then
+        touched = string.sub(data, 1, -2) .. "," .. field
+    end
+    redis.call("SET", KEYS[1], touched, "KEEPTTL")
+    return {touched, ttl}
+"#;
+
+/// Moves a session (KEYS[1]) to a new key (KEYS[2]), keeping its TTL, and swaps its id ARGV[1]
+/// for ARGV[2] in the user's index (KEYS[3]). Returns 0 if the session doesn't exist, and -1 if
+/// the new id is taken.
+const REGENERATE_ID: &str = r#"
+    if redis.call("EXISTS", KEYS[1]) == 0 then
+        return 0
+    end
+    if redis.call("EXISTS", KEYS[2]) == 1 then
+        return -1
+    end
+    redis.call("RENAME", KEYS[1], KEYS[2])
+    redis.call("SREM", KEYS[3], ARGV[1])
+    redis.call("SADD", KEYS[3], ARGV[2])
+    return 1
+"#;
+
+/// Expires every session in the user's index (KEYS[1]) except the one with id ARGV[1],
+/// returning how many sessions were still alive.
+const EXPIRE_USER_SESSIONS: &str = r#"
+    local expired = 0
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[1])) do
+        if id ~= ARGV[1] then
+            expired = expired + redis.call("DEL", "session/" .. id)
+            redis.call("SREM", KEYS[1], id)
+        end
+    end
+    return expired
+"#;
+
+/// Returns `{id, data, ttl}`, with the TTL in milliseconds, of every session in the user's index (KEYS[1]), dropping the ids
+/// of sessions that have expired from the index.
+const USER_SESSIONS: &str = r#"
+    local sessions = {}
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[1])) do
+        local key = "session/" .. id
+        local data = redis.call("GET", key)
+        if data then
+            table.insert(sessions, {id, data, redis.call("PTTL", key)})
+        else
+            redis.call("SREM", KEYS[1], id)
+        end
+    end
+    return sessions
+"#;
+
+/// Drops the ids of sessions that have expired from a user's index (KEYS[1]), which Redis
+/// deletes once it is empty.
+const PRUNE_USER_SESSIONS: &str = r#"
+    for _, id in ipairs(redis.call("SMEMBERS", KEYS[1])) do
+        if redis.call("EXISTS", "session/" .. id) == 0 then
+            redis.call("SREM", KEYS[1], id)
+        end
+    end
+"#;
+
+/// Number of keys asked for per SCAN call when walking all sessions.
+const SCAN_BATCH: usize = 1000;
+
+/// Key of the set indexing the ids of all sessions belonging to a user.
+fn user_sessions_key<U: Serialize>(user_id: &U) -> Result<String, serde_json::Error> {
+    let user_id = serde_json::to_string(user_id)?;
+    Ok(format!("user/{}/sessions", user_id.trim_matches('"')))
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session<U: Clone> {
     pub id: SessionId,
@@ -16,9 +179,45 @@ pub struct Session<U: Clone> {
     pub expires_at: DateTime<Utc>,
 }
 
+impl<U: Clone> SessionUser for Session<U> {
+    type UserId = U;
+
+    fn session_id(&self) -> &SessionId {
+        &self.id
+    }
+
+    fn user_id(&self) -> &U {
+        &self.data.user_id
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SessionData<U> {
     pub user_id: U,
+    /// Whatever the application keeps in the session, e.g. a CSRF token.
+    #[serde(default = "empty_object")]
+    pub data: serde_json::Value,
+    /// Stored as a timestamp, so that [`READ_SESSION`] can read it.
+    #[serde(default, with = "chrono::serde::ts_seconds_option")]
+    pub absolute_expires_at: Option<DateTime<Utc>>,
+    /// Sessions stored before this was tracked count as created at the epoch, so that
+    /// [`SessionBackend::expire_created_before`](super::SessionBackend::expire_created_before)
+    /// always sweeps them.
+    #[serde(default = "unix_epoch")]
+    pub created_at: DateTime<Utc>,
+    /// When the session was last read through
+    /// [`SessionBackend::session`](super::SessionBackend::session). Like `created_at`, the
+    /// epoch for sessions stored before this was tracked.
+    #[serde(default = "unix_epoch")]
+    pub last_accessed_at: DateTime<Utc>,
+}
+
+fn empty_object() -> serde_json::Value {
+    serde_json::Value::Object(Default::default())
+}
+
+fn unix_epoch() -> DateTime<Utc> {
+    Utc.timestamp_opt(0, 0).unwrap()
 }
 
 pub struct Backend<U: Clone> {
@@ -55,8 +254,67 @@ pub enum Error {
     #[error("Json parsing error")]
     Json(#[from] serde_json::Error),
 
+    #[error("Stored data for session {id} could not be decoded")]
+    DecodeSession {
+        id: SessionId,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    #[error("Stored data for password reset {id} could not be decoded")]
+    DecodePasswordReset {
+        id: PasswordResetId,
+        #[source]
+        source: serde_json::Error,
+    },
+
+    /// Redis drops expired sessions by itself, so unlike the memory backend this can't tell an
+    /// expired session from one that never existed. Both are reported as not found.
     #[error("Session not found for given id {0}")]
     NotFound(SessionId),
+
+    /// The [`SessionIdGenerator`](super::SessionIdGenerator) came up with the id of an existing
+    /// session.
+    #[error("Session id {0} is already taken")]
+    IdTaken(SessionId),
+
+    /// The password reset id is unknown, expired or already consumed.
+    #[error("Password reset not found for given id {0}")]
+    PasswordResetNotFound(PasswordResetId),
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
+}
+
+/// One step of walking the keys matching `pattern` with SCAN rather than KEYS, so that Redis
+/// isn't blocked while the keyspace is walked. Returns the next cursor, which is 0 once done.
+async fn scan_keys(
+    conn: &mut deadpool_redis::Connection,
+    pattern: &str,
+    cursor: u64,
+) -> Result<(u64, Vec<String>), redis::RedisError> {
+    redis::cmd("SCAN")
+        .arg(cursor)
+        .arg("MATCH")
+        .arg(pattern)
+        .arg("COUNT")
+        .arg(SCAN_BATCH)
+        .query_async(conn)
+        .await
+}
+
+fn decode_session_data<U: DeserializeOwned>(
+    id: &SessionId,
+    raw: &str,
+) -> Result<SessionData<U>, Error> {
+    serde_json::from_str(raw).map_err(|source| Error::DecodeSession {
+        id: id.clone(),
+        source,
+    })
+}
+
+fn decode_password_reset<U: DeserializeOwned>(id: PasswordResetId, raw: &str) -> Result<U, Error> {
+    serde_json::from_str(raw).map_err(|source| Error::DecodePasswordReset { id, source })
 }
 
 #[async_trait]
@@ -70,23 +328,111 @@ where
 
     async fn new_session(
         &self,
+        session_id: SessionId,
         user_id: Self::UserId,
+        data: serde_json::Value,
         expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
     ) -> Result<Self::Session, Self::Error> {
         let mut conn = self.pool.get().await?;
-        let session_id = SessionId::new();
+        let now = Utc::now();
         let session = Session {
             id: session_id,
-            data: SessionData { user_id },
+            data: SessionData {
+                user_id,
+                data,
+                absolute_expires_at,
+                created_at: now,
+                last_accessed_at: now,
+            },
             expires_at,
         };
-        redis::cmd("SET")
-            .arg(format!("session/{}", session_id))
-            .arg(serde_json::to_string(&session.data).unwrap())
-            .arg("EXAT")
+        let created: i64 = redis::Script::new(NEW_SESSION)
+            .key(format!("session/{}", session.id))
+            .key(user_sessions_key(&session.data.user_id)?)
+            .arg(serde_json::to_string(&session.data)?)
             .arg(expires_at.timestamp())
-            .query_async(&mut conn)
+            .arg(session.id.as_str())
+            .invoke_async(&mut conn)
+            .await?;
+
+        if created == 0 {
+            return Err(Error::IdTaken(session.id));
+        }
+        Ok(session)
+    }
+
+    async fn new_limited_session(
+        &self,
+        session_id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+        max: usize,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let now = Utc::now();
+        let session = Session {
+            id: session_id,
+            data: SessionData {
+                user_id,
+                data,
+                absolute_expires_at,
+                created_at: now,
+                last_accessed_at: now,
+            },
+            expires_at,
+        };
+        let created: i64 = redis::Script::new(NEW_LIMITED_SESSION)
+            .key(format!("session/{}", session.id))
+            .key(user_sessions_key(&session.data.user_id)?)
+            .arg(serde_json::to_string(&session.data)?)
+            .arg(expires_at.timestamp())
+            .arg(session.id.as_str())
+            .arg(max)
+            .invoke_async(&mut conn)
             .await?;
+
+        if created == 0 {
+            return Err(Error::IdTaken(session.id));
+        }
+        Ok(session)
+    }
+
+    async fn new_exclusive_session(
+        &self,
+        session_id: SessionId,
+        user_id: Self::UserId,
+        data: serde_json::Value,
+        expires_at: DateTime<Utc>,
+        absolute_expires_at: Option<DateTime<Utc>>,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let now = Utc::now();
+        let session = Session {
+            id: session_id,
+            data: SessionData {
+                user_id,
+                data,
+                absolute_expires_at,
+                created_at: now,
+                last_accessed_at: now,
+            },
+            expires_at,
+        };
+        let created: i64 = redis::Script::new(NEW_EXCLUSIVE_SESSION)
+            .key(user_sessions_key(&session.data.user_id)?)
+            .key(format!("session/{}", session.id))
+            .arg(serde_json::to_string(&session.data)?)
+            .arg(expires_at.timestamp())
+            .arg(session.id.as_str())
+            .invoke_async(&mut conn)
+            .await?;
+
+        if created == 0 {
+            return Err(Error::IdTaken(session.id));
+        }
         Ok(session)
     }
 
@@ -97,58 +443,236 @@ where
     ) -> Result<Self::Session, Self::Error> {
         let mut conn = self.pool.get().await?;
 
-        // TODO: handle NotFound properly. Right now it's hidden in a RedisError.
-
-        let (session_data, ttl): (String, i64) = match extend_expiry {
-            Some(expiry) => {
-                redis::pipe()
-                    .atomic()
-                    .cmd("GETEX")
-                    .arg(format!("session/{}", id))
-                    .arg("EXAT")
-                    .arg(expiry.timestamp())
-                    .cmd("TTL")
-                    .arg(format!("session/{}", id))
-                    .query_async(&mut conn)
-                    .await?
-            }
-            None => {
-                redis::pipe()
-                    .atomic()
-                    .cmd("GET")
-                    .arg(format!("session/{}", id))
-                    .cmd("TTL")
-                    .arg(format!("session/{}", id))
-                    .query_async(&mut conn)
-                    .await?
-            }
+        let now = Utc::now();
+        let found: Option<(String, i64)> = redis::Script::new(READ_SESSION)
+            .key(format!("session/{}", id))
+            .arg(serde_json::to_value(now)?.as_str().unwrap_or_default())
+            .arg(
+                extend_expiry
+                    .map(|expiry| expiry.timestamp().to_string())
+                    .unwrap_or_default(),
+            )
+            .invoke_async(&mut conn)
+            .await?;
+
+        let (session_data, ttl) = match found {
+            Some(found) => found,
+            None => return Err(Error::NotFound(id)),
         };
 
-        let data = serde_json::from_str(&session_data)?;
+        // The absolute expiry may have been reached just now.
+        if ttl < 0 {
+            return Err(Error::NotFound(id));
+        }
+
+        let data = decode_session_data::<U>(&id, &session_data)?;
 
         let session = Session {
             id,
             data,
-            expires_at: Utc::now() + Duration::seconds(ttl),
+            expires_at: Utc::now() + Duration::milliseconds(ttl),
         };
 
         Ok(session)
     }
 
+    async fn session_ttl(&self, id: SessionId) -> Result<Duration, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let ttl: i64 = redis::cmd("PTTL")
+            .arg(format!("session/{}", id))
+            .query_async(&mut conn)
+            .await?;
+
+        // -2 means the key doesn't exist, -1 that it has no expiry, which is never the case
+        // for sessions stored by this backend.
+        match ttl {
+            ttl if ttl < 0 => Err(Error::NotFound(id)),
+            ttl => Ok(Duration::milliseconds(ttl)),
+        }
+    }
+
+    /// Redis drops sessions by itself once they expire, so this only removes those that can't
+    /// expire, i.e. whose key has lost its TTL (e.g. through a `PERSIST` or a restore). It also
+    /// drops the ids of expired sessions from the users' indexes, which only happens otherwise
+    /// when a user logs in again or their sessions are listed.
     async fn clear_stale_sessions(&self) -> Result<(), Self::Error> {
-        // Not really supported by Redis, does it itself.
+        let mut conn = self.pool.get().await?;
+        let mut cursor = 0u64;
+
+        loop {
+            let (next, keys) = scan_keys(&mut conn, "session/*", cursor).await?;
+
+            if !keys.is_empty() {
+                let mut pipe = redis::pipe();
+                for key in &keys {
+                    pipe.cmd("TTL").arg(key);
+                }
+                let ttls: Vec<i64> = pipe.query_async(&mut conn).await?;
+
+                let mut pipe = redis::pipe();
+                for (key, ttl) in keys.iter().zip(ttls) {
+                    // -2 means the key is already gone.
+                    if ttl == -1 {
+                        pipe.cmd("DEL").arg(key).ignore();
+                    }
+                }
+                pipe.query_async::<_, ()>(&mut conn).await?;
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        let prune = redis::Script::new(PRUNE_USER_SESSIONS);
+        loop {
+            let (next, keys) = scan_keys(&mut conn, "user/*/sessions", cursor).await?;
+            for key in keys {
+                prune.key(key).invoke_async::<_, ()>(&mut conn).await?;
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
         Ok(())
     }
 
     async fn expire(&self, session: Self::Session) -> Result<(), Self::Error> {
         let mut conn = self.pool.get().await?;
-        redis::cmd("DEL")
+        redis::pipe()
+            .atomic()
+            .cmd("DEL")
             .arg(format!("session/{}", session.id))
-            .query_async(&mut conn)
+            .ignore()
+            .cmd("SREM")
+            .arg(user_sessions_key(&session.data.user_id)?)
+            .arg(session.id.to_string())
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
             .await?;
         Ok(())
     }
 
+    async fn regenerate_id(
+        &self,
+        session: Self::Session,
+        new_id: SessionId,
+    ) -> Result<Self::Session, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let moved: i64 = redis::Script::new(REGENERATE_ID)
+            .key(format!("session/{}", session.id))
+            .key(format!("session/{}", new_id))
+            .key(user_sessions_key(&session.data.user_id)?)
+            .arg(session.id.as_str())
+            .arg(new_id.as_str())
+            .invoke_async(&mut conn)
+            .await?;
+
+        match moved {
+            0 => Err(Error::NotFound(session.id)),
+            -1 => Err(Error::IdTaken(new_id)),
+            _ => self.session(new_id, None).await,
+        }
+    }
+
+    async fn expire_user_sessions(
+        &self,
+        user_id: Self::UserId,
+        keep: Option<SessionId>,
+    ) -> Result<u64, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        Ok(redis::Script::new(EXPIRE_USER_SESSIONS)
+            .key(user_sessions_key(&user_id)?)
+            .arg(keep.map(|id| id.to_string()).unwrap_or_default())
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    async fn expire_created_before(&self, cutoff: DateTime<Utc>) -> Result<u64, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let mut cursor = 0u64;
+        let mut expired = 0;
+
+        loop {
+            let (next, keys) = scan_keys(&mut conn, "session/*", cursor).await?;
+
+            if !keys.is_empty() {
+                let values: Vec<Option<String>> =
+                    redis::cmd("MGET").arg(&keys).query_async(&mut conn).await?;
+
+                let mut pipe = redis::pipe();
+                for (key, value) in keys.iter().zip(values) {
+                    let id = match key.strip_prefix("session/").map(SessionId::try_from) {
+                        Some(Ok(id)) => id,
+                        _ => continue,
+                    };
+                    // Gone since it was scanned.
+                    let value = match value {
+                        Some(value) => value,
+                        None => continue,
+                    };
+
+                    // Left alone rather than failing the whole sweep. It can't be read as a
+                    // session anyway, so it's as good as expired.
+                    let data = match decode_session_data::<U>(&id, &value) {
+                        Ok(data) => data,
+                        Err(_) => continue,
+                    };
+                    if data.created_at < cutoff {
+                        pipe.cmd("DEL").arg(key);
+                        pipe.cmd("SREM")
+                            .arg(user_sessions_key(&data.user_id)?)
+                            .arg(id.as_str())
+                            .ignore();
+                    }
+                }
+
+                let deleted: Vec<u64> = pipe.query_async(&mut conn).await?;
+                expired += deleted.iter().sum::<u64>();
+            }
+
+            cursor = next;
+            if cursor == 0 {
+                break;
+            }
+        }
+
+        Ok(expired)
+    }
+
+    async fn sessions_for_user(
+        &self,
+        user_id: Self::UserId,
+    ) -> Result<Vec<Self::Session>, Self::Error> {
+        let mut conn = self.pool.get().await?;
+        let found: Vec<(String, String, i64)> = redis::Script::new(USER_SESSIONS)
+            .key(user_sessions_key(&user_id)?)
+            .invoke_async(&mut conn)
+            .await?;
+
+        let now = Utc::now();
+        let mut sessions = Vec::with_capacity(found.len());
+        for (id, data, ttl) in found {
+            // Ids are only ever added by this backend.
+            let id = match SessionId::try_from(id.as_str()) {
+                Ok(id) => id,
+                Err(_) => continue,
+            };
+            sessions.push(Session {
+                data: decode_session_data(&id, &data)?,
+                id,
+                expires_at: now + Duration::milliseconds(ttl),
+            });
+        }
+        sessions.sort_by_key(|s| s.data.created_at);
+
+        Ok(sessions)
+    }
+
     async fn extend_expiry_date(
         &self,
         session: Self::Session,
@@ -167,7 +691,7 @@ where
 
         redis::cmd("SET")
             .arg(format!("password-reset/{}", &*password_reset_id))
-            .arg(serde_json::to_string(&id).unwrap())
+            .arg(serde_json::to_string(&id)?)
             .arg("EXAT")
             .arg(expires_at.timestamp())
             .query_async(&mut conn)
@@ -181,11 +705,11 @@ where
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
         let mut conn = self.pool.get().await?;
-        let result: String = redis::cmd("GET")
+        let result: Option<String> = redis::cmd("GET")
             .arg(format!("password-reset/{}", &*id))
             .query_async(&mut conn)
             .await?;
-        Ok(serde_json::from_str(&result)?)
+        decode_password_reset(id, &result.ok_or(Error::PasswordResetNotFound(id))?)
     }
 
     async fn consume_password_reset_id(
@@ -193,10 +717,474 @@ where
         id: PasswordResetId,
     ) -> Result<Self::UserId, Self::Error> {
         let mut conn = self.pool.get().await?;
-        let result: String = redis::cmd("GETDEL")
+        // Read and deleted in one step, so that concurrent requests can't both use the id.
+        let result: Option<String> = redis::cmd("GETDEL")
             .arg(format!("password-reset/{}", &*id))
             .query_async(&mut conn)
             .await?;
-        Ok(serde_json::from_str(&result)?)
+        decode_password_reset(id, &result.ok_or(Error::PasswordResetNotFound(id))?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::SessionBackend;
+
+    #[test]
+    fn corrupt_session_data_names_session() {
+        let id = SessionId::new();
+        let err = decode_session_data::<uuid::Uuid>(&id, "{not json").unwrap_err();
+
+        assert!(matches!(err, Error::DecodeSession { id: ref err_id, .. } if *err_id == id));
+        assert!(err.to_string().contains(&id.to_string()));
+    }
+
+    #[test]
+    fn sessions_for_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            let user_id = uuid::Uuid::new_v4();
+            let first = handler.new_session(user_id).await.unwrap();
+            let second = handler.new_session(user_id).await.unwrap();
+            handler.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|s| s.id.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![first.id.clone(), second.id.clone()]);
+
+            handler.expire(first).await.unwrap();
+            let sessions = handler.sessions_for_user(user_id).await.unwrap();
+            assert_eq!(sessions.len(), 1);
+            assert_eq!(sessions[0].id, second.id);
+        });
+    }
+
+    #[test]
+    fn max_sessions_per_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = std::sync::Arc::new(
+                SessionManager::new(
+                    true,
+                    Duration::minutes(5),
+                    Backend::<uuid::Uuid>::with_pool(pool),
+                )
+                .with_max_sessions_per_user(3),
+            );
+            let user_id = uuid::Uuid::new_v4();
+
+            let logins = (0..8)
+                .map(|_| {
+                    let handler = handler.clone();
+                    tokio::spawn(async move { handler.new_session(user_id).await.unwrap() })
+                })
+                .collect::<Vec<_>>();
+            for login in logins {
+                login.await.unwrap();
+            }
+            assert_eq!(handler.sessions_for_user(user_id).await.unwrap().len(), 3);
+
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+            }
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|s| s.id)
+                .collect::<Vec<_>>();
+            assert_eq!(ids, sessions.into_iter().map(|s| s.id).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn user_index_drops_expired_sessions() {
+        async fn members(conn: &mut deadpool_redis::Connection, index: &str) -> Vec<String> {
+            redis::cmd("SMEMBERS")
+                .arg(index)
+                .query_async(conn)
+                .await
+                .unwrap()
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let backend = Backend::<uuid::Uuid>::with_pool(pool.clone());
+            let user_id = uuid::Uuid::new_v4();
+            let index = user_sessions_key(&user_id).unwrap();
+            let mut conn = pool.get().await.unwrap();
+
+            let mut ids = Vec::new();
+            for _ in 0..2 {
+                let session = backend
+                    .new_session(
+                        SessionId::new(),
+                        user_id,
+                        serde_json::json!({}),
+                        Utc::now() + Duration::seconds(1),
+                        None,
+                    )
+                    .await
+                    .unwrap();
+                // Only the new session is left once the previous one expired.
+                assert_eq!(
+                    members(&mut conn, &index).await,
+                    vec![session.id.to_string()]
+                );
+                ids.push(session.id);
+                std::thread::sleep(std::time::Duration::from_secs(2));
+            }
+            assert_eq!(members(&mut conn, &index).await, vec![ids[1].to_string()]);
+
+            // Clearing stale sessions drops it too, leaving no index behind.
+            backend.clear_stale_sessions().await.unwrap();
+            let exists: bool = redis::cmd("EXISTS")
+                .arg(&index)
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            assert!(!exists);
+        });
+    }
+
+    #[test]
+    fn expire_created_before_skips_undecodable_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let backend = Backend::<uuid::Uuid>::with_pool(pool.clone());
+            let mut conn = pool.get().await.unwrap();
+
+            // Created at the epoch, like sessions stored before `created_at` was tracked, so
+            // that the sweep below leaves the sessions of other tests sharing the Redis alone.
+            let old = SessionId::new();
+            let corrupt = SessionId::new();
+            redis::pipe()
+                .cmd("SET")
+                .arg(format!("session/{}", old))
+                .arg(serde_json::json!({ "user_id": uuid::Uuid::new_v4() }).to_string())
+                .arg("EX")
+                .arg(60)
+                .cmd("SET")
+                .arg(format!("session/{}", corrupt))
+                .arg("{not json")
+                .arg("EX")
+                .arg(60)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .unwrap();
+            let current = backend
+                .new_session(
+                    SessionId::new(),
+                    uuid::Uuid::new_v4(),
+                    serde_json::json!({}),
+                    Utc::now() + Duration::minutes(1),
+                    None,
+                )
+                .await
+                .unwrap();
+
+            let cutoff = Utc.timestamp_opt(60, 0).unwrap();
+            assert_eq!(backend.expire_created_before(cutoff).await.unwrap(), 1);
+            assert!(matches!(
+                backend.session(old, None).await,
+                Err(Error::NotFound(_))
+            ));
+            assert!(matches!(
+                backend.session(corrupt, None).await,
+                Err(Error::DecodeSession { .. })
+            ));
+            assert!(backend.session(current.id, None).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn session_data_without_data_is_empty() {
+        let data = decode_session_data::<uuid::Uuid>(
+            &SessionId::new(),
+            r#"{"user_id":"7d4e9e2c-3f4a-4b7e-9c1d-2a5b6c7d8e9f","created_at":"2023-01-01T00:00:00Z"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(data.data, serde_json::json!({}));
+    }
+
+    #[test]
+    fn session_data_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            // Empty arrays and large numbers included, which re-encoding in Lua would mangle.
+            let data = serde_json::json!({
+                "csrf": "abc123",
+                "roles": ["admin"],
+                "flags": [],
+                "big": 9_007_199_254_740_993u64,
+            });
+
+            let session = handler
+                .new_session_with_data(uuid::Uuid::new_v4(), data.clone())
+                .await
+                .unwrap();
+            assert_eq!(
+                handler.session(session.id.clone()).await.unwrap().data.data,
+                data
+            );
+        });
+    }
+
+    #[test]
+    fn unknown_session_is_not_found() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let backend = Backend::<uuid::Uuid>::with_pool(pool);
+            let id = SessionId::new();
+
+            for extend_expiry in [None, Some(Utc::now() + Duration::minutes(5))] {
+                assert!(matches!(
+                    backend.session(id.clone(), extend_expiry).await,
+                    Err(Error::NotFound(err_id)) if err_id == id
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn last_accessed_at_advances() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool.clone()),
+            );
+            let session = handler.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            let first = handler.session(session.id.clone()).await.unwrap();
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            let second = handler.session(session.id.clone()).await.unwrap();
+            assert!(second.data.last_accessed_at > first.data.last_accessed_at);
+
+            // Stored, not just reported.
+            let mut conn = pool.get().await.unwrap();
+            let stored: String = redis::cmd("GET")
+                .arg(format!("session/{}", session.id))
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            let stored = decode_session_data::<uuid::Uuid>(&session.id, &stored).unwrap();
+            assert_eq!(stored.last_accessed_at, second.data.last_accessed_at);
+
+            // Also for sessions stored before it was tracked.
+            let legacy = SessionId::new();
+            redis::cmd("SET")
+                .arg(format!("session/{}", legacy))
+                .arg(serde_json::json!({ "user_id": uuid::Uuid::new_v4() }).to_string())
+                .arg("EX")
+                .arg(60)
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .unwrap();
+            let before = Utc::now();
+            let read = handler.session(legacy.clone()).await.unwrap();
+            assert!(read.data.last_accessed_at >= before);
+            let stored: String = redis::cmd("GET")
+                .arg(format!("session/{}", legacy))
+                .query_async(&mut conn)
+                .await
+                .unwrap();
+            let stored = decode_session_data::<uuid::Uuid>(&legacy, &stored).unwrap();
+            assert_eq!(stored.last_accessed_at, read.data.last_accessed_at);
+        });
+    }
+
+    #[test]
+    fn clear_stale_sessions_removes_keys_without_ttl() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool.clone()),
+            );
+            let stale = handler.new_session(uuid::Uuid::new_v4()).await.unwrap();
+            let alive = handler.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+            let mut conn = pool.get().await.unwrap();
+            redis::cmd("PERSIST")
+                .arg(format!("session/{}", stale.id))
+                .query_async::<_, ()>(&mut conn)
+                .await
+                .unwrap();
+
+            handler.clear_stale_sessions().await.unwrap();
+            assert!(matches!(
+                handler.session(stale.id.clone()).await,
+                Err(Error::NotFound(_))
+            ));
+            assert!(handler.session(alive.id.clone()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn regenerate_id() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            let user_id = uuid::Uuid::new_v4();
+            let old = handler.new_session(user_id).await.unwrap();
+            let old_id = old.id.clone();
+
+            let new = handler.regenerate_id(old).await.unwrap();
+            assert_ne!(new.id, old_id);
+            assert!(matches!(
+                handler.session(old_id).await,
+                Err(Error::NotFound(_))
+            ));
+            assert_eq!(
+                handler.session(new.id.clone()).await.unwrap().data.user_id,
+                user_id
+            );
+
+            let ids = handler
+                .sessions_for_user(user_id)
+                .await
+                .unwrap()
+                .iter()
+                .map(|s| s.id.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(ids, vec![new.id]);
+        });
+    }
+
+    #[test]
+    fn expire_all_user_sessions() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            let user_id = uuid::Uuid::new_v4();
+            let mut sessions = Vec::new();
+            for _ in 0..3 {
+                sessions.push(handler.new_session(user_id).await.unwrap());
+            }
+
+            assert_eq!(
+                handler.expire_user_sessions(user_id, None).await.unwrap(),
+                3
+            );
+            for session in sessions {
+                assert!(matches!(
+                    handler.session(session.id.clone()).await,
+                    Err(Error::NotFound(_))
+                ));
+            }
+            assert_eq!(
+                handler.expire_user_sessions(user_id, None).await.unwrap(),
+                0
+            );
+        });
+    }
+
+    #[test]
+    fn password_reset_is_single_use() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            let user_id = uuid::Uuid::new_v4();
+            let id = handler
+                .generate_password_reset_id(user_id, Utc::now() + Duration::minutes(5))
+                .await
+                .unwrap();
+
+            assert_eq!(
+                handler.consume_password_reset_id(id).await.unwrap(),
+                user_id
+            );
+            assert!(matches!(
+                handler.consume_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(err_id)) if err_id == id
+            ));
+            assert!(matches!(
+                handler.verify_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn expired_password_reset_is_not_found() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::redis_pool();
+            let handler = SessionManager::new(
+                true,
+                Duration::minutes(5),
+                Backend::<uuid::Uuid>::with_pool(pool),
+            );
+            // EXAT has a resolution of seconds.
+            let id = handler
+                .generate_password_reset_id(uuid::Uuid::new_v4(), Utc::now() + Duration::seconds(1))
+                .await
+                .unwrap();
+            assert!(handler.verify_password_reset_id(id).await.is_ok());
+
+            tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+            assert!(matches!(
+                handler.verify_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+            assert!(matches!(
+                handler.consume_password_reset_id(id).await,
+                Err(Error::PasswordResetNotFound(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn corrupt_password_reset_names_reset_id() {
+        let id = PasswordResetId::new();
+        let err = decode_password_reset::<uuid::Uuid>(id, "\"not a uuid\"").unwrap_err();
+
+        assert!(matches!(err, Error::DecodePasswordReset { id: err_id, .. } if err_id == id));
+        assert!(err.to_string().contains(&id.to_string()));
     }
 }
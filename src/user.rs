@@ -1,7 +1,11 @@
 pub(crate) mod postgres;
+pub mod reservation;
+#[cfg(feature = "sqlite")]
+pub(crate) mod sqlite;
 
 use async_trait::async_trait;
-use secrecy::Secret;
+use chrono::{DateTime, Utc};
+use secrecy::{ExposeSecret, Secret};
 
 use crate::{
     password_strategy::Strategy,
@@ -9,48 +13,122 @@ use crate::{
     username::{Username, UsernameType},
 };
 
+use self::reservation::ReservationToken;
+
 #[nova::newtype(serde, sqlx, copy, new)]
 pub type UserId = uuid::Uuid;
 
-pub type PgUsers<S, U> = postgres::Backend<S, U>;
+pub type PgUsers<S, U> = postgres::Backend<S, U, sqlx::PgPool>;
 
 #[cfg(feature = "deadpool")]
 pub type DeadpoolPgUsers<S, U> = postgres::DeadpoolBackend<S, U>;
 
-#[derive(Debug)]
+#[cfg(feature = "sqlite")]
+pub type SqliteUsers<S, U> = sqlite::Backend<S, U>;
+
+/// Stored in place of a password hash for users without a password, see [`NewUser::invited`].
+/// No strategy generates it, so it never verifies.
+pub(crate) const NO_PASSWORD_HASH: &str = "!";
+
+/// Merges the keys of `patch` into `meta` like Postgres' `jsonb || jsonb` does for objects.
+/// Anything but two objects is replaced by `patch`.
+pub(crate) fn merge_meta(meta: serde_json::Value, patch: serde_json::Value) -> serde_json::Value {
+    match (meta, patch) {
+        (serde_json::Value::Object(mut meta), serde_json::Value::Object(patch)) => {
+            meta.extend(patch);
+            serde_json::Value::Object(meta)
+        }
+        (_, patch) => patch,
+    }
+}
+
 pub struct NewUser<U: UsernameType> {
     pub username: Username<U>,
-    pub password: Secret<String>,
+    /// `None` for an invited user, who can't log in until [`UserBackend::set_initial_password`].
+    pub password: Option<Secret<String>>,
     pub meta: serde_json::Value,
     pub id: Option<UserId>,
+    /// Reservation held for this username, consumed when the user is created.
+    pub reservation: Option<ReservationToken>,
+}
+
+// Meta may hold personal data, so it's kept out of logs along with the password.
+impl<U: UsernameType + std::fmt::Debug> std::fmt::Debug for NewUser<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewUser")
+            .field("username", &self.username)
+            .field("password", &self.password)
+            .field("meta", &"[REDACTED]")
+            .field("id", &self.id)
+            .field("reservation", &self.reservation)
+            .finish()
+    }
 }
 
 impl<U: UsernameType> NewUser<U> {
     pub fn new(username: &str, password: &str) -> Result<Self, U::Err> {
         Ok(Self {
             username: username.parse()?,
-            password: Secret::new(password.to_string()),
+            password: Some(Secret::new(password.to_string())),
             meta: Default::default(),
             id: None,
+            reservation: None,
         })
     }
 
     pub fn with_id(id: UserId, username: &str, password: &str) -> Result<Self, U::Err> {
         Ok(Self {
             username: username.parse()?,
-            password: Secret::new(password.to_string()),
+            password: Some(Secret::new(password.to_string())),
             meta: Default::default(),
             id: Some(id),
+            reservation: None,
         })
     }
+
+    /// A user without a password, who sets their own when claiming the account through
+    /// [`UserBackend::set_initial_password`].
+    pub fn invited(username: &str) -> Result<Self, U::Err> {
+        Ok(Self {
+            username: username.parse()?,
+            password: None,
+            meta: Default::default(),
+            id: None,
+            reservation: None,
+        })
+    }
+
+    pub fn with_reservation(mut self, token: ReservationToken) -> Self {
+        self.reservation = Some(token);
+        self
+    }
 }
 
-#[derive(Debug)]
 pub struct User<U: UsernameType> {
     pub id: UserId,
     pub username: Username<U>,
     pub password_hash: Secret<String>,
     pub meta: serde_json::Value,
+    /// Bumped on every update, for optimistic concurrency control (see
+    /// [`UserBackend::update_meta`]).
+    pub version: i32,
+    pub created_at: DateTime<Utc>,
+    /// When the user was last updated, along with [`User::version`].
+    pub updated_at: DateTime<Utc>,
+}
+
+impl<U: UsernameType + std::fmt::Debug> std::fmt::Debug for User<U> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("User")
+            .field("id", &self.id)
+            .field("username", &self.username)
+            .field("password_hash", &self.password_hash)
+            .field("meta", &"[REDACTED]")
+            .field("version", &self.version)
+            .field("created_at", &self.created_at)
+            .field("updated_at", &self.updated_at)
+            .finish()
+    }
 }
 
 impl<U: UsernameType> User<U> {
@@ -61,26 +139,189 @@ impl<U: UsernameType> User<U> {
         meta: Option<serde_json::Value>,
     ) -> Result<Self, U::TryIntoError> {
         let username: Username<U> = username.parse()?;
+        let now = Utc::now();
 
         Ok(Self {
             id,
             username,
             password_hash: Secret::new(password_hash),
             meta: meta.unwrap_or(serde_json::Value::Null),
+            version: 0,
+            created_at: now,
+            updated_at: now,
         })
     }
+
+    /// Whether the user has a password yet, see [`NewUser::invited`].
+    pub fn has_password(&self) -> bool {
+        self.password_hash.expose_secret() != NO_PASSWORD_HASH
+    }
 }
 
+/// A page of users in id order, see [`UserBackend::list_users_after`].
+#[derive(Debug)]
+pub struct UserPage<U: UsernameType> {
+    pub users: Vec<User<U>>,
+    /// Cursor for the next page, or `None` if this was the last one.
+    pub next: Option<UserId>,
+}
+
+/// Returned by [`UserBackend`] methods that a backend doesn't provide. Only creating, finding
+/// and listing users, and verifying and changing their passwords, is required of every backend.
+#[derive(Debug, thiserror::Error)]
+#[error("Not supported by this user backend")]
+pub struct Unsupported;
+
 #[async_trait]
 pub trait UserBackend<S: Strategy, U: UsernameType> {
-    type Error: std::error::Error;
+    type Error: std::error::Error + From<Unsupported>;
 
     async fn create_user(&self, user: NewUser<U>) -> Result<User<U>, Self::Error>;
+
+    /// Creates all of the users, or none of them if any fails.
+    async fn create_users(&self, users: Vec<NewUser<U>>) -> Result<Vec<User<U>>, Self::Error>;
+
+    /// Creates the user with `default_password` if it doesn't exist yet, with its username
+    /// checked like on registration. Otherwise merges the keys of `meta_patch` (a JSON object)
+    /// into the existing meta, leaving the password as is.
+    async fn ensure_user(
+        &self,
+        _username: &str,
+        _default_password: &Secret<String>,
+        _meta_patch: serde_json::Value,
+    ) -> Result<User<U>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Holds `username` for `ttl`. Only a [`NewUser`] carrying the returned token can take it
+    /// until the reservation expires.
+    async fn reserve_username(
+        &self,
+        _username: &str,
+        _ttl: chrono::Duration,
+    ) -> Result<ReservationToken, Self::Error> {
+        Err(Unsupported.into())
+    }
     async fn find_user_by_id(&self, id: UserId) -> Result<User<U>, Self::Error>;
     async fn find_user_by_username(&self, name: &str) -> Result<User<U>, Self::Error>;
+
+    /// Whether a user is called `username`, compared like [`UserBackend::find_user_by_username`]
+    /// does, without reading the user.
+    async fn username_exists(&self, _username: &str) -> Result<bool, Self::Error> {
+        Err(Unsupported.into())
+    }
+    /// Lists all users in id order. Fails if any of them can't be read, e.g. because its
+    /// username doesn't parse as `U`, rather than leaving it out.
     async fn list_users(&self) -> Result<Vec<User<U>>, Self::Error>;
+
+    /// Lists up to `limit` users in id order, skipping the first `offset` of them. `limit` must
+    /// be positive, and backends may cap it. Deep offsets get slow and pages shift as users are
+    /// created or deleted, so prefer [`UserBackend::list_users_after`] to walk large tables.
+    async fn list_users_paged(
+        &self,
+        _limit: i64,
+        _offset: i64,
+    ) -> Result<Vec<User<U>>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Lists up to `limit` users with ids after `after`, in id order. Pass the returned
+    /// [`UserPage::next`] as `after` to get the next page. Unlike offsets, the cursor doesn't
+    /// skip or repeat users when others are created or deleted in between. `limit` must be
+    /// positive, and backends may cap it.
+    async fn list_users_after(
+        &self,
+        _after: Option<UserId>,
+        _limit: i64,
+    ) -> Result<UserPage<U>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Lists users whose password hash wasn't made with the strategy's current settings, in id
+    /// order, skipping the first `offset` of them. `limit` must be positive, and backends may
+    /// cap it. Hashes the strategy can't read at all, such as those of another algorithm, are
+    /// included. Their passwords aren't known, so this can't upgrade them, but they can be made
+    /// to reset their passwords or be flagged.
+    ///
+    /// Hashes made with a retired pepper are only included if the strategy tags hashes with an
+    /// id of their pepper, see [`crate::password_strategy::Argon2idStrategy::with_pepper_id`].
+    ///
+    /// Every hash is checked, so this reads the whole table in the worst case.
+    async fn users_needing_rehash(
+        &self,
+        _limit: i64,
+        _offset: i64,
+    ) -> Result<Vec<UserId>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Finds users whose username contains `query`, case-insensitively. `%` and `_` in the
+    /// query are matched literally. At most `limit` users are returned. `limit` must be positive.
+    ///
+    /// On large tables, back this with a trigram index:
+    /// `CREATE INDEX ON users USING gin ((username::TEXT) gin_trgm_ops);` (needs `pg_trgm`).
+    async fn search_users(&self, _query: &str, _limit: i64) -> Result<Vec<User<U>>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Replaces the user's meta. With `expected_version`, the update only goes through if the
+    /// user is still at that version, i.e. nobody else updated it since it was read.
+    async fn update_meta(
+        &self,
+        _id: UserId,
+        _meta: serde_json::Value,
+        _expected_version: Option<i32>,
+    ) -> Result<User<U>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Deletes the user. Sessions and password reset ids referencing it through a foreign key
+    /// go with it if the key is declared `ON DELETE CASCADE`, as [`crate::schema`] does.
+    async fn delete_user(&self, _id: UserId) -> Result<(), Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Renames the user, failing if another user has `new_username` already.
+    async fn change_username(
+        &self,
+        _id: UserId,
+        _new_username: &str,
+    ) -> Result<User<U>, Self::Error> {
+        Err(Unsupported.into())
+    }
     fn verify_password(&self, user: &User<U>, password: &str) -> Result<(), Self::Error>;
     async fn change_password(&self, user: &User<U>, new_password: &str) -> Result<(), Self::Error>;
+
+    /// Sets the password of a user created without one, see [`NewUser::invited`]. Fails if the
+    /// user already has a password, so that an invite can't be used to take over an account.
+    async fn set_initial_password(
+        &self,
+        _id: UserId,
+        _password: &str,
+    ) -> Result<User<U>, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    /// Like [`UserBackend::verify_password`], but keeps the password wrapped until the
+    /// strategy needs it.
+    fn verify_password_secret(
+        &self,
+        user: &User<U>,
+        password: &Secret<String>,
+    ) -> Result<(), Self::Error> {
+        self.verify_password(user, password.expose_secret())
+    }
+
+    /// Like [`UserBackend::change_password`], but keeps the password wrapped until the
+    /// strategy needs it.
+    async fn change_password_secret(
+        &self,
+        user: &User<U>,
+        new_password: &Secret<String>,
+    ) -> Result<(), Self::Error> {
+        self.change_password(user, new_password.expose_secret())
+            .await
+    }
 }
 
 #[async_trait]
@@ -95,3 +336,119 @@ pub trait UserBackendTransactional<'a, S: Strategy, U: UsernameType, UT>:
         user: NewUser<U>,
     ) -> Result<User<U>, Self::Error>;
 }
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+
+    use crate::{password_strategy::Argon2idStrategy, username::ascii::AsciiUsername};
+
+    use super::{merge_meta, NewUser, Unsupported, User, UserBackend, UserId};
+
+    /// Implements only what every backend has to.
+    struct Minimal;
+
+    #[async_trait]
+    impl UserBackend<Argon2idStrategy, AsciiUsername> for Minimal {
+        type Error = Unsupported;
+
+        async fn create_user(
+            &self,
+            _user: NewUser<AsciiUsername>,
+        ) -> Result<User<AsciiUsername>, Self::Error> {
+            Err(Unsupported)
+        }
+
+        async fn create_users(
+            &self,
+            _users: Vec<NewUser<AsciiUsername>>,
+        ) -> Result<Vec<User<AsciiUsername>>, Self::Error> {
+            Err(Unsupported)
+        }
+
+        async fn find_user_by_id(&self, _id: UserId) -> Result<User<AsciiUsername>, Self::Error> {
+            Err(Unsupported)
+        }
+
+        async fn find_user_by_username(
+            &self,
+            _name: &str,
+        ) -> Result<User<AsciiUsername>, Self::Error> {
+            Err(Unsupported)
+        }
+
+        async fn list_users(&self) -> Result<Vec<User<AsciiUsername>>, Self::Error> {
+            Ok(Vec::new())
+        }
+
+        fn verify_password(
+            &self,
+            _user: &User<AsciiUsername>,
+            _password: &str,
+        ) -> Result<(), Self::Error> {
+            Err(Unsupported)
+        }
+
+        async fn change_password(
+            &self,
+            _user: &User<AsciiUsername>,
+            _new_password: &str,
+        ) -> Result<(), Self::Error> {
+            Err(Unsupported)
+        }
+    }
+
+    #[test]
+    fn optional_methods_default_to_unsupported() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let id = UserId(uuid::Uuid::new_v4());
+            assert!(Minimal.list_users().await.unwrap().is_empty());
+            assert!(matches!(
+                Minimal.change_username(id, "bob").await,
+                Err(Unsupported)
+            ));
+            assert!(matches!(Minimal.delete_user(id).await, Err(Unsupported)));
+        });
+    }
+
+    #[test]
+    fn merge_meta_like_jsonb_concatenation() {
+        assert_eq!(
+            merge_meta(
+                serde_json::json!({ "a": 1, "b": { "c": 2 } }),
+                serde_json::json!({ "b": { "d": 3 }, "e": 4 })
+            ),
+            serde_json::json!({ "a": 1, "b": { "d": 3 }, "e": 4 })
+        );
+        assert_eq!(
+            merge_meta(serde_json::json!({ "a": 1 }), serde_json::json!([1])),
+            serde_json::json!([1])
+        );
+        assert_eq!(
+            merge_meta(serde_json::Value::Null, serde_json::json!({ "a": 1 })),
+            serde_json::json!({ "a": 1 })
+        );
+    }
+
+    #[test]
+    fn debug_hides_secrets() {
+        let mut new_user = NewUser::<AsciiUsername>::new("alice", "hunter2hunter2").unwrap();
+        new_user.meta = serde_json::json!({ "ssn": "078-05-1120" });
+        let debug = format!("{:?}", new_user);
+        assert!(debug.contains("alice"));
+        assert!(!debug.contains("hunter2"));
+        assert!(!debug.contains("078-05-1120"));
+
+        let user = User::<AsciiUsername>::new(
+            UserId(uuid::Uuid::new_v4()),
+            "alice",
+            "$argon2id$v=19$m=15360,t=2,p=1$c2FsdA$aGFzaA".into(),
+            Some(serde_json::json!({ "ssn": "078-05-1120" })),
+        )
+        .unwrap();
+        let debug = format!("{:?}", user);
+        assert!(!debug.contains("argon2id"));
+        assert!(!debug.contains("078-05-1120"));
+    }
+}
@@ -1,27 +1,36 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, sync::OnceLock};
 
 use async_trait::async_trait;
-use secrecy::ExposeSecret;
-use sqlx::{Acquire, PgPool, Postgres, Transaction};
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{Connection, PgPool, Postgres, Transaction};
 
+#[cfg(feature = "deadpool")]
+use crate::util;
 use crate::{
+    meta_cipher::{self, MetaCipher},
     password_strategy::Strategy,
-    session::{PasswordResetId, SessionBackend, SessionManager},
-    username::UsernameType,
-    util,
+    session::{PasswordResetId, SessionBackend, SessionId, SessionManager},
+    username::{Username, UsernameType},
+    util::ConnSource,
 };
 
-use super::{NewUser, PgUsers, User, UserBackend, UserBackendTransactional, UserId};
-#[cfg(feature = "deadpool")]
-use super::{DeadpoolPgUsers};
+use super::{
+    merge_meta,
+    reservation::{self, ReservationToken, UsernameReservations},
+    NewUser, User, UserBackend, UserBackendTransactional, UserId, UserPage, NO_PASSWORD_HASH,
+};
+
+/// Postgres' SQLSTATE for unique violations.
+const UNIQUE_VIOLATION: &str = "23505";
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[cfg(feature = "deadpool")]
-    #[error("sqlx error")]
+    #[error("connection pool error: {0}")]
     SqlxPool(#[from] deadpool::managed::PoolError<sqlx::Error>),
 
-    #[error("sqlx error")]
+    // sqlx never includes the query or its bound values in its messages, so this is safe to log.
+    #[error("sqlx error: {0}")]
     Sqlx(#[from] sqlx::Error),
 
     #[error("invalid username")]
@@ -32,84 +41,351 @@ pub enum Error {
 
     #[error("The entered password was invalid.")]
     InvalidPassword,
+
+    #[error("The user has not set a password yet.")]
+    PasswordNotSet,
+
+    #[error("The user already has a password.")]
+    PasswordAlreadySet,
+
+    #[error("username reservation error: {0}")]
+    Reservation(#[from] reservation::Error),
+
+    #[error("No username reservation store is configured.")]
+    ReservationsUnavailable,
+
+    #[error("meta encryption error: {0}")]
+    MetaCipher(#[from] meta_cipher::Error),
+
+    #[error("The user was updated by someone else since it was read.")]
+    Conflict,
+
+    #[error("The user does not exist.")]
+    UserNotFound,
+
+    #[error("The username is already taken.")]
+    UsernameTaken,
+
+    #[error("The page limit must be positive.")]
+    InvalidLimit,
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
+}
+
+impl Error {
+    /// Name of the violated constraint, if Postgres rejected a query because of one.
+    pub fn constraint(&self) -> Option<&str> {
+        match self {
+            Error::Sqlx(e) => e.as_database_error()?.constraint(),
+            _ => None,
+        }
+    }
 }
 
-pub struct Backend<S: Strategy, U: UsernameType> {
+/// Postgres user backend, generic over where connections come from (see [`ConnSource`]).
+///
+/// The table needs the columns [`crate::schema::users`] creates. Tables made before
+/// `created_at` and `updated_at` existed need them added:
+/// `ALTER TABLE users ADD created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+/// ADD updated_at TIMESTAMPTZ NOT NULL DEFAULT now();`
+pub struct Backend<S: Strategy, U: UsernameType, C = PgPool> {
     strategy: S,
-    pool: PgPool,
+    pool: C,
     table_name: &'static str,
+    reservations: Option<Box<dyn UsernameReservations>>,
+    meta_cipher: Option<MetaCipher>,
+    soft_delete: bool,
+    /// Hash verified against when there is no user, see [`Backend::verify_password_or_dummy`].
+    /// `None` inside if the strategy can't generate hashes.
+    dummy_hash: OnceLock<Option<Secret<String>>>,
     _username: PhantomData<U>,
 }
 
-impl<S: Strategy, U: UsernameType> Backend<S, U> {
-    pub fn new(pool: PgPool, table_name: &'static str, strategy: S) -> Self {
+/// Password of the dummy hash. Nothing is ever verified as matching it.
+const DUMMY_PASSWORD: &str = "thetc-auth dummy password";
+
+impl<S: Strategy, U: UsernameType, C> Backend<S, U, C> {
+    pub fn new(pool: C, table_name: &'static str, strategy: S) -> Self {
         Self {
             strategy,
             pool,
             table_name,
+            reservations: None,
+            meta_cipher: None,
+            soft_delete: false,
+            dummy_hash: OnceLock::new(),
             _username: PhantomData,
         }
     }
+
+    /// Makes `create_user` honour username reservations held in `reservations`.
+    pub fn with_reservations(mut self, reservations: impl UsernameReservations + 'static) -> Self {
+        self.reservations = Some(Box::new(reservations));
+        self
+    }
+
+    /// Stores `meta` encrypted, see [`MetaCipher`].
+    pub fn with_meta_cipher(mut self, meta_cipher: MetaCipher) -> Self {
+        self.meta_cipher = Some(meta_cipher);
+        self
+    }
+
+    /// Makes `delete_user` only mark users as deleted, by setting their `deleted_at`. Deleted
+    /// users are hidden from finds and listings, and updating them, including their password,
+    /// fails with [`Error::UserNotFound`]. The table needs the `deleted_at` column [`crate::schema`]
+    /// creates. The rows stay, so their sessions do too, and their usernames stay taken.
+    pub fn with_soft_delete(mut self) -> Self {
+        self.soft_delete = true;
+        self
+    }
+
+    fn open_user(&self, user: User<U>) -> Result<User<U>, Error> {
+        open_user(self.meta_cipher.as_ref(), user)
+    }
 }
 
-#[cfg(feature = "deadpool")]
-pub struct DeadpoolBackend<S: Strategy, U: UsernameType> {
-    strategy: S,
-    pool: util::deadpool::PgPool,
-    table_name: &'static str,
-    _username: PhantomData<U>,
+impl<S, U, C> Backend<S, U, C>
+where
+    S: Strategy,
+    U: UsernameType,
+    C: ConnSource,
+    Error: From<C::Error>,
+{
+    /// Verifies `password`, and if the stored hash was made with weaker settings than the
+    /// strategy's, replaces it with a fresh one. Nothing is written unless the password is
+    /// correct, nor if the stored hash changed since `user` was read, e.g. because the password
+    /// was changed meanwhile.
+    pub async fn authenticate_and_upgrade(
+        &self,
+        user: &User<U>,
+        password: &str,
+    ) -> Result<(), Error> {
+        let password_hash = match self.upgraded_hash(user, password)? {
+            Some(password_hash) => password_hash,
+            None => return Ok(()),
+        };
+
+        let mut conn = self.pool.acquire().await?;
+        let replaced = database::replace_password_hash(
+            &mut conn,
+            user.id,
+            &user.password_hash,
+            password_hash,
+            self.soft_delete,
+            self.table_name,
+        )
+        .await?;
+        if !replaced {
+            // Either the user is gone, or the newer hash stays.
+            database::find_user_by_id::<U>(&mut conn, user.id, self.soft_delete, self.table_name)
+                .await
+                .map_err(user_not_found)?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Strategy, U: UsernameType, C> Backend<S, U, C> {
+    /// Verifies `password` like [`UserBackend::verify_password`] when there is a user with a
+    /// password, and otherwise verifies it against a dummy hash and fails with
+    /// [`Error::InvalidPassword`].
+    ///
+    /// Pass the outcome of looking up a login's username, so that logging in as someone who
+    /// doesn't exist takes as long as with a wrong password, and timing doesn't reveal which
+    /// usernames are taken.
+    pub fn verify_password_or_dummy(
+        &self,
+        maybe_user: Option<&User<U>>,
+        password: &str,
+    ) -> Result<(), Error> {
+        let hash = match maybe_user {
+            Some(user) if user.has_password() => user.password_hash.expose_secret(),
+            _ => {
+                let dummy_hash = self
+                    .dummy_hash
+                    .get_or_init(|| self.strategy.generate_password_hash(DUMMY_PASSWORD).ok());
+                if let Some(dummy_hash) = dummy_hash {
+                    let _ = self
+                        .strategy
+                        .verify_password(dummy_hash.expose_secret(), password);
+                }
+                return Err(Error::InvalidPassword);
+            }
+        };
+
+        match self.strategy.verify_password(hash, password)? {
+            true => Ok(()),
+            false => Err(Error::InvalidPassword),
+        }
+    }
+
+    /// The hash to replace the user's with, if `password` is correct and the stored hash is due
+    /// for an upgrade.
+    fn upgraded_hash(
+        &self,
+        user: &User<U>,
+        password: &str,
+    ) -> Result<Option<Secret<String>>, Error> {
+        if !user.has_password() {
+            return Err(Error::PasswordNotSet);
+        }
+
+        let hash = user.password_hash.expose_secret();
+        match self.strategy.verify_and_check_rehash(hash, password)? {
+            Some(true) => Ok(Some(self.strategy.generate_password_hash(password)?)),
+            Some(false) => Ok(None),
+            None => Err(Error::InvalidPassword),
+        }
+    }
+}
+
+/// Turns a missing row into [`Error::UserNotFound`], for queries looking up a single user.
+fn user_not_found(e: sqlx::Error) -> Error {
+    match e {
+        sqlx::Error::RowNotFound => Error::UserNotFound,
+        e => Error::Sqlx(e),
+    }
+}
+
+/// Tells a rename onto an existing username apart from other update failures.
+fn rename_error(e: sqlx::Error) -> Error {
+    let is_unique_violation = e
+        .as_database_error()
+        .and_then(|e| e.code())
+        .map_or(false, |code| code == UNIQUE_VIOLATION);
+
+    match is_unique_violation {
+        true => Error::UsernameTaken,
+        false => user_not_found(e),
+    }
+}
+
+fn seal_meta(
+    meta_cipher: Option<&MetaCipher>,
+    id: UserId,
+    meta: serde_json::Value,
+) -> Result<serde_json::Value, Error> {
+    match meta_cipher {
+        Some(cipher) => Ok(cipher.encrypt(*id, &meta)?),
+        None => Ok(meta),
+    }
+}
+
+fn open_user<U: UsernameType>(
+    meta_cipher: Option<&MetaCipher>,
+    mut user: User<U>,
+) -> Result<User<U>, Error> {
+    if let Some(cipher) = meta_cipher {
+        user.meta = cipher.decrypt(*user.id, user.meta)?;
+    }
+    Ok(user)
 }
 
 #[cfg(feature = "deadpool")]
-impl<S: Strategy, U: UsernameType> DeadpoolBackend<S, U> {
-    pub fn new(pool: util::deadpool::PgPool, table_name: &'static str, strategy: S) -> Self {
-        Self {
-            strategy,
-            pool,
-            table_name,
-            _username: PhantomData,
+pub type DeadpoolBackend<S, U> = Backend<S, U, util::deadpool::PgPool>;
+
+/// Checks that the user may take its username, without consuming its reservation yet, so that
+/// the reservation survives a failure to create the user. Returns the reservation to consume
+/// once the user is created.
+async fn check_reservation<U: UsernameType>(
+    reservations: Option<&dyn UsernameReservations>,
+    user: &NewUser<U>,
+) -> Result<Option<(String, ReservationToken)>, Error> {
+    match (reservations, user.reservation) {
+        (Some(reservations), Some(token)) => {
+            reservations
+                .check_reservation(&user.username, token)
+                .await?;
+            Ok(Some((user.username.to_string(), token)))
         }
+        (Some(reservations), None) => {
+            if reservations.is_reserved(&user.username).await? {
+                return Err(reservation::Error::AlreadyReserved.into());
+            }
+            Ok(None)
+        }
+        (None, Some(_)) => Err(Error::ReservationsUnavailable),
+        (None, None) => Ok(None),
     }
 }
 
-#[inline]
-async fn create_user<'a, S: Strategy, U: UsernameType>(
+/// Consumes the reservations of created users. Called last before committing, so that a user
+/// whose reservation expired in the meantime isn't created after all.
+async fn consume_reservations(
+    reservations: Option<&dyn UsernameReservations>,
+    held: Vec<(String, ReservationToken)>,
+) -> Result<(), Error> {
+    if let Some(reservations) = reservations {
+        for (username, token) in held {
+            reservations.consume_reservation(&username, token).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn insert_user<'a, S: Strategy, U: UsernameType>(
     mut conn: &mut Transaction<'a, Postgres>,
     strategy: &'a S,
+    meta_cipher: Option<&MetaCipher>,
     table_name: &'static str,
     user: NewUser<U>,
 ) -> Result<User<U>, Error> {
-    let password_hash = strategy.generate_password_hash(user.password.expose_secret())?;
-    let user_id = match user.id {
+    user.username
+        .check_registrable()
+        .map_err(|e| Error::Username(Box::new(e)))?;
+    let password_hash = match user.password {
+        Some(password) => strategy.generate_password_hash(password.expose_secret())?,
+        None => Secret::new(NO_PASSWORD_HASH.to_string()),
+    };
+    // Encrypted meta is bound to the id, so that has to be known before inserting.
+    let id = match (user.id, meta_cipher) {
+        (None, Some(_)) => Some(UserId(uuid::Uuid::new_v4())),
+        (id, _) => id,
+    };
+    let meta = match id {
+        Some(id) => seal_meta(meta_cipher, id, user.meta)?,
+        None => user.meta,
+    };
+    let user_id = match id {
         Some(id) => {
             database::insert_user_with_id(
                 &mut conn,
                 id,
                 user.username,
                 password_hash,
-                user.meta,
+                meta,
                 table_name,
             )
             .await?
         }
         None => {
-            database::insert_user(
-                &mut conn,
-                user.username,
-                password_hash,
-                user.meta,
-                table_name,
-            )
-            .await?
+            database::insert_user(&mut conn, user.username, password_hash, meta, table_name).await?
         }
     };
-    let user = database::find_user_by_id(&mut conn, user_id, table_name).await?;
+    let user = database::find_user_by_id(&mut conn, user_id, false, table_name).await?;
+    open_user(meta_cipher, user)
+}
+
+async fn create_user<'a, S: Strategy, U: UsernameType>(
+    conn: &mut Transaction<'a, Postgres>,
+    strategy: &'a S,
+    reservations: Option<&dyn UsernameReservations>,
+    meta_cipher: Option<&MetaCipher>,
+    table_name: &'static str,
+    user: NewUser<U>,
+) -> Result<User<U>, Error> {
+    let held = check_reservation(reservations, &user).await?;
+    let user = insert_user(conn, strategy, meta_cipher, table_name, user).await?;
+    consume_reservations(reservations, held.into_iter().collect()).await?;
     Ok(user)
 }
 
 #[async_trait]
-impl<'a, S: Strategy, U: UsernameType> UserBackendTransactional<'a, S, U, UserId>
-    for Backend<S, U>
+impl<'a, S: Strategy, U: UsernameType, C: ConnSource> UserBackendTransactional<'a, S, U, UserId>
+    for Backend<S, U, C>
+where
+    Error: From<C::Error>,
 {
     type Tx = Transaction<'a, Postgres>;
 
@@ -118,40 +394,376 @@ impl<'a, S: Strategy, U: UsernameType> UserBackendTransactional<'a, S, U, UserId
         tx: &mut Self::Tx,
         user: NewUser<U>,
     ) -> Result<User<U>, Self::Error> {
-        create_user(tx, &self.strategy, self.table_name, user).await
+        create_user(
+            tx,
+            &self.strategy,
+            self.reservations.as_deref(),
+            self.meta_cipher.as_ref(),
+            self.table_name,
+            user,
+        )
+        .await
     }
 }
 
 #[async_trait]
-impl<S: Strategy, U: UsernameType> UserBackend<S, U> for Backend<S, U> {
+impl<S, U, C> UserBackend<S, U> for Backend<S, U, C>
+where
+    S: Strategy,
+    U: UsernameType,
+    C: ConnSource,
+    Error: From<C::Error>,
+{
     type Error = Error;
 
     async fn create_user(&self, user: NewUser<U>) -> Result<User<U>, Self::Error> {
-        let mut conn = self.pool.begin().await?;
-        let user = create_user(&mut conn, &self.strategy, self.table_name, user).await?;
-        conn.commit().await?;
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let user = create_user(
+            &mut tx,
+            &self.strategy,
+            self.reservations.as_deref(),
+            self.meta_cipher.as_ref(),
+            self.table_name,
+            user,
+        )
+        .await?;
+        tx.commit().await?;
         Ok(user)
     }
 
+    async fn create_users(&self, users: Vec<NewUser<U>>) -> Result<Vec<User<U>>, Self::Error> {
+        let reservations = self.reservations.as_deref();
+        let mut held = Vec::new();
+        for user in &users {
+            held.extend(check_reservation(reservations, user).await?);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut created = Vec::with_capacity(users.len());
+        for user in users {
+            created.push(
+                insert_user(
+                    &mut tx,
+                    &self.strategy,
+                    self.meta_cipher.as_ref(),
+                    self.table_name,
+                    user,
+                )
+                .await?,
+            );
+        }
+        consume_reservations(reservations, held).await?;
+        tx.commit().await?;
+        Ok(created)
+    }
+
+    async fn ensure_user(
+        &self,
+        username: &str,
+        default_password: &Secret<String>,
+        meta_patch: serde_json::Value,
+    ) -> Result<User<U>, Self::Error> {
+        let username = username
+            .parse::<Username<U>>()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        let mut conn = self.pool.acquire().await?;
+        // Existing users are left their names, but new ones have to pass the same checks as on
+        // registration. Should the user be created meanwhile, this errs on rejecting the call.
+        if !database::username_exists(&mut conn, &username, self.table_name).await? {
+            username
+                .check_registrable()
+                .map_err(|e| Error::Username(Box::new(e)))?;
+            if let Some(reservations) = self.reservations.as_deref() {
+                if reservations.is_reserved(&username).await? {
+                    return Err(reservation::Error::AlreadyReserved.into());
+                }
+            }
+        }
+        // Hashed up front, as whether the user exists is only known once the upsert ran.
+        let password_hash = self
+            .strategy
+            .generate_password_hash(default_password.expose_secret())?;
+
+        let cipher = match self.meta_cipher.as_ref() {
+            Some(cipher) => cipher,
+            None => {
+                let user_id = database::upsert_user_meta(
+                    &mut conn,
+                    username,
+                    password_hash,
+                    meta_patch,
+                    self.soft_delete,
+                    self.table_name,
+                )
+                .await?
+                .ok_or(Error::UserNotFound)?;
+                let user = database::find_user_by_id(
+                    &mut conn,
+                    user_id,
+                    self.soft_delete,
+                    self.table_name,
+                )
+                .await?;
+                return Ok(user);
+            }
+        };
+
+        // Encrypted meta can't be merged by Postgres, so the merge happens here, with the row
+        // locked in between reading and writing it.
+        let mut tx = conn.begin().await?;
+        let new_id = UserId(uuid::Uuid::new_v4());
+        let inserted = database::insert_user_if_absent(
+            &mut tx,
+            new_id,
+            username.clone(),
+            password_hash,
+            cipher.encrypt(*new_id, &meta_patch)?,
+            self.table_name,
+        )
+        .await?;
+        let user_id = match inserted {
+            Some(user_id) => user_id,
+            None => {
+                let (user_id, stored) =
+                    database::lock_user_meta(&mut tx, &username, self.soft_delete, self.table_name)
+                        .await
+                        .map_err(user_not_found)?;
+                let meta = merge_meta(cipher.decrypt(*user_id, stored)?, meta_patch);
+                let meta = cipher.encrypt(*user_id, &meta)?;
+                database::set_meta(&mut tx, user_id, meta, self.table_name).await?;
+                user_id
+            }
+        };
+        let user =
+            database::find_user_by_id(&mut tx, user_id, self.soft_delete, self.table_name).await?;
+        tx.commit().await?;
+        self.open_user(user)
+    }
+
+    async fn reserve_username(
+        &self,
+        username: &str,
+        ttl: chrono::Duration,
+    ) -> Result<ReservationToken, Self::Error> {
+        let username = username
+            .parse::<Username<U>>()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        let reservations = self
+            .reservations
+            .as_deref()
+            .ok_or(Error::ReservationsUnavailable)?;
+        Ok(reservations.reserve_username(&username, ttl).await?)
+    }
+
     async fn find_user_by_id(&self, id: UserId) -> Result<User<U>, Self::Error> {
         let mut conn = self.pool.acquire().await?;
-        Ok(database::find_user_by_id(&mut conn, id, self.table_name).await?)
+        let user = database::find_user_by_id(&mut conn, id, self.soft_delete, self.table_name)
+            .await
+            .map_err(user_not_found)?;
+        self.open_user(user)
     }
 
     async fn find_user_by_username(&self, username: &str) -> Result<User<U>, Self::Error> {
         let mut conn = self.pool.acquire().await?;
-        Ok(
-            database::find_user_by_username(&mut conn, username.to_string(), self.table_name)
-                .await?,
+        let user = database::find_user_by_username(
+            &mut conn,
+            username.to_string(),
+            self.soft_delete,
+            self.table_name,
         )
+        .await
+        .map_err(user_not_found)?;
+        self.open_user(user)
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::username_exists(&mut conn, username, self.table_name).await?)
     }
 
     async fn list_users(&self) -> Result<Vec<User<U>>, Self::Error> {
         let mut conn = self.pool.acquire().await?;
-        Ok(database::list_users(&mut conn, self.table_name).await?)
+        let users = database::list_users(&mut conn, self.soft_delete, self.table_name).await?;
+        users.into_iter().map(|u| self.open_user(u)).collect()
+    }
+
+    async fn list_users_paged(&self, limit: i64, offset: i64) -> Result<Vec<User<U>>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let limit = limit.min(database::MAX_PAGE_LIMIT);
+        let users = database::list_users_paged(
+            &mut conn,
+            limit,
+            offset.max(0),
+            self.soft_delete,
+            self.table_name,
+        )
+        .await?;
+        users.into_iter().map(|u| self.open_user(u)).collect()
+    }
+
+    async fn list_users_after(
+        &self,
+        after: Option<UserId>,
+        limit: i64,
+    ) -> Result<UserPage<U>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let limit = limit.min(database::MAX_PAGE_LIMIT);
+        let users =
+            database::list_users_after(&mut conn, after, limit, self.soft_delete, self.table_name)
+                .await?
+                .into_iter()
+                .map(|u| self.open_user(u))
+                .collect::<Result<Vec<_>, _>>()?;
+
+        // A short page is the last one. A full one may be too, which the next call finds out.
+        let next = match users.len() as i64 == limit {
+            true => users.last().map(|u| u.id),
+            false => None,
+        };
+        Ok(UserPage { users, next })
+    }
+
+    async fn users_needing_rehash(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserId>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let limit = limit.min(database::MAX_PAGE_LIMIT) as usize;
+        let mut to_skip = offset.max(0) as usize;
+        let mut user_ids = Vec::new();
+        let mut after = None;
+        let mut conn = self.pool.acquire().await?;
+
+        while user_ids.len() < limit {
+            let hashes = database::list_password_hashes_after(
+                &mut conn,
+                after,
+                database::MAX_PAGE_LIMIT,
+                self.soft_delete,
+                self.table_name,
+            )
+            .await?;
+            let last_page = (hashes.len() as i64) < database::MAX_PAGE_LIMIT;
+            after = hashes.last().map(|(id, _)| *id);
+
+            for (id, hash) in hashes {
+                if hash == NO_PASSWORD_HASH || !self.strategy.needs_rehash(&hash).unwrap_or(true) {
+                    continue;
+                }
+                match to_skip {
+                    0 => user_ids.push(id),
+                    _ => to_skip -= 1,
+                }
+                if user_ids.len() == limit {
+                    break;
+                }
+            }
+
+            if last_page {
+                break;
+            }
+        }
+
+        Ok(user_ids)
+    }
+
+    async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User<U>>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let users =
+            database::search_users(&mut conn, query, limit, self.soft_delete, self.table_name)
+                .await?;
+        users.into_iter().map(|u| self.open_user(u)).collect()
+    }
+
+    async fn update_meta(
+        &self,
+        id: UserId,
+        meta: serde_json::Value,
+        expected_version: Option<i32>,
+    ) -> Result<User<U>, Self::Error> {
+        let meta = seal_meta(self.meta_cipher.as_ref(), id, meta)?;
+        let mut conn = self.pool.acquire().await?;
+        match database::update_meta(
+            &mut conn,
+            id,
+            meta,
+            expected_version,
+            self.soft_delete,
+            self.table_name,
+        )
+        .await?
+        {
+            Some(user) => self.open_user(user),
+            // Either the user is gone or it moved on.
+            None => {
+                database::find_user_by_id::<U>(&mut conn, id, self.soft_delete, self.table_name)
+                    .await
+                    .map_err(user_not_found)?;
+                Err(Error::Conflict)
+            }
+        }
+    }
+
+    async fn delete_user(&self, id: UserId) -> Result<(), Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let deleted = match self.soft_delete {
+            true => database::soft_delete_user(&mut conn, id, self.table_name).await?,
+            false => database::delete_user(&mut conn, id, self.table_name).await?,
+        };
+        match deleted {
+            true => Ok(()),
+            false => Err(Error::UserNotFound),
+        }
+    }
+
+    async fn change_username(
+        &self,
+        id: UserId,
+        new_username: &str,
+    ) -> Result<User<U>, Self::Error> {
+        let username = new_username
+            .parse::<Username<U>>()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        username
+            .check_registrable()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        if let Some(reservations) = self.reservations.as_deref() {
+            if reservations.is_reserved(&username).await? {
+                return Err(reservation::Error::AlreadyReserved.into());
+            }
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let user =
+            database::set_username(&mut conn, id, username, self.soft_delete, self.table_name)
+                .await
+                .map_err(rename_error)?;
+        self.open_user(user)
     }
 
     fn verify_password(&self, user: &User<U>, password: &str) -> Result<(), Self::Error> {
+        if !user.has_password() {
+            return Err(Error::PasswordNotSet);
+        }
+
         match self
             .strategy
             .verify_password(user.password_hash.expose_secret(), password)?
@@ -166,33 +778,71 @@ impl<S: Strategy, U: UsernameType> UserBackend<S, U> for Backend<S, U> {
         let password_hash = self.strategy.generate_password_hash(new_password)?;
         database::set_password(
             &mut conn,
-            user.username.clone(),
+            user.id,
             password_hash,
+            self.soft_delete,
             self.table_name,
         )
-        .await?;
+        .await
+        .map_err(user_not_found)?;
         Ok(())
     }
+
+    async fn set_initial_password(
+        &self,
+        id: UserId,
+        password: &str,
+    ) -> Result<User<U>, Self::Error> {
+        let password_hash = self.strategy.generate_password_hash(password)?;
+        let mut conn = self.pool.acquire().await?;
+        match database::set_initial_password(
+            &mut conn,
+            id,
+            password_hash,
+            self.soft_delete,
+            self.table_name,
+        )
+        .await?
+        {
+            Some(user) => self.open_user(user),
+            // Either the user is gone or it has a password.
+            None => {
+                database::find_user_by_id::<U>(&mut conn, id, self.soft_delete, self.table_name)
+                    .await
+                    .map_err(user_not_found)?;
+                Err(Error::PasswordAlreadySet)
+            }
+        }
+    }
 }
 
-pub struct PgPasswordResetBackend<T, St, Se, Ut, E>
+pub struct PgPasswordResetBackend<T, St, Se, Ut, E, C = PgPool>
 where
     T: SessionBackend<Error = E, Session = Se, UserId = UserId>,
     St: Strategy,
     Ut: UsernameType,
 {
     session_manager: SessionManager<T, Se, UserId, E>,
-    users: PgUsers<St, Ut>,
+    users: Backend<St, Ut, C>,
 }
 
-impl<T, St, Se, Ut, E> PgPasswordResetBackend<T, St, Se, Ut, E>
+#[cfg(feature = "deadpool")]
+pub type DeadpoolPasswordResetBackend<T, St, Se, Ut, E> =
+    PgPasswordResetBackend<T, St, Se, Ut, E, util::deadpool::PgPool>;
+
+impl<T, St, Se, Ut, E, C> PgPasswordResetBackend<T, St, Se, Ut, E, C>
 where
     E: std::error::Error + 'static,
     T: SessionBackend<Error = E, Session = Se, UserId = UserId>,
     St: Strategy,
     Ut: UsernameType,
+    C: ConnSource,
+    Error: From<C::Error>,
 {
-    pub fn new(session_manager: SessionManager<T, Se, UserId, E>, users: PgUsers<St, Ut>) -> Self {
+    pub fn new(
+        session_manager: SessionManager<T, Se, UserId, E>,
+        users: Backend<St, Ut, C>,
+    ) -> Self {
         Self {
             session_manager,
             users,
@@ -204,139 +854,110 @@ where
         password_reset_id: PasswordResetId,
         new_password: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let user_id = self.session_manager.verify_password_reset_id(password_reset_id).await?;
-        let user = self.users.find_user_by_id(user_id).await?;
-        self.users.change_password(&user, new_password).await?;
-        self
+        // Checked first, so that unknown ids are turned away before the costly hashing, and
+        // hashed before the id is used up, so that a password the strategy rejects doesn't.
+        self.session_manager
+            .verify_password_reset_id(password_reset_id)
+            .await?;
+        let password_hash = self.users.strategy.generate_password_hash(new_password)?;
+
+        // Only one of concurrent requests with the same id gets to consume it, and with it to
+        // change the password.
+        let user_id = self
             .session_manager
             .consume_password_reset_id(password_reset_id)
             .await?;
+        let mut conn = self.users.pool.acquire().await.map_err(Error::from)?;
+        database::set_password(
+            &mut conn,
+            user_id,
+            password_hash,
+            self.users.soft_delete,
+            self.users.table_name,
+        )
+        .await
+        .map_err(user_not_found)?;
 
         Ok(())
     }
-}
 
-#[cfg(feature = "deadpool")]
-pub struct DeadpoolPasswordResetBackend<T, St, Se, Ut, E>
-where
-    T: SessionBackend<Error = E, Session = Se, UserId = UserId>,
-    St: Strategy,
-    Ut: UsernameType,
-{
-    session_manager: SessionManager<T, Se, UserId, E>,
-    users: DeadpoolPgUsers<St, Ut>,
+    /// Changes the user's password. With `revoke_other_sessions`, every session of the user
+    /// except `current_session` is expired, so a leaked session dies with the old password.
+    pub async fn change_password(
+        &self,
+        user: &User<Ut>,
+        new_password: &str,
+        revoke_other_sessions: bool,
+        current_session: Option<SessionId>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.users.change_password(user, new_password).await?;
+        if revoke_other_sessions {
+            self.session_manager
+                .expire_user_sessions(user.id, current_session)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Self-service variant of [`Self::change_password`]: the user has to provide their
+    /// current password as well.
+    pub async fn change_password_checked(
+        &self,
+        user: &User<Ut>,
+        current_password: &str,
+        new_password: &str,
+        revoke_other_sessions: bool,
+        current_session: Option<SessionId>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.users.verify_password(user, current_password)?;
+        self.change_password(user, new_password, revoke_other_sessions, current_session)
+            .await
+    }
 }
 
-#[cfg(feature = "deadpool")]
-impl<T, St, Se, Ut, E> DeadpoolPasswordResetBackend<T, St, Se, Ut, E>
-where
-    E: std::error::Error + 'static,
-    T: SessionBackend<Error = E, Session = Se, UserId = UserId>,
-    St: Strategy,
-    Ut: UsernameType,
-{
-    pub fn new(session_manager: SessionManager<T, Se, UserId, E>, users: DeadpoolPgUsers<St, Ut>) -> Self {
-        Self {
-            session_manager,
-            users,
-        }
-    }
-
-    pub async fn reset_password(
-        &self,
-        password_reset_id: PasswordResetId,
-        new_password: &str,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let user_id = self.session_manager.verify_password_reset_id(password_reset_id).await?;
-        let user = self.users.find_user_by_id(user_id).await?;
-        self.users.change_password(&user, new_password).await?;
-        self
-            .session_manager
-            .consume_password_reset_id(password_reset_id)
-            .await?;
-
-        Ok(())
-    }
-}
-
-#[cfg(feature = "deadpool")]
-#[async_trait]
-impl<'a, S: Strategy, U: UsernameType> UserBackendTransactional<'a, S, U, UserId>
-    for DeadpoolBackend<S, U>
-{
-    type Tx = Transaction<'a, Postgres>;
-
-    async fn create_user_transaction(
-        &'a self,
-        tx: &mut Self::Tx,
-        user: NewUser<U>,
-    ) -> Result<User<U>, Self::Error> {
-        create_user(tx, &self.strategy, self.table_name, user).await
-    }
-}
-
-#[cfg(feature = "deadpool")]
-#[async_trait]
-impl<S: Strategy, U: UsernameType> UserBackend<S, U> for DeadpoolBackend<S, U> {
-    type Error = Error;
+mod database {
+    use secrecy::{ExposeSecret, Secret};
+    use sqlx::{postgres::PgRow, PgConnection, Row};
 
-    async fn create_user(&self, user: NewUser<U>) -> Result<User<U>, Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        let mut conn = conn.begin().await?;
-        let user = create_user(&mut conn, &self.strategy, self.table_name, user).await?;
-        conn.commit().await?;
-        Ok(user)
-    }
+    use crate::{
+        username::{Username, UsernameType},
+        util::escape_like,
+    };
 
-    async fn find_user_by_id(&self, id: UserId) -> Result<User<U>, Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        Ok(database::find_user_by_id(&mut conn, id, self.table_name).await?)
-    }
+    use super::{User, UserId, NO_PASSWORD_HASH};
 
-    async fn find_user_by_username(&self, username: &str) -> Result<User<U>, Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        Ok(
-            database::find_user_by_username(&mut conn, username.to_string(), self.table_name)
-                .await?,
-        )
-    }
+    /// Upper bound on the number of rows returned by a search.
+    const MAX_SEARCH_LIMIT: i64 = 100;
 
-    async fn list_users(&self) -> Result<Vec<User<U>>, Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        Ok(database::list_users(&mut conn, self.table_name).await?)
-    }
+    /// Upper bound on the number of rows in a page.
+    pub const MAX_PAGE_LIMIT: i64 = 1000;
 
-    fn verify_password(&self, user: &User<U>, password: &str) -> Result<(), Self::Error> {
-        match self
-            .strategy
-            .verify_password(user.password_hash.expose_secret(), password)?
-        {
-            true => Ok(()),
-            false => Err(Error::InvalidPassword),
+    /// Condition matching the users that aren't soft deleted, if `skip_deleted`, or all of them.
+    fn live(skip_deleted: bool) -> &'static str {
+        match skip_deleted {
+            true => "deleted_at IS NULL",
+            false => "TRUE",
         }
     }
 
-    async fn change_password(&self, user: &User<U>, new_password: &str) -> Result<(), Self::Error> {
-        let mut conn = self.pool.acquire().await?;
-        let password_hash = self.strategy.generate_password_hash(new_password)?;
-        database::set_password(
-            &mut conn,
-            user.username.clone(),
-            password_hash,
-            self.table_name,
-        )
-        .await?;
-        Ok(())
-    }
-}
-
-mod database {
-    use secrecy::{ExposeSecret, Secret};
-    use sqlx::{PgConnection, Row};
-
-    use crate::username::{Username, UsernameType};
+    fn user_from_row<U: UsernameType>(r: &PgRow) -> Result<User<U>, sqlx::Error> {
+        let raw_username: String = r.get(1);
+        let username: Username<U> = match raw_username.parse() {
+            Ok(v) => v,
+            Err(e) => return Err(sqlx::Error::Decode(Box::new(e))),
+        };
 
-    use super::{User, UserId};
+        Ok(User {
+            id: r.get(0),
+            username,
+            password_hash: Secret::new(r.get(2)),
+            meta: r.get(3),
+            version: r.get(4),
+            created_at: r.get(5),
+            updated_at: r.get(6),
+        })
+    }
 
     pub async fn insert_user_with_id<U: UsernameType>(
         conn: &mut PgConnection,
@@ -386,30 +1007,281 @@ mod database {
         Ok(UserId(rec.get(0)))
     }
 
-    pub async fn set_password<U: UsernameType>(
+    /// Merges like `merge_meta`, which `jsonb || jsonb` only does for two objects. Returns
+    /// `None` if the user exists but is soft deleted, when `skip_deleted`.
+    pub async fn upsert_user_meta<U: UsernameType>(
         conn: &mut PgConnection,
         username: Username<U>,
         password_hash: Secret<String>,
+        meta_patch: serde_json::Value,
+        skip_deleted: bool,
         table_name: &'static str,
-    ) -> Result<(), sqlx::Error> {
+    ) -> Result<Option<UserId>, sqlx::Error> {
         let rec = sqlx::query(&format!(
             r#"
-                UPDATE {} SET password_hash = $1 WHERE username = $2::text
+                INSERT INTO {0}(username, password_hash, meta) VALUES ($1::text, $2, $3)
+                ON CONFLICT (username) DO UPDATE
+                    SET meta = CASE
+                            WHEN jsonb_typeof({0}.meta) = 'object'
+                                AND jsonb_typeof(EXCLUDED.meta) = 'object'
+                            THEN {0}.meta || EXCLUDED.meta
+                            ELSE EXCLUDED.meta
+                        END,
+                        version = {0}.version + 1, updated_at = now()
+                    WHERE {1}
                 RETURNING id;
             "#,
-            table_name
+            table_name,
+            // Qualified, as `EXCLUDED` has the same columns.
+            match skip_deleted {
+                true => format!("{}.deleted_at IS NULL", table_name),
+                false => "TRUE".to_string(),
+            }
         ))
+        .bind(&*username)
         .bind(password_hash.expose_secret())
+        .bind(meta_patch)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(rec.map(|r| UserId(r.get(0))))
+    }
+
+    /// Inserts the user unless the username is taken, returning the id if it was inserted.
+    pub async fn insert_user_if_absent<U: UsernameType>(
+        conn: &mut PgConnection,
+        id: UserId,
+        username: Username<U>,
+        password_hash: Secret<String>,
+        meta: serde_json::Value,
+        table_name: &'static str,
+    ) -> Result<Option<UserId>, sqlx::Error> {
+        let rec = sqlx::query(&format!(
+            r#"
+                INSERT INTO {}(id, username, password_hash, meta) VALUES ($1, $2::text, $3, $4)
+                ON CONFLICT (username) DO NOTHING
+                RETURNING id;
+            "#,
+            table_name
+        ))
+        .bind(*id)
         .bind(&*username)
+        .bind(password_hash.expose_secret())
+        .bind(meta)
+        .fetch_optional(conn)
+        .await?;
+
+        Ok(rec.map(|r| UserId(r.get(0))))
+    }
+
+    /// Reads a user's stored meta, locking the row until the end of the transaction.
+    pub async fn lock_user_meta<U: UsernameType>(
+        conn: &mut PgConnection,
+        username: &Username<U>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<(UserId, serde_json::Value), sqlx::Error> {
+        let rec = sqlx::query(&format!(
+            r#"
+                SELECT id, meta FROM {}
+                WHERE username = $1::text AND {}
+                FOR UPDATE
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(&**username)
+        .fetch_one(conn)
+        .await?;
+
+        Ok((UserId(rec.get(0)), rec.get(1)))
+    }
+
+    pub async fn set_meta(
+        conn: &mut PgConnection,
+        id: UserId,
+        meta: serde_json::Value,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            r#"
+                UPDATE {} SET meta = $1, version = version + 1, updated_at = now()
+                WHERE id = $2
+            "#,
+            table_name
+        ))
+        .bind(meta)
+        .bind(*id)
+        .execute(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replaces the meta of the user, if it is at `expected_version` when given. Returns `None`
+    /// if no row matched.
+    pub async fn update_meta<U: UsernameType>(
+        conn: &mut PgConnection,
+        id: UserId,
+        meta: serde_json::Value,
+        expected_version: Option<i32>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Option<User<U>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET meta = $1, version = version + 1, updated_at = now()
+                WHERE id = $2 AND ($3::INTEGER IS NULL OR version = $3) AND {}
+                RETURNING id, username::TEXT, password_hash, meta, version, created_at, updated_at
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(meta)
+        .bind(*id)
+        .bind(expected_version)
+        .fetch_optional(conn)
+        .await?;
+
+        r.as_ref().map(user_from_row).transpose()
+    }
+
+    /// Sets the password hash if the user has none yet, returning `None` if it has.
+    pub async fn set_initial_password<U: UsernameType>(
+        conn: &mut PgConnection,
+        id: UserId,
+        password_hash: Secret<String>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Option<User<U>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET password_hash = $1, version = version + 1, updated_at = now()
+                WHERE id = $2 AND password_hash = $3 AND {}
+                RETURNING id, username::TEXT, password_hash, meta, version, created_at, updated_at
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(password_hash.expose_secret())
+        .bind(*id)
+        .bind(NO_PASSWORD_HASH)
+        .fetch_optional(conn)
+        .await?;
+
+        r.as_ref().map(user_from_row).transpose()
+    }
+
+    pub async fn set_password(
+        conn: &mut PgConnection,
+        id: UserId,
+        password_hash: Secret<String>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error> {
+        // By id, as the username may have been changed and taken by someone else since.
+        sqlx::query(&format!(
+            r#"
+                UPDATE {} SET password_hash = $1, version = version + 1, updated_at = now()
+                WHERE id = $2 AND {}
+                RETURNING id;
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(password_hash.expose_secret())
+        .bind(*id)
         .fetch_one(conn)
         .await?;
 
         Ok(())
     }
 
+    /// Replaces the password hash only if it still is `old_hash`, returning whether it did.
+    pub async fn replace_password_hash(
+        conn: &mut PgConnection,
+        id: UserId,
+        old_hash: &Secret<String>,
+        password_hash: Secret<String>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET password_hash = $1, version = version + 1, updated_at = now()
+                WHERE id = $2 AND password_hash = $3 AND {}
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(password_hash.expose_secret())
+        .bind(*id)
+        .bind(old_hash.expose_secret())
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn set_username<U: UsernameType>(
+        conn: &mut PgConnection,
+        id: UserId,
+        username: Username<U>,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET username = $1, version = version + 1, updated_at = now()
+                WHERE id = $2 AND {}
+                RETURNING id, username::TEXT, password_hash, meta, version, created_at, updated_at
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(&*username)
+        .bind(*id)
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    /// Sets `deleted_at`, returning whether there was a user that wasn't deleted yet.
+    pub async fn soft_delete_user(
+        conn: &mut PgConnection,
+        id: UserId,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!(
+            "UPDATE {} SET deleted_at = now() WHERE id = $1 AND deleted_at IS NULL",
+            table_name
+        ))
+        .bind(*id)
+        .execute(conn)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns whether there was a user to delete.
+    pub async fn delete_user(
+        conn: &mut PgConnection,
+        id: UserId,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE id = $1", table_name))
+            .bind(*id)
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     pub async fn find_user_by_id<U: UsernameType>(
         conn: &mut PgConnection,
         id: UserId,
+        skip_deleted: bool,
         table_name: &'static str,
     ) -> Result<User<U>, sqlx::Error> {
         let r = sqlx::query(&format!(
@@ -418,34 +1290,28 @@ mod database {
                     id as "id: UserId",
                     username::TEXT,
                     password_hash,
-                    meta
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
                 FROM {}
-                WHERE id = $1
+                WHERE id = $1 AND {}
                 LIMIT 1;
             "#,
-            table_name
+            table_name,
+            live(skip_deleted)
         ))
         .bind(*id)
         .fetch_one(conn)
         .await?;
 
-        let raw_username: String = r.get(1);
-        let username: Username<U> = match raw_username.parse() {
-            Ok(v) => v,
-            Err(e) => return Err(sqlx::Error::Decode(Box::new(e))),
-        };
-
-        Ok(User {
-            id: r.get(0),
-            username,
-            password_hash: Secret::new(r.get(2)),
-            meta: r.get(3),
-        })
+        user_from_row(&r)
     }
 
     pub async fn find_user_by_username<U: UsernameType>(
         conn: &mut PgConnection,
         username: String,
+        skip_deleted: bool,
         table_name: &'static str,
     ) -> Result<User<U>, sqlx::Error> {
         let r = sqlx::query(&format!(
@@ -454,33 +1320,41 @@ mod database {
                     id as "id: UserId",
                     username::TEXT,
                     password_hash,
-                    meta
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
                 FROM {}
-                WHERE LOWER(username) = $1
+                WHERE LOWER(username) = $1 AND {}
                 LIMIT 1;
             "#,
-            table_name
+            table_name,
+            live(skip_deleted)
         ))
         .bind(username.to_lowercase())
         .fetch_one(conn)
         .await?;
 
-        let raw_username: String = r.get(1);
-        let username: Username<U> = match raw_username.parse() {
-            Ok(v) => v,
-            Err(e) => return Err(sqlx::Error::Decode(Box::new(e))),
-        };
+        user_from_row(&r)
+    }
 
-        Ok(User {
-            id: r.get(0),
-            username,
-            password_hash: Secret::new(r.get(2)),
-            meta: r.get(3),
-        })
+    pub async fn username_exists(
+        conn: &mut PgConnection,
+        username: &str,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            "SELECT EXISTS(SELECT 1 FROM {} WHERE LOWER(username) = $1)",
+            table_name
+        ))
+        .bind(username.to_lowercase())
+        .fetch_one(conn)
+        .await
     }
 
     pub async fn list_users<U: UsernameType>(
         conn: &mut PgConnection,
+        skip_deleted: bool,
         table_name: &'static str,
     ) -> Result<Vec<User<U>>, sqlx::Error> {
         let rows = sqlx::query(&format!(
@@ -489,33 +1363,1329 @@ mod database {
                     id as "id: UserId",
                     username::TEXT,
                     password_hash,
-                    meta
-                FROM {};
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
+                FROM {}
+                WHERE {}
+                ORDER BY id;
             "#,
-            table_name
+            table_name,
+            live(skip_deleted)
         ))
         .fetch_all(conn)
         .await?;
 
-        let users = rows
-            .iter()
-            .map(|r| {
-                let raw_username: String = r.get(1);
-                let username: Username<U> = match raw_username.parse() {
-                    Ok(v) => v,
-                    Err(e) => return Err(sqlx::Error::Decode(Box::new(e))),
-                };
-                Ok(User {
-                    id: r.get(0),
-                    username,
-                    password_hash: Secret::new(r.get(2)),
-                    meta: r.get(3),
-                })
-            })
-            // TODO: handle errors better
-            .flat_map(|u| u.ok())
-            .collect();
-
-        Ok(users)
+        rows.iter().map(user_from_row).collect()
+    }
+
+    pub async fn list_users_paged<U: UsernameType>(
+        conn: &mut PgConnection,
+        limit: i64,
+        offset: i64,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT
+                    id as "id: UserId",
+                    username::TEXT,
+                    password_hash,
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
+                FROM {}
+                WHERE {}
+                ORDER BY id
+                LIMIT $1 OFFSET $2;
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+
+    pub async fn list_users_after<U: UsernameType>(
+        conn: &mut PgConnection,
+        after: Option<UserId>,
+        limit: i64,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT
+                    id as "id: UserId",
+                    username::TEXT,
+                    password_hash,
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
+                FROM {}
+                WHERE ($1::UUID IS NULL OR id > $1) AND {}
+                ORDER BY id
+                LIMIT $2;
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(after)
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+
+    /// Like [`list_users_after`], but only reads ids and password hashes.
+    pub async fn list_password_hashes_after(
+        conn: &mut PgConnection,
+        after: Option<UserId>,
+        limit: i64,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Vec<(UserId, String)>, sqlx::Error> {
+        sqlx::query_as(&format!(
+            r#"
+                SELECT id, password_hash
+                FROM {}
+                WHERE ($1::UUID IS NULL OR id > $1) AND {}
+                ORDER BY id
+                LIMIT $2;
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(after)
+        .bind(limit)
+        .fetch_all(conn)
+        .await
+    }
+
+    pub async fn search_users<U: UsernameType>(
+        conn: &mut PgConnection,
+        query: &str,
+        limit: i64,
+        skip_deleted: bool,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT
+                    id as "id: UserId",
+                    username::TEXT,
+                    password_hash,
+                    meta,
+                    version,
+                    created_at,
+                    updated_at
+                FROM {}
+                WHERE username::TEXT ILIKE $1 AND {}
+                ORDER BY username
+                LIMIT $2;
+            "#,
+            table_name,
+            live(skip_deleted)
+        ))
+        .bind(format!("%{}%", escape_like(query)))
+        .bind(limit.clamp(0, MAX_SEARCH_LIMIT))
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use secrecy::{ExposeSecret, Secret};
+    use sqlx::PgPool;
+
+    use crate::{
+        meta_cipher::{self, MetaCipher},
+        password_strategy::{Argon2idStrategy, Error as PasswordError, Strategy},
+        session::memory,
+        user::{
+            reservation::{self, MemoryReservations},
+            NewUser, User, UserBackend, UserId,
+        },
+        username::{ascii::AsciiUsername, reserved::Reserved},
+        util::escape_like,
+    };
+
+    use super::{Backend, Error, PgPasswordResetBackend};
+
+    #[derive(Debug)]
+    struct UniqueViolation;
+
+    impl std::fmt::Display for UniqueViolation {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str(r#"duplicate key value violates unique constraint "users_username_key""#)
+        }
+    }
+
+    impl std::error::Error for UniqueViolation {}
+
+    impl sqlx::error::DatabaseError for UniqueViolation {
+        fn message(&self) -> &str {
+            r#"duplicate key value violates unique constraint "users_username_key""#
+        }
+
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
+
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
+
+        fn constraint(&self) -> Option<&str> {
+            Some("users_username_key")
+        }
+    }
+
+    #[test]
+    fn constraint_violation_is_displayed() {
+        let err = Error::from(sqlx::Error::Database(Box::new(UniqueViolation)));
+
+        assert!(err.to_string().contains("users_username_key"));
+        assert_eq!(err.constraint(), Some("users_username_key"));
+    }
+
+    #[test]
+    fn escape_like_metacharacters() {
+        assert_eq!(escape_like("alice"), "alice");
+        assert_eq!(escape_like("100%_sure"), "100\\%\\_sure");
+        assert_eq!(escape_like("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn verify_password_secret() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let strategy = crate::util::test_db::strategy();
+            let hash = strategy
+                .generate_password_hash("this is my password")
+                .unwrap();
+
+            // The pool is never used, verification happens entirely in the strategy.
+            let pool = PgPool::connect_lazy("postgres://localhost/thetcauth").unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = User::new(
+                UserId(uuid::Uuid::new_v4()),
+                "alice",
+                hash.expose_secret().to_string(),
+                None,
+            )
+            .unwrap();
+
+            assert!(users
+                .verify_password_secret(&user, &Secret::new("this is my password".into()))
+                .is_ok());
+            assert!(matches!(
+                users.verify_password_secret(&user, &Secret::new("not my password".into())),
+                Err(Error::InvalidPassword)
+            ));
+        });
+    }
+
+    /// Counts verifications, to check which paths verify at all.
+    struct CountingStrategy {
+        inner: Argon2idStrategy,
+        verifications: Arc<AtomicUsize>,
+    }
+
+    impl Strategy for CountingStrategy {
+        fn generate_password_hash(&self, input: &str) -> Result<Secret<String>, PasswordError> {
+            self.inner.generate_password_hash(input)
+        }
+
+        fn verify_password(&self, hash: &str, input: &str) -> Result<bool, PasswordError> {
+            self.verifications.fetch_add(1, Ordering::SeqCst);
+            self.inner.verify_password(hash, input)
+        }
+    }
+
+    #[test]
+    fn verify_password_or_dummy() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let inner = crate::util::test_db::strategy();
+            let hash = inner.generate_password_hash("this is my password").unwrap();
+            let verifications = Arc::new(AtomicUsize::new(0));
+            let strategy = CountingStrategy {
+                inner,
+                verifications: verifications.clone(),
+            };
+
+            // The pool is never used, verification happens entirely in the strategy.
+            let pool = PgPool::connect_lazy("postgres://localhost/thetcauth").unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = User::new(
+                UserId(uuid::Uuid::new_v4()),
+                "alice",
+                hash.expose_secret().to_string(),
+                None,
+            )
+            .unwrap();
+
+            assert!(users
+                .verify_password_or_dummy(Some(&user), "this is my password")
+                .is_ok());
+            assert!(matches!(
+                users.verify_password_or_dummy(Some(&user), "not my password"),
+                Err(Error::InvalidPassword)
+            ));
+            assert_eq!(verifications.load(Ordering::SeqCst), 2);
+
+            for password in ["this is my password", super::DUMMY_PASSWORD] {
+                assert!(matches!(
+                    users.verify_password_or_dummy(None, password),
+                    Err(Error::InvalidPassword)
+                ));
+            }
+            assert_eq!(verifications.load(Ordering::SeqCst), 4);
+        });
+    }
+
+    #[test]
+    fn upgrade_weak_hash_on_correct_password() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let weak = crate::util::test_db::strategy();
+            let hash = weak.generate_password_hash("this is my password").unwrap();
+
+            let strategy =
+                Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 4, 1)
+                    .unwrap();
+            let pool = PgPool::connect_lazy("postgres://localhost/thetcauth").unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = User::new(
+                UserId(uuid::Uuid::new_v4()),
+                "alice",
+                hash.expose_secret().to_string(),
+                None,
+            )
+            .unwrap();
+
+            assert!(matches!(
+                users.upgraded_hash(&user, "not my password"),
+                Err(Error::InvalidPassword)
+            ));
+
+            let upgraded = users
+                .upgraded_hash(&user, "this is my password")
+                .unwrap()
+                .unwrap();
+            assert_ne!(upgraded.expose_secret(), hash.expose_secret());
+            assert!(upgraded.expose_secret().contains("t=4"));
+
+            let user =
+                User::new(user.id, "alice", upgraded.expose_secret().to_string(), None).unwrap();
+            assert!(users
+                .upgraded_hash(&user, "this is my password")
+                .unwrap()
+                .is_none());
+        });
+    }
+
+    #[test]
+    fn upgrade_keeps_password_changed_meanwhile() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let weak = crate::util::test_db::strategy();
+            let strategy =
+                Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 2)
+                    .unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy);
+            let alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            let stale_hash = weak.generate_password_hash("this is my password").unwrap();
+            sqlx::query("UPDATE users SET password_hash = $1 WHERE id = $2")
+                .bind(stale_hash.expose_secret())
+                .bind(*alice.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            let stale = User {
+                password_hash: stale_hash,
+                ..alice
+            };
+
+            // Logging in with the old password races a password change.
+            users
+                .change_password(&stale, "my new password")
+                .await
+                .unwrap();
+            users
+                .authenticate_and_upgrade(&stale, "this is my password")
+                .await
+                .unwrap();
+
+            let current = users.find_user_by_id(stale.id).await.unwrap();
+            assert!(users.verify_password(&current, "my new password").is_ok());
+            assert!(users
+                .verify_password(&current, "this is my password")
+                .is_err());
+        });
+    }
+
+    #[test]
+    fn stale_meta_update_conflicts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            let (a, b) = tokio::join!(
+                users.update_meta(user.id, serde_json::json!({ "a": 1 }), Some(user.version)),
+                users.update_meta(user.id, serde_json::json!({ "b": 2 }), Some(user.version)),
+            );
+            let (updated, stale) = match (a, b) {
+                (Ok(updated), stale) | (stale, Ok(updated)) => (updated, stale),
+                _ => panic!("neither update went through"),
+            };
+            assert!(matches!(stale, Err(Error::Conflict)));
+            assert_eq!(updated.version, user.version + 1);
+
+            let found = users.find_user_by_id(user.id).await.unwrap();
+            assert_eq!(found.meta, updated.meta);
+
+            // Unconditional updates always go through.
+            let forced = users
+                .update_meta(user.id, serde_json::json!({ "c": 3 }), None)
+                .await
+                .unwrap();
+            assert_eq!(forced.version, user.version + 2);
+        });
+    }
+
+    #[test]
+    fn create_users_all_or_nothing() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+
+            let err = users
+                .create_users(vec![
+                    NewUser::new("alice", "this is my password").unwrap(),
+                    NewUser::new("ALICE", "this is my password").unwrap(),
+                    NewUser::new("bob", "this is my password").unwrap(),
+                ])
+                .await
+                .unwrap_err();
+            assert_eq!(err.constraint(), Some("users_username_key"));
+            assert!(users.list_users().await.unwrap().is_empty());
+
+            let created = users
+                .create_users(vec![
+                    NewUser::new("alice", "this is my password").unwrap(),
+                    NewUser::new("bob", "this is my password").unwrap(),
+                ])
+                .await
+                .unwrap();
+            assert_eq!(created.len(), 2);
+            assert_eq!(users.list_users().await.unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn failed_create_keeps_reservation() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy)
+                .with_reservations(MemoryReservations::default());
+            let taken = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            // The insert fails on the taken id, which mustn't use up the reservation.
+            let token = users
+                .reserve_username("bob", chrono::Duration::minutes(5))
+                .await
+                .unwrap();
+            let err = users
+                .create_user(
+                    NewUser::with_id(taken.id, "bob", "this is my password")
+                        .unwrap()
+                        .with_reservation(token),
+                )
+                .await
+                .unwrap_err();
+            assert_eq!(err.constraint(), Some("users_pkey"));
+
+            // Nor does a batch that is rolled back.
+            let err = users
+                .create_users(vec![
+                    NewUser::new("bob", "this is my password")
+                        .unwrap()
+                        .with_reservation(token),
+                    NewUser::new("ALICE", "this is my password").unwrap(),
+                ])
+                .await
+                .unwrap_err();
+            assert_eq!(err.constraint(), Some("users_username_key"));
+
+            let bob = users
+                .create_user(
+                    NewUser::new("bob", "this is my password")
+                        .unwrap()
+                        .with_reservation(token),
+                )
+                .await
+                .unwrap();
+            assert_eq!(bob.username.to_string(), "bob");
+
+            // Now it's used up.
+            assert!(matches!(
+                users
+                    .create_user(
+                        NewUser::new("BOB", "this is my password")
+                            .unwrap()
+                            .with_reservation(token),
+                    )
+                    .await,
+                Err(Error::Reservation(reservation::Error::InvalidReservation))
+            ));
+        });
+    }
+
+    #[test]
+    fn change_username() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            users
+                .create_user(NewUser::new("bob", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            let renamed = users.change_username(alice.id, "alicia").await.unwrap();
+            assert_eq!(renamed.id, alice.id);
+            assert_eq!(&*renamed.username, "alicia");
+            assert_eq!(renamed.version, alice.version + 1);
+            assert!(matches!(
+                users.find_user_by_username("alice").await,
+                Err(Error::UserNotFound)
+            ));
+
+            // A stale user only ever changes its own password, not that of whoever took its
+            // old username since.
+            let new_alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            users
+                .change_password(&alice, "another password")
+                .await
+                .unwrap();
+            let renamed = users.find_user_by_id(alice.id).await.unwrap();
+            assert!(users.verify_password(&renamed, "another password").is_ok());
+            let new_alice = users.find_user_by_id(new_alice.id).await.unwrap();
+            assert!(users
+                .verify_password(&new_alice, "this is my password")
+                .is_ok());
+
+            // Usernames are case-insensitive, so this clashes with bob.
+            assert!(matches!(
+                users.change_username(alice.id, "BOB").await,
+                Err(Error::UsernameTaken)
+            ));
+            assert!(matches!(
+                users
+                    .change_username(UserId(uuid::Uuid::new_v4()), "carol")
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn reserved_names_only_checked_on_registration() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, Reserved<AsciiUsername>>::new(pool.clone(), "users", strategy);
+
+            assert!(matches!(
+                users
+                    .create_user(NewUser::new("Admin", "this is my password").unwrap())
+                    .await,
+                Err(Error::Username(_))
+            ));
+            let alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            assert!(matches!(
+                users.change_username(alice.id, "root").await,
+                Err(Error::Username(_))
+            ));
+
+            // Nor can it be provisioned, but an account from before the name was reserved is
+            // left alone.
+            let password = Secret::new("this is my password".to_string());
+            assert!(matches!(
+                users
+                    .ensure_user("admin", &password, serde_json::json!({}))
+                    .await,
+                Err(Error::Username(_))
+            ));
+            sqlx::query("UPDATE users SET username = 'admin' WHERE id = $1")
+                .bind(*alice.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            let admin = users
+                .ensure_user("admin", &password, serde_json::json!({ "seeded": true }))
+                .await
+                .unwrap();
+            assert_eq!(admin.id, alice.id);
+            assert_eq!(admin.meta, serde_json::json!({ "seeded": true }));
+            assert_eq!(users.list_users().await.unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    fn ensure_user_honours_reservations() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy)
+                .with_reservations(MemoryReservations::default());
+            users
+                .reserve_username("alice", chrono::Duration::minutes(5))
+                .await
+                .unwrap();
+
+            let password = Secret::new("this is my password".to_string());
+            assert!(matches!(
+                users
+                    .ensure_user("alice", &password, serde_json::json!({}))
+                    .await,
+                Err(Error::Reservation(reservation::Error::AlreadyReserved))
+            ));
+            assert!(matches!(
+                users.find_user_by_username("alice").await,
+                Err(Error::UserNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn list_users_reports_bad_usernames() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy);
+            users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(users.list_users().await.unwrap().len(), 1);
+
+            // Written by something that doesn't share our idea of a username.
+            sqlx::query("INSERT INTO users(username, password_hash) VALUES ('björn', '')")
+                .execute(&pool)
+                .await
+                .unwrap();
+            assert!(matches!(
+                users.list_users().await,
+                Err(Error::Sqlx(sqlx::Error::Decode(_)))
+            ));
+        });
+    }
+
+    #[test]
+    fn username_exists() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            assert!(users.username_exists("alice").await.unwrap());
+            assert!(users.username_exists("Alice").await.unwrap());
+            assert!(!users.username_exists("bob").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn soft_delete_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy =
+                Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 2)
+                    .unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy.clone())
+                .with_soft_delete();
+            let alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            users
+                .create_user(NewUser::new("bob", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            users.delete_user(alice.id).await.unwrap();
+            assert!(matches!(
+                users.find_user_by_id(alice.id).await,
+                Err(Error::UserNotFound)
+            ));
+            assert!(matches!(
+                users.find_user_by_username("alice").await,
+                Err(Error::UserNotFound)
+            ));
+            assert_eq!(users.list_users().await.unwrap().len(), 1);
+            assert_eq!(
+                users.list_users_after(None, 10).await.unwrap().users.len(),
+                1
+            );
+            assert!(users.search_users("ali", 10).await.unwrap().is_empty());
+            assert!(matches!(
+                users.delete_user(alice.id).await,
+                Err(Error::UserNotFound)
+            ));
+
+            // The row is still there, and so is its claim on the username.
+            let deleted: bool =
+                sqlx::query_scalar("SELECT deleted_at IS NOT NULL FROM users WHERE id = $1")
+                    .bind(*alice.id)
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap();
+            assert!(deleted);
+            assert!(users.username_exists("alice").await.unwrap());
+
+            // Nor can the user be changed, or log in, through a copy read before the deletion.
+            assert!(matches!(
+                users
+                    .update_meta(alice.id, serde_json::json!({}), None)
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+            assert!(matches!(
+                users.change_username(alice.id, "alicia").await,
+                Err(Error::UserNotFound)
+            ));
+            assert!(matches!(
+                users.change_password(&alice, "a new password").await,
+                Err(Error::UserNotFound)
+            ));
+            // A hash with less parallelism than the strategy's, which would be upgraded.
+            let weak = crate::util::test_db::strategy();
+            let stale = User {
+                password_hash: weak.generate_password_hash("this is my password").unwrap(),
+                ..alice
+            };
+            assert!(matches!(
+                users
+                    .authenticate_and_upgrade(&stale, "this is my password")
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+            let invited = users
+                .create_user(NewUser::invited("carol").unwrap())
+                .await
+                .unwrap();
+            users.delete_user(invited.id).await.unwrap();
+            assert!(matches!(
+                users
+                    .set_initial_password(invited.id, "this is my password")
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+
+            // Nor is a deleted user's meta patched and handed back as live, encrypted or not.
+            let password = Secret::new("this is my password".to_string());
+            assert!(matches!(
+                users
+                    .ensure_user("alice", &password, serde_json::json!({ "a": 1 }))
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+            let sealed = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy.clone())
+                .with_soft_delete()
+                .with_meta_cipher(MetaCipher::new("k1", Secret::new([7; 32])));
+            assert!(matches!(
+                sealed
+                    .ensure_user("alice", &password, serde_json::json!({ "a": 1 }))
+                    .await,
+                Err(Error::UserNotFound)
+            ));
+
+            let version: i32 = sqlx::query_scalar("SELECT version FROM users WHERE id = $1")
+                .bind(*stale.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(version, stale.version);
+        });
+    }
+
+    #[test]
+    fn updated_at_advances() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            assert_eq!(user.created_at, user.updated_at);
+
+            users
+                .change_password(&user, "this is my new password")
+                .await
+                .unwrap();
+            let changed = users.find_user_by_id(user.id).await.unwrap();
+            assert_eq!(changed.created_at, user.created_at);
+            assert!(changed.updated_at > user.updated_at);
+
+            let updated = users
+                .update_meta(user.id, serde_json::json!({ "a": 1 }), None)
+                .await
+                .unwrap();
+            assert!(updated.updated_at > changed.updated_at);
+        });
+    }
+
+    #[cfg(feature = "deadpool")]
+    #[test]
+    fn sqlx_and_deadpool_pools_share_users() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let deadpool = crate::util::test_db::deadpool_pool(&pool, 2).await;
+            let strategy = crate::util::test_db::strategy();
+            let sqlx_users = Backend::<_, AsciiUsername>::new(pool, "users", strategy.clone());
+            let deadpool_users = Backend::<_, AsciiUsername, _>::new(deadpool, "users", strategy);
+
+            let alice = sqlx_users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            let bob = deadpool_users
+                .create_user(NewUser::new("bob", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            // Each sees, and can act on, what the other wrote.
+            let found = deadpool_users.find_user_by_username("alice").await.unwrap();
+            assert_eq!(found.id, alice.id);
+            assert!(deadpool_users
+                .verify_password(&found, "this is my password")
+                .is_ok());
+            let found = sqlx_users.find_user_by_id(bob.id).await.unwrap();
+            assert_eq!(found.username.to_string(), "bob");
+
+            deadpool_users.delete_user(alice.id).await.unwrap();
+            assert!(matches!(
+                sqlx_users.find_user_by_id(alice.id).await,
+                Err(Error::UserNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn reset_password_consumes_id_once() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy.clone());
+            let alice = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            let sessions = memory::SessionManager::new(
+                true,
+                chrono::Duration::minutes(5),
+                memory::Backend::default(),
+            );
+            let reset_id = sessions
+                .generate_password_reset_id(
+                    alice.id,
+                    chrono::Utc::now() + chrono::Duration::minutes(5),
+                )
+                .await
+                .unwrap();
+            let resets = PgPasswordResetBackend::new(
+                sessions,
+                Backend::<_, AsciiUsername>::new(pool, "users", strategy),
+            );
+
+            // A password the strategy rejects leaves the id for another try.
+            assert!(resets.reset_password(reset_id, "short").await.is_err());
+
+            let (first, second) = tokio::join!(
+                resets.reset_password(reset_id, "first new password"),
+                resets.reset_password(reset_id, "second new password"),
+            );
+            let alice = users.find_user_by_id(alice.id).await.unwrap();
+            match (first, second) {
+                (Ok(()), Err(_)) => {
+                    assert!(users.verify_password(&alice, "first new password").is_ok())
+                }
+                (Err(_), Ok(())) => {
+                    assert!(users.verify_password(&alice, "second new password").is_ok())
+                }
+                _ => panic!("the reset id was used more or less than once"),
+            }
+        });
+    }
+
+    #[test]
+    fn find_missing_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+
+            assert!(matches!(
+                users.find_user_by_id(UserId(uuid::Uuid::new_v4())).await,
+                Err(Error::UserNotFound)
+            ));
+            assert!(matches!(
+                users.find_user_by_username("nobody").await,
+                Err(Error::UserNotFound)
+            ));
+        });
+    }
+
+    #[test]
+    fn delete_user() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy);
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            sqlx::query(
+                "INSERT INTO sessions(id, user_id, expires_at) \
+                 VALUES ($1, $2, now() + interval '1 hour')",
+            )
+            .bind(crate::session::SessionId::new().as_str())
+            .bind(*user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+            users.delete_user(user.id).await.unwrap();
+            assert!(matches!(
+                users.find_user_by_id(user.id).await,
+                Err(Error::UserNotFound)
+            ));
+            assert!(matches!(
+                users.delete_user(user.id).await,
+                Err(Error::UserNotFound)
+            ));
+
+            // The user's sessions went with it.
+            let sessions: i64 = sqlx::query_scalar("SELECT count(*) FROM sessions")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_eq!(sessions, 0);
+        });
+    }
+
+    #[test]
+    fn update_meta_round_trips() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", strategy)
+                .with_meta_cipher(MetaCipher::new("k1", Secret::new([7; 32])));
+            let user = users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            let meta = serde_json::json!({ "team": "blue", "flags": ["beta"] });
+            let updated = users
+                .update_meta(user.id, meta.clone(), None)
+                .await
+                .unwrap();
+            assert_eq!(updated.meta, meta);
+            assert_eq!(users.find_user_by_id(user.id).await.unwrap().meta, meta);
+
+            // Stored encrypted, not as given.
+            let stored: serde_json::Value = sqlx::query_scalar("SELECT meta FROM users")
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+            assert_ne!(stored, meta);
+
+            // Ciphertext copied to another user's row doesn't decrypt there.
+            let bob = users
+                .create_user(NewUser::new("bob", "this is my password").unwrap())
+                .await
+                .unwrap();
+            sqlx::query("UPDATE users SET meta = $1 WHERE id = $2")
+                .bind(stored)
+                .bind(*bob.id)
+                .execute(&pool)
+                .await
+                .unwrap();
+            assert!(matches!(
+                users.find_user_by_id(bob.id).await,
+                Err(Error::MetaCipher(meta_cipher::Error::Decrypt))
+            ));
+
+            // Merged outside of Postgres, both when inserting and when updating.
+            let password = Secret::new("this is my password".to_string());
+            users
+                .ensure_user("carol", &password, serde_json::json!({ "team": "red" }))
+                .await
+                .unwrap();
+            let carol = users
+                .ensure_user("carol", &password, serde_json::json!({ "admin": true }))
+                .await
+                .unwrap();
+            assert_eq!(
+                carol.meta,
+                serde_json::json!({ "team": "red", "admin": true })
+            );
+            assert_eq!(
+                users.find_user_by_id(carol.id).await.unwrap().meta,
+                carol.meta
+            );
+        });
+    }
+
+    #[test]
+    fn ensure_user_merges_meta() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+            let password = Secret::new("this is my password".to_string());
+
+            let created = users
+                .ensure_user("alice", &password, serde_json::json!({ "a": 1, "b": 1 }))
+                .await
+                .unwrap();
+            assert_eq!(created.meta, serde_json::json!({ "a": 1, "b": 1 }));
+            assert!(users
+                .verify_password(&created, "this is my password")
+                .is_ok());
+
+            let ensured = users
+                .ensure_user(
+                    "alice",
+                    &Secret::new("another password".into()),
+                    serde_json::json!({ "b": 2, "c": 3 }),
+                )
+                .await
+                .unwrap();
+            assert_eq!(ensured.id, created.id);
+            assert_eq!(ensured.meta, serde_json::json!({ "a": 1, "b": 2, "c": 3 }));
+            assert!(users
+                .verify_password(&ensured, "this is my password")
+                .is_ok());
+            assert!(users.verify_password(&ensured, "another password").is_err());
+            assert_eq!(
+                users.find_user_by_username("alice").await.unwrap().meta,
+                ensured.meta
+            );
+
+            assert!(matches!(
+                users
+                    .ensure_user("not a valid name!", &password, serde_json::json!({}))
+                    .await,
+                Err(Error::Username(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn keyset_pages_survive_inserts() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+
+            let mut ids = Vec::new();
+            for name in ["alice", "bob", "carol", "dave", "erin"] {
+                let user = users
+                    .create_user(NewUser::new(name, "this is my password").unwrap())
+                    .await
+                    .unwrap();
+                ids.push(user.id);
+            }
+            ids.sort_by_key(|id| id.0);
+
+            let mut seen = Vec::new();
+            let mut after = None;
+            loop {
+                let page = users.list_users_after(after, 2).await.unwrap();
+                assert!(page.users.len() <= 2);
+                seen.extend(page.users.iter().map(|u| u.id));
+
+                if seen.len() == 2 {
+                    users
+                        .create_user(NewUser::new("mallory", "this is my password").unwrap())
+                        .await
+                        .unwrap();
+                }
+
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+
+            // Ids only go up, so nothing repeats, and every user that was there all along shows.
+            assert!(seen.windows(2).all(|w| w[0].0 < w[1].0));
+            assert!(ids.iter().all(|id| seen.contains(id)));
+        });
+    }
+
+    #[test]
+    fn offset_pages() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+
+            let mut ids = Vec::new();
+            for name in ["alice", "bob", "carol", "dave", "erin"] {
+                let user = users
+                    .create_user(NewUser::new(name, "this is my password").unwrap())
+                    .await
+                    .unwrap();
+                ids.push(user.id);
+            }
+            ids.sort_by_key(|id| id.0);
+
+            let mut seen = Vec::new();
+            for offset in [0, 2, 4] {
+                let page = users.list_users_paged(2, offset).await.unwrap();
+                seen.extend(page.iter().map(|u| u.id));
+            }
+            assert_eq!(seen, ids);
+            assert!(users.list_users_paged(2, 6).await.unwrap().is_empty());
+
+            assert!(matches!(
+                users.list_users_paged(0, 0).await,
+                Err(Error::InvalidLimit)
+            ));
+            assert!(matches!(
+                users.list_users_after(None, 0).await,
+                Err(Error::InvalidLimit)
+            ));
+            assert!(matches!(
+                users.users_needing_rehash(-1, 0).await,
+                Err(Error::InvalidLimit)
+            ));
+            assert!(matches!(
+                users.search_users("a", 0).await,
+                Err(Error::InvalidLimit)
+            ));
+            assert_eq!(users.list_users_paged(5000, 0).await.unwrap().len(), 5);
+            assert_eq!(users.search_users("a", 5000).await.unwrap().len(), 3);
+        });
+    }
+
+    #[test]
+    fn users_needing_rehash() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let weak = crate::util::test_db::strategy();
+            let weak_users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", weak);
+            let strong =
+                Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 3, 1)
+                    .unwrap();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strong);
+
+            let mut weak_ids = Vec::new();
+            for name in ["alice", "bob", "carol"] {
+                let user = weak_users
+                    .create_user(NewUser::new(name, "this is my password").unwrap())
+                    .await
+                    .unwrap();
+                weak_ids.push(user.id);
+            }
+            let strong_user = users
+                .create_user(NewUser::new("dave", "this is my password").unwrap())
+                .await
+                .unwrap();
+            weak_ids.sort_by_key(|id| id.0);
+
+            assert_eq!(users.users_needing_rehash(10, 0).await.unwrap(), weak_ids);
+            assert_eq!(
+                users.users_needing_rehash(1, 1).await.unwrap(),
+                &weak_ids[1..2]
+            );
+            assert!(!users
+                .users_needing_rehash(10, 0)
+                .await
+                .unwrap()
+                .contains(&strong_user.id));
+            // Hashes differing either way are reported.
+            assert_eq!(
+                weak_users.users_needing_rehash(10, 0).await.unwrap(),
+                vec![strong_user.id]
+            );
+        });
+    }
+
+    #[test]
+    fn users_needing_rehash_after_pepper_rotation() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let old = crate::util::test_db::strategy()
+                .with_pepper_id(b"1")
+                .unwrap();
+            let old_users = Backend::<_, AsciiUsername>::new(pool.clone(), "users", old);
+            let rotated = Argon2idStrategy::new(Secret::new("a brand new pepper".into()), 15, 2, 1)
+                .unwrap()
+                .with_pepper_id(b"2")
+                .unwrap()
+                .with_retired_pepper(Secret::new("hello pepper is my friend".into()));
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", rotated);
+
+            let old_user = old_users
+                .create_user(NewUser::new("alice", "this is my password").unwrap())
+                .await
+                .unwrap();
+            users
+                .create_user(NewUser::new("bob", "this is my password").unwrap())
+                .await
+                .unwrap();
+
+            assert_eq!(
+                users.users_needing_rehash(10, 0).await.unwrap(),
+                vec![old_user.id]
+            );
+        });
+    }
+
+    #[test]
+    fn invited_user_sets_initial_password() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+
+        rt.block_on(async move {
+            let pool = crate::util::test_db::pool().await;
+            let strategy = crate::util::test_db::strategy();
+            let users = Backend::<_, AsciiUsername>::new(pool, "users", strategy);
+
+            let invited = users
+                .create_user(NewUser::invited("alice").unwrap())
+                .await
+                .unwrap();
+            assert!(!invited.has_password());
+            for password in ["", "!", "this is my password"] {
+                assert!(matches!(
+                    users.verify_password(&invited, password),
+                    Err(Error::PasswordNotSet)
+                ));
+            }
+            assert!(matches!(
+                users.verify_password_or_dummy(Some(&invited), "!"),
+                Err(Error::InvalidPassword)
+            ));
+
+            let claimed = users
+                .set_initial_password(invited.id, "this is my password")
+                .await
+                .unwrap();
+            assert!(claimed.has_password());
+            assert!(users
+                .verify_password(&claimed, "this is my password")
+                .is_ok());
+
+            // The invite can't be used again to take the account over.
+            assert!(matches!(
+                users
+                    .set_initial_password(invited.id, "this is not my password")
+                    .await,
+                Err(Error::PasswordAlreadySet)
+            ));
+            let found = users.find_user_by_id(invited.id).await.unwrap();
+            assert!(users.verify_password(&found, "this is my password").is_ok());
+        });
     }
 }
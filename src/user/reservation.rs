@@ -0,0 +1,293 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use deadpool_redis::{Config, Runtime};
+
+#[nova::newtype(serde, copy)]
+pub type ReservationToken = uuid::Uuid;
+
+impl ReservationToken {
+    pub fn new() -> Self {
+        ReservationToken(uuid::Uuid::new_v4())
+    }
+}
+
+impl Default for ReservationToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Username is already reserved.")]
+    AlreadyReserved,
+
+    #[error("Reservation has expired or belongs to someone else.")]
+    InvalidReservation,
+
+    #[error("Error establishing connection to Redis pool")]
+    Pool(#[from] deadpool_redis::PoolError),
+
+    #[error("Redis error")]
+    Redis(#[from] redis::RedisError),
+}
+
+/// Holds usernames for a while, so that multi-step signups don't race for the same name.
+#[async_trait]
+pub trait UsernameReservations: Send + Sync {
+    /// Reserves `username` for `ttl`, failing if someone else holds it.
+    async fn reserve_username(
+        &self,
+        username: &str,
+        ttl: Duration,
+    ) -> Result<ReservationToken, Error>;
+
+    async fn is_reserved(&self, username: &str) -> Result<bool, Error>;
+
+    /// Fails like [`UsernameReservations::consume_reservation`] would, without releasing the
+    /// reservation, so that it can be checked before work that may still fail. Defaults to
+    /// passing, leaving the check to `consume_reservation`.
+    async fn check_reservation(
+        &self,
+        _username: &str,
+        _token: ReservationToken,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Releases the reservation, failing if it expired or `token` doesn't match.
+    async fn consume_reservation(
+        &self,
+        username: &str,
+        token: ReservationToken,
+    ) -> Result<(), Error>;
+}
+
+/// Usernames are unique case-insensitively, so are their reservations.
+fn reservation_key(username: &str) -> String {
+    format!("username-reservation/{}", username.trim().to_lowercase())
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryReservations {
+    reservations: Mutex<HashMap<String, (ReservationToken, DateTime<Utc>)>>,
+}
+
+#[async_trait]
+impl UsernameReservations for MemoryReservations {
+    async fn reserve_username(
+        &self,
+        username: &str,
+        ttl: Duration,
+    ) -> Result<ReservationToken, Error> {
+        let mut guard = self.reservations.lock().unwrap();
+        let key = reservation_key(username);
+
+        if let Some((_, expires_at)) = guard.get(&key) {
+            if Utc::now() < *expires_at {
+                return Err(Error::AlreadyReserved);
+            }
+        }
+
+        let token = ReservationToken::new();
+        guard.insert(key, (token, Utc::now() + ttl));
+        Ok(token)
+    }
+
+    async fn is_reserved(&self, username: &str) -> Result<bool, Error> {
+        let guard = self.reservations.lock().unwrap();
+        Ok(match guard.get(&reservation_key(username)) {
+            Some((_, expires_at)) => Utc::now() < *expires_at,
+            None => false,
+        })
+    }
+
+    async fn check_reservation(
+        &self,
+        username: &str,
+        token: ReservationToken,
+    ) -> Result<(), Error> {
+        let guard = self.reservations.lock().unwrap();
+        match guard.get(&reservation_key(username)) {
+            Some((held, expires_at)) if *held == token && Utc::now() < *expires_at => Ok(()),
+            _ => Err(Error::InvalidReservation),
+        }
+    }
+
+    async fn consume_reservation(
+        &self,
+        username: &str,
+        token: ReservationToken,
+    ) -> Result<(), Error> {
+        let mut guard = self.reservations.lock().unwrap();
+        let key = reservation_key(username);
+
+        match guard.get(&key) {
+            Some((held, expires_at)) if *held == token && Utc::now() < *expires_at => {
+                guard.remove(&key);
+                Ok(())
+            }
+            _ => Err(Error::InvalidReservation),
+        }
+    }
+}
+
+/// Deletes the reservation (KEYS[1]) only if it is held with the given token (ARGV[1]).
+const CONSUME_RESERVATION: &str = r#"
+    if redis.call("GET", KEYS[1]) == ARGV[1] then
+        return redis.call("DEL", KEYS[1])
+    end
+    return 0
+"#;
+
+pub struct RedisReservations {
+    pool: deadpool_redis::Pool,
+}
+
+impl RedisReservations {
+    pub fn new(url: &str) -> Result<Self, deadpool_redis::CreatePoolError> {
+        let config = Config::from_url(url);
+        let pool = config.create_pool(Some(Runtime::Tokio1))?;
+        Ok(Self { pool })
+    }
+
+    pub fn with_pool(pool: deadpool_redis::Pool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl UsernameReservations for RedisReservations {
+    async fn reserve_username(
+        &self,
+        username: &str,
+        ttl: Duration,
+    ) -> Result<ReservationToken, Error> {
+        let mut conn = self.pool.get().await?;
+        let token = ReservationToken::new();
+
+        let reserved: Option<String> = redis::cmd("SET")
+            .arg(reservation_key(username))
+            .arg(token.to_string())
+            .arg("NX")
+            .arg("PX")
+            .arg(ttl.num_milliseconds())
+            .query_async(&mut conn)
+            .await?;
+
+        match reserved {
+            Some(_) => Ok(token),
+            None => Err(Error::AlreadyReserved),
+        }
+    }
+
+    async fn is_reserved(&self, username: &str) -> Result<bool, Error> {
+        let mut conn = self.pool.get().await?;
+        Ok(redis::cmd("EXISTS")
+            .arg(reservation_key(username))
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    async fn check_reservation(
+        &self,
+        username: &str,
+        token: ReservationToken,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        let held: Option<String> = redis::cmd("GET")
+            .arg(reservation_key(username))
+            .query_async(&mut conn)
+            .await?;
+
+        match held {
+            Some(held) if held == token.to_string() => Ok(()),
+            _ => Err(Error::InvalidReservation),
+        }
+    }
+
+    async fn consume_reservation(
+        &self,
+        username: &str,
+        token: ReservationToken,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get().await?;
+        let deleted: i64 = redis::Script::new(CONSUME_RESERVATION)
+            .key(reservation_key(username))
+            .arg(token.to_string())
+            .invoke_async(&mut conn)
+            .await?;
+
+        match deleted {
+            1 => Ok(()),
+            _ => Err(Error::InvalidReservation),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserve_then_consume() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let reservations = MemoryReservations::default();
+            let token = reservations
+                .reserve_username("Alice", Duration::minutes(5))
+                .await
+                .unwrap();
+
+            assert!(reservations.is_reserved("alice").await.unwrap());
+            assert!(matches!(
+                reservations
+                    .reserve_username("alice", Duration::minutes(5))
+                    .await,
+                Err(Error::AlreadyReserved)
+            ));
+            assert!(matches!(
+                reservations
+                    .consume_reservation("alice", ReservationToken::new())
+                    .await,
+                Err(Error::InvalidReservation)
+            ));
+            reservations
+                .check_reservation("ALICE", token)
+                .await
+                .unwrap();
+            assert!(matches!(
+                reservations
+                    .check_reservation("alice", ReservationToken::new())
+                    .await,
+                Err(Error::InvalidReservation)
+            ));
+
+            reservations
+                .consume_reservation("alice", token)
+                .await
+                .unwrap();
+            assert!(!reservations.is_reserved("alice").await.unwrap());
+        });
+    }
+
+    #[test]
+    fn expired_reservation() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let reservations = MemoryReservations::default();
+            let token = reservations
+                .reserve_username("alice", Duration::seconds(-1))
+                .await
+                .unwrap();
+
+            assert!(!reservations.is_reserved("alice").await.unwrap());
+            assert!(matches!(
+                reservations.consume_reservation("alice", token).await,
+                Err(Error::InvalidReservation)
+            ));
+        });
+    }
+}
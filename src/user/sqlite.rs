@@ -0,0 +1,1102 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use secrecy::{ExposeSecret, Secret};
+use sqlx::{Connection, SqliteConnection, SqlitePool};
+
+use crate::{
+    password_strategy::Strategy,
+    username::{Username, UsernameType},
+};
+
+use super::{
+    merge_meta, reservation::ReservationToken, NewUser, User, UserBackend, UserId, UserPage,
+    NO_PASSWORD_HASH,
+};
+
+/// SQLite's extended result code for unique violations, `SQLITE_CONSTRAINT_UNIQUE`.
+const UNIQUE_VIOLATION: &str = "2067";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("sqlx error: {0}")]
+    Sqlx(#[from] sqlx::Error),
+
+    #[error("invalid username")]
+    Username(#[source] Box<dyn std::error::Error + Sync + Send>),
+
+    #[error("password error")]
+    Password(#[from] crate::password_strategy::Error),
+
+    #[error("The entered password was invalid.")]
+    InvalidPassword,
+
+    #[error("The user has not set a password yet.")]
+    PasswordNotSet,
+
+    #[error("The user already has a password.")]
+    PasswordAlreadySet,
+
+    #[error("The SQLite backend doesn't support username reservations.")]
+    ReservationsUnavailable,
+
+    #[error("The user was updated by someone else since it was read.")]
+    Conflict,
+
+    #[error("The user does not exist.")]
+    UserNotFound,
+
+    #[error("The username is already taken.")]
+    UsernameTaken,
+
+    #[error("The page limit must be positive.")]
+    InvalidLimit,
+
+    #[error(transparent)]
+    Unsupported(#[from] super::Unsupported),
+}
+
+/// SQLite user backend, for local development and small single-node deployments where running
+/// Postgres is overkill.
+///
+/// The table needs the columns [`crate::schema::sqlite_users`] creates. Usernames are looked up
+/// ignoring ASCII case only, as SQLite doesn't fold the case of other characters. Unlike the
+/// Postgres backend, there are no username reservations, encrypted meta or soft deletes.
+///
+/// There is no SQLite session backend. A single node can keep its sessions in
+/// [`crate::session::memory`], at the cost of logging everyone out on restart.
+pub struct Backend<S: Strategy, U: UsernameType> {
+    strategy: S,
+    pool: SqlitePool,
+    table_name: &'static str,
+    _username: PhantomData<U>,
+}
+
+impl<S: Strategy, U: UsernameType> Backend<S, U> {
+    pub fn new(pool: SqlitePool, table_name: &'static str, strategy: S) -> Self {
+        Self {
+            strategy,
+            pool,
+            table_name,
+            _username: PhantomData,
+        }
+    }
+}
+
+/// Turns a missing row into [`Error::UserNotFound`], for queries looking up a single user.
+fn user_not_found(e: sqlx::Error) -> Error {
+    match e {
+        sqlx::Error::RowNotFound => Error::UserNotFound,
+        e => Error::Sqlx(e),
+    }
+}
+
+/// Tells a rename onto an existing username apart from other update failures.
+fn rename_error(e: sqlx::Error) -> Error {
+    let is_unique_violation = e
+        .as_database_error()
+        .and_then(|e| e.code())
+        .map_or(false, |code| code == UNIQUE_VIOLATION);
+
+    match is_unique_violation {
+        true => Error::UsernameTaken,
+        false => user_not_found(e),
+    }
+}
+
+async fn create_user<S: Strategy, U: UsernameType>(
+    conn: &mut SqliteConnection,
+    strategy: &S,
+    table_name: &'static str,
+    user: NewUser<U>,
+) -> Result<User<U>, Error> {
+    if user.reservation.is_some() {
+        return Err(Error::ReservationsUnavailable);
+    }
+    user.username
+        .check_registrable()
+        .map_err(|e| Error::Username(Box::new(e)))?;
+
+    let password_hash = match user.password {
+        Some(password) => strategy.generate_password_hash(password.expose_secret())?,
+        None => Secret::new(NO_PASSWORD_HASH.to_string()),
+    };
+    let id = user.id.unwrap_or_else(|| UserId(uuid::Uuid::new_v4()));
+    Ok(database::insert_user(
+        conn,
+        id,
+        user.username,
+        password_hash,
+        user.meta,
+        table_name,
+    )
+    .await?)
+}
+
+#[async_trait]
+impl<S, U> UserBackend<S, U> for Backend<S, U>
+where
+    S: Strategy,
+    U: UsernameType,
+{
+    type Error = Error;
+
+    async fn create_user(&self, user: NewUser<U>) -> Result<User<U>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        create_user(&mut conn, &self.strategy, self.table_name, user).await
+    }
+
+    async fn create_users(&self, users: Vec<NewUser<U>>) -> Result<Vec<User<U>>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let mut tx = conn.begin().await?;
+        let mut created = Vec::with_capacity(users.len());
+        for user in users {
+            created.push(create_user(&mut tx, &self.strategy, self.table_name, user).await?);
+        }
+        tx.commit().await?;
+        Ok(created)
+    }
+
+    async fn ensure_user(
+        &self,
+        username: &str,
+        default_password: &Secret<String>,
+        meta_patch: serde_json::Value,
+    ) -> Result<User<U>, Self::Error> {
+        let username = username
+            .parse::<Username<U>>()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        let mut conn = self.pool.acquire().await?;
+        // Existing users are left their names, but new ones have to pass the same checks as on
+        // registration.
+        if !database::username_exists(&mut conn, &username, self.table_name).await? {
+            username
+                .check_registrable()
+                .map_err(|e| Error::Username(Box::new(e)))?;
+        }
+        // Hashed up front, as whether the user exists is only known once the insert ran.
+        let password_hash = self
+            .strategy
+            .generate_password_hash(default_password.expose_secret())?;
+
+        // SQLite has no `jsonb || jsonb`, so the merge happens here. The insert takes the
+        // database's write lock, which keeps others out until the merged meta is written.
+        let mut tx = conn.begin().await?;
+        let inserted = database::insert_user_if_absent(
+            &mut tx,
+            UserId(uuid::Uuid::new_v4()),
+            username.clone(),
+            password_hash,
+            meta_patch.clone(),
+            self.table_name,
+        )
+        .await?;
+        let user = match inserted {
+            Some(user) => user,
+            None => {
+                let user =
+                    database::find_user_by_username::<U>(&mut tx, &username, self.table_name)
+                        .await?;
+                let meta = merge_meta(user.meta, meta_patch);
+                database::set_meta(&mut tx, user.id, meta, self.table_name).await?
+            }
+        };
+        tx.commit().await?;
+        Ok(user)
+    }
+
+    async fn reserve_username(
+        &self,
+        _username: &str,
+        _ttl: chrono::Duration,
+    ) -> Result<ReservationToken, Self::Error> {
+        Err(Error::ReservationsUnavailable)
+    }
+
+    async fn find_user_by_id(&self, id: UserId) -> Result<User<U>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        database::find_user_by_id(&mut conn, id, self.table_name)
+            .await
+            .map_err(user_not_found)
+    }
+
+    async fn find_user_by_username(&self, username: &str) -> Result<User<U>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        database::find_user_by_username(&mut conn, username, self.table_name)
+            .await
+            .map_err(user_not_found)
+    }
+
+    async fn username_exists(&self, username: &str) -> Result<bool, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::username_exists(&mut conn, username, self.table_name).await?)
+    }
+
+    async fn list_users(&self) -> Result<Vec<User<U>>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::list_users_after(&mut conn, None, -1, self.table_name).await?)
+    }
+
+    async fn list_users_paged(&self, limit: i64, offset: i64) -> Result<Vec<User<U>>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let limit = limit.min(database::MAX_PAGE_LIMIT);
+        Ok(database::list_users_paged(&mut conn, limit, offset.max(0), self.table_name).await?)
+    }
+
+    async fn list_users_after(
+        &self,
+        after: Option<UserId>,
+        limit: i64,
+    ) -> Result<UserPage<U>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        let limit = limit.min(database::MAX_PAGE_LIMIT);
+        let users = database::list_users_after(&mut conn, after, limit, self.table_name).await?;
+
+        // A short page is the last one. A full one may be too, which the next call finds out.
+        let next = match users.len() as i64 == limit {
+            true => users.last().map(|u| u.id),
+            false => None,
+        };
+        Ok(UserPage { users, next })
+    }
+
+    async fn users_needing_rehash(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<UserId>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let limit = limit.min(database::MAX_PAGE_LIMIT) as usize;
+        let mut to_skip = offset.max(0) as usize;
+        let mut user_ids = Vec::new();
+        let mut after = None;
+        let mut conn = self.pool.acquire().await?;
+
+        while user_ids.len() < limit {
+            let hashes = database::list_password_hashes_after(
+                &mut conn,
+                after,
+                database::MAX_PAGE_LIMIT,
+                self.table_name,
+            )
+            .await?;
+            let last_page = (hashes.len() as i64) < database::MAX_PAGE_LIMIT;
+            after = hashes.last().map(|(id, _)| *id);
+
+            for (id, hash) in hashes {
+                if hash == NO_PASSWORD_HASH || !self.strategy.needs_rehash(&hash).unwrap_or(true) {
+                    continue;
+                }
+                match to_skip {
+                    0 => user_ids.push(id),
+                    _ => to_skip -= 1,
+                }
+                if user_ids.len() == limit {
+                    break;
+                }
+            }
+
+            if last_page {
+                break;
+            }
+        }
+
+        Ok(user_ids)
+    }
+
+    async fn search_users(&self, query: &str, limit: i64) -> Result<Vec<User<U>>, Self::Error> {
+        if limit <= 0 {
+            return Err(Error::InvalidLimit);
+        }
+
+        let mut conn = self.pool.acquire().await?;
+        Ok(database::search_users(&mut conn, query, limit, self.table_name).await?)
+    }
+
+    async fn update_meta(
+        &self,
+        id: UserId,
+        meta: serde_json::Value,
+        expected_version: Option<i32>,
+    ) -> Result<User<U>, Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        match database::update_meta(&mut conn, id, meta, expected_version, self.table_name).await? {
+            Some(user) => Ok(user),
+            // Either the user is gone or it moved on.
+            None => {
+                database::find_user_by_id::<U>(&mut conn, id, self.table_name)
+                    .await
+                    .map_err(user_not_found)?;
+                Err(Error::Conflict)
+            }
+        }
+    }
+
+    async fn delete_user(&self, id: UserId) -> Result<(), Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        match database::delete_user(&mut conn, id, self.table_name).await? {
+            true => Ok(()),
+            false => Err(Error::UserNotFound),
+        }
+    }
+
+    async fn change_username(
+        &self,
+        id: UserId,
+        new_username: &str,
+    ) -> Result<User<U>, Self::Error> {
+        let username = new_username
+            .parse::<Username<U>>()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+        username
+            .check_registrable()
+            .map_err(|e| Error::Username(Box::new(e)))?;
+
+        let mut conn = self.pool.acquire().await?;
+        database::set_username(&mut conn, id, username, self.table_name)
+            .await
+            .map_err(rename_error)
+    }
+
+    fn verify_password(&self, user: &User<U>, password: &str) -> Result<(), Self::Error> {
+        if !user.has_password() {
+            return Err(Error::PasswordNotSet);
+        }
+
+        match self
+            .strategy
+            .verify_password(user.password_hash.expose_secret(), password)?
+        {
+            true => Ok(()),
+            false => Err(Error::InvalidPassword),
+        }
+    }
+
+    async fn change_password(&self, user: &User<U>, new_password: &str) -> Result<(), Self::Error> {
+        let mut conn = self.pool.acquire().await?;
+        let password_hash = self.strategy.generate_password_hash(new_password)?;
+        database::set_password(&mut conn, user.id, password_hash, self.table_name)
+            .await
+            .map_err(user_not_found)
+    }
+
+    async fn set_initial_password(
+        &self,
+        id: UserId,
+        password: &str,
+    ) -> Result<User<U>, Self::Error> {
+        let password_hash = self.strategy.generate_password_hash(password)?;
+        let mut conn = self.pool.acquire().await?;
+        match database::set_initial_password(&mut conn, id, password_hash, self.table_name).await? {
+            Some(user) => Ok(user),
+            // Either the user is gone or it has a password.
+            None => {
+                database::find_user_by_id::<U>(&mut conn, id, self.table_name)
+                    .await
+                    .map_err(user_not_found)?;
+                Err(Error::PasswordAlreadySet)
+            }
+        }
+    }
+}
+
+mod database {
+    use chrono::Utc;
+    use secrecy::{ExposeSecret, Secret};
+    use sqlx::{sqlite::SqliteRow, Row, SqliteConnection};
+
+    use crate::{
+        username::{Username, UsernameType},
+        util::escape_like,
+    };
+
+    use super::{User, UserId, NO_PASSWORD_HASH};
+
+    /// Upper bound on the number of rows returned by a search.
+    const MAX_SEARCH_LIMIT: i64 = 100;
+
+    /// Upper bound on the number of rows in a page.
+    pub const MAX_PAGE_LIMIT: i64 = 1000;
+
+    /// The columns [`user_from_row`] reads, in order.
+    const COLUMNS: &str = "id, username, password_hash, meta, version, created_at, updated_at";
+
+    fn user_from_row<U: UsernameType>(r: &SqliteRow) -> Result<User<U>, sqlx::Error> {
+        let raw_username: String = r.get(1);
+        let username: Username<U> = match raw_username.parse() {
+            Ok(v) => v,
+            Err(e) => return Err(sqlx::Error::Decode(Box::new(e))),
+        };
+
+        Ok(User {
+            id: UserId(r.get(0)),
+            username,
+            password_hash: Secret::new(r.get(2)),
+            meta: r.get(3),
+            version: r.get(4),
+            created_at: r.get(5),
+            updated_at: r.get(6),
+        })
+    }
+
+    pub async fn insert_user<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        username: Username<U>,
+        password_hash: Secret<String>,
+        meta: serde_json::Value,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                INSERT INTO {}(id, username, password_hash, meta, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(*id)
+        .bind(&*username)
+        .bind(password_hash.expose_secret())
+        .bind(meta)
+        .bind(Utc::now())
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    /// Inserts the user unless the username is taken, returning it if it was inserted.
+    pub async fn insert_user_if_absent<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        username: Username<U>,
+        password_hash: Secret<String>,
+        meta: serde_json::Value,
+        table_name: &'static str,
+    ) -> Result<Option<User<U>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                INSERT INTO {}(id, username, password_hash, meta, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?5)
+                ON CONFLICT (username) DO NOTHING
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(*id)
+        .bind(&*username)
+        .bind(password_hash.expose_secret())
+        .bind(meta)
+        .bind(Utc::now())
+        .fetch_optional(conn)
+        .await?;
+
+        r.as_ref().map(user_from_row).transpose()
+    }
+
+    pub async fn set_meta<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        meta: serde_json::Value,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET meta = ?1, version = version + 1, updated_at = ?2
+                WHERE id = ?3
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(meta)
+        .bind(Utc::now())
+        .bind(*id)
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    /// Replaces the meta of the user, if it is at `expected_version` when given. Returns `None`
+    /// if no row matched.
+    pub async fn update_meta<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        meta: serde_json::Value,
+        expected_version: Option<i32>,
+        table_name: &'static str,
+    ) -> Result<Option<User<U>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET meta = ?1, version = version + 1, updated_at = ?2
+                WHERE id = ?3 AND (?4 IS NULL OR version = ?4)
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(meta)
+        .bind(Utc::now())
+        .bind(*id)
+        .bind(expected_version)
+        .fetch_optional(conn)
+        .await?;
+
+        r.as_ref().map(user_from_row).transpose()
+    }
+
+    /// Sets the password hash if the user has none yet, returning `None` if it has.
+    pub async fn set_initial_password<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        password_hash: Secret<String>,
+        table_name: &'static str,
+    ) -> Result<Option<User<U>>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET password_hash = ?1, version = version + 1, updated_at = ?2
+                WHERE id = ?3 AND password_hash = ?4
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(password_hash.expose_secret())
+        .bind(Utc::now())
+        .bind(*id)
+        .bind(NO_PASSWORD_HASH)
+        .fetch_optional(conn)
+        .await?;
+
+        r.as_ref().map(user_from_row).transpose()
+    }
+
+    pub async fn set_password(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        password_hash: Secret<String>,
+        table_name: &'static str,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(&format!(
+            r#"
+                UPDATE {} SET password_hash = ?1, version = version + 1, updated_at = ?2
+                WHERE id = ?3
+                RETURNING id
+            "#,
+            table_name
+        ))
+        .bind(password_hash.expose_secret())
+        .bind(Utc::now())
+        .bind(*id)
+        .fetch_one(conn)
+        .await?;
+
+        Ok(())
+    }
+
+    pub async fn set_username<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        username: Username<U>,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            r#"
+                UPDATE {} SET username = ?1, version = version + 1, updated_at = ?2
+                WHERE id = ?3
+                RETURNING {}
+            "#,
+            table_name, COLUMNS
+        ))
+        .bind(&*username)
+        .bind(Utc::now())
+        .bind(*id)
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    /// Returns whether there was a user to delete.
+    pub async fn delete_user(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(&format!("DELETE FROM {} WHERE id = ?1", table_name))
+            .bind(*id)
+            .execute(conn)
+            .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn find_user_by_id<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        id: UserId,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            "SELECT {} FROM {} WHERE id = ?1",
+            COLUMNS, table_name
+        ))
+        .bind(*id)
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    pub async fn find_user_by_username<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        username: &str,
+        table_name: &'static str,
+    ) -> Result<User<U>, sqlx::Error> {
+        let r = sqlx::query(&format!(
+            "SELECT {} FROM {} WHERE username = ?1",
+            COLUMNS, table_name
+        ))
+        .bind(username)
+        .fetch_one(conn)
+        .await?;
+
+        user_from_row(&r)
+    }
+
+    pub async fn username_exists(
+        conn: &mut SqliteConnection,
+        username: &str,
+        table_name: &'static str,
+    ) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar(&format!(
+            "SELECT EXISTS(SELECT 1 FROM {} WHERE username = ?1)",
+            table_name
+        ))
+        .bind(username)
+        .fetch_one(conn)
+        .await
+    }
+
+    pub async fn list_users_paged<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        limit: i64,
+        offset: i64,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM {} ORDER BY id LIMIT ?1 OFFSET ?2",
+            COLUMNS, table_name
+        ))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+
+    /// Lists up to `limit` users with ids after `after`, or all of them if `limit` is negative.
+    pub async fn list_users_after<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        after: Option<UserId>,
+        limit: i64,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT {} FROM {}
+                WHERE ?1 IS NULL OR id > ?1
+                ORDER BY id
+                LIMIT ?2
+            "#,
+            COLUMNS, table_name
+        ))
+        .bind(after.map(|id| *id))
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+
+    /// Like [`list_users_after`], but only reads ids and password hashes.
+    pub async fn list_password_hashes_after(
+        conn: &mut SqliteConnection,
+        after: Option<UserId>,
+        limit: i64,
+        table_name: &'static str,
+    ) -> Result<Vec<(UserId, String)>, sqlx::Error> {
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT id, password_hash FROM {}
+                WHERE ?1 IS NULL OR id > ?1
+                ORDER BY id
+                LIMIT ?2
+            "#,
+            table_name
+        ))
+        .bind(after.map(|id| *id))
+        .bind(limit)
+        .fetch_all(conn)
+        .await?;
+
+        Ok(rows.iter().map(|r| (UserId(r.get(0)), r.get(1))).collect())
+    }
+
+    pub async fn search_users<U: UsernameType>(
+        conn: &mut SqliteConnection,
+        query: &str,
+        limit: i64,
+        table_name: &'static str,
+    ) -> Result<Vec<User<U>>, sqlx::Error> {
+        // LIKE ignores ASCII case in SQLite.
+        let rows = sqlx::query(&format!(
+            r#"
+                SELECT {} FROM {}
+                WHERE username LIKE ?1 ESCAPE '\'
+                ORDER BY username
+                LIMIT ?2
+            "#,
+            COLUMNS, table_name
+        ))
+        .bind(format!("%{}%", escape_like(query)))
+        .bind(limit.clamp(0, MAX_SEARCH_LIMIT))
+        .fetch_all(conn)
+        .await?;
+
+        rows.iter().map(user_from_row).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use secrecy::Secret;
+
+    use crate::{
+        password_strategy::Argon2idStrategy,
+        user::{NewUser, UserBackend, UserId},
+        username::{ascii::AsciiUsername, reserved::Reserved},
+        util::test_db,
+    };
+
+    use super::{Backend, Error};
+
+    async fn backend() -> Backend<Argon2idStrategy, AsciiUsername> {
+        let strategy = crate::util::test_db::strategy();
+        Backend::new(test_db::sqlite_pool().await, "users", strategy)
+    }
+
+    #[test]
+    fn user_happy_path() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+
+                let mut new_user = NewUser::new("Alice", "this is my password").unwrap();
+                new_user.meta = serde_json::json!({ "team": "blue" });
+                let created = users.create_user(new_user).await.unwrap();
+                assert_eq!(&*created.username, "Alice");
+                assert_eq!(created.version, 0);
+
+                let found = users.find_user_by_username("ALICE").await.unwrap();
+                assert_eq!(found.id, created.id);
+                assert_eq!(found.meta, serde_json::json!({ "team": "blue" }));
+                assert_eq!(found.created_at, created.created_at);
+                assert!(users.username_exists("alice").await.unwrap());
+                assert!(!users.username_exists("bob").await.unwrap());
+
+                assert!(users.verify_password(&found, "this is my password").is_ok());
+                users
+                    .change_password(&found, "this is my new password")
+                    .await
+                    .unwrap();
+                let found = users.find_user_by_id(created.id).await.unwrap();
+                assert_eq!(found.version, 1);
+                assert!(matches!(
+                    users.verify_password(&found, "this is my password"),
+                    Err(Error::InvalidPassword)
+                ));
+                assert!(users
+                    .verify_password(&found, "this is my new password")
+                    .is_ok());
+
+                users.delete_user(created.id).await.unwrap();
+                assert!(matches!(
+                    users.find_user_by_id(created.id).await,
+                    Err(Error::UserNotFound)
+                ));
+                assert!(matches!(
+                    users.delete_user(created.id).await,
+                    Err(Error::UserNotFound)
+                ));
+            });
+    }
+
+    #[test]
+    fn usernames_are_unique() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                users
+                    .create_user(NewUser::new("alice", "this is my password").unwrap())
+                    .await
+                    .unwrap();
+                assert!(users
+                    .create_user(NewUser::new("ALICE", "this is my password").unwrap())
+                    .await
+                    .is_err());
+
+                let bob = users
+                    .create_user(NewUser::new("bob", "this is my password").unwrap())
+                    .await
+                    .unwrap();
+                assert!(matches!(
+                    users.change_username(bob.id, "Alice").await,
+                    Err(Error::UsernameTaken)
+                ));
+                let renamed = users.change_username(bob.id, "robert").await.unwrap();
+                assert_eq!(&*renamed.username, "robert");
+                assert!(matches!(
+                    users
+                        .change_username(UserId(uuid::Uuid::new_v4()), "carol")
+                        .await,
+                    Err(Error::UserNotFound)
+                ));
+            });
+    }
+
+    #[test]
+    fn create_users_all_or_nothing() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                let batch = vec![
+                    NewUser::new("alice", "this is my password").unwrap(),
+                    NewUser::new("alice", "this is my password").unwrap(),
+                ];
+                assert!(users.create_users(batch).await.is_err());
+                assert!(users.list_users().await.unwrap().is_empty());
+
+                let batch = vec![
+                    NewUser::new("alice", "this is my password").unwrap(),
+                    NewUser::new("bob", "this is my password").unwrap(),
+                ];
+                assert_eq!(users.create_users(batch).await.unwrap().len(), 2);
+                assert_eq!(users.list_users().await.unwrap().len(), 2);
+            });
+    }
+
+    #[test]
+    fn ensure_user_merges_meta() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                let password = Secret::new("this is my password".to_string());
+
+                let created = users
+                    .ensure_user("alice", &password, serde_json::json!({ "a": 1, "b": 1 }))
+                    .await
+                    .unwrap();
+                let ensured = users
+                    .ensure_user(
+                        "alice",
+                        &Secret::new("another password".into()),
+                        serde_json::json!({ "b": 2 }),
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(ensured.id, created.id);
+                assert_eq!(ensured.meta, serde_json::json!({ "a": 1, "b": 2 }));
+                assert!(users
+                    .verify_password(&ensured, "this is my password")
+                    .is_ok());
+            });
+    }
+
+    #[test]
+    fn ensure_user_checks_new_usernames() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let strategy = crate::util::test_db::strategy();
+                let pool = test_db::sqlite_pool().await;
+                let users =
+                    Backend::<_, Reserved<AsciiUsername>>::new(pool.clone(), "users", strategy);
+                let password = Secret::new("this is my password".to_string());
+
+                assert!(matches!(
+                    users
+                        .ensure_user("admin", &password, serde_json::json!({}))
+                        .await,
+                    Err(Error::Username(_))
+                ));
+
+                // One from before the name was reserved is left alone.
+                let alice = users
+                    .ensure_user("alice", &password, serde_json::json!({}))
+                    .await
+                    .unwrap();
+                sqlx::query("UPDATE users SET username = 'admin' WHERE id = ?1")
+                    .bind(*alice.id)
+                    .execute(&pool)
+                    .await
+                    .unwrap();
+                let admin = users
+                    .ensure_user("admin", &password, serde_json::json!({ "seeded": true }))
+                    .await
+                    .unwrap();
+                assert_eq!(admin.id, alice.id);
+                assert_eq!(admin.meta, serde_json::json!({ "seeded": true }));
+            });
+    }
+
+    #[test]
+    fn update_meta_checks_version() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                let user = users
+                    .create_user(NewUser::new("alice", "this is my password").unwrap())
+                    .await
+                    .unwrap();
+
+                let updated = users
+                    .update_meta(user.id, serde_json::json!({ "a": 1 }), Some(user.version))
+                    .await
+                    .unwrap();
+                assert_eq!(updated.version, user.version + 1);
+                assert!(updated.updated_at >= user.updated_at);
+
+                assert!(matches!(
+                    users
+                        .update_meta(user.id, serde_json::json!({ "a": 2 }), Some(user.version))
+                        .await,
+                    Err(Error::Conflict)
+                ));
+                assert!(matches!(
+                    users
+                        .update_meta(UserId(uuid::Uuid::new_v4()), serde_json::json!({}), None)
+                        .await,
+                    Err(Error::UserNotFound)
+                ));
+            });
+    }
+
+    #[test]
+    fn invited_user_sets_initial_password() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                let invited = users
+                    .create_user(NewUser::invited("alice").unwrap())
+                    .await
+                    .unwrap();
+                assert!(!invited.has_password());
+                assert!(matches!(
+                    users.verify_password(&invited, ""),
+                    Err(Error::PasswordNotSet)
+                ));
+
+                let user = users
+                    .set_initial_password(invited.id, "this is my password")
+                    .await
+                    .unwrap();
+                assert!(users.verify_password(&user, "this is my password").is_ok());
+                assert!(matches!(
+                    users.set_initial_password(invited.id, "taking over").await,
+                    Err(Error::PasswordAlreadySet)
+                ));
+            });
+    }
+
+    #[test]
+    fn list_and_search() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                for name in ["alice", "bob", "carol", "100%_sure"] {
+                    users
+                        .create_user(NewUser::new(name, "this is my password").unwrap())
+                        .await
+                        .unwrap();
+                }
+
+                let all = users.list_users().await.unwrap();
+                assert_eq!(all.len(), 4);
+                assert!(all.windows(2).all(|w| w[0].id < w[1].id));
+
+                let page = users.list_users_paged(2, 1).await.unwrap();
+                assert_eq!(
+                    page.iter().map(|u| u.id).collect::<Vec<_>>(),
+                    all[1..3].iter().map(|u| u.id).collect::<Vec<_>>()
+                );
+                assert!(matches!(
+                    users.list_users_paged(0, 0).await,
+                    Err(Error::InvalidLimit)
+                ));
+                assert!(matches!(
+                    users.list_users_after(None, 0).await,
+                    Err(Error::InvalidLimit)
+                ));
+                assert!(matches!(
+                    users.users_needing_rehash(-1, 0).await,
+                    Err(Error::InvalidLimit)
+                ));
+                assert!(matches!(
+                    users.search_users("a", 0).await,
+                    Err(Error::InvalidLimit)
+                ));
+
+                let first = users.list_users_after(None, 3).await.unwrap();
+                assert_eq!(first.users.len(), 3);
+                let rest = users.list_users_after(first.next, 3).await.unwrap();
+                assert_eq!(rest.users.len(), 1);
+                assert_eq!(rest.users[0].id, all[3].id);
+                assert!(rest.next.is_none());
+
+                let found = users.search_users("AR", 10).await.unwrap();
+                assert_eq!(
+                    found.iter().map(|u| &*u.username).collect::<Vec<_>>(),
+                    ["carol"]
+                );
+                let found = users.search_users("%_", 10).await.unwrap();
+                assert_eq!(
+                    found.iter().map(|u| &*u.username).collect::<Vec<_>>(),
+                    ["100%_sure"]
+                );
+            });
+    }
+
+    #[test]
+    fn reservations_are_unavailable() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let users = backend().await;
+                assert!(matches!(
+                    users
+                        .reserve_username("alice", chrono::Duration::minutes(5))
+                        .await,
+                    Err(Error::ReservationsUnavailable)
+                ));
+            });
+    }
+}
@@ -18,12 +18,17 @@ pub enum TryIntoAsciiUsernameError {
     UsernameTooLong,
 }
 
+/// Longest [`AsciiUsername`] unless another limit is given.
+pub const DEFAULT_MAX_LEN: usize = 64;
+
+/// A username of printable ASCII, compared case-insensitively. `MAX` is the longest it may be,
+/// so `AsciiUsername<32>` allows 32 characters.
 #[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::Type)]
 #[sqlx(transparent)]
 #[serde(try_from = "String")]
-pub struct AsciiUsername(String);
+pub struct AsciiUsername<const MAX: usize = DEFAULT_MAX_LEN>(String);
 
-impl UsernameType for AsciiUsername {
+impl<const MAX: usize> UsernameType for AsciiUsername<MAX> {
     type TryIntoError = TryIntoAsciiUsernameError;
 
     fn into_inner(self) -> String {
@@ -31,41 +36,46 @@ impl UsernameType for AsciiUsername {
     }
 }
 
-impl From<AsciiUsername> for Username<AsciiUsername> {
-    fn from(x: AsciiUsername) -> Self {
+impl<const MAX: usize> From<AsciiUsername<MAX>> for Username<AsciiUsername<MAX>> {
+    fn from(x: AsciiUsername<MAX>) -> Self {
         Self(x)
     }
 }
 
-impl FromStr for AsciiUsername {
-    type Err = TryIntoAsciiUsernameError;
+/// Trims `value` and checks that what's left is a valid ASCII username of at most `max` characters.
+fn validate(value: &str, max: usize) -> Result<&str, TryIntoAsciiUsernameError> {
+    let value = value.trim();
 
-    fn from_str(value: &str) -> Result<Self, Self::Err> {
-        let value = value.trim();
+    if value.len() == 0 {
+        return Err(TryIntoAsciiUsernameError::Empty);
+    }
 
-        if value.len() == 0 {
-            return Err(TryIntoAsciiUsernameError::Empty);
+    if value.len() > max {
+        return Err(TryIntoAsciiUsernameError::UsernameTooLong);
+    }
+
+    for c in value.chars() {
+        if !c.is_ascii() {
+            return Err(TryIntoAsciiUsernameError::NonAscii);
         }
 
-        if value.len() > 64 {
-            return Err(TryIntoAsciiUsernameError::UsernameTooLong);
+        if !c.is_ascii_graphic() {
+            return Err(TryIntoAsciiUsernameError::NonPrintable);
         }
+    }
 
-        for c in value.chars() {
-            if !c.is_ascii() {
-                return Err(TryIntoAsciiUsernameError::NonAscii);
-            }
+    Ok(value)
+}
 
-            if !c.is_ascii_graphic() {
-                return Err(TryIntoAsciiUsernameError::NonPrintable);
-            }
-        }
+impl<const MAX: usize> FromStr for AsciiUsername<MAX> {
+    type Err = TryIntoAsciiUsernameError;
 
-        Ok(Self(value.to_string()))
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(validate(value, MAX)?.to_string()))
     }
 }
 
-impl Deref for AsciiUsername {
+impl<const MAX: usize> Deref for AsciiUsername<MAX> {
     type Target = str;
 
     fn deref(&self) -> &Self::Target {
@@ -73,13 +83,13 @@ impl Deref for AsciiUsername {
     }
 }
 
-impl Display for AsciiUsername {
+impl<const MAX: usize> Display for AsciiUsername<MAX> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.0, f)
     }
 }
 
-impl PartialEq for AsciiUsername {
+impl<const MAX: usize> PartialEq for AsciiUsername<MAX> {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
         self.0
@@ -88,9 +98,9 @@ impl PartialEq for AsciiUsername {
     }
 }
 
-impl Eq for AsciiUsername {}
+impl<const MAX: usize> Eq for AsciiUsername<MAX> {}
 
-impl PartialOrd for AsciiUsername {
+impl<const MAX: usize> PartialOrd for AsciiUsername<MAX> {
     #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         self.0
@@ -99,7 +109,7 @@ impl PartialOrd for AsciiUsername {
     }
 }
 
-impl Ord for AsciiUsername {
+impl<const MAX: usize> Ord for AsciiUsername<MAX> {
     #[inline]
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         self.0
@@ -108,17 +118,151 @@ impl Ord for AsciiUsername {
     }
 }
 
-impl Hash for AsciiUsername {
+impl<const MAX: usize> Hash for AsciiUsername<MAX> {
     #[inline]
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.0.to_ascii_lowercase().hash(state)
     }
 }
 
-impl TryFrom<String> for AsciiUsername {
+impl<const MAX: usize> TryFrom<String> for AsciiUsername<MAX> {
+    type Error = <Self as FromStr>::Err;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Like [`AsciiUsername`], but `JohnDoe` and `johndoe` are different usernames.
+///
+/// This only affects comparisons in Rust. The Postgres backend looks usernames up
+/// case-insensitively, and [`crate::schema::users`] makes the column `CITEXT`, so both would
+/// have to change for the database to tell them apart too.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Deserialize,
+    serde::Serialize,
+    sqlx::Type,
+)]
+#[sqlx(transparent)]
+#[serde(try_from = "String")]
+pub struct CaseSensitiveAsciiUsername<const MAX: usize = DEFAULT_MAX_LEN>(String);
+
+impl<const MAX: usize> UsernameType for CaseSensitiveAsciiUsername<MAX> {
+    type TryIntoError = TryIntoAsciiUsernameError;
+
+    fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl<const MAX: usize> From<CaseSensitiveAsciiUsername<MAX>>
+    for Username<CaseSensitiveAsciiUsername<MAX>>
+{
+    fn from(x: CaseSensitiveAsciiUsername<MAX>) -> Self {
+        Self(x)
+    }
+}
+
+impl<const MAX: usize> FromStr for CaseSensitiveAsciiUsername<MAX> {
+    type Err = TryIntoAsciiUsernameError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(validate(value, MAX)?.to_string()))
+    }
+}
+
+impl<const MAX: usize> Deref for CaseSensitiveAsciiUsername<MAX> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<const MAX: usize> Display for CaseSensitiveAsciiUsername<MAX> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl<const MAX: usize> TryFrom<String> for CaseSensitiveAsciiUsername<MAX> {
     type Error = <Self as FromStr>::Err;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         value.parse()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn case_sensitivity() {
+        let insensitive = ["JohnDoe", "johndoe"].map(|s| s.parse::<AsciiUsername>().unwrap());
+        assert_eq!(insensitive[0], insensitive[1]);
+        assert_eq!(insensitive.iter().collect::<HashSet<_>>().len(), 1);
+
+        let sensitive =
+            ["JohnDoe", "johndoe"].map(|s| s.parse::<CaseSensitiveAsciiUsername>().unwrap());
+        assert_ne!(sensitive[0], sensitive[1]);
+        assert_eq!(sensitive.iter().collect::<HashSet<_>>().len(), 2);
+        assert_eq!(
+            sensitive[0],
+            "JohnDoe".parse::<CaseSensitiveAsciiUsername>().unwrap()
+        );
+    }
+
+    #[test]
+    fn custom_max_len() {
+        assert!("a".repeat(32).parse::<AsciiUsername<32>>().is_ok());
+        assert!(matches!(
+            "a".repeat(33).parse::<AsciiUsername<32>>(),
+            Err(TryIntoAsciiUsernameError::UsernameTooLong)
+        ));
+
+        // Surrounding whitespace doesn't count.
+        assert!(format!(" {} ", "a".repeat(128))
+            .parse::<CaseSensitiveAsciiUsername<128>>()
+            .is_ok());
+        assert!(matches!(
+            "a".repeat(129).parse::<CaseSensitiveAsciiUsername<128>>(),
+            Err(TryIntoAsciiUsernameError::UsernameTooLong)
+        ));
+
+        assert!("a".repeat(DEFAULT_MAX_LEN).parse::<AsciiUsername>().is_ok());
+        assert!("a"
+            .repeat(DEFAULT_MAX_LEN + 1)
+            .parse::<AsciiUsername>()
+            .is_err());
+    }
+
+    #[test]
+    fn case_sensitive_validates_like_ascii() {
+        assert_eq!(
+            &*" JohnDoe ".parse::<CaseSensitiveAsciiUsername>().unwrap(),
+            "JohnDoe"
+        );
+        assert!(matches!(
+            "".parse::<CaseSensitiveAsciiUsername>(),
+            Err(TryIntoAsciiUsernameError::Empty)
+        ));
+        assert!(matches!(
+            "bjørn".parse::<CaseSensitiveAsciiUsername>(),
+            Err(TryIntoAsciiUsernameError::NonAscii)
+        ));
+        assert!(matches!(
+            "john doe".parse::<CaseSensitiveAsciiUsername>(),
+            Err(TryIntoAsciiUsernameError::NonPrintable)
+        ));
+    }
+}
@@ -1,4 +1,6 @@
-use std::{convert::TryFrom, fmt::Display, hash::Hash, ops::Deref, str::FromStr};
+use std::{
+    convert::TryFrom, fmt::Display, hash::Hash, marker::PhantomData, ops::Deref, str::FromStr,
+};
 
 use validator::validate_email;
 
@@ -14,14 +16,75 @@ pub enum TryIntoEmailUsernameError {
 
     #[error("Username too long.")]
     UsernameTooLong,
+
+    #[error("The part of the email before the @ is too long.")]
+    LocalPartTooLong,
+}
+
+/// Longest [`EmailUsername`] unless another limit is given, the most RFC 5321 allows.
+pub const DEFAULT_MAX_LEN: usize = 254;
+
+/// Longest part of an email before the `@`, per RFC 5321.
+const MAX_LOCAL_PART_LEN: usize = 64;
+
+/// How two [`EmailUsername`]s are compared, for those that reach the same inbox.
+pub trait EmailNormalization: Send + Sync + 'static {
+    /// The form of `email` that is compared, never shown.
+    fn normalize(email: &str) -> String;
+}
+
+/// Compares whole addresses, ignoring ASCII case.
+#[derive(Debug, Clone, Copy)]
+pub struct CaseInsensitive;
+
+impl EmailNormalization for CaseInsensitive {
+    fn normalize(email: &str) -> String {
+        email.to_ascii_lowercase()
+    }
+}
+
+/// Like [`CaseInsensitive`], but also ignores what Gmail ignores in `gmail.com` and
+/// `googlemail.com` addresses, so `John.Doe+spam@gmail.com` is the same as `johndoe@gmail.com`.
+/// Other addresses are left alone, since not every provider ignores dots or `+` tags.
+///
+/// This only affects comparisons in Rust. The backends store and look up the address as
+/// entered, so `johndoe@gmail.com` can still register next to `john.doe@gmail.com`. To prevent
+/// that, store [`EmailUsername::normalized`] in a column of its own with a unique index, and
+/// set it whenever a user is created or renamed.
+#[derive(Debug, Clone, Copy)]
+pub struct Gmail;
+
+impl EmailNormalization for Gmail {
+    fn normalize(email: &str) -> String {
+        let email = email.to_ascii_lowercase();
+
+        match email.rsplit_once('@') {
+            Some((local_part, "gmail.com" | "googlemail.com")) => {
+                let local_part = local_part.split('+').next().unwrap_or_default();
+                format!("{}@gmail.com", local_part.replace('.', ""))
+            }
+            _ => email,
+        }
+    }
 }
 
-#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::Type)]
-#[sqlx(transparent)]
-#[serde(try_from = "String")]
-pub struct EmailUsername(String);
+/// A username that is an email address. `MAX` is the longest the whole address may be, and the
+/// part before the `@` may be at most 64 characters regardless. Addresses are compared by their
+/// [`normalized`](Self::normalized) form, which ignores case unless `N` says otherwise.
+pub struct EmailUsername<const MAX: usize = DEFAULT_MAX_LEN, N = CaseInsensitive>(
+    String,
+    PhantomData<N>,
+);
 
-impl UsernameType for EmailUsername {
+impl<const MAX: usize, N: EmailNormalization> EmailUsername<MAX, N> {
+    /// The address as `N` normalizes it, for storing alongside the username to find accounts
+    /// that share an inbox.
+    pub fn normalized(&self) -> String {
+        N::normalize(&self.0)
+    }
+}
+
+impl<const MAX: usize, N: EmailNormalization> UsernameType for EmailUsername<MAX, N> {
     type TryIntoError = TryIntoEmailUsernameError;
 
     fn into_inner(self) -> String {
@@ -29,13 +92,15 @@ impl UsernameType for EmailUsername {
     }
 }
 
-impl From<EmailUsername> for Username<EmailUsername> {
-    fn from(x: EmailUsername) -> Self {
+impl<const MAX: usize, N: EmailNormalization> From<EmailUsername<MAX, N>>
+    for Username<EmailUsername<MAX, N>>
+{
+    fn from(x: EmailUsername<MAX, N>) -> Self {
         Self(x)
     }
 }
 
-impl FromStr for EmailUsername {
+impl<const MAX: usize, N> FromStr for EmailUsername<MAX, N> {
     type Err = TryIntoEmailUsernameError;
 
     fn from_str(value: &str) -> Result<Self, Self::Err> {
@@ -45,19 +110,26 @@ impl FromStr for EmailUsername {
             return Err(TryIntoEmailUsernameError::Empty);
         }
 
-        if value.len() > 64 {
+        if value.len() > MAX {
             return Err(TryIntoEmailUsernameError::UsernameTooLong);
         }
 
+        match value.rsplit_once('@') {
+            Some((local_part, _)) if local_part.len() > MAX_LOCAL_PART_LEN => {
+                return Err(TryIntoEmailUsernameError::LocalPartTooLong)
+            }
+            _ => {}
+        }
+
         if !validate_email(value) {
             return Err(TryIntoEmailUsernameError::NotValidEmail);
         }
 
-        Ok(Self(value.to_string()))
+        Ok(Self(value.to_string(), PhantomData))
     }
 }
 
-impl Deref for EmailUsername {
+impl<const MAX: usize, N> Deref for EmailUsername<MAX, N> {
     type Target = str;
 
     fn deref(&self) -> &Self::Target {
@@ -65,52 +137,185 @@ impl Deref for EmailUsername {
     }
 }
 
-impl Display for EmailUsername {
+impl<const MAX: usize, N> Display for EmailUsername<MAX, N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.0, f)
     }
 }
 
-impl PartialEq for EmailUsername {
+impl<const MAX: usize, N> std::fmt::Debug for EmailUsername<MAX, N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("EmailUsername").field(&self.0).finish()
+    }
+}
+
+impl<const MAX: usize, N> Clone for EmailUsername<MAX, N> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<const MAX: usize, N: EmailNormalization> PartialEq for EmailUsername<MAX, N> {
     #[inline]
     fn eq(&self, other: &Self) -> bool {
-        self.0
-            .to_ascii_lowercase()
-            .eq(&other.0.to_ascii_lowercase())
+        self.normalized().eq(&other.normalized())
     }
 }
 
-impl Eq for EmailUsername {}
+impl<const MAX: usize, N: EmailNormalization> Eq for EmailUsername<MAX, N> {}
 
-impl PartialOrd for EmailUsername {
+impl<const MAX: usize, N: EmailNormalization> PartialOrd for EmailUsername<MAX, N> {
     #[inline]
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.0
-            .to_ascii_lowercase()
-            .partial_cmp(&other.0.to_ascii_lowercase())
+        Some(self.cmp(other))
     }
 }
 
-impl Ord for EmailUsername {
+impl<const MAX: usize, N: EmailNormalization> Ord for EmailUsername<MAX, N> {
     #[inline]
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        self.0
-            .to_ascii_lowercase()
-            .cmp(&other.0.to_ascii_lowercase())
+        self.normalized().cmp(&other.normalized())
     }
 }
 
-impl Hash for EmailUsername {
+impl<const MAX: usize, N: EmailNormalization> Hash for EmailUsername<MAX, N> {
     #[inline]
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.to_ascii_lowercase().hash(state)
+        self.normalized().hash(state)
     }
 }
 
-impl TryFrom<String> for EmailUsername {
+impl<const MAX: usize, N> TryFrom<String> for EmailUsername<MAX, N> {
     type Error = <Self as FromStr>::Err;
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         value.parse()
     }
 }
+
+impl<const MAX: usize, N> serde::Serialize for EmailUsername<MAX, N> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, const MAX: usize, N> serde::Deserialize<'de> for EmailUsername<MAX, N> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// What `#[sqlx(transparent)]` would give, which only supports a single field.
+impl<DB: sqlx::Database, const MAX: usize, N> sqlx::Type<DB> for EmailUsername<MAX, N>
+where
+    String: sqlx::Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        <String as sqlx::Type<DB>>::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        <String as sqlx::Type<DB>>::compatible(ty)
+    }
+}
+
+impl<'q, DB: sqlx::Database, const MAX: usize, N> sqlx::Encode<'q, DB> for EmailUsername<MAX, N>
+where
+    String: sqlx::Encode<'q, DB>,
+{
+    fn encode_by_ref(
+        &self,
+        buf: &mut <DB as sqlx::database::HasArguments<'q>>::ArgumentBuffer,
+    ) -> sqlx::encode::IsNull {
+        self.0.encode_by_ref(buf)
+    }
+}
+
+impl<'r, DB: sqlx::Database, const MAX: usize, N> sqlx::Decode<'r, DB> for EmailUsername<MAX, N>
+where
+    String: sqlx::Decode<'r, DB>,
+{
+    fn decode(
+        value: <DB as sqlx::database::HasValueRef<'r>>::ValueRef,
+    ) -> Result<Self, sqlx::error::BoxDynError> {
+        Ok(Self(String::decode(value)?, PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_long_emails() {
+        let email = "jonathan.alexander.worthington-smythe@engineering.example-groups.co.uk";
+        assert_eq!(email.len(), 70);
+        assert_eq!(&*email.parse::<EmailUsername>().unwrap(), email);
+    }
+
+    #[test]
+    fn limits_length() {
+        // Four labels of 61 characters and ".com" make a 248 character domain.
+        let domain = format!("{}com", format!("{}.", "a".repeat(61)).repeat(4));
+        let email = format!(
+            "{}@{}",
+            "b".repeat(DEFAULT_MAX_LEN - domain.len() - 1),
+            domain
+        );
+        assert_eq!(email.len(), DEFAULT_MAX_LEN);
+        assert!(email.parse::<EmailUsername>().is_ok());
+        assert!(matches!(
+            format!("b{}", email).parse::<EmailUsername>(),
+            Err(TryIntoEmailUsernameError::UsernameTooLong)
+        ));
+
+        assert!("alice@example.com".parse::<EmailUsername<17>>().is_ok());
+        assert!(matches!(
+            "alice@example.com".parse::<EmailUsername<16>>(),
+            Err(TryIntoEmailUsernameError::UsernameTooLong)
+        ));
+    }
+
+    #[test]
+    fn gmail_normalization() {
+        let tagged = "John.Doe+spam@Gmail.com";
+        let plain = "johndoe@gmail.com";
+
+        assert_ne!(
+            tagged.parse::<EmailUsername>().unwrap(),
+            plain.parse::<EmailUsername>().unwrap()
+        );
+
+        let tagged = tagged
+            .parse::<EmailUsername<DEFAULT_MAX_LEN, Gmail>>()
+            .unwrap();
+        assert_eq!(&*tagged, "John.Doe+spam@Gmail.com");
+        assert_eq!(tagged.normalized(), plain);
+        assert_eq!(tagged, plain.parse().unwrap());
+        assert_eq!(tagged, "johndoe@googlemail.com".parse().unwrap());
+
+        let set = vec![tagged, plain.parse().unwrap()]
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(set.len(), 1);
+
+        // Other providers may treat dots and tags as part of the address.
+        let other = "john.doe+spam@example.com"
+            .parse::<EmailUsername<DEFAULT_MAX_LEN, Gmail>>()
+            .unwrap();
+        assert_eq!(other.normalized(), "john.doe+spam@example.com");
+        assert_ne!(other, "johndoe@example.com".parse().unwrap());
+    }
+
+    #[test]
+    fn limits_local_part() {
+        assert!(format!("{}@example.com", "a".repeat(64))
+            .parse::<EmailUsername>()
+            .is_ok());
+        assert!(matches!(
+            format!("{}@example.com", "a".repeat(65)).parse::<EmailUsername>(),
+            Err(TryIntoEmailUsernameError::LocalPartTooLong)
+        ));
+    }
+}
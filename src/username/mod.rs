@@ -1,5 +1,8 @@
 pub mod ascii;
 pub mod email;
+pub mod reserved;
+#[cfg(feature = "unicode")]
+pub mod unicode;
 
 use std::{fmt::Debug, ops::Deref, str::FromStr};
 
@@ -19,6 +22,40 @@ pub trait UsernameType:
     type TryIntoError: std::error::Error + Send + Sync + 'static;
 
     fn into_inner(self) -> String;
+
+    /// Checks that a new user may take this name, on top of it parsing. Backends call this when
+    /// creating or renaming a user, but not when reading users back, so that tightening the
+    /// rules later doesn't make existing users unreadable.
+    fn check_registrable(&self) -> Result<(), Self::TryIntoError> {
+        Ok(())
+    }
+}
+
+impl<U: UsernameType> Username<U> {
+    /// Validates `value` as a username of type `U`.
+    ///
+    /// ```
+    /// use thetc_auth::username::{ascii::AsciiUsername, Username};
+    ///
+    /// let username = Username::<AsciiUsername>::try_new("alice").unwrap();
+    /// assert_eq!(&*username, "alice");
+    ///
+    /// assert!(Username::<AsciiUsername>::try_new("").is_err());
+    /// assert!(Username::<AsciiUsername>::try_from_string("bjørn".to_string()).is_err());
+    /// ```
+    pub fn try_new(value: &str) -> Result<Self, U::Err> {
+        value.parse()
+    }
+
+    /// Same as [`Username::try_new`], for an owned string.
+    pub fn try_from_string(value: String) -> Result<Self, U::Err> {
+        Self::try_new(&value)
+    }
+
+    /// See [`UsernameType::check_registrable`].
+    pub fn check_registrable(&self) -> Result<(), U::Err> {
+        self.0.check_registrable()
+    }
 }
 
 impl<U: UsernameType> FromStr for Username<U> {
@@ -0,0 +1,228 @@
+use std::{
+    convert::TryFrom, fmt::Display, hash::Hash, marker::PhantomData, ops::Deref, str::FromStr,
+};
+
+use super::{Username, UsernameType};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TryIntoReservedUsernameError<E: std::error::Error + 'static> {
+    #[error("Username is reserved.")]
+    Reserved,
+
+    #[error(transparent)]
+    Invalid(#[from] E),
+}
+
+/// A set of usernames nobody may register, for [`Reserved`].
+pub trait ReservedNames: Send + Sync + 'static {
+    /// Compared case-insensitively against the whole username, so an [`EmailUsername`] needs
+    /// whole addresses here.
+    ///
+    /// [`EmailUsername`]: super::email::EmailUsername
+    const NAMES: &'static [&'static str];
+
+    fn is_reserved(value: &str) -> bool {
+        let value = value.to_lowercase();
+        Self::NAMES.iter().any(|name| name.to_lowercase() == value)
+    }
+}
+
+/// Names that look like they belong to whoever runs the service.
+#[derive(Debug, Clone, Copy)]
+pub struct DefaultReservedNames;
+
+impl ReservedNames for DefaultReservedNames {
+    const NAMES: &'static [&'static str] = &[
+        "abuse",
+        "admin",
+        "administrator",
+        "help",
+        "hostmaster",
+        "no-reply",
+        "noreply",
+        "postmaster",
+        "root",
+        "security",
+        "support",
+        "system",
+        "webmaster",
+    ];
+}
+
+/// A username of type `U` that isn't one of the names in `R`.
+///
+/// ```
+/// use thetc_auth::username::{ascii::AsciiUsername, reserved::Reserved, Username};
+///
+/// let admin = Username::<Reserved<AsciiUsername>>::try_new("Admin").unwrap();
+/// assert!(admin.check_registrable().is_err());
+///
+/// let alice = Username::<Reserved<AsciiUsername>>::try_new("alice").unwrap();
+/// assert!(alice.check_registrable().is_ok());
+/// ```
+///
+/// Names are only checked by [`UsernameType::check_registrable`], i.e. when a user is created
+/// or renamed, so existing users with a name added to `R` later can still be read. Users created
+/// through [`UserBackend::ensure_user`](crate::user::UserBackend::ensure_user) aren't checked
+/// either, so that a service can seed an account such as `admin` for itself.
+pub struct Reserved<U, R = DefaultReservedNames>(U, PhantomData<R>);
+
+impl<U: UsernameType, R: ReservedNames> UsernameType for Reserved<U, R> {
+    type TryIntoError = TryIntoReservedUsernameError<U::TryIntoError>;
+
+    fn into_inner(self) -> String {
+        self.0.into_inner()
+    }
+
+    fn check_registrable(&self) -> Result<(), Self::TryIntoError> {
+        self.0.check_registrable()?;
+        match R::is_reserved(&self.0) {
+            true => Err(TryIntoReservedUsernameError::Reserved),
+            false => Ok(()),
+        }
+    }
+}
+
+impl<U: UsernameType, R: ReservedNames> From<Reserved<U, R>> for Username<Reserved<U, R>> {
+    fn from(x: Reserved<U, R>) -> Self {
+        Self(x)
+    }
+}
+
+impl<U: UsernameType, R: ReservedNames> FromStr for Reserved<U, R> {
+    type Err = TryIntoReservedUsernameError<U::TryIntoError>;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self(value.parse()?, PhantomData))
+    }
+}
+
+impl<U: Deref<Target = str>, R> Deref for Reserved<U, R> {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<U: Display, R> Display for Reserved<U, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl<U: std::fmt::Debug, R> std::fmt::Debug for Reserved<U, R> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl<U: Clone, R> Clone for Reserved<U, R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<U: PartialEq, R> PartialEq for Reserved<U, R> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.0.eq(&other.0)
+    }
+}
+
+impl<U: Eq, R> Eq for Reserved<U, R> {}
+
+impl<U: PartialOrd, R> PartialOrd for Reserved<U, R> {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.0.partial_cmp(&other.0)
+    }
+}
+
+impl<U: Ord, R> Ord for Reserved<U, R> {
+    #[inline]
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.cmp(&other.0)
+    }
+}
+
+impl<U: Hash, R> Hash for Reserved<U, R> {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.hash(state)
+    }
+}
+
+impl<U: UsernameType, R: ReservedNames> TryFrom<String> for Reserved<U, R> {
+    type Error = <Self as FromStr>::Err;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl<U: serde::Serialize, R> serde::Serialize for Reserved<U, R> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de, U: UsernameType, R: ReservedNames> serde::Deserialize<'de> for Reserved<U, R> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        value.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::username::{ascii::AsciiUsername, email::EmailUsername};
+
+    struct Staff;
+
+    impl ReservedNames for Staff {
+        const NAMES: &'static [&'static str] = &["staff@example.com"];
+    }
+
+    fn check<U: UsernameType, R: ReservedNames>(
+        name: &str,
+    ) -> Result<(), TryIntoReservedUsernameError<U::TryIntoError>> {
+        name.parse::<Reserved<U, R>>()?.check_registrable()
+    }
+
+    #[test]
+    fn rejects_reserved_names() {
+        for name in ["admin", "ADMIN", " Root "] {
+            // Still parses, as existing users have to stay readable.
+            assert!(name.parse::<Reserved<AsciiUsername>>().is_ok());
+            assert!(matches!(
+                check::<AsciiUsername, DefaultReservedNames>(name),
+                Err(TryIntoReservedUsernameError::Reserved)
+            ));
+        }
+
+        let alice = "alice".parse::<Reserved<AsciiUsername>>().unwrap();
+        assert!(alice.check_registrable().is_ok());
+        assert_eq!(&*alice, "alice");
+        assert_eq!(alice, "ALICE".parse::<Reserved<AsciiUsername>>().unwrap());
+    }
+
+    #[test]
+    fn keeps_inner_errors() {
+        assert!(matches!(
+            "".parse::<Reserved<AsciiUsername>>(),
+            Err(TryIntoReservedUsernameError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn custom_names() {
+        assert!(matches!(
+            check::<EmailUsername, Staff>("Staff@Example.com"),
+            Err(TryIntoReservedUsernameError::Reserved)
+        ));
+        assert!(check::<EmailUsername, Staff>("alice@example.com").is_ok());
+        assert!(check::<EmailUsername, Staff>("admin@example.com").is_ok());
+    }
+}
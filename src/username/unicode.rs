@@ -0,0 +1,250 @@
+use std::{convert::TryFrom, fmt::Display, hash::Hash, ops::Deref, str::FromStr};
+
+use unicode_general_category::{get_general_category, GeneralCategory};
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+use super::{Username, UsernameType};
+
+/// Longest username, in grapheme clusters, i.e. what a reader would count as characters.
+const MAX_GRAPHEMES: usize = 64;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TryIntoUnicodeUsernameError {
+    #[error("Username must not be empty string.")]
+    Empty,
+
+    #[error("Non-printable characters found in username.")]
+    NonPrintable,
+
+    #[error("Unassigned code points found in username.")]
+    Unassigned,
+
+    #[error("Username too long.")]
+    UsernameTooLong,
+}
+
+/// A username of printable Unicode, such as `Zoë` or `渡辺`. Stored in NFC, and compared with
+/// Unicode case folding, so `ZOË` is the same username as `zoë` however either was composed.
+///
+/// The case folding only affects comparisons in Rust. The Postgres backend looks usernames up
+/// with `LOWER()`, and [`crate::schema::users`] makes the column `CITEXT`, which lowercase
+/// according to the database's locale rather than fold case fully. So `straße` and `STRASSE`
+/// are one username here but two in the database, and both can be registered. Both sides see
+/// the same NFC form, so how a name was composed makes no difference to either.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize, sqlx::Type)]
+#[sqlx(transparent)]
+#[serde(try_from = "String")]
+pub struct UnicodeUsername(String);
+
+impl UnicodeUsername {
+    /// What usernames are compared by.
+    fn folded(&self) -> String {
+        caseless::default_case_fold_str(&self.0).nfc().collect()
+    }
+
+    /// The username with lookalike characters replaced by a canonical one, per the confusable
+    /// mappings of [UTS #39](https://www.unicode.org/reports/tr39/#Confusable_Detection), so
+    /// `pаypal` with a Cyrillic `а` has the same skeleton as `PayPal`.
+    ///
+    /// Equality still only folds case. To stop lookalike names, store the skeleton next to the
+    /// username and keep it unique too. The skeleton isn't meant to be shown, and may change
+    /// with the Unicode version.
+    ///
+    /// Neither backend does that for you: [`crate::schema::users`] has no skeleton column, so
+    /// add one (e.g. `username_skeleton TEXT UNIQUE`) and set it from here whenever a user is
+    /// created or renamed. Compute it in Rust rather than in SQL, as it relies on full case
+    /// folding, which the database's `LOWER()` doesn't do (see [`UnicodeUsername`]).
+    pub fn skeleton(&self) -> String {
+        unicode_security::skeleton(&self.folded()).collect()
+    }
+
+    /// Whether `self` and `other` look alike, i.e. have the same [`skeleton`](Self::skeleton).
+    pub fn is_confusable_with(&self, other: &Self) -> bool {
+        self.skeleton() == other.skeleton()
+    }
+}
+
+impl UsernameType for UnicodeUsername {
+    type TryIntoError = TryIntoUnicodeUsernameError;
+
+    fn into_inner(self) -> String {
+        self.0
+    }
+}
+
+impl From<UnicodeUsername> for Username<UnicodeUsername> {
+    fn from(x: UnicodeUsername) -> Self {
+        Self(x)
+    }
+}
+
+impl FromStr for UnicodeUsername {
+    type Err = TryIntoUnicodeUsernameError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let value = value.trim().nfc().collect::<String>();
+
+        if value.is_empty() {
+            return Err(TryIntoUnicodeUsernameError::Empty);
+        }
+
+        if value.graphemes(true).count() > MAX_GRAPHEMES {
+            return Err(TryIntoUnicodeUsernameError::UsernameTooLong);
+        }
+
+        for c in value.chars() {
+            match get_general_category(c) {
+                GeneralCategory::Unassigned => return Err(TryIntoUnicodeUsernameError::Unassigned),
+                // Format characters are invisible, and some reorder text, so they'd allow
+                // usernames that look like others.
+                GeneralCategory::Control
+                | GeneralCategory::Format
+                | GeneralCategory::Surrogate
+                | GeneralCategory::PrivateUse
+                | GeneralCategory::SpaceSeparator
+                | GeneralCategory::LineSeparator
+                | GeneralCategory::ParagraphSeparator => {
+                    return Err(TryIntoUnicodeUsernameError::NonPrintable)
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self(value))
+    }
+}
+
+impl Deref for UnicodeUsername {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl Display for UnicodeUsername {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl PartialEq for UnicodeUsername {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.folded().eq(&other.folded())
+    }
+}
+
+impl Eq for UnicodeUsername {}
+
+impl PartialOrd for UnicodeUsername {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for UnicodeUsername {
+    #[inline]
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.folded().cmp(&other.folded())
+    }
+}
+
+impl Hash for UnicodeUsername {
+    #[inline]
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.folded().hash(state)
+    }
+}
+
+impl TryFrom<String> for UnicodeUsername {
+    type Error = <Self as FromStr>::Err;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_accented_names() {
+        for name in ["zoë", "José", "Åsa", "渡辺", "Ωμέγα", "o'brien"] {
+            let username = name.parse::<UnicodeUsername>().unwrap();
+            assert_eq!(&*username, name);
+        }
+
+        assert_eq!(&*"  björn ".parse::<UnicodeUsername>().unwrap(), "björn");
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        // "e" followed by a combining acute accent.
+        let decomposed = "jose\u{301}".parse::<UnicodeUsername>().unwrap();
+        assert_eq!(&*decomposed, "jos\u{e9}");
+        assert_eq!(decomposed, "josé".parse::<UnicodeUsername>().unwrap());
+    }
+
+    #[test]
+    fn compares_case_folded() {
+        let lower = "straße".parse::<UnicodeUsername>().unwrap();
+        assert_eq!(lower, "STRASSE".parse::<UnicodeUsername>().unwrap());
+        assert_eq!(
+            "ZOË".parse::<UnicodeUsername>().unwrap(),
+            "zoë".parse::<UnicodeUsername>().unwrap()
+        );
+        assert_ne!(lower, "strase".parse::<UnicodeUsername>().unwrap());
+    }
+
+    #[test]
+    fn skeleton() {
+        let latin = "paypal".parse::<UnicodeUsername>().unwrap();
+        // The second letter is a Cyrillic "а".
+        let cyrillic = "p\u{430}ypal".parse::<UnicodeUsername>().unwrap();
+
+        assert_ne!(latin, cyrillic);
+        assert_eq!(latin.skeleton(), cyrillic.skeleton());
+        assert!(cyrillic.is_confusable_with(&latin));
+        assert!(cyrillic.is_confusable_with(&"PayPal".parse().unwrap()));
+        assert!(!cyrillic.is_confusable_with(&"paypals".parse().unwrap()));
+    }
+
+    #[test]
+    fn rejects_control_characters() {
+        for name in [
+            "al\u{0}ice",
+            "al\nice",
+            "al\u{7f}ice",
+            "al\u{202e}ice",
+            "al ice",
+        ] {
+            assert!(matches!(
+                name.parse::<UnicodeUsername>(),
+                Err(TryIntoUnicodeUsernameError::NonPrintable)
+            ));
+        }
+        assert!(matches!(
+            "al\u{378}ice".parse::<UnicodeUsername>(),
+            Err(TryIntoUnicodeUsernameError::Unassigned)
+        ));
+        assert!(matches!(
+            " ".parse::<UnicodeUsername>(),
+            Err(TryIntoUnicodeUsernameError::Empty)
+        ));
+    }
+
+    #[test]
+    fn limits_graphemes() {
+        // Each is two code points, but one grapheme.
+        let name = "e\u{301}".repeat(MAX_GRAPHEMES);
+        assert!(name.parse::<UnicodeUsername>().is_ok());
+        assert!(matches!(
+            format!("{}a", name).parse::<UnicodeUsername>(),
+            Err(TryIntoUnicodeUsernameError::UsernameTooLong)
+        ));
+    }
+}
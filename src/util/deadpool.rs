@@ -2,7 +2,8 @@ use std::ops::{Deref, DerefMut};
 use std::str::FromStr;
 
 use async_trait::async_trait;
-use deadpool::managed::{Manager, PoolError, RecycleResult};
+use deadpool::managed::{BuildError, Manager, PoolConfig, PoolError, RecycleResult};
+use deadpool::Runtime;
 use sqlx::postgres::PgConnectOptions;
 use sqlx::{ConnectOptions, Connection, Error as SqlxError, PgConnection};
 
@@ -17,6 +18,26 @@ impl PgPool {
     ) -> Result<deadpool::managed::Object<PgHandle>, PoolError<SqlxError>> {
         self.0.get().await
     }
+
+    /// Pre-establishes up to `count` connections so that the first requests after startup
+    /// don't pay for connection establishment.
+    pub async fn warmup(&self, count: usize) -> Result<(), PoolError<SqlxError>> {
+        let count = count.min(self.0.status().max_size);
+
+        // Hold every connection until the end, otherwise the pool keeps handing back the same one.
+        let mut conns = Vec::with_capacity(count);
+        for _ in 0..count {
+            conns.push(self.0.get().await?);
+        }
+
+        Ok(())
+    }
+
+    /// How many connections the pool has and how many are idle, e.g. for metrics. While every
+    /// connection is in use, `available` is minus the number of callers waiting for one.
+    pub fn status(&self) -> deadpool::Status {
+        self.0.status()
+    }
 }
 
 impl Deref for PgPool {
@@ -44,10 +65,55 @@ pub struct PgHandle {
 }
 
 impl PgPool {
+    /// A pool of up to `size` connections. Acquiring waits as long as it takes, so prefer
+    /// [`PgPool::with_config`] with timeouts where the database may be unreachable.
     pub fn new(url: String, size: usize) -> PgPool {
         let manager = PgHandle { url };
         PgPool(Pool::builder(manager).max_size(size).build().unwrap())
     }
+
+    /// A pool configured by `config`, whose `timeouts` bound how long [`PgPool::acquire`] waits
+    /// for a free slot (`wait`), to connect (`create`) and to check an idle connection
+    /// (`recycle`). Once one passes, acquiring fails with [`PoolError::Timeout`].
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// use deadpool::managed::{PoolConfig, Timeouts};
+    /// use thetc_auth::PgPool;
+    ///
+    /// let pool = PgPool::with_config(
+    ///     "postgres://localhost/app".into(),
+    ///     PoolConfig {
+    ///         timeouts: Timeouts {
+    ///             wait: Some(Duration::from_secs(5)),
+    ///             create: Some(Duration::from_secs(5)),
+    ///             recycle: Some(Duration::from_secs(5)),
+    ///         },
+    ///         ..PoolConfig::new(16)
+    ///     },
+    /// )
+    /// .unwrap();
+    /// ```
+    pub fn with_config(url: String, config: PoolConfig) -> Result<PgPool, BuildError<SqlxError>> {
+        let manager = PgHandle { url };
+        // Timeouts need a runtime to time with.
+        let pool = Pool::builder(manager)
+            .config(config)
+            .runtime(Runtime::Tokio1)
+            .build()?;
+        Ok(PgPool(pool))
+    }
+}
+
+#[async_trait]
+impl super::ConnSource for PgPool {
+    type Conn = deadpool::managed::Object<PgHandle>;
+    type Error = PoolError<SqlxError>;
+
+    async fn acquire(&self) -> Result<Self::Conn, Self::Error> {
+        self.0.get().await
+    }
 }
 
 #[async_trait]
@@ -62,3 +128,65 @@ impl Manager for PgHandle {
         Ok(obj.ping().await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use deadpool::managed::{TimeoutType, Timeouts};
+
+    use super::*;
+
+    #[test]
+    fn warmup() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                let pool = PgPool::new(crate::util::test_db::database_url(), 4);
+
+                pool.warmup(3).await.unwrap();
+                let status = pool.status();
+                assert_eq!((status.size, status.available), (3, 3));
+
+                // No more than the pool holds, rather than waiting forever for a free slot.
+                pool.warmup(10).await.unwrap();
+                let status = pool.status();
+                assert_eq!((status.size, status.available), (4, 4));
+            });
+    }
+
+    #[test]
+    fn acquire_times_out() {
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(async move {
+                // Accepts connections but never answers, like a wedged database.
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let url = format!(
+                    "postgres://postgres@{}/postgres",
+                    listener.local_addr().unwrap()
+                );
+
+                let pool = PgPool::with_config(
+                    url,
+                    PoolConfig {
+                        timeouts: Timeouts {
+                            wait: Some(Duration::from_millis(200)),
+                            create: Some(Duration::from_millis(200)),
+                            recycle: Some(Duration::from_millis(200)),
+                        },
+                        ..PoolConfig::new(1)
+                    },
+                )
+                .unwrap();
+
+                let started = Instant::now();
+                let result = pool.acquire().await;
+                assert!(matches!(
+                    result,
+                    Err(PoolError::Timeout(TimeoutType::Create))
+                ));
+                assert!(started.elapsed() < Duration::from_secs(5));
+            });
+    }
+}
@@ -1,2 +1,87 @@
 #[cfg(feature = "deadpool")]
 pub mod deadpool;
+#[cfg(test)]
+pub mod test_db;
+
+use std::ops::DerefMut;
+
+use async_trait::async_trait;
+use sqlx::PgConnection;
+
+/// A source of Postgres connections, so that backends can be written once for both sqlx's
+/// [`sqlx::PgPool`] and the deadpool based `PgPool`.
+#[async_trait]
+pub trait ConnSource: Send + Sync {
+    type Conn: DerefMut<Target = PgConnection> + Send;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    async fn acquire(&self) -> Result<Self::Conn, Self::Error>;
+}
+
+#[async_trait]
+impl ConnSource for sqlx::PgPool {
+    type Conn = sqlx::pool::PoolConnection<sqlx::Postgres>;
+    type Error = sqlx::Error;
+
+    async fn acquire(&self) -> Result<Self::Conn, Self::Error> {
+        sqlx::Pool::acquire(self).await
+    }
+}
+
+/// Escapes LIKE metacharacters so that `input` only ever matches literally, with `\\` as the
+/// escape character.
+pub(crate) fn escape_like(input: &str) -> String {
+    let mut escaped = String::with_capacity(input.len());
+    for c in input.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Pre-establishes up to `count` connections on a sqlx pool so that the first requests after
+/// startup don't pay for connection establishment.
+pub async fn warmup_pg_pool(pool: &sqlx::PgPool, count: usize) -> Result<(), sqlx::Error> {
+    let count = max_connections(pool).map_or(count, |max| count.min(max));
+
+    // Hold every connection until the end, otherwise the pool keeps handing back the same one.
+    let mut conns = Vec::with_capacity(count);
+    for _ in 0..count {
+        conns.push(pool.acquire().await?);
+    }
+
+    Ok(())
+}
+
+/// sqlx 0.6 has no getter for the pool's max connections, only its `Debug` output carries it.
+fn max_connections(pool: &sqlx::PgPool) -> Option<usize> {
+    let options = format!("{:?}", pool.options());
+    let (_, rest) = options.split_once("max_connections: ")?;
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::warmup_pg_pool;
+
+    #[test]
+    fn warmup_leaves_idle_connections() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            let pool = super::test_db::empty_pool().await;
+            assert!(pool.size() < 3);
+
+            warmup_pg_pool(&pool, 3).await.unwrap();
+            assert!(pool.size() >= 3);
+
+            // No more than the pool holds, rather than waiting for a free slot until timing out.
+            warmup_pg_pool(&pool, 10).await.unwrap();
+            assert_eq!(pool.size(), 4);
+        });
+    }
+}
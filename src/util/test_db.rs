@@ -0,0 +1,119 @@
+//! Throwaway Postgres schemas and Redis connections for tests that need them, and the strategy
+//! they share.
+//!
+//! The backend tests need `DATABASE_URL` and `REDIS_URL` to point at a Postgres and a Redis
+//! they may write to, and fail without, rather than pass without having checked anything.
+
+use secrecy::Secret;
+use sqlx::{
+    postgres::{PgConnectOptions, PgPoolOptions},
+    ConnectOptions, Executor, PgPool,
+};
+
+use crate::password_strategy::Argon2idStrategy;
+
+/// The value of the environment variable `name`, which the backend tests can't do without.
+fn required_var(name: &str) -> String {
+    std::env::var(name).unwrap_or_else(|_| {
+        panic!(
+            "{} must be set to run the backend tests, see src/util/test_db.rs",
+            name
+        )
+    })
+}
+
+/// The Postgres the tests create their schemas in.
+pub fn database_url() -> String {
+    required_var("DATABASE_URL")
+}
+
+/// A cheap strategy, so that tests don't spend their time hashing.
+pub fn strategy() -> Argon2idStrategy {
+    Argon2idStrategy::new(Secret::new("hello pepper is my friend".into()), 15, 2, 1).unwrap()
+}
+
+/// A pool whose connections use a fresh schema set up by [`crate::schema::migrate`].
+pub async fn pool() -> PgPool {
+    let pool = empty_pool().await;
+    crate::schema::migrate(&pool).await.unwrap();
+    pool
+}
+
+/// Like [`pool`], but leaves the schema empty.
+pub async fn empty_pool() -> PgPool {
+    let url = database_url();
+    let schema = format!("test_{}", uuid::Uuid::new_v4().simple());
+
+    let mut conn = url
+        .parse::<PgConnectOptions>()
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    // Extensions are per database, so tests running in parallel would race creating it.
+    conn.execute("SELECT pg_advisory_lock(7435)").await.unwrap();
+    conn.execute("CREATE EXTENSION IF NOT EXISTS citext SCHEMA public")
+        .await
+        .unwrap();
+    conn.execute("SELECT pg_advisory_unlock(7435)")
+        .await
+        .unwrap();
+    conn.execute(&*format!("CREATE SCHEMA {}", schema))
+        .await
+        .unwrap();
+
+    let options = url
+        .parse::<PgConnectOptions>()
+        .unwrap()
+        .options([("search_path", format!("{},public", schema))]);
+    PgPoolOptions::new()
+        .max_connections(4)
+        .connect_with(options)
+        .await
+        .unwrap()
+}
+
+/// A pool for the Redis at `REDIS_URL`. Tests share the Redis, so they must use keys of their
+/// own.
+pub fn redis_pool() -> deadpool_redis::Pool {
+    let config = deadpool_redis::Config::from_url(required_var("REDIS_URL"));
+    config
+        .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+        .unwrap()
+}
+
+/// A pool for a fresh in-memory SQLite database with the users table of
+/// [`crate::schema::sqlite_users`] set up, named `users`.
+#[cfg(feature = "sqlite")]
+pub async fn sqlite_pool() -> sqlx::SqlitePool {
+    // Every connection to `:memory:` opens a database of its own, so there must only ever be
+    // the one.
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    pool.execute(&*crate::schema::sqlite_users("users"))
+        .await
+        .unwrap();
+    pool
+}
+
+/// A deadpool pool of up to `size` connections to the schema `pool` uses, see [`pool`].
+#[cfg(feature = "deadpool")]
+pub async fn deadpool_pool(pool: &PgPool, size: usize) -> crate::util::deadpool::PgPool {
+    let url = database_url();
+    let search_path: String = sqlx::query_scalar("SHOW search_path")
+        .fetch_one(pool)
+        .await
+        .unwrap();
+    let url = format!(
+        "{}{}options=-c%20search_path%3D{}",
+        url,
+        if url.contains('?') { '&' } else { '?' },
+        search_path.replace(' ', ""),
+    );
+    crate::util::deadpool::PgPool::new(url, size)
+}
@@ -0,0 +1,376 @@
+#![cfg(feature = "axum")]
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use axum::{
+    body::Body,
+    http::{header, Request, StatusCode},
+    routing::get,
+    Extension, Router,
+};
+use chrono::Duration;
+use secrecy::Secret;
+use thetc_auth::{
+    appauth::{AppAuth, AppAuthBackend, AppAuthId, NewAppAuth, Unsupported},
+    axum::{
+        AppAuthPrincipal, AppAuthState, AuthError, AuthenticatedUser, SessionAuth, SessionIdSource,
+        SessionLayer,
+    },
+    session::{memory, redis},
+};
+use tower::ServiceExt;
+
+async fn me(user: AuthenticatedUser<uuid::Uuid>) -> String {
+    user.user_id.to_string()
+}
+
+async fn app_name(AppAuthPrincipal(app_auth): AppAuthPrincipal) -> String {
+    app_auth.name
+}
+
+async fn me_loaded(Extension(user): Extension<AuthenticatedUser<uuid::Uuid>>) -> String {
+    user.user_id.to_string()
+}
+
+async fn maybe_me(user: Option<Extension<AuthenticatedUser<uuid::Uuid>>>) -> String {
+    match user {
+        Some(Extension(user)) => user.user_id.to_string(),
+        None => "anonymous".into(),
+    }
+}
+
+async fn body_string(response: axum::response::Response) -> String {
+    use axum::body::HttpBody;
+
+    let mut body = response.into_body();
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.unwrap());
+    }
+    String::from_utf8(bytes).unwrap()
+}
+
+fn get_request(header: Option<(header::HeaderName, String)>) -> Request<Body> {
+    let mut request = Request::builder().uri("/");
+    if let Some((name, value)) = header {
+        request = request.header(name, value);
+    }
+    request.body(Body::empty()).unwrap()
+}
+
+#[test]
+fn session_cookie_resolves_user() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let manager = Arc::new(memory::SessionManager::new(
+            true,
+            Duration::minutes(5),
+            memory::Backend::default(),
+        ));
+        let user_id = uuid::Uuid::new_v4();
+        let session = manager.new_session(user_id).await.unwrap();
+
+        let app = Router::new()
+            .route("/", get(me))
+            .with_state(SessionAuth::new(
+                manager,
+                SessionIdSource::Cookie("sid".into()),
+            ));
+
+        let cookie = format!("theme=dark; sid={}", session.id);
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::COOKIE, cookie))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let unknown = format!("sid={}", uuid::Uuid::new_v4());
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::COOKIE, unknown))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::COOKIE, "sid=garbage".into()))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(get_request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    });
+}
+
+#[test]
+fn expired_session_is_rejected() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let manager = Arc::new(memory::SessionManager::new(
+            false,
+            Duration::milliseconds(50),
+            memory::Backend::default(),
+        ));
+        let session = manager.new_session(uuid::Uuid::new_v4()).await.unwrap();
+
+        let app = Router::new()
+            .route("/", get(me))
+            .with_state(SessionAuth::new(
+                manager,
+                SessionIdSource::Cookie("sid".into()),
+            ));
+        let cookie = format!("sid={}", session.id);
+
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::COOKIE, cookie.clone()))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        let response = app
+            .oneshot(get_request(Some((header::COOKIE, cookie))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    });
+}
+
+#[derive(Debug, thiserror::Error)]
+enum FixedTokenError {
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("backend unavailable")]
+    Unavailable,
+    #[error(transparent)]
+    Unsupported(#[from] Unsupported),
+}
+
+impl AuthError for FixedTokenError {
+    fn is_unauthorized(&self) -> bool {
+        matches!(self, FixedTokenError::InvalidToken)
+    }
+}
+
+/// Knows a single bearer token, unless it is down.
+struct FixedToken {
+    token: String,
+    down: bool,
+}
+
+#[async_trait]
+impl AppAuthBackend for FixedToken {
+    type Error = FixedTokenError;
+
+    async fn create_appauth(&self, _app_auth: NewAppAuth) -> Result<AppAuth, Self::Error> {
+        Err(Unsupported.into())
+    }
+
+    async fn verify_token(&self, _id: AppAuthId, _token: &str) -> Result<(), Self::Error> {
+        Err(FixedTokenError::InvalidToken)
+    }
+
+    async fn authenticate_bearer(&self, bearer: &str) -> Result<AppAuth, Self::Error> {
+        if self.down {
+            return Err(FixedTokenError::Unavailable);
+        }
+        if bearer != self.token {
+            return Err(FixedTokenError::InvalidToken);
+        }
+
+        Ok(AppAuth {
+            id: AppAuthId::from_token(bearer).unwrap(),
+            name: "integration".into(),
+            description: None,
+            token: Secret::new(bearer.into()),
+            token_hint: String::new(),
+            signing_secret: None,
+            meta: Default::default(),
+            expires_at: None,
+            rate_limit: None,
+            remaining_quota: None,
+            scopes: Vec::new(),
+            last_used_at: None,
+            version: 0,
+        })
+    }
+}
+
+#[test]
+fn bearer_token_resolves_app_auth() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (_, token) = NewAppAuth::generate("integration".into(), None, Default::default(), None);
+        let token = secrecy::ExposeSecret::expose_secret(&token).clone();
+
+        let app = Router::new()
+            .route("/", get(app_name))
+            .with_state(AppAuthState::new(Arc::new(FixedToken {
+                token: token.clone(),
+                down: false,
+            })));
+
+        let authorization = format!("Bearer {}", token);
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::AUTHORIZATION, authorization))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((
+                header::AUTHORIZATION,
+                "Bearer tca_wrong".into(),
+            ))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app.oneshot(get_request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    });
+}
+
+#[test]
+fn app_auth_backend_failure_is_not_unauthorized() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let (_, token) = NewAppAuth::generate("integration".into(), None, Default::default(), None);
+        let token = secrecy::ExposeSecret::expose_secret(&token).clone();
+
+        let app = Router::new()
+            .route("/", get(app_name))
+            .with_state(AppAuthState::new(Arc::new(FixedToken {
+                token: token.clone(),
+                down: true,
+            })));
+
+        let authorization = format!("Bearer {}", token);
+        let response = app
+            .oneshot(get_request(Some((header::AUTHORIZATION, authorization))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    });
+}
+
+#[test]
+fn session_backend_failure_is_not_unauthorized() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        // Nothing listens there, so every lookup fails to connect.
+        let manager = Arc::new(redis::SessionManager::new(
+            true,
+            Duration::minutes(5),
+            redis::Backend::new("redis://127.0.0.1:1").unwrap(),
+        ));
+        let cookie = format!("sid={}", uuid::Uuid::new_v4());
+
+        let app = Router::new()
+            .route("/", get(me))
+            .with_state(SessionAuth::new(
+                manager.clone(),
+                SessionIdSource::Cookie("sid".into()),
+            ));
+        let response = app
+            .oneshot(get_request(Some((header::COOKIE, cookie.clone()))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Not even routes open to anonymous requests carry on as if nobody was signed in.
+        let app = Router::new().route("/", get(maybe_me)).layer(
+            SessionLayer::new(manager, SessionIdSource::Cookie("sid".into()))
+                .with_anonymous_requests(),
+        );
+        let response = app
+            .oneshot(get_request(Some((header::COOKIE, cookie))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    });
+}
+
+#[test]
+fn session_layer_loads_session() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let manager = Arc::new(memory::SessionManager::new(
+            false,
+            Duration::milliseconds(200),
+            memory::Backend::default(),
+        ));
+        let user_id = uuid::Uuid::new_v4();
+        let session = manager.new_session(user_id).await.unwrap();
+
+        let app = Router::new()
+            .route("/", get(me_loaded))
+            .layer(SessionLayer::new(
+                manager,
+                SessionIdSource::Cookie("sid".into()),
+            ));
+        let cookie = format!("sid={}", session.id);
+
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((header::COOKIE, cookie.clone()))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, user_id.to_string());
+
+        let response = app.clone().oneshot(get_request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+        let response = app
+            .oneshot(get_request(Some((header::COOKIE, cookie))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    });
+}
+
+#[test]
+fn session_layer_allows_anonymous_requests() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async move {
+        let manager = Arc::new(memory::SessionManager::new(
+            true,
+            Duration::minutes(5),
+            memory::Backend::default(),
+        ));
+        let user_id = uuid::Uuid::new_v4();
+        let session = manager.new_session(user_id).await.unwrap();
+
+        let app = Router::new().route("/", get(maybe_me)).layer(
+            SessionLayer::new(manager, SessionIdSource::Cookie("sid".into()))
+                .with_anonymous_requests(),
+        );
+
+        let response = app
+            .clone()
+            .oneshot(get_request(Some((
+                header::COOKIE,
+                format!("sid={}", session.id),
+            ))))
+            .await
+            .unwrap();
+        assert_eq!(body_string(response).await, user_id.to_string());
+
+        let unknown = format!("sid={}", uuid::Uuid::new_v4());
+        let response = app
+            .oneshot(get_request(Some((header::COOKIE, unknown))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_string(response).await, "anonymous");
+    });
+}